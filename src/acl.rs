@@ -0,0 +1,251 @@
+//! Access control lists deciding which destinations the server opens flows to for clients.
+//!
+//! A rule is written as `ACTION PROTOCOL NETWORK [PORTS]`, e.g. `allow tcp 10.0.0.0/8 5432`
+//! or `deny any 0.0.0.0/0 25-26`. The first matching rule wins. The default rules, which deny
+//! loopback, link-local and private networks, come after all others, so any of these can be
+//! allowed explicitly. Anything no rule matches is allowed.
+
+use core::fmt;
+use core::ops::RangeInclusive;
+use core::str::FromStr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Denied unless allowed by an earlier rule. `0.0.0.0/8` and `::/128` are included as
+/// connecting to them reaches the server itself.
+const DEFAULT_RULES: &[Rule] = &[
+	Rule::deny(Network::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8)),
+	Rule::deny(Network::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)), 8)),
+	Rule::deny(Network::new(IpAddr::V4(Ipv4Addr::new(169, 254, 0, 0)), 16)),
+	Rule::deny(Network::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8)),
+	Rule::deny(Network::new(IpAddr::V4(Ipv4Addr::new(172, 16, 0, 0)), 12)),
+	Rule::deny(Network::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 0)), 16)),
+	Rule::deny(Network::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 128)),
+	Rule::deny(Network::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 128)),
+	Rule::deny(Network::new(IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0)), 10)),
+	Rule::deny(Network::new(IpAddr::V6(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0)), 7)),
+];
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Acl {
+	rules: Vec<Rule>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+	action: Action,
+	/// The protocol the rule applies to, or `None` for all.
+	protocol: Option<Protocol>,
+	network: Network,
+	/// Ignored for ICMP, which has no ports.
+	ports: RangeInclusive<u16>,
+}
+
+/// An address with a prefix length, e.g. `10.0.0.0/8`, or a single address without one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Network {
+	address: IpAddr,
+	prefix_len: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+	Allow,
+	Deny,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+	Tcp,
+	Udp,
+	Icmp,
+}
+
+impl Acl {
+	/// Add the rules of `other` after all others, but before the default rules.
+	pub fn extend(&mut self, other: Self) {
+		self.rules.extend(other.rules);
+	}
+
+	/// Whether flows of the protocol may be opened to the address.
+	pub fn allows(&self, protocol: Protocol, address: SocketAddr) -> bool {
+		self.rules
+			.iter()
+			.chain(DEFAULT_RULES)
+			.find(|r| r.matches(protocol, address))
+			.is_none_or(|r| r.action == Action::Allow)
+	}
+}
+
+impl Rule {
+	const fn deny(network: Network) -> Self {
+		Self { action: Action::Deny, protocol: None, network, ports: 0..=u16::MAX }
+	}
+
+	fn matches(&self, protocol: Protocol, address: SocketAddr) -> bool {
+		self.protocol.is_none_or(|p| p == protocol)
+			&& (protocol == Protocol::Icmp || self.ports.contains(&address.port()))
+			&& self.network.contains(address.ip())
+	}
+}
+
+impl Network {
+	pub const fn new(address: IpAddr, prefix_len: u8) -> Self {
+		Self { address, prefix_len }
+	}
+
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		// Otherwise ::ffff:127.0.0.1 would slip past 127.0.0.0/8.
+		let ip = match ip {
+			IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+			ip => ip,
+		};
+		match (self.address, ip) {
+			(IpAddr::V4(n), IpAddr::V4(a)) => prefix_eq(u32::from(n).into(), u32::from(a).into(), 32, self.prefix_len),
+			(IpAddr::V6(n), IpAddr::V6(a)) => prefix_eq(n.into(), a.into(), 128, self.prefix_len),
+			_ => false,
+		}
+	}
+}
+
+/// Whether the first `prefix_len` of the `bits` lowest bits of both numbers are equal.
+fn prefix_eq(a: u128, b: u128, bits: u8, prefix_len: u8) -> bool {
+	prefix_len == 0 || (a ^ b) >> (bits - prefix_len) == 0
+}
+
+impl FromStr for Rule {
+	type Err = ParseError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut words = s.split_whitespace();
+		let action = match words.next() {
+			Some("allow") => Action::Allow,
+			Some("deny") => Action::Deny,
+			_ => return Err(ParseError::Action),
+		};
+		let protocol = match words.next() {
+			Some("tcp") => Some(Protocol::Tcp),
+			Some("udp") => Some(Protocol::Udp),
+			Some("icmp") => Some(Protocol::Icmp),
+			Some("any") => None,
+			_ => return Err(ParseError::Protocol),
+		};
+		let network = words.next().ok_or(ParseError::Network)?.parse().map_err(|_| ParseError::Network)?;
+		let ports = match words.next() {
+			Some(p) => {
+				let (start, end) = p.split_once('-').unwrap_or((p, p));
+				let (start, end) = start.parse::<u16>().ok().zip(end.parse::<u16>().ok()).ok_or(ParseError::Ports)?;
+				(start <= end).then_some(start..=end).ok_or(ParseError::Ports)?
+			}
+			None => 0..=u16::MAX,
+		};
+		if words.next().is_some() {
+			return Err(ParseError::Trailing);
+		}
+		Ok(Self { action, protocol, network, ports })
+	}
+}
+
+impl FromStr for Network {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (address, prefix_len) = match s.split_once('/') {
+			Some((a, len)) => (a, Some(len)),
+			None => (s, None),
+		};
+		let address = address.parse::<IpAddr>().map_err(|_| ())?;
+		let bits = if address.is_ipv4() { 32 } else { 128 };
+		let prefix_len = match prefix_len {
+			Some(len) => len.parse().ok().filter(|len| *len <= bits).ok_or(())?,
+			None => bits,
+		};
+		Ok(Self { address, prefix_len })
+	}
+}
+
+impl From<Rule> for Acl {
+	fn from(rule: Rule) -> Self {
+		Self { rules: vec![rule] }
+	}
+}
+
+impl fmt::Display for Protocol {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			Self::Tcp => "TCP",
+			Self::Udp => "UDP",
+			Self::Icmp => "ICMP",
+		})
+	}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+	Action,
+	Protocol,
+	Network,
+	Ports,
+	Trailing,
+}
+
+impl fmt::Display for ParseError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			Self::Action => "expected allow or deny",
+			Self::Protocol => "expected tcp, udp, icmp or any",
+			Self::Network => "expected an address with an optional prefix length",
+			Self::Ports => "expected a port or range of ports",
+			Self::Trailing => "unexpected text after the ports",
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn acl(rules: &[&str]) -> Acl {
+		let mut acl = Acl::default();
+		for r in rules {
+			acl.extend(r.parse::<Rule>().unwrap().into());
+		}
+		acl
+	}
+
+	#[test]
+	fn defaults() {
+		let acl = Acl::default();
+		assert!(acl.allows(Protocol::Tcp, "1.1.1.1:443".parse().unwrap()));
+		assert!(acl.allows(Protocol::Udp, "[2606:4700::1111]:53".parse().unwrap()));
+		assert!(acl.allows(Protocol::Tcp, "172.32.0.1:80".parse().unwrap()));
+		for a in ["127.0.0.1:22", "0.0.0.0:22", "10.1.2.3:80", "172.16.0.1:80", "192.168.1.1:53", "169.254.169.254:80", "[::1]:22", "[::]:22", "[fe80::1]:22", "[fd00::1]:22", "[::ffff:127.0.0.1]:22"] {
+			assert!(!acl.allows(Protocol::Tcp, a.parse().unwrap()), "{}", a);
+		}
+		assert!(!acl.allows(Protocol::Icmp, "127.0.0.1:0".parse().unwrap()));
+	}
+
+	#[test]
+	fn rules() {
+		assert!(acl(&["allow any 0.0.0.0/0"]).allows(Protocol::Tcp, "127.0.0.1:22".parse().unwrap()));
+		let acl = acl(&["allow tcp 10.1.0.0/16 5432", "deny any 0.0.0.0/0 25", "allow udp 192.168.1.1 53-54", "deny icmp ::/0 7"]);
+		assert!(acl.allows(Protocol::Tcp, "10.1.2.3:5432".parse().unwrap()));
+		assert!(!acl.allows(Protocol::Tcp, "10.1.2.3:5433".parse().unwrap()));
+		assert!(!acl.allows(Protocol::Udp, "10.1.2.3:5432".parse().unwrap()));
+		assert!(!acl.allows(Protocol::Tcp, "1.1.1.1:25".parse().unwrap()));
+		assert!(acl.allows(Protocol::Udp, "192.168.1.1:54".parse().unwrap()));
+		assert!(!acl.allows(Protocol::Udp, "192.168.1.2:53".parse().unwrap()));
+		// Ports don't apply to ICMP.
+		assert!(!acl.allows(Protocol::Icmp, "[2001:db8::1]:1".parse().unwrap()));
+	}
+
+	#[test]
+	fn parse() {
+		assert_eq!("allow tcp".parse::<Rule>(), Err(ParseError::Network));
+		assert_eq!("permit tcp 10.0.0.0/8".parse::<Rule>(), Err(ParseError::Action));
+		assert_eq!("allow sctp 10.0.0.0/8".parse::<Rule>(), Err(ParseError::Protocol));
+		assert_eq!("allow tcp 10.0.0.0/33".parse::<Rule>(), Err(ParseError::Network));
+		assert_eq!("allow tcp 10.0.0.0/8 90-80".parse::<Rule>(), Err(ParseError::Ports));
+		assert_eq!("allow tcp 10.0.0.0/8 80 81".parse::<Rule>(), Err(ParseError::Trailing));
+		assert!("deny udp fd00::/8 53".parse::<Rule>().is_ok());
+	}
+}
@@ -1,35 +1,154 @@
+//! Internet checksum ([RFC 1071](https://datatracker.ietf.org/doc/html/rfc1071))
+//!
+//! Data is summed 4 bytes at a time in native byte order into a 64 bit accumulator, which
+//! the compiler can vectorize. The byte order independence of the one's complement sum
+//! means only the final result needs to be swapped.
+
 pub struct Checksum {
-	odd_byte: Option<u8>,
-	sum: usize,
+	sum: u64,
+	/// Whether an odd amount of bytes has been fed so far.
+	odd: bool,
 }
 
 impl Checksum {
 	pub const fn new() -> Self {
 		Self {
-			odd_byte: None,
 			sum: 0,
+			odd: false,
 		}
 	}
 
-	pub fn feed(&mut self, iter: impl IntoIterator<Item = u8>) -> &mut Self {
-		for x in iter {
-			match self.odd_byte.take() {
-				Some(y) => self.sum += usize::from(y) << 8 | usize::from(x),
-				None => self.odd_byte = Some(x),
-			}
-		}
+	pub fn feed(&mut self, data: &[u8]) -> &mut Self {
+		let sum = sum(data);
+		// If the data starts at an odd offset every byte ends up in the other half of a word,
+		// which is the same as swapping the bytes of the sum.
+		self.sum += u64::from(if self.odd { sum.swap_bytes() } else { sum });
+		self.odd ^= data.len() % 2 != 0;
 		self
 	}
 
-	pub fn feed_ref<'a>(&mut self, iter: impl IntoIterator<Item = &'a u8>) -> &mut Self {
-		self.feed(iter.into_iter().copied())
+	pub fn finish(&self) -> u16 {
+		!fold(self.sum)
+	}
+
+	/// Incrementally update a checksum after some data covered by it changed, as described in
+	/// [RFC 1624](https://datatracker.ietf.org/doc/html/rfc1624).
+	///
+	/// The old and new data must have the same length and start at an even offset.
+	pub fn update(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+		debug_assert_eq!(old.len(), new.len(), "old and new data differ in length");
+		// HC' = ~(~HC + ~m + m')
+		let sum = u64::from(!checksum) + u64::from(!sum(old)) + u64::from(sum(new));
+		!fold(sum)
+	}
+}
+
+/// Sum the data as big-endian 16 bit words.
+fn sum(data: &[u8]) -> u16 {
+	let words = data.chunks_exact(4);
+	let rem = words.remainder();
+	// Each word is less than 2^32, so this can't overflow unless there are more than 2^32 words.
+	let mut sum = words
+		.map(|w| u64::from(u32::from_ne_bytes(w.try_into().unwrap())))
+		.sum::<u64>();
+	for w in rem.chunks(2) {
+		let w = [w[0], w.get(1).copied().unwrap_or(0)];
+		sum += u64::from(u16::from_ne_bytes(w));
+	}
+	// The bytes are in memory order, so interpret them as big-endian.
+	u16::from_be_bytes(fold(sum).to_ne_bytes())
+}
+
+/// Fold the carries back into the lower 16 bits.
+fn fold(mut sum: u64) -> u16 {
+	while sum > 0xffff {
+		sum = (sum & 0xffff) + (sum >> 16);
+	}
+	sum as u16
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Straightforward implementation of RFC 1071
+	fn reference(data: &[u8]) -> u16 {
+		let mut sum = 0u64;
+		for w in data.chunks(2) {
+			sum += u64::from(w[0]) << 8 | u64::from(w.get(1).copied().unwrap_or(0));
+		}
+		while sum > 0xffff {
+			sum = (sum & 0xffff) + (sum >> 16);
+		}
+		!(sum as u16)
+	}
+
+	/// xorshift64
+	struct Rng(u64);
+
+	impl Rng {
+		fn next(&mut self) -> u64 {
+			self.0 ^= self.0 << 13;
+			self.0 ^= self.0 >> 7;
+			self.0 ^= self.0 << 17;
+			self.0
+		}
+
+		fn below(&mut self, n: usize) -> usize {
+			(self.next() % n as u64) as usize
+		}
+
+		fn bytes(&mut self, len: usize) -> Vec<u8> {
+			(0..len).map(|_| self.next() as u8).collect()
+		}
+	}
+
+	#[test]
+	fn rfc1071_example() {
+		let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+		assert_eq!(Checksum::new().feed(&data).finish(), !0xddf2);
 	}
 
-	pub fn finish(&mut self) -> u16 {
-		let sum = self.sum + (usize::from(self.odd_byte.unwrap_or(0)) << 8);
-		// TODO
-		debug_assert!(sum <= u32::MAX as usize);
-		dbg!(sum);
-		(sum as u16 + (sum >> 16) as u16) ^ 0xffff
+	#[test]
+	fn large() {
+		let data = vec![0xff; 1 << 20];
+		assert_eq!(Checksum::new().feed(&data).finish(), reference(&data));
+		let data = vec![0xfe; (1 << 20) + 1];
+		assert_eq!(Checksum::new().feed(&data).finish(), reference(&data));
+	}
+
+	#[test]
+	fn split_feeds() {
+		let mut rng = Rng(0x2545f4914f6cdd1d);
+		for _ in 0..1000 {
+			let len = rng.below(3000);
+			let data = rng.bytes(len);
+			let mut c = Checksum::new();
+			let mut d = &data[..];
+			while !d.is_empty() {
+				let (l, r) = d.split_at(rng.below(d.len() + 1));
+				c.feed(l);
+				d = r;
+			}
+			assert_eq!(c.finish(), reference(&data), "{:?}", data);
+		}
+	}
+
+	#[test]
+	fn incremental_update() {
+		let mut rng = Rng(0x9e3779b97f4a7c15);
+		for _ in 0..1000 {
+			let len = rng.below(1500) + 16;
+			let mut data = rng.bytes(len);
+			let checksum = reference(&data);
+
+			let len = (rng.below(8) + 1) * 2;
+			let offset = rng.below((data.len() - len) / 2) * 2;
+			let new = rng.bytes(len);
+			let updated = Checksum::update(checksum, &data[offset..][..len], &new);
+			data[offset..][..len].copy_from_slice(&new);
+
+			assert_eq!(updated, reference(&data), "{:?}", data);
+		}
 	}
 }
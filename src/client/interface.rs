@@ -0,0 +1,586 @@
+//! The tun front end, which carries the packets of applications on this host.
+
+use super::*;
+use crate::control::{Counters, Line};
+use stupid::ConnectError;
+use std::net::{SocketAddrV6, Ipv6Addr};
+
+/// A TCP connection on the tun and the flow carrying it to the server.
+struct TcpFlow {
+	connection: tcp::Tcp6Connection,
+	/// The address of the other end of the flow.
+	remote: SocketAddrV4,
+	window: stupid::Window,
+	/// Data of the server the application has no room for yet.
+	unsent: VecDeque<u8>,
+	/// Whether the server finished the flow and the connection is to be closed once all data
+	/// has been sent.
+	finishing: bool,
+	opened: Instant,
+	counters: Counters,
+}
+
+impl TcpFlow {
+	fn new(connection: tcp::Tcp6Connection, remote: SocketAddrV4) -> Self {
+		Self {
+			connection,
+			remote,
+			window: stupid::Window::new(),
+			unsent: VecDeque::new(),
+			finishing: false,
+			opened: Instant::now(),
+			counters: Counters::default(),
+		}
+	}
+}
+
+pub struct Interface {
+	local_address: Ipv6Addr,
+	/// The prefix IPv4 addresses are mapped into.
+	prefix: [u8; 12],
+	init_seq_n: u32,
+	init_seq_n_offt: u32,
+	tun: tun::Tun,
+	tcp_connections: HashMap<u16, TcpFlow>,
+	mtu: usize,
+	verify_checksums: bool,
+	/// Packets dropped because of a bad TCP or UDP checksum.
+	bad_checksums: u64,
+	reassembler: fragment::Reassembler,
+	fragment_id: u32,
+	/// Whether packets were left in the tun because the server couldn't keep up.
+	blocked: bool,
+}
+
+impl Interface {
+	/// Create and configure the tun interface.
+	pub fn new(config: &config::ClientConfig) -> Result<Self, RunError> {
+		debug!("creating interface {}", config.tun_name);
+		let mut tun = tun::Tun::new(config.tun_name.as_bytes()).map_err(RunError::CreateTun)?;
+		tun.set_mtu(config.mtu).map_err(RunError::ConfigureTun)?;
+		let local_address = config.address;
+		debug!("adding address {}/96", local_address);
+		tun.add_ipv6_address(local_address, 96).map_err(RunError::ConfigureTun)?;
+		Ok(Self {
+			local_address,
+			prefix: config.prefix.octets()[..12].try_into().unwrap(),
+			init_seq_n: 2930232,
+			init_seq_n_offt: 239020923,
+			tun,
+			tcp_connections: HashMap::new(),
+			mtu: config.mtu,
+			verify_checksums: config.verify_checksums,
+			bad_checksums: 0,
+			reassembler: fragment::Reassembler::new(config.fragment_timeout, fragment::Reassembler::DEFAULT_MAX_BYTES),
+			fragment_id: 0,
+			blocked: false,
+		})
+	}
+
+	/// Handle all packets written to the tun.
+	fn handle_tun(&mut self, stupid: &mut StupidClient) -> Result<(), RunError> {
+		let mut buf = [0; 0x10000];
+		loop {
+			// Leave packets queued so they aren't acknowledged and lost.
+			if !stupid.is_connected() || !stupid.has_room() {
+				self.blocked = true;
+				return Ok(());
+			}
+			let len = match self.tun.read(&mut buf) {
+				Ok(len) => len,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+				Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+				Err(e) => return Err(RunError::Tun(e)),
+			};
+
+			let (header, extra) = match ip::IPv6Header::from_raw(&buf[..len]) {
+				Ok(r) => r,
+				Err(e) => {
+					debug!("dropping malformed packet: {:?}", e);
+					continue;
+				}
+			};
+			for h in header.extension_headers(extra) {
+				match h {
+					Ok(ip::Header::Upper(protocol, payload)) => self.handle_upper(stupid, header, protocol, payload)?,
+					Ok(ip::Header::Fragment(f, data)) => self.handle_fragment(stupid, header, &f, data)?,
+					Ok(_) => (),
+					Err(e) => debug!("bad extension headers: {:?}", e),
+				}
+			}
+		}
+	}
+
+	fn handle_fragment(&mut self, stupid: &mut StupidClient, header: &ip::IPv6Header, fragment: &ip::FragmentHeader, data: &[u8]) -> Result<(), RunError> {
+		let (header, payload) = match self.reassembler.insert(Instant::now(), header, fragment, data) {
+			Ok(Some(r)) => r,
+			Ok(None) => return Ok(()),
+			Err(e) => {
+				debug!("dropping fragment: {:?}", e);
+				return Ok(());
+			}
+		};
+		match header.extension_headers(&payload).upper_layer() {
+			Ok(Some((protocol, payload))) => return self.handle_upper(stupid, &header, protocol, payload),
+			Ok(None) => debug!("dropping nested fragment"),
+			Err(e) => debug!("bad extension headers: {:?}", e),
+		}
+		Ok(())
+	}
+
+	fn handle_upper(&mut self, stupid: &mut StupidClient, header: &ip::IPv6Header, protocol: u8, payload: &[u8]) -> Result<(), RunError> {
+		let d_ip = header.destination_address().octets();
+		if d_ip[..12] != self.prefix {
+			// e.g. multicast, which can't be mapped to an IPv4 address.
+			return Ok(());
+		}
+		let d_ip = net::Ipv4Addr::from(<[u8; 4]>::try_from(&d_ip[12..]).unwrap());
+
+		match protocol {
+			6 => {
+				let tcp = if self.verify_checksums {
+					tcp::TcpHeader::from_raw_ipv6(payload, header.source_address(), header.destination_address())
+				} else {
+					tcp::TcpHeader::from_raw_ipv6_unchecked(payload)
+				};
+				let (tcp, opt, data) = match tcp {
+					Ok(r) => r,
+					Err(tcp::FromRawError::BadChecksum) => {
+						self.bad_checksums += 1;
+						debug!("dropping TCP segment with bad checksum ({} total)", self.bad_checksums);
+						return Ok(());
+					}
+					Err(e) => {
+						debug!("dropping malformed TCP segment: {:?}", e);
+						return Ok(());
+					}
+				};
+
+				let d_port = tcp.destination();
+				let s_port = tcp.source();
+				let addr = SocketAddrV4::new(d_ip, d_port);
+				let ctx = Context::NONE.flow(s_port, addr);
+
+				let (src6, dst6) = (header.source_address(), header.destination_address());
+				let src4: [u8; 4] = src6.octets()[12..].try_into().unwrap();
+				let dst4: [u8; 4] = dst6.octets()[12..].try_into().unwrap();
+
+				let k = (
+					SocketAddrV4::new(src4.into(), tcp.source()),
+					SocketAddrV4::new(dst4.into(), tcp.destination()),
+				);
+
+				let mut out = [0; 0x10000];
+
+				match self.tcp_connections.entry(tcp.source()) {
+					Entry::Occupied(mut e) => {
+						let flow = e.get_mut();
+						// The window advertised to the application never exceeds the credit, so
+						// only a misbehaving application sends more.
+						if data.len() > flow.window.credit() {
+							debug!(ctx: ctx, "dropping TCP segment beyond the window");
+							return Ok(());
+						}
+						let acknowledged = flow.connection.acknowledge(tcp);
+						flow.connection.set_window(flow.window.credit() - data.len());
+						let mut remove = false;
+						let response = match flow.connection.receive(tcp, data, &mut out) {
+							Ok(r) => r,
+							Err(()) => {
+								debug!(ctx: ctx, "resetting TCP");
+								return self.reset_tcp(stupid, s_port, addr);
+							}
+						};
+						match response {
+							tcp::Response::Acknowledge(r) => {
+								trace!(ctx: ctx, "acknowledge");
+								self.tun.write(r).map_err(RunError::Tun)?;
+							},
+							tcp::Response::Finish(r) => {
+								debug!(ctx: ctx, "closing TCP");
+								self.tun.write(r).map_err(RunError::Tun)?;
+								remove = true;
+							},
+							tcp::Response::Finished(r) => {
+								debug!(ctx: ctx, "closed TCP");
+								self.tun.write(r).map_err(RunError::Tun)?;
+								remove = true;
+							},
+							tcp::Response::None => (),
+						}
+						for data in data.chunks(stupid.max_stream_data()) {
+							if let Err(e) = flow.window.sent(data.len()) {
+								debug!(ctx: ctx, "resetting TCP: {}", e);
+								return self.reset_tcp(stupid, s_port, addr);
+							}
+							flow.counters.sent(data.len());
+							stupid.send(StupidType::TCP, addr, s_port, data).map_err(RunError::Send)?;
+						}
+						// Data the application acknowledged is consumed, so the server may send more.
+						if let Some(update) = flow.window.consumed(acknowledged) {
+							stupid.send(StupidType::WindowUpdate, addr, s_port, &update).map_err(RunError::Send)?;
+						}
+						if remove {
+							stupid.send(StupidType::TcpFinish, addr, s_port, &[]).map_err(RunError::Send)?;
+							e.remove();
+						} else {
+							// The application may have room for more now.
+							self.write_tcp(stupid, s_port, addr)?;
+						}
+					}
+					Entry::Vacant(e) => {
+						let ip: [u8; 4] = header.destination_address().octets()[12..].try_into().unwrap();
+						let addr = SocketAddrV4::new(ip.into(), d_port);
+						let out = if tcp.flags.synchronize() {
+							debug!(ctx: ctx, "connecting TCP");
+							stupid.send(StupidType::TcpConnect, addr, s_port, &[]).map_err(RunError::Send)?;
+							let (conn, out) = tcp::Tcp6Connection::new(header, tcp, opt, self.init_seq_n, &mut out);
+							self.init_seq_n = self.init_seq_n.wrapping_add(self.init_seq_n_offt);
+							e.insert(TcpFlow::new(conn, addr));
+							out
+						} else {
+							debug!(ctx: ctx, "resetting segment for unknown TCP connection");
+							let tcp = tcp::TcpHeader::new(
+								(header.destination_address(), tcp.destination()),
+								(header.source_address(), tcp.source()),
+								0,
+								0,
+								tcp::Flags::new().set_reset(true),
+								0,
+								tcp::Options::NONE,
+								&[],
+								);
+							let ip = ip::IPv6Header::new(tcp.length(&[]).unwrap(), 6, 255, header.destination_address(), header.source_address());
+							out[..ip.byte_len()].copy_from_slice(ip.as_ref());
+							out[ip.byte_len()..][..tcp.byte_len()].copy_from_slice(tcp.as_ref());
+							&out[..ip.byte_len() + tcp.byte_len()]
+						};
+						self.tun.write(out).map_err(RunError::Tun)?;
+					}
+				}
+			}
+			17 => {
+				let udp = if self.verify_checksums {
+					udp::UDPHeader::from_raw_ipv6(payload, header.source_address(), header.destination_address())
+				} else {
+					udp::UDPHeader::from_raw_ipv6_unchecked(payload)
+				};
+				let (uh, data) = match udp {
+					Ok(r) => r,
+					Err(udp::FromRawError::BadChecksum) => {
+						self.bad_checksums += 1;
+						debug!("dropping UDP datagram with bad checksum ({} total)", self.bad_checksums);
+						return Ok(());
+					}
+					Err(e) => {
+						debug!("dropping malformed UDP datagram: {:?}", e);
+						return Ok(());
+					}
+				};
+
+				let d_port = uh.destination_port();
+				let s_port = uh.source_port();
+				let addr = SocketAddrV4::new(d_ip, d_port);
+
+				stupid.send(StupidType::UDP, addr, s_port, data).map_err(RunError::Send)?;
+			}
+			58 => {
+				match icmp::ICMPv6Echo::from_raw(payload) {
+					Ok((echo, data)) if echo.header().ty() == icmp::ICMPv6Header::ECHO_REQUEST => {
+						let addr = SocketAddrV4::new(d_ip, echo.sequence_num());
+						stupid.send(StupidType::IcmpEchoRequest, addr, echo.identifier(), data).map_err(RunError::Send)?;
+					}
+					// Neighbour discovery & other messages are handled by the kernel.
+					_ => (),
+				}
+			}
+			_ => (),
+		}
+		Ok(())
+	}
+
+	/// Abort a TCP connection on both the tun and the server.
+	fn reset_tcp(&mut self, stupid: &mut StupidClient, local_port: u16, remote: SocketAddrV4) -> Result<(), RunError> {
+		if let Some(mut flow) = self.tcp_connections.remove(&local_port) {
+			let mut out = [0; 0x100];
+			self.tun.write(flow.connection.reset(&mut out)).map_err(RunError::Tun)?;
+		}
+		stupid.send(StupidType::TcpFinish, remote, local_port, &[]).map_err(RunError::Send)
+	}
+
+	/// Send data of the server to the application as far as its window allows. The connection
+	/// is closed once all data is sent if the server finished the flow.
+	fn write_tcp(&mut self, stupid: &mut StupidClient, local_port: u16, remote: SocketAddrV4) -> Result<(), RunError> {
+		let flow = match self.tcp_connections.get_mut(&local_port) {
+			Some(flow) => flow,
+			None => return Ok(()),
+		};
+		let mut out = [0; 0x10100];
+		// Don't send segments larger than the tun can carry.
+		let max_segment_size = self.mtu - 40 - 20;
+		loop {
+			let data = flow.unsent.as_slices().0;
+			let len = data.len().min(max_segment_size).min(flow.connection.sendable());
+			if len == 0 {
+				break;
+			}
+			match flow.connection.send(&data[..len], &mut out) {
+				Ok(out) => drop(self.tun.write(out).map_err(RunError::Tun)?),
+				Err(()) => {
+					debug!(ctx: Context::NONE.flow(local_port, remote), "resetting TCP");
+					return self.reset_tcp(stupid, local_port, remote);
+				}
+			}
+			flow.unsent.drain(..len);
+		}
+		if flow.finishing && flow.unsent.is_empty() {
+			flow.finishing = false;
+			match flow.connection.close(&[], &mut out) {
+				Ok(out) => drop(self.tun.write(out).map_err(RunError::Tun)?),
+				Err(()) => return self.reset_tcp(stupid, local_port, remote),
+			}
+		}
+		Ok(())
+	}
+
+	/// Map an IPv4 address into the IPv6 prefix.
+	fn map_ipv4(&self, ip: Ipv4Addr) -> Ipv6Addr {
+		let mut addr = [0; 16];
+		addr[..12].copy_from_slice(&self.prefix);
+		addr[12..].copy_from_slice(&ip.octets());
+		addr.into()
+	}
+}
+
+impl Frontend for Interface {
+	fn register(&mut self, registry: &Registry) -> Result<(), Error> {
+		registry.register(&mut self.tun, Token(0), Interest::READABLE)
+	}
+
+	fn handle_event(&mut self, stupid: &mut StupidClient, _registry: &Registry, _token: Token) -> Result<(), RunError> {
+		self.handle_tun(stupid)
+	}
+
+	fn handle_frame(&mut self, stupid: &mut StupidClient, _registry: &Registry, ty: StupidType, h: &StupidDataHeader, data: &[u8]) -> Result<(), RunError> {
+		let ctx = Context::NONE.flow(h.local(), h.remote());
+		let mut out = [0; 0x10100];
+
+		match ty {
+			StupidType::UDP => {
+				let addr6 = SocketAddrV6::new(self.map_ipv4(*h.remote().ip()), h.remote().port(), 0, 0);
+				let local6 = SocketAddrV6::new(self.local_address, h.local(), 0, 0);
+
+				let udp = match udp::UDPHeader::new_ipv6(addr6, local6, data) {
+					Ok(udp) => udp,
+					Err(e) => {
+						debug!(ctx: ctx, "dropping UDP datagram: {:?}", e);
+						return Ok(());
+					}
+				};
+				let ip = ip::IPv6Header::new(udp.length(data).unwrap(), 17, 255, *addr6.ip(), self.local_address);
+
+				out[..ip.byte_len()].copy_from_slice(ip.as_ref());
+				out[ip.byte_len()..][..udp.byte_len()].copy_from_slice(udp.as_ref());
+				out[ip.byte_len()..][udp.byte_len()..][..data.len()].copy_from_slice(data);
+				let out = &out[..ip.byte_len() + udp.byte_len() + data.len()];
+
+				if out.len() <= self.mtu {
+					self.tun.write(out).map_err(RunError::Tun)?;
+				} else {
+					let mut frag = [0; 0x10000];
+					let mut f = fragment::Fragmenter::new(&ip, &out[ip.byte_len()..], self.mtu, self.fragment_id);
+					self.fragment_id = self.fragment_id.wrapping_add(1);
+					while let Some(p) = f.next_packet(&mut frag) {
+						self.tun.write(p).map_err(RunError::Tun)?;
+					}
+				}
+			}
+			StupidType::TcpConnect => (), // TODO only send SYN,ACK on receiving this
+			StupidType::TCP => {
+				let flow = match self.tcp_connections.get_mut(&h.local()) {
+					Some(flow) => flow,
+					None => {
+						debug!(ctx: ctx, "closing unknown TCP connection");
+						return stupid.send(StupidType::TcpFinish, h.remote(), h.local(), &[]).map_err(RunError::Send);
+					}
+				};
+				if let Err(e) = flow.window.received(data.len()) {
+					debug!(ctx: ctx, "resetting TCP: {}", e);
+					return self.reset_tcp(stupid, h.local(), h.remote());
+				}
+				flow.unsent.extend(data);
+				flow.counters.received(data.len());
+				return self.write_tcp(stupid, h.local(), h.remote());
+			}
+			StupidType::TcpFinish => {
+				let flow = match self.tcp_connections.get_mut(&h.local()) {
+					Some(flow) => flow,
+					None => return Ok(()),
+				};
+				if let Some(e) = ConnectError::from_data(data) {
+					debug!(ctx: ctx, "resetting TCP: {}", e);
+					self.tun.write(flow.connection.reset(&mut out)).map_err(RunError::Tun)?;
+					self.tcp_connections.remove(&h.local());
+					return Ok(());
+				}
+				debug!(ctx: ctx, "TCP connection closed by remote");
+				flow.finishing = true;
+				return self.write_tcp(stupid, h.local(), h.remote());
+			}
+			StupidType::WindowUpdate => {
+				let flow = match self.tcp_connections.get_mut(&h.local()) {
+					Some(flow) => flow,
+					// The connection may have been closed while the update was underway.
+					None => return Ok(()),
+				};
+				if let Err(e) = flow.window.update(data) {
+					debug!(ctx: ctx, "resetting TCP: {}", e);
+					return self.reset_tcp(stupid, h.local(), h.remote());
+				}
+				// Let the application know if it may send more than it was told.
+				if usize::from(flow.connection.window()) < flow.window.credit().min(0xffff) {
+					flow.connection.set_window(flow.window.credit());
+					self.tun.write(flow.connection.window_update(&mut out)).map_err(RunError::Tun)?;
+				}
+			}
+			StupidType::IcmpEchoReply => {
+				let addr = self.map_ipv4(*h.remote().ip());
+
+				let echo = match icmp::ICMPv6Echo::new_reply_ipv6(addr, self.local_address, h.local(), h.remote().port(), data) {
+					Ok(echo) => echo,
+					Err(e) => {
+						debug!(ctx: ctx, "dropping echo reply: {:?}", e);
+						return Ok(());
+					}
+				};
+				let ip = ip::IPv6Header::new(echo.length(data).unwrap(), 58, 255, addr, self.local_address);
+
+				out[..ip.byte_len()].copy_from_slice(ip.as_ref());
+				out[ip.byte_len()..][..echo.byte_len()].copy_from_slice(echo.as_ref());
+				out[ip.byte_len()..][echo.byte_len()..][..data.len()].copy_from_slice(data);
+
+				self.tun.write(&out[..ip.byte_len() + echo.byte_len() + data.len()]).map_err(RunError::Tun)?;
+			}
+			StupidType::Reject => {
+				let error = ConnectError::from_data(data).unwrap_or(ConnectError::Failed);
+				debug!(ctx: ctx, "server rejected flow: {}", error);
+			}
+			_ => debug!(ctx: ctx, "ignoring unexpected {:?} from server", ty),
+		}
+		Ok(())
+	}
+
+	fn resume(&mut self, stupid: &mut StupidClient, _registry: &Registry) -> Result<(), RunError> {
+		if mem::take(&mut self.blocked) {
+			self.handle_tun(stupid)?;
+		}
+		Ok(())
+	}
+
+	fn reset(&mut self) -> Result<(), RunError> {
+		self.reset_tcp_flows()
+	}
+
+	fn reset_tcp_flows(&mut self) -> Result<(), RunError> {
+		let mut out = [0; 0x100];
+		for (_, mut flow) in self.tcp_connections.drain() {
+			self.tun.write(flow.connection.reset(&mut out)).map_err(RunError::Tun)?;
+		}
+		Ok(())
+	}
+
+	fn next_timeout(&self, now: Instant) -> Option<Duration> {
+		self.reassembler.next_timeout(now)
+	}
+
+	fn expire(&mut self, now: Instant) {
+		self.reassembler.expire(now);
+	}
+
+	fn report(&self, now: Instant, out: &mut String) {
+		Line::new(out, "interface")
+			.field("bad-checksums", self.bad_checksums)
+			.end();
+		let mut ports = self.tcp_connections.keys().copied().collect::<Vec<_>>();
+		ports.sort_unstable();
+		for port in ports {
+			let flow = &self.tcp_connections[&port];
+			Line::new(out, "flow")
+				.field("protocol", "tcp")
+				.field("port", port)
+				.field("remote", flow.remote)
+				.duration("age", now.saturating_duration_since(flow.opened))
+				.counters(&flow.counters)
+				.end();
+		}
+	}
+
+	/// Only TCP connections are tracked, UDP and ICMP are relayed as they come.
+	fn kill(&mut self, stupid: &mut StupidClient, protocol: acl::Protocol, port: u16) -> Result<bool, RunError> {
+		let remote = match (protocol, self.tcp_connections.get(&port)) {
+			(acl::Protocol::Tcp, Some(flow)) => flow.remote,
+			_ => return Ok(false),
+		};
+		info!(ctx: Context::NONE.flow(port, remote), "resetting TCP on request");
+		self.reset_tcp(stupid, port, remote)?;
+		Ok(true)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	const PREFIX: Ipv6Addr = Ipv6Addr::new(0xabcd, 0xef00, 0, 0, 0, 0, 0, 0);
+
+	fn interface(tun: tun::Tun) -> Interface {
+		Interface {
+			local_address: Ipv6Addr::new(0xabcd, 0xef00, 0, 0, 0, 0, 0, 1),
+			prefix: PREFIX.octets()[..12].try_into().unwrap(),
+			init_seq_n: 0,
+			init_seq_n_offt: 0,
+			tun,
+			tcp_connections: HashMap::new(),
+			mtu: 1500,
+			verify_checksums: true,
+			bad_checksums: 0,
+			reassembler: fragment::Reassembler::new(Duration::from_secs(60), fragment::Reassembler::DEFAULT_MAX_BYTES),
+			fragment_id: 0,
+			blocked: false,
+		}
+	}
+
+	#[test]
+	fn bad_checksums() {
+		let (tun, peer) = tun::Tun::pair().unwrap();
+		let mut interface = interface(tun);
+		// Not connected, so anything forwarded fails to be sent.
+		let endpoint = stupid::Endpoint::Unix("/nonexistent".into());
+		let keepalive = stupid::Keepalive::new(Duration::from_secs(10), Duration::from_secs(30), Instant::now());
+		let mut stupid = StupidClient::new(endpoint, b"key", keepalive, stupid::Priorities::default(), stupid::Compression::None, 0);
+
+		let (src, dst) = (interface.local_address, Ipv6Addr::new(0xabcd, 0xef00, 0, 0, 0, 0, 0x7f00, 1));
+		let tcp = tcp::TcpHeader::new((src, 1000), (dst, 80), 1, 0, tcp::Flags::new().set_synchronize(true), 1000, tcp::Options::NONE, &[]);
+		let mut tcp = tcp.as_ref().to_vec();
+		let udp = udp::UDPHeader::new_ipv6(SocketAddrV6::new(src, 1000, 0, 0), SocketAddrV6::new(dst, 53, 0, 0), b"query").unwrap();
+		let mut udp = [&udp.as_ref()[..], b"query"].concat();
+		let ip = ip::IPv6Header::new(0, 0, 64, src, dst);
+
+		tcp[17] ^= 1;
+		udp[7] ^= 1;
+		interface.handle_upper(&mut stupid, &ip, 6, &tcp).unwrap();
+		interface.handle_upper(&mut stupid, &ip, 17, &udp).unwrap();
+		assert_eq!(interface.bad_checksums, 2);
+		assert_eq!(stupid.queued(), 0);
+		assert_eq!(peer.recv(&mut [0; 0x10000]).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+		let mut out = String::new();
+		interface.report(Instant::now(), &mut out);
+		assert!(out.contains("bad-checksums=2"), "{}", out);
+
+		// The same datagram intact is forwarded.
+		udp[7] ^= 1;
+		assert!(matches!(interface.handle_upper(&mut stupid, &ip, 17, &udp), Err(RunError::Send(_))));
+		assert_eq!(interface.bad_checksums, 2);
+	}
+}
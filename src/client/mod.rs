@@ -0,0 +1,379 @@
+mod interface;
+mod proxy;
+
+use crate::*;
+use crate::control::{self, Control, Line};
+use crate::log::Context;
+use stupid::{StupidClient, StupidDataHeader, StupidType};
+use core::fmt;
+use core::mem;
+use std::collections::hash_map::{HashMap, Entry};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{SocketAddrV4, Ipv4Addr};
+use std::time::{Duration, Instant};
+use mio::{Interest, Registry, Token};
+
+use interface::Interface;
+use proxy::Proxy;
+
+/// The token of the connection to the server. Front ends use tokens below it.
+const STUPID_TOKEN: usize = 0x10_0000;
+/// The token of the control socket. Connections to it use the tokens following it.
+const CONTROL_TOKEN: usize = 0x20_0000;
+
+pub struct Client {
+	config: config::ClientConfig,
+}
+
+impl Client {
+	pub fn new(config: config::ClientConfig) -> Self {
+		Self { config }
+	}
+
+	pub fn run(self) -> Result<!, RunError> {
+		let mut poll = mio::Poll::new().map_err(RunError::Poll)?;
+
+		let endpoint = self.config.endpoint();
+		info!("connecting to {}", endpoint);
+		let keepalive = stupid::Keepalive::new(self.config.keepalive.interval, self.config.keepalive.timeout, Instant::now());
+		let compression = &self.config.compression;
+		let mut stupid = StupidClient::new(endpoint, self.config.key.as_bytes(), keepalive, self.config.priorities.clone(), compression.algorithm, compression.threshold);
+		stupid.connect(poll.registry(), Token(STUPID_TOKEN))
+			.map_err(RunError::ConnectError)?;
+
+		let mut frontend: Box<dyn Frontend> = if self.config.is_proxy() {
+			Box::new(Proxy::new(&self.config)?)
+		} else {
+			Box::new(Interface::new(&self.config)?)
+		};
+		frontend.register(poll.registry()).map_err(RunError::Poll)?;
+
+		let mut control = match &self.config.control_socket {
+			Some(path) => {
+				let control = Control::bind(path, poll.registry(), CONTROL_TOKEN).map_err(RunError::Listen)?;
+				info!("accepting commands on control socket {}", path.display());
+				Some(control)
+			}
+			None => None,
+		};
+
+		let mut events = mio::Events::with_capacity(1024);
+
+		let mut state = State {
+			stupid,
+			frontend,
+			stats: Stats::default(),
+			backoff: Backoff::new(self.config.reconnect_delay, self.config.max_reconnect_delay),
+		};
+
+		let mut reconnect_at = None;
+
+		loop {
+			let now = Instant::now();
+			let timeout = state.frontend.next_timeout(now)
+				.into_iter()
+				.chain(reconnect_at.map(|t: Instant| t.saturating_duration_since(now)))
+				.chain(state.stupid.is_connected().then(|| state.stupid.keepalive().next_timeout(now)))
+				.chain(state.stupid.next_timeout(now))
+				.min();
+			match poll.poll(&mut events, timeout) {
+				// e.g. after being suspended and resumed.
+				Err(e) if e.kind() == ErrorKind::Interrupted => (),
+				r => r.map_err(RunError::Poll)?,
+			}
+			let now = Instant::now();
+			state.frontend.expire(now);
+
+			for e in &events {
+				let result = match e.token() {
+					Token(STUPID_TOKEN) => state.handle_stupid(poll.registry()),
+					token if control.as_ref().is_some_and(|c| c.owns(token)) => {
+						let mut result = Ok(());
+						control.as_mut().unwrap().handle_event(poll.registry(), token, |c, out| state.control(c, out, now, &mut result));
+						result
+					}
+					token => state.frontend.handle_event(&mut state.stupid, poll.registry(), token),
+				};
+				state.check_connection(result, now, &mut reconnect_at)?;
+			}
+
+			let result = state.keepalive(now)
+				.and_then(|()| state.stupid.poll(now).map_err(RunError::Send));
+			state.check_connection(result, now, &mut reconnect_at)?;
+
+			if reconnect_at.map_or(false, |t| t <= now) {
+				reconnect_at = None;
+				info!("reconnecting to {}", state.stupid.endpoint());
+				let result = state.stupid.connect(poll.registry(), Token(STUPID_TOKEN))
+					.map_err(RunError::ConnectError)
+					// Data may have been queued while disconnected.
+					.and_then(|()| state.frontend.resume(&mut state.stupid, poll.registry()))
+					.and_then(|()| state.handle_stupid(poll.registry()));
+				state.check_connection(result, now, &mut reconnect_at)?;
+			}
+		}
+
+	}
+}
+
+/// Where the traffic carried to the server comes from.
+trait Frontend {
+	/// Register all sockets with tokens below [`STUPID_TOKEN`].
+	fn register(&mut self, registry: &Registry) -> Result<(), Error>;
+
+	/// Handle an event of one of the registered sockets.
+	fn handle_event(&mut self, stupid: &mut StupidClient, registry: &Registry, token: Token) -> Result<(), RunError>;
+
+	/// Handle a frame of the server that belongs to a flow.
+	fn handle_frame(&mut self, stupid: &mut StupidClient, registry: &Registry, ty: StupidType, h: &StupidDataHeader, data: &[u8]) -> Result<(), RunError>;
+
+	/// Continue reading what was left unread while the server was away or had no room for
+	/// more frames.
+	fn resume(&mut self, stupid: &mut StupidClient, registry: &Registry) -> Result<(), RunError>;
+
+	/// Drop all flows, as the server lost them together with the previous session.
+	fn reset(&mut self) -> Result<(), RunError>;
+
+	/// Drop all TCP flows, as data of them may have been lost with the connection to the
+	/// server. The server drops them too once the session is resumed.
+	fn reset_tcp_flows(&mut self) -> Result<(), RunError>;
+
+	/// Set up what the server keeps per session, once a new session started.
+	fn start(&mut self, _stupid: &mut StupidClient) -> Result<(), RunError> {
+		Ok(())
+	}
+
+	/// The time until [`Self::expire`] needs to be called, if at all.
+	fn next_timeout(&self, _now: Instant) -> Option<Duration> {
+		None
+	}
+
+	fn expire(&mut self, _now: Instant) {}
+
+	/// Write a line per flow, after any about the front end itself, for the control socket.
+	fn report(&self, now: Instant, out: &mut String);
+
+	/// Close a flow on request, returning whether there is one. The server closes its end of
+	/// a UDP flow once it is idle.
+	fn kill(&mut self, stupid: &mut StupidClient, protocol: acl::Protocol, port: u16) -> Result<bool, RunError>;
+}
+
+/// Exponentially increasing delay between reconnection attempts.
+struct Backoff {
+	min: Duration,
+	max: Duration,
+	next: Duration,
+}
+
+impl Backoff {
+	fn new(min: Duration, max: Duration) -> Self {
+		Self { min, max, next: min }
+	}
+
+	fn next(&mut self) -> Duration {
+		let delay = self.next;
+		self.next = (delay * 2).min(self.max);
+		delay
+	}
+
+	fn reset(&mut self) {
+		self.next = self.min;
+	}
+}
+
+struct State {
+	stupid: StupidClient,
+	frontend: Box<dyn Frontend>,
+	stats: Stats,
+	backoff: Backoff,
+}
+
+impl State {
+	/// Schedule a reconnect if the connection to the server was lost. Other errors are
+	/// returned.
+	fn check_connection(&mut self, result: Result<(), RunError>, now: Instant, reconnect_at: &mut Option<Instant>) -> Result<(), RunError> {
+		match result {
+			Ok(()) => Ok(()),
+			Err(e @ (RunError::ConnectError(_) | RunError::Receive(_) | RunError::Send(_) | RunError::Dead)) => {
+				self.stupid.disconnect();
+				self.frontend.reset_tcp_flows()?;
+				self.stats.since = Some(now);
+				self.stats.disconnects += 1;
+				self.stats.last_error = Some(e.to_string());
+				let delay = self.backoff.next();
+				warn!("lost connection to server: {}, reconnecting in {:?}", e, delay);
+				*reconnect_at = Some(now + delay);
+				Ok(())
+			}
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Ping the server if needed and check whether it is still responding.
+	fn keepalive(&mut self, now: Instant) -> Result<(), RunError> {
+		if !self.stupid.is_connected() {
+			return Ok(());
+		}
+		match self.stupid.keepalive().poll(now) {
+			Ok(Some(ping)) => {
+				let any = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+				self.stupid.send(StupidType::Ping, any, 0, &ping).map_err(RunError::Send)
+			}
+			Ok(None) => Ok(()),
+			Err(stupid::Dead) => Err(RunError::Dead),
+		}
+	}
+
+	/// Handle all frames the server sent.
+	fn handle_stupid(&mut self, registry: &Registry) -> Result<(), RunError> {
+		let mut buf = [0; 0x10000];
+		while let Some((h, data)) = self.stupid.receive(&mut buf).map_err(RunError::Receive)? {
+			self.handle_frame(registry, &h, data)?;
+		}
+		self.stupid.flush().map_err(RunError::Send)?;
+		// Sending may have made room for more.
+		self.frontend.resume(&mut self.stupid, registry)
+	}
+
+	fn handle_frame(&mut self, registry: &Registry, h: &StupidDataHeader, data: &[u8]) -> Result<(), RunError> {
+		let ctx = Context::NONE.flow(h.local(), h.remote());
+
+		match h.ty() {
+			Ok(StupidType::Welcome) => match self.stupid.welcome(h, data) {
+				Ok(stupid::Welcome::New) => {
+					info!("started session");
+					self.backoff.reset();
+					self.stats.since = Some(Instant::now());
+					self.frontend.start(&mut self.stupid)?;
+				}
+				Ok(stupid::Welcome::Resumed) => {
+					info!("resumed session");
+					self.backoff.reset();
+					self.stats.since = Some(Instant::now());
+				}
+				Ok(stupid::Welcome::Replaced) => {
+					info!("started new session");
+					self.backoff.reset();
+					self.stats.since = Some(Instant::now());
+					// Any connections of the previous session are gone.
+					self.frontend.reset()?;
+					self.frontend.start(&mut self.stupid)?;
+				}
+				Err(_) => warn!("ignoring invalid welcome"),
+			},
+			Ok(StupidType::Ping) => {
+				self.stupid.send(StupidType::Pong, h.remote(), h.local(), data).map_err(RunError::Send)?;
+			}
+			Ok(StupidType::Pong) => {
+				if let Some(rtt) = self.stupid.keepalive().pong(data, Instant::now()) {
+					self.stats.rtt = self.stupid.keepalive().rtt();
+					debug!("round-trip time to server: {:?}", rtt);
+				}
+				if let Some((sent, received)) = self.stupid.compression_stats() {
+					debug!("compressed {} sent, {} received", sent, received);
+				}
+			}
+			Ok(StupidType::Hello) => debug!("ignoring hello from server"),
+			Ok(ty) => self.frontend.handle_frame(&mut self.stupid, registry, ty, h, data)?,
+			Err(e) => warn!(ctx: ctx, "ignoring frame with unknown type {}", e.0),
+		}
+		Ok(())
+	}
+
+	/// Run a command received on the control socket. An error sending to the server is left
+	/// in `result`.
+	fn control(&mut self, command: control::Command, out: &mut String, now: Instant, result: &mut Result<(), RunError>) -> Result<(), String> {
+		match command {
+			control::Command::Stats => {
+				self.report(now, out);
+				self.frontend.report(now, out);
+				Ok(())
+			}
+			control::Command::Kill { session: Some(_), .. } => Err("the client has no sessions, leave out the session".into()),
+			control::Command::Kill { session: None, protocol, port } => {
+				if !self.stupid.is_connected() || result.is_err() {
+					return Err("not connected to the server".into());
+				}
+				match self.frontend.kill(&mut self.stupid, protocol, port) {
+					Ok(true) => Ok(()),
+					Ok(false) => Err(format!("no {} flow {}", protocol, port)),
+					Err(e) => {
+						let message = format!("closed the flow, but {}", e);
+						*result = Err(e);
+						Err(message)
+					}
+				}
+			}
+			control::Command::Drop(_) => Err("the client has no sessions".into()),
+			// Handled by the control socket itself.
+			control::Command::LogLevel(_) | control::Command::Help => unreachable!(),
+		}
+	}
+
+	/// Write a line about the connection to the server for the control socket.
+	fn report(&self, now: Instant, out: &mut String) {
+		let state = if self.stupid.is_connected() { "connected" } else { "disconnected" };
+		let mut line = Line::new(out, "connection")
+			.text("server", &self.stupid.endpoint().to_string())
+			.field("state", state);
+		if let Some(since) = self.stats.since {
+			line = line.duration("since", now.saturating_duration_since(since));
+		}
+		if let Some(rtt) = self.stats.rtt {
+			line = line.duration("rtt", rtt.latest).duration("smoothed-rtt", rtt.smoothed).duration("min-rtt", rtt.min);
+		}
+		if let Some(r) = self.stupid.retransmissions() {
+			line = line.field("retransmissions", r);
+		}
+		line = line.field("queued", self.stupid.queued()).field("disconnects", self.stats.disconnects);
+		if let Some(e) = &self.stats.last_error {
+			line = line.text("last-error", e);
+		}
+		line.end();
+	}
+}
+
+#[derive(Default, Debug)]
+struct Stats {
+	/// Round-trip time to the server, once a ping has been answered.
+	rtt: Option<stupid::Rtt>,
+	/// When the client was last welcomed by the server or lost the connection to it.
+	since: Option<Instant>,
+	/// How often the connection to the server was lost, and why the last time.
+	disconnects: u64,
+	last_error: Option<String>,
+}
+
+/// An error that ends the client.
+#[derive(Debug)]
+pub enum RunError {
+	ConnectError(Error),
+	CreateTun(tun::NewTunError),
+	ConfigureTun(Error),
+	/// Reading from or writing to the tun failed.
+	Tun(Error),
+	/// Binding a socket of a proxy front end failed.
+	Listen(Error),
+	Receive(stupid::ReceiveError),
+	Send(Error),
+	/// Nothing was received from the server within the keepalive timeout.
+	Dead,
+	Poll(Error),
+}
+
+impl fmt::Display for RunError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::ConnectError(e) => write!(f, "failed to connect to server: {}", e),
+			Self::CreateTun(e) => write!(f, "failed to create tun: {}", e),
+			Self::ConfigureTun(e) => write!(f, "failed to configure tun: {}", e),
+			Self::Tun(e) => write!(f, "tun failed: {}", e),
+			Self::Listen(e) => write!(f, "failed to listen: {}", e),
+			Self::Receive(e) => write!(f, "failed to receive from server: {}", e),
+			Self::Send(e) => write!(f, "failed to send to server: {}", e),
+			Self::Dead => f.write_str("server stopped responding"),
+			Self::Poll(e) => write!(f, "failed to poll: {}", e),
+		}
+	}
+}
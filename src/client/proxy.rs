@@ -0,0 +1,1336 @@
+//! The proxy front end, which serves applications that connect to the client themselves
+//! instead of having their packets routed to a tun.
+//!
+//! Applications can ask for a connection with SOCKS5 or with an HTTP CONNECT request, or
+//! connect to a local forward with a fixed destination. Remote forwards are served here too.
+
+use super::*;
+use crate::{acl, http, socks};
+use crate::control::{Counters, Line};
+use crate::resolve::{self, Answers, Resolver};
+use stupid::{ConnectError, Protocol, Window, ACCEPTED_PORTS, constant_time_eq};
+use std::net::SocketAddr;
+use mio::net::{TcpListener, TcpStream, UdpSocket};
+
+/// Tokens consist of the kind of event and the local port of the flow.
+const EVENT_MASK: usize = 0xf_0000;
+const PORT_MASK: usize = 0xffff;
+
+const LISTEN_SOCKS_EVENT: usize = 0x1_0000;
+const TCP_EVENT: usize = 0x2_0000;
+const UDP_EVENT: usize = 0x3_0000;
+const LISTEN_HTTP_EVENT: usize = 0x4_0000;
+/// The port is the index of the local forward instead.
+const LISTEN_FORWARD_EVENT: usize = 0x5_0000;
+const REMOTE_UDP_EVENT: usize = 0x6_0000;
+/// Destinations of remote forwards were resolved.
+const RESOLVE_EVENT: usize = 0x7_0000;
+
+/// The most an application may send before its request is complete.
+const MAX_HANDSHAKE: usize = http::MAX_HEAD;
+
+pub struct Proxy {
+	socks: Option<TcpListener>,
+	http: Option<TcpListener>,
+	/// The username and password SOCKS clients must authenticate with, if any.
+	credentials: Option<(String, String)>,
+	/// Connections of applications by the local port of their flow.
+	connections: HashMap<u16, Connection>,
+	local_forwards: Vec<LocalForward>,
+	/// The remote forwards, with the number the server knows them by as index.
+	remote_forwards: Vec<config::Forward>,
+	/// Where the datagrams of the server go, by the local port of their flow.
+	udp_flows: HashMap<u16, UdpFlow>,
+	/// How long a UDP flow of a forward may be idle before it is forgotten.
+	udp_timeout: Duration,
+	next_port: u16,
+	/// Whether connections were left unread because the server was away or couldn't keep up.
+	blocked: bool,
+	/// Resolves the destinations of remote forwards, once registered.
+	resolver: Option<Resolver<(u16, u64)>>,
+	answers: Option<Answers<(u16, u64)>>,
+	/// Peers of remote forwards waiting for the destination to be resolved, by their port.
+	lookups: HashMap<u16, Lookup>,
+	next_lookup: u64,
+}
+
+/// A connection of an application.
+struct Connection {
+	stream: TcpStream,
+	/// How the application asks for a connection.
+	kind: Kind,
+	phase: Phase,
+	/// Data of the application that is part of the handshake or that came right after it.
+	received: Vec<u8>,
+	/// The address of the other end of the flow.
+	remote: SocketAddrV4,
+	window: Window,
+	/// Data of the server that hasn't been written to the stream yet.
+	pending: Vec<u8>,
+	/// Whether the stream is only registered for writable events, as the server has no credit
+	/// left to receive more.
+	paused: bool,
+	/// Set once the server finished the flow, which is closed once the pending data is written.
+	finished: bool,
+	opened: Instant,
+	counters: Counters,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+	Socks,
+	Http,
+	/// Connected to the local forward with this index.
+	Forward(usize),
+	/// Connected by the client to the destination of a remote forward.
+	Remote,
+}
+
+enum Phase {
+	/// Waiting for the head of an HTTP CONNECT request.
+	HttpRequest,
+	/// Waiting for the authentication methods the application supports.
+	Greeting,
+	/// Waiting for the username and password.
+	Password,
+	/// Waiting for the request.
+	Request,
+	/// Waiting for the server to connect.
+	Connecting,
+	/// Connecting to the destination of a remote forward.
+	Dialing,
+	Open,
+	/// Relaying datagrams until the connection is closed.
+	Associated(Association),
+}
+
+/// A socket listening for a local forward.
+struct LocalForward {
+	socket: ForwardSocket,
+	/// Where the server connects to.
+	target: socks::Address,
+	/// The local ports of the flows of UDP peers by their address.
+	peers: HashMap<SocketAddr, u16>,
+}
+
+enum ForwardSocket {
+	Tcp(TcpListener),
+	Udp(UdpSocket),
+}
+
+/// A UDP flow of the server.
+struct UdpFlow {
+	target: UdpTarget,
+	opened: Instant,
+	counters: Counters,
+}
+
+/// Where the datagrams of the server for a flow go.
+enum UdpTarget {
+	/// To the application of the SOCKS UDP association of the connection with this port.
+	Association {
+		port: u16,
+		/// The destination the application sent to, which the datagrams come from.
+		destination: socks::Address,
+	},
+	/// To a peer of a local forward.
+	Local {
+		forward: usize,
+		peer: SocketAddr,
+		/// The destination of the forward.
+		remote: SocketAddrV4,
+		last_used: Instant,
+	},
+	/// To the destination of a remote forward, for a peer the server accepted.
+	Remote {
+		socket: UdpSocket,
+		peer: SocketAddrV4,
+		last_used: Instant,
+	},
+}
+
+/// A peer of a remote forward the server accepted, whose destination is being resolved.
+struct Lookup {
+	/// Tells the answer apart from that of an earlier lookup for the same port.
+	id: u64,
+	peer: SocketAddrV4,
+	/// The index of the remote forward.
+	forward: usize,
+	/// Datagrams of the peer waiting for the answer.
+	queued: Vec<Vec<u8>>,
+}
+
+/// The most datagrams kept while resolving the destination of a remote forward.
+const MAX_QUEUED_DATAGRAMS: usize = 16;
+
+/// A SOCKS UDP association.
+struct Association {
+	socket: UdpSocket,
+	/// The address the application sends datagrams from, once the first one arrived.
+	peer: Option<SocketAddr>,
+	/// The local ports of the flows by destination.
+	flows: HashMap<socks::Address, u16>,
+}
+
+impl Proxy {
+	pub fn new(config: &config::ClientConfig) -> Result<Self, RunError> {
+		let socks = config.socks.map(TcpListener::bind).transpose().map_err(RunError::Listen)?;
+		if let Some(address) = config.socks {
+			info!("accepting SOCKS clients on {}", address);
+		}
+		let http = config.http_proxy.map(TcpListener::bind).transpose().map_err(RunError::Listen)?;
+		if let Some(address) = config.http_proxy {
+			info!("accepting HTTP CONNECT requests on {}", address);
+		}
+		let local_forwards = config.local_forwards.iter().map(|f| {
+			let socket = match f.protocol {
+				Protocol::Tcp => ForwardSocket::Tcp(TcpListener::bind(f.listen)?),
+				Protocol::Udp => ForwardSocket::Udp(UdpSocket::bind(f.listen)?),
+			};
+			info!("forwarding {} on {} to {}:{}", f.protocol, f.listen, f.host, f.port);
+			Ok(LocalForward { socket, target: address(&f.host, f.port), peers: HashMap::new() })
+		}).collect::<Result<_, _>>().map_err(RunError::Listen)?;
+		Ok(Self {
+			socks,
+			http,
+			credentials: config.socks_username.clone().zip(config.socks_password.clone()),
+			connections: HashMap::new(),
+			local_forwards,
+			remote_forwards: config.remote_forwards.clone(),
+			udp_flows: HashMap::new(),
+			udp_timeout: config.udp_timeout,
+			next_port: 1,
+			blocked: false,
+			resolver: None,
+			answers: None,
+			lookups: HashMap::new(),
+			next_lookup: 0,
+		})
+	}
+
+	fn accept(&mut self, stupid: &mut StupidClient, registry: &Registry, kind: Kind) -> Result<(), RunError> {
+		loop {
+			let listener = match kind {
+				Kind::Socks => self.socks.as_ref(),
+				Kind::Http => self.http.as_ref(),
+				Kind::Forward(i) => match &self.local_forwards[i].socket {
+					ForwardSocket::Tcp(l) => Some(l),
+					ForwardSocket::Udp(_) => None,
+				},
+				Kind::Remote => None,
+			};
+			if matches!(kind, Kind::Forward(_)) && (!stupid.is_connected() || !stupid.has_room()) {
+				// Leave connections in the backlog until their flow can be opened.
+				self.blocked = true;
+				return Ok(());
+			}
+			let (mut stream, peer) = match listener.unwrap().accept() {
+				Ok(r) => r,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+				Err(e) => {
+					warn!("failed to accept {} client: {}", kind, e);
+					return Ok(());
+				}
+			};
+			let port = match self.allocate_port() {
+				Some(p) => p,
+				None => {
+					warn!("dropping {} client {}, no ports left", kind, peer);
+					continue;
+				}
+			};
+			if let Err(e) = registry.register(&mut stream, Token(TCP_EVENT | usize::from(port)), Interest::READABLE | Interest::WRITABLE) {
+				warn!("failed to register {} client: {}", kind, e);
+				continue;
+			}
+			debug!("accepted {} client {} as {}", kind, peer, port);
+			self.connections.insert(port, Connection::new(stream, kind));
+			if let Kind::Forward(i) = kind {
+				let target = self.local_forwards[i].target.clone();
+				self.connect(stupid, port, &target)?;
+			}
+		}
+	}
+
+	/// Pick a local port that isn't used by any flow, below the ports the server picks for
+	/// remote forwards.
+	fn allocate_port(&mut self) -> Option<u16> {
+		let end = *ACCEPTED_PORTS.start();
+		for _ in 1..end {
+			let port = self.next_port;
+			self.next_port = if port + 1 == end { 1 } else { port + 1 };
+			if !self.connections.contains_key(&port) && !self.udp_flows.contains_key(&port) {
+				return Some(port);
+			}
+		}
+		None
+	}
+
+	/// Handle an event of a connection, writing what the server sent and reading what the
+	/// application sent.
+	fn handle_connection(&mut self, stupid: &mut StupidClient, registry: &Registry, port: u16) -> Result<(), RunError> {
+		if !stupid.is_connected() {
+			// Nothing can be sent about a connection that breaks.
+			self.blocked = true;
+			return Ok(());
+		}
+		if self.connections.get(&port).is_some_and(|c| matches!(c.phase, Phase::Dialing)) && !self.dialed(stupid, port)? {
+			return Ok(());
+		}
+		self.write(stupid, port)?;
+		self.read(stupid, registry, port)
+	}
+
+	/// Check whether the connection to the destination of a remote forward was established,
+	/// telling the server if it was or if it failed.
+	fn dialed(&mut self, stupid: &mut StupidClient, port: u16) -> Result<bool, RunError> {
+		let conn = self.connections.get_mut(&port).unwrap();
+		let ctx = Context::NONE.flow(port, conn.remote);
+		// The stream becomes writable once the connection is established or failed.
+		let result = match conn.stream.take_error() {
+			Ok(Some(e)) | Err(e) => Err(e),
+			Ok(None) => conn.stream.peer_addr(),
+		};
+		match result {
+			Ok(_) => {
+				debug!(ctx: ctx, "connected TCP");
+				conn.phase = Phase::Open;
+				stupid.send(StupidType::TcpConnect, conn.remote, port, &[]).map_err(RunError::Send)?;
+				Ok(true)
+			}
+			Err(e) if e.kind() == ErrorKind::NotConnected => Ok(false),
+			Err(e) => {
+				debug!(ctx: ctx, "failed to connect TCP: {}", e);
+				let remote = conn.remote;
+				self.connections.remove(&port);
+				stupid.send(StupidType::TcpFinish, remote, port, &[ConnectError::from(&e) as u8]).map_err(RunError::Send)?;
+				Ok(false)
+			}
+		}
+	}
+
+	/// Read what the application sent, as far as the server has room for it.
+	fn read(&mut self, stupid: &mut StupidClient, registry: &Registry, port: u16) -> Result<(), RunError> {
+		let mut buf = [0; StupidDataHeader::MAX_DATA_LENGTH];
+		let max = stupid.max_stream_data();
+		loop {
+			if !stupid.is_connected() || !stupid.has_room() {
+				self.blocked = true;
+				return Ok(());
+			}
+			let conn = match self.connections.get_mut(&port) {
+				Some(c) => c,
+				None => return Ok(()),
+			};
+			let ctx = Context::NONE.flow(port, conn.remote);
+			let max = match conn.phase {
+				Phase::Open => max.min(conn.window.credit()),
+				// Anything sent before the connection is established is read once it is.
+				Phase::Connecting | Phase::Dialing => return Ok(()),
+				_ => MAX_HANDSHAKE + 1 - conn.received.len(),
+			};
+			if max == 0 {
+				// Leave the data in the socket until the server granted more credit.
+				if !conn.paused {
+					trace!(ctx: ctx, "pausing TCP, no credit left");
+					let token = Token(TCP_EVENT | usize::from(port));
+					if let Err(e) = registry.reregister(&mut conn.stream, token, Interest::WRITABLE) {
+						debug!(ctx: ctx, "closing connection: {}", e);
+						return self.close(stupid, port);
+					}
+					conn.paused = true;
+				}
+				return Ok(());
+			}
+			let len = match conn.stream.read(&mut buf[..max]) {
+				Ok(0) => {
+					debug!(ctx: ctx, "connection closed by application");
+					return self.close(stupid, port);
+				}
+				Ok(len) => len,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+				Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+				Err(e) => {
+					debug!(ctx: ctx, "closing connection: {}", e);
+					return self.close(stupid, port);
+				}
+			};
+			match conn.phase {
+				Phase::Open => {
+					if let Err(e) = conn.window.sent(len) {
+						debug!(ctx: ctx, "closing connection: {}", e);
+						return self.close(stupid, port);
+					}
+					conn.counters.sent(len);
+					stupid.send(StupidType::TCP, conn.remote, port, &buf[..len]).map_err(RunError::Send)?;
+				}
+				// Nothing but the end of the connection is expected.
+				Phase::Associated(_) => (),
+				_ if conn.received.len() + len > MAX_HANDSHAKE => {
+					debug!(ctx: ctx, "closing connection, handshake is too large");
+					return self.close(stupid, port);
+				}
+				_ => {
+					conn.received.extend_from_slice(&buf[..len]);
+					self.handshake(stupid, registry, port)?;
+				}
+			}
+		}
+	}
+
+	/// Write data of the server to the application as far as it takes it, granting the server
+	/// credit for what was written.
+	fn write(&mut self, stupid: &mut StupidClient, port: u16) -> Result<(), RunError> {
+		let conn = match self.connections.get_mut(&port) {
+			Some(c) => c,
+			None => return Ok(()),
+		};
+		let ctx = Context::NONE.flow(port, conn.remote);
+		let mut written = 0;
+		while written < conn.pending.len() {
+			match conn.stream.write(&conn.pending[written..]) {
+				Ok(0) => {
+					debug!(ctx: ctx, "closing connection: {}", Error::from(ErrorKind::WriteZero));
+					return self.close(stupid, port);
+				}
+				Ok(n) => written += n,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+				Err(e) if e.kind() == ErrorKind::Interrupted => (),
+				Err(e) => {
+					debug!(ctx: ctx, "closing connection: {}", e);
+					return self.close(stupid, port);
+				}
+			}
+		}
+		conn.pending.drain(..written);
+		if let Some(update) = conn.window.consumed(written) {
+			stupid.send(StupidType::WindowUpdate, conn.remote, port, &update).map_err(RunError::Send)?;
+		}
+		if conn.finished && conn.pending.is_empty() {
+			debug!(ctx: ctx, "closed TCP");
+			self.connections.remove(&port);
+		}
+		Ok(())
+	}
+
+	/// Continue the SOCKS handshake with what the application sent so far.
+	fn handshake(&mut self, stupid: &mut StupidClient, registry: &Registry, port: u16) -> Result<(), RunError> {
+		let conn = self.connections.get_mut(&port).unwrap();
+		let request = match conn.handshake(self.credentials.as_ref()) {
+			Ok(Some(r)) => r,
+			Ok(None) => return Ok(()),
+			Err(e) => {
+				debug!(ctx: Context::NONE.flow(port, conn.remote), "closing connection: {}", e);
+				self.connections.remove(&port);
+				return Ok(());
+			}
+		};
+		conn.remote = remote(&request.address);
+		let ctx = Context::NONE.flow(port, conn.remote);
+		match request.command {
+			socks::Command::Connect => self.connect(stupid, port, &request.address),
+			socks::Command::UdpAssociate => {
+				let token = Token(UDP_EVENT | usize::from(port));
+				let association = conn.stream.local_addr()
+					.and_then(|a| UdpSocket::bind(SocketAddr::new(a.ip(), 0)))
+					.and_then(|mut s| registry.register(&mut s, token, Interest::READABLE).map(|()| s))
+					.and_then(|s| Ok((s.local_addr()?, s)));
+				let (bound, socket) = match association {
+					Ok(r) => r,
+					Err(e) => {
+						debug!(ctx: ctx, "failed to open UDP association: {}", e);
+						let _ = conn.reply(&socks::reply(socks::Reply::Failure, unspecified()));
+						self.connections.remove(&port);
+						return Ok(());
+					}
+				};
+				debug!(ctx: ctx, "opened UDP association on {}", bound);
+				if let Err(e) = conn.reply(&socks::reply(socks::Reply::Succeeded, bound)) {
+					debug!(ctx: ctx, "closing connection: {}", e);
+					self.connections.remove(&port);
+					return Ok(());
+				}
+				conn.phase = Phase::Associated(Association { socket, peer: None, flows: HashMap::new() });
+				Ok(())
+			}
+			socks::Command::Bind => {
+				debug!(ctx: ctx, "refusing unsupported BIND");
+				let _ = conn.reply(&socks::reply(socks::Reply::CommandNotSupported, unspecified()));
+				self.connections.remove(&port);
+				Ok(())
+			}
+		}
+	}
+
+	/// Ask the server to connect the flow of a connection to `address`.
+	fn connect(&mut self, stupid: &mut StupidClient, port: u16, address: &socks::Address) -> Result<(), RunError> {
+		let conn = self.connections.get_mut(&port).unwrap();
+		let remote = remote(address);
+		conn.remote = remote;
+		conn.phase = Phase::Connecting;
+		debug!(ctx: Context::NONE.flow(port, remote), "connecting TCP to {}", address);
+		match address {
+			socks::Address::Ip(SocketAddr::V4(_)) => stupid.send(StupidType::TcpConnect, remote, port, &[]),
+			socks::Address::Ip(a) => stupid.send(StupidType::TcpConnectName, remote, port, a.ip().to_string().as_bytes()),
+			socks::Address::Domain(name, _) => stupid.send(StupidType::TcpConnectName, remote, port, name.as_bytes()),
+		}.map_err(RunError::Send)
+	}
+
+	/// Relay all datagrams the application sent for a UDP association.
+	fn handle_association(&mut self, stupid: &mut StupidClient, port: u16) -> Result<(), RunError> {
+		let mut buf = [0; 0x10000];
+		loop {
+			if !stupid.is_connected() || !stupid.has_room() {
+				self.blocked = true;
+				return Ok(());
+			}
+			let (association, peer_ip) = match self.connections.get_mut(&port) {
+				Some(Connection { phase: Phase::Associated(a), stream, .. }) => (a, stream.peer_addr().map(|a| a.ip())),
+				_ => return Ok(()),
+			};
+			let (len, source) = match association.socket.recv_from(&mut buf) {
+				Ok(r) => r,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+				Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+				Err(e) => {
+					debug!("failed to receive datagram of application: {}", e);
+					return Ok(());
+				}
+			};
+			// Only the application that asked for the association may use it.
+			match association.peer {
+				Some(peer) if peer != source => continue,
+				Some(_) => (),
+				None if peer_ip.ok() == Some(source.ip()) => association.peer = Some(source),
+				None => continue,
+			}
+			let (address, data) = match socks::parse_datagram(&buf[..len]) {
+				Ok(r) => r,
+				Err(e) => {
+					debug!("dropping datagram of application: {}", e);
+					continue;
+				}
+			};
+			let local = match association.flows.get(&address) {
+				Some(p) => *p,
+				None => {
+					let local = match self.allocate_port() {
+						Some(p) => p,
+						None => {
+							debug!("dropping datagram to {}, no ports left", address);
+							continue;
+						}
+					};
+					self.udp_flows.insert(local, UdpFlow::new(UdpTarget::Association { port, destination: address.clone() }));
+					match self.connections.get_mut(&port) {
+						Some(Connection { phase: Phase::Associated(a), .. }) => drop(a.flows.insert(address.clone(), local)),
+						_ => unreachable!(),
+					}
+					local
+				}
+			};
+			send_datagram(stupid, &address, local, data)?;
+			if let Some(f) = self.udp_flows.get_mut(&local) {
+				f.counters.sent(data.len());
+			}
+		}
+	}
+
+	/// Relay all datagrams peers sent to a local forward, or accept its connections.
+	fn handle_forward(&mut self, stupid: &mut StupidClient, registry: &Registry, index: usize) -> Result<(), RunError> {
+		if let ForwardSocket::Tcp(_) = self.local_forwards[index].socket {
+			return self.accept(stupid, registry, Kind::Forward(index));
+		}
+		let mut buf = [0; 0x10000];
+		loop {
+			if !stupid.is_connected() || !stupid.has_room() {
+				self.blocked = true;
+				return Ok(());
+			}
+			let forward = &self.local_forwards[index];
+			let result = match &forward.socket {
+				ForwardSocket::Udp(s) => s.recv_from(&mut buf),
+				ForwardSocket::Tcp(_) => unreachable!(),
+			};
+			let (len, peer) = match result {
+				Ok(r) => r,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+				Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+				Err(e) => {
+					debug!("failed to receive datagram of forward: {}", e);
+					return Ok(());
+				}
+			};
+			let local = match forward.peers.get(&peer) {
+				Some(p) => *p,
+				None => {
+					let remote = remote(&forward.target);
+					let local = match self.allocate_port() {
+						Some(p) => p,
+						None => {
+							debug!("dropping datagram of {}, no ports left", peer);
+							continue;
+						}
+					};
+					debug!(ctx: Context::NONE.flow(local, remote), "forwarding UDP of {}", peer);
+					self.local_forwards[index].peers.insert(peer, local);
+					self.udp_flows.insert(local, UdpFlow::new(UdpTarget::Local { forward: index, peer, remote, last_used: Instant::now() }));
+					local
+				}
+			};
+			match self.udp_flows.get_mut(&local) {
+				Some(UdpFlow { target: UdpTarget::Local { last_used, .. }, counters, .. }) => {
+					*last_used = Instant::now();
+					counters.sent(len);
+				}
+				_ => unreachable!(),
+			}
+			send_datagram(stupid, &self.local_forwards[index].target, local, &buf[..len])?;
+		}
+	}
+
+	/// Resolve the destination of a remote forward for a peer the server accepted.
+	fn accept_remote(&mut self, stupid: &mut StupidClient, h: &StupidDataHeader, data: &[u8]) -> Result<(), RunError> {
+		let (port, peer) = (h.local(), h.remote());
+		let ctx = Context::NONE.flow(port, peer);
+		let index = match data.try_into().ok().map(|n| usize::from(u16::from_be_bytes(n))).filter(|i| *i < self.remote_forwards.len()) {
+			Some(i) => i,
+			None => {
+				debug!(ctx: ctx, "refusing peer of unknown remote forward");
+				return stupid.send(StupidType::TcpFinish, peer, port, &[ConnectError::Failed as u8]).map_err(RunError::Send);
+			}
+		};
+		let forward = &self.remote_forwards[index];
+		debug!(ctx: ctx, "resolving {} for remote forward", forward.host);
+		let id = self.next_lookup;
+		self.next_lookup += 1;
+		self.resolver.as_ref().unwrap().resolve((port, id), &forward.host, forward.port);
+		self.lookups.insert(port, Lookup { id, peer, forward: index, queued: Vec::new() });
+		Ok(())
+	}
+
+	/// Open the flows of the peers of remote forwards whose destination was resolved.
+	fn resolved(&mut self, stupid: &mut StupidClient, registry: &Registry) -> Result<(), RunError> {
+		let answers = match &self.answers {
+			Some(a) => a.take().collect::<Vec<_>>(),
+			None => return Ok(()),
+		};
+		for ((port, id), result) in answers {
+			match self.lookups.entry(port) {
+				Entry::Occupied(e) if e.get().id == id => {
+					let lookup = e.remove();
+					let address = result.and_then(|a| a.into_iter().next().ok_or_else(|| Error::new(ErrorKind::NotFound, "name has no addresses")));
+					self.dial_remote(stupid, registry, port, lookup, address)?;
+				}
+				// The peer left while its destination was being resolved.
+				_ => (),
+			}
+		}
+		Ok(())
+	}
+
+	/// Open a flow to the resolved destination of a remote forward for a peer.
+	fn dial_remote(&mut self, stupid: &mut StupidClient, registry: &Registry, port: u16, lookup: Lookup, address: Result<SocketAddr, Error>) -> Result<(), RunError> {
+		let Lookup { peer, forward, queued, .. } = lookup;
+		let forward = &self.remote_forwards[forward];
+		let ctx = Context::NONE.flow(port, peer);
+		if let Ok(a) = &address {
+			debug!(ctx: ctx, "connecting {} to {} for remote forward", forward.protocol, a);
+		}
+		match forward.protocol {
+			Protocol::Tcp => {
+				let token = Token(TCP_EVENT | usize::from(port));
+				let stream = address
+					.as_ref()
+					.map_err(|e| Error::new(ErrorKind::NotFound, e.to_string()))
+					.and_then(|a| TcpStream::connect(*a))
+					.and_then(|mut s| registry.register(&mut s, token, Interest::READABLE | Interest::WRITABLE).map(|()| s));
+				match stream {
+					Ok(stream) => {
+						let mut conn = Connection::new(stream, Kind::Remote);
+						conn.remote = peer;
+						self.connections.insert(port, conn);
+						Ok(())
+					}
+					Err(e) => {
+						debug!(ctx: ctx, "failed to connect TCP: {}", e);
+						let error = match e.kind() {
+							ErrorKind::NotFound if address.is_err() => ConnectError::NotResolved,
+							_ => ConnectError::from(&e),
+						};
+						stupid.send(StupidType::TcpFinish, peer, port, &[error as u8]).map_err(RunError::Send)
+					}
+				}
+			}
+			Protocol::Udp => {
+				let token = Token(REMOTE_UDP_EVENT | usize::from(port));
+				let socket = address.and_then(|a| {
+					let mut socket = UdpSocket::bind(SocketAddr::new(unspecified_ip(a), 0))?;
+					socket.connect(a)?;
+					registry.register(&mut socket, token, Interest::READABLE)?;
+					Ok(socket)
+				});
+				let socket = match socket {
+					Ok(s) => s,
+					Err(e) => {
+						debug!(ctx: ctx, "dropping UDP peer: {}", e);
+						return Ok(());
+					}
+				};
+				// The peer may have sent more while its destination was being resolved.
+				for datagram in &queued {
+					if let Err(e) = socket.send(datagram) {
+						debug!(ctx: ctx, "dropping datagram: {}", e);
+					}
+				}
+				self.udp_flows.insert(port, UdpFlow::new(UdpTarget::Remote { socket, peer, last_used: Instant::now() }));
+				Ok(())
+			}
+		}
+	}
+
+	/// Relay all datagrams the destination of a remote forward sent to its peer.
+	fn handle_remote_udp(&mut self, stupid: &mut StupidClient, port: u16) -> Result<(), RunError> {
+		let mut buf = [0; StupidDataHeader::MAX_DATA_LENGTH];
+		loop {
+			if !stupid.is_connected() || !stupid.has_room() {
+				self.blocked = true;
+				return Ok(());
+			}
+			let (socket, peer, last_used, counters) = match self.udp_flows.get_mut(&port) {
+				Some(UdpFlow { target: UdpTarget::Remote { socket, peer, last_used }, counters, .. }) => (socket, *peer, last_used, counters),
+				_ => return Ok(()),
+			};
+			let len = match socket.recv(&mut buf) {
+				Ok(len) => len,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+				Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+				Err(e) => {
+					debug!(ctx: Context::NONE.flow(port, peer), "failed to receive datagram: {}", e);
+					return Ok(());
+				}
+			};
+			*last_used = Instant::now();
+			counters.sent(len);
+			stupid.send(StupidType::UDP, peer, port, &buf[..len]).map_err(RunError::Send)?;
+		}
+	}
+
+	/// Send a datagram of the server to the application of its UDP association, the peer of
+	/// its local forward or the destination of its remote forward.
+	fn relay_datagram(&mut self, h: &StupidDataHeader, data: &[u8]) {
+		let ctx = Context::NONE.flow(h.local(), h.remote());
+		let flow = match self.udp_flows.get_mut(&h.local()) {
+			Some(f) => f,
+			None if self.lookups.contains_key(&h.local()) => {
+				let queued = &mut self.lookups.get_mut(&h.local()).unwrap().queued;
+				if queued.len() < MAX_QUEUED_DATAGRAMS {
+					queued.push(data.to_vec());
+				}
+				return;
+			}
+			None => {
+				debug!(ctx: ctx, "dropping datagram of unknown UDP flow");
+				return;
+			}
+		};
+		flow.counters.received(data.len());
+		let result = match &mut flow.target {
+			UdpTarget::Association { port, destination } => {
+				let association = match self.connections.get_mut(port) {
+					Some(Connection { phase: Phase::Associated(a), .. }) => a,
+					_ => return,
+				};
+				let peer = match association.peer {
+					Some(p) => p,
+					None => return,
+				};
+				let mut out = Vec::with_capacity(10 + data.len());
+				socks::datagram_header(destination, &mut out);
+				out.extend_from_slice(data);
+				association.socket.send_to(&out, peer)
+			}
+			UdpTarget::Local { forward, peer, last_used, .. } => {
+				*last_used = Instant::now();
+				match &self.local_forwards[*forward].socket {
+					ForwardSocket::Udp(s) => s.send_to(data, *peer),
+					ForwardSocket::Tcp(_) => unreachable!(),
+				}
+			}
+			UdpTarget::Remote { socket, last_used, .. } => {
+				*last_used = Instant::now();
+				socket.send(data)
+			}
+		};
+		if let Err(e) = result {
+			debug!(ctx: ctx, "dropping datagram: {}", e);
+		}
+	}
+
+	/// Ask the server to listen for every remote forward.
+	fn listen(&mut self, stupid: &mut StupidClient) -> Result<(), RunError> {
+		for (i, f) in self.remote_forwards.iter().enumerate() {
+			let address = match f.listen {
+				SocketAddr::V4(a) => a,
+				SocketAddr::V6(_) => unreachable!("remote forwards listen on IPv4"),
+			};
+			stupid.send(StupidType::Listen, address, i.try_into().unwrap(), &[f.protocol as u8]).map_err(RunError::Send)?;
+		}
+		Ok(())
+	}
+
+	/// Handle the outcome of connecting to the destination of a SOCKS request.
+	fn connected(&mut self, stupid: &mut StupidClient, registry: &Registry, port: u16, error: Option<ConnectError>) -> Result<(), RunError> {
+		let conn = match self.connections.get_mut(&port) {
+			Some(c) if matches!(c.phase, Phase::Connecting) => c,
+			_ => return Ok(()),
+		};
+		let ctx = Context::NONE.flow(port, conn.remote);
+		if let Some(e) = error {
+			debug!(ctx: ctx, "failed to connect TCP: {}", e);
+			let _ = conn.reply_connected(Some(e));
+			self.connections.remove(&port);
+			return Ok(());
+		}
+		debug!(ctx: ctx, "connected TCP");
+		if let Err(e) = conn.reply_connected(None) {
+			debug!(ctx: ctx, "closing connection: {}", e);
+			return self.close(stupid, port);
+		}
+		conn.phase = Phase::Open;
+		// The application may have sent data right after its request.
+		let early = mem::take(&mut conn.received);
+		if !early.is_empty() {
+			if let Err(e) = conn.window.sent(early.len()) {
+				debug!(ctx: ctx, "closing connection: {}", e);
+				return self.close(stupid, port);
+			}
+			conn.counters.sent(early.len());
+			stupid.send(StupidType::TCP, conn.remote, port, &early).map_err(RunError::Send)?;
+		}
+		self.read(stupid, registry, port)
+	}
+
+	/// Close a connection, finishing its flow if it has one.
+	fn close(&mut self, stupid: &mut StupidClient, port: u16) -> Result<(), RunError> {
+		let conn = match self.connections.remove(&port) {
+			Some(c) => c,
+			None => return Ok(()),
+		};
+		match conn.phase {
+			Phase::Connecting | Phase::Dialing | Phase::Open => stupid.send(StupidType::TcpFinish, conn.remote, port, &[]).map_err(RunError::Send),
+			Phase::Associated(a) => {
+				// The server closes the sockets of the flows once they are idle.
+				for local in a.flows.values() {
+					self.udp_flows.remove(local);
+				}
+				Ok(())
+			}
+			_ => Ok(()),
+		}
+	}
+}
+
+impl Frontend for Proxy {
+	fn register(&mut self, registry: &Registry) -> Result<(), Error> {
+		if let Some(l) = &mut self.socks {
+			registry.register(l, Token(LISTEN_SOCKS_EVENT), Interest::READABLE)?;
+		}
+		if let Some(l) = &mut self.http {
+			registry.register(l, Token(LISTEN_HTTP_EVENT), Interest::READABLE)?;
+		}
+		for (i, f) in self.local_forwards.iter_mut().enumerate() {
+			let token = Token(LISTEN_FORWARD_EVENT | i);
+			match &mut f.socket {
+				ForwardSocket::Tcp(l) => registry.register(l, token, Interest::READABLE)?,
+				ForwardSocket::Udp(s) => registry.register(s, token, Interest::READABLE)?,
+			}
+		}
+		if !self.remote_forwards.is_empty() {
+			let (resolver, answers) = resolve::start(registry, Token(RESOLVE_EVENT))?;
+			self.resolver = Some(resolver);
+			self.answers = Some(answers);
+		}
+		Ok(())
+	}
+
+	fn handle_event(&mut self, stupid: &mut StupidClient, registry: &Registry, token: Token) -> Result<(), RunError> {
+		let (ty, port) = (token.0 & EVENT_MASK, (token.0 & PORT_MASK) as u16);
+		match ty {
+			LISTEN_SOCKS_EVENT => self.accept(stupid, registry, Kind::Socks),
+			LISTEN_HTTP_EVENT => self.accept(stupid, registry, Kind::Http),
+			LISTEN_FORWARD_EVENT => self.handle_forward(stupid, registry, usize::from(port)),
+			TCP_EVENT => self.handle_connection(stupid, registry, port),
+			UDP_EVENT => self.handle_association(stupid, port),
+			REMOTE_UDP_EVENT => self.handle_remote_udp(stupid, port),
+			RESOLVE_EVENT => self.resolved(stupid, registry),
+			_ => unreachable!(),
+		}
+	}
+
+	fn handle_frame(&mut self, stupid: &mut StupidClient, registry: &Registry, ty: StupidType, h: &StupidDataHeader, data: &[u8]) -> Result<(), RunError> {
+		let (port, ctx) = (h.local(), Context::NONE.flow(h.local(), h.remote()));
+		match ty {
+			StupidType::UDP => self.relay_datagram(h, data),
+			StupidType::TcpConnect => return self.connected(stupid, registry, port, None),
+			StupidType::Accept => return self.accept_remote(stupid, h, data),
+			StupidType::Listen => {
+				let forward = match self.remote_forwards.get(usize::from(port)) {
+					Some(f) => f,
+					None => return Ok(()),
+				};
+				match data {
+					[] => info!("server listening on {} for {}:{}", forward.listen, forward.host, forward.port),
+					e => warn!("server failed to listen on {}: {}", forward.listen, String::from_utf8_lossy(e)),
+				}
+			}
+			StupidType::Reject => {
+				let error = ConnectError::from_data(data).unwrap_or(ConnectError::Failed);
+				info!(ctx: ctx, "server rejected UDP flow: {}", error);
+			}
+			StupidType::TCP => {
+				let conn = match self.connections.get_mut(&port) {
+					Some(c) if matches!(c.phase, Phase::Open) => c,
+					_ => {
+						debug!(ctx: ctx, "closing unknown TCP connection");
+						return stupid.send(StupidType::TcpFinish, h.remote(), port, &[]).map_err(RunError::Send);
+					}
+				};
+				if let Err(e) = conn.window.received(data.len()) {
+					debug!(ctx: ctx, "closing connection: {}", e);
+					return self.close(stupid, port);
+				}
+				conn.pending.extend_from_slice(data);
+				conn.counters.received(data.len());
+				return self.write(stupid, port);
+			}
+			StupidType::TcpFinish => {
+				let conn = match self.connections.get_mut(&port) {
+					Some(c) => c,
+					None => {
+						// The peer of a remote forward may leave before its destination is resolved.
+						self.lookups.remove(&port);
+						return Ok(());
+					}
+				};
+				if matches!(conn.phase, Phase::Connecting) {
+					let error = ConnectError::from_data(data).unwrap_or(ConnectError::Failed);
+					return self.connected(stupid, registry, port, Some(error));
+				}
+				debug!(ctx: ctx, "TCP connection closed by remote");
+				conn.finished = true;
+				return self.write(stupid, port);
+			}
+			StupidType::WindowUpdate => {
+				let conn = match self.connections.get_mut(&port) {
+					Some(c) => c,
+					// The connection may have been closed while the update was underway.
+					None => return Ok(()),
+				};
+				if let Err(e) = conn.window.update(data) {
+					debug!(ctx: ctx, "closing connection: {}", e);
+					return self.close(stupid, port);
+				}
+				if conn.paused {
+					let token = Token(TCP_EVENT | usize::from(port));
+					if let Err(e) = registry.reregister(&mut conn.stream, token, Interest::READABLE | Interest::WRITABLE) {
+						debug!(ctx: ctx, "closing connection: {}", e);
+						return self.close(stupid, port);
+					}
+					conn.paused = false;
+					// Read what arrived while the server had no credit left.
+					return self.read(stupid, registry, port);
+				}
+			}
+			_ => debug!(ctx: ctx, "ignoring unexpected {:?} from server", ty),
+		}
+		Ok(())
+	}
+
+	fn resume(&mut self, stupid: &mut StupidClient, registry: &Registry) -> Result<(), RunError> {
+		if !mem::take(&mut self.blocked) {
+			return Ok(());
+		}
+		for port in self.connections.keys().copied().collect::<Vec<_>>() {
+			self.handle_connection(stupid, registry, port)?;
+			self.handle_association(stupid, port)?;
+		}
+		for i in 0..self.local_forwards.len() {
+			self.handle_forward(stupid, registry, i)?;
+		}
+		for port in self.udp_flows.keys().copied().collect::<Vec<_>>() {
+			self.handle_remote_udp(stupid, port)?;
+		}
+		Ok(())
+	}
+
+	fn reset(&mut self) -> Result<(), RunError> {
+		// Connections still in the handshake have no flow yet.
+		self.connections.retain(|_, c| matches!(c.phase, Phase::HttpRequest | Phase::Greeting | Phase::Password | Phase::Request));
+		self.udp_flows.clear();
+		self.lookups.clear();
+		for f in &mut self.local_forwards {
+			f.peers.clear();
+		}
+		Ok(())
+	}
+
+	fn reset_tcp_flows(&mut self) -> Result<(), RunError> {
+		// UDP associations only carry datagrams, which may be lost anyway.
+		self.connections.retain(|_, c| !matches!(c.phase, Phase::Connecting | Phase::Dialing | Phase::Open));
+		let forwards = &self.remote_forwards;
+		self.lookups.retain(|_, l| forwards[l.forward].protocol == Protocol::Udp);
+		Ok(())
+	}
+
+	fn start(&mut self, stupid: &mut StupidClient) -> Result<(), RunError> {
+		self.listen(stupid)
+	}
+
+	fn next_timeout(&self, now: Instant) -> Option<Duration> {
+		self.udp_flows.values()
+			.filter_map(UdpFlow::last_used)
+			.min()
+			.map(|t| (t + self.udp_timeout).saturating_duration_since(now))
+	}
+
+	fn expire(&mut self, now: Instant) {
+		let (timeout, forwards) = (self.udp_timeout, &mut self.local_forwards);
+		self.udp_flows.retain(|port, f| {
+			let keep = f.last_used().is_none_or(|t| now.saturating_duration_since(t) < timeout);
+			if !keep {
+				trace!(ctx: Context::NONE.flow(*port, f.remote()), "forgetting idle UDP flow");
+				if let UdpTarget::Local { forward, peer, .. } = &f.target {
+					forwards[*forward].peers.remove(peer);
+				}
+			}
+			keep
+		});
+	}
+
+	fn report(&self, now: Instant, out: &mut String) {
+		let mut ports = self.connections.iter()
+			.filter(|(_, c)| c.has_flow())
+			.map(|(p, _)| *p)
+			.collect::<Vec<_>>();
+		ports.sort_unstable();
+		for port in ports {
+			let c = &self.connections[&port];
+			Line::new(out, "flow")
+				.field("protocol", "tcp")
+				.field("port", port)
+				.field("remote", c.remote)
+				.text("kind", &c.kind.to_string())
+				.duration("age", now.saturating_duration_since(c.opened))
+				.counters(&c.counters)
+				.end();
+		}
+		let mut ports = self.udp_flows.keys().copied().collect::<Vec<_>>();
+		ports.sort_unstable();
+		for port in ports {
+			let f = &self.udp_flows[&port];
+			let (remote, kind) = match &f.target {
+				UdpTarget::Association { destination, .. } => (destination.clone(), Kind::Socks),
+				UdpTarget::Local { forward, .. } => (self.local_forwards[*forward].target.clone(), Kind::Forward(*forward)),
+				UdpTarget::Remote { .. } => (socks::Address::Ip(f.remote().into()), Kind::Remote),
+			};
+			let mut line = Line::new(out, "flow")
+				.field("protocol", "udp")
+				.field("port", port)
+				.field("remote", remote)
+				.text("kind", &kind.to_string())
+				.duration("age", now.saturating_duration_since(f.opened));
+			if let Some(t) = f.last_used() {
+				line = line.duration("idle", now.saturating_duration_since(t));
+			}
+			line.counters(&f.counters).end();
+		}
+	}
+
+	fn kill(&mut self, stupid: &mut StupidClient, protocol: acl::Protocol, port: u16) -> Result<bool, RunError> {
+		match protocol {
+			acl::Protocol::Tcp if self.connections.get(&port).is_some_and(Connection::has_flow) => {
+				info!(ctx: Context::NONE.flow(port, self.connections[&port].remote), "closing TCP flow on request");
+				self.close(stupid, port)?;
+				Ok(true)
+			}
+			acl::Protocol::Udp => {
+				let flow = match self.udp_flows.remove(&port) {
+					Some(f) => f,
+					None => return Ok(false),
+				};
+				info!(ctx: Context::NONE.flow(port, flow.remote()), "forgetting UDP flow on request");
+				match flow.target {
+					UdpTarget::Association { port: c, destination } => {
+						if let Some(Connection { phase: Phase::Associated(a), .. }) = self.connections.get_mut(&c) {
+							a.flows.remove(&destination);
+						}
+					}
+					UdpTarget::Local { forward, peer, .. } => drop(self.local_forwards[forward].peers.remove(&peer)),
+					UdpTarget::Remote { .. } => (),
+				}
+				Ok(true)
+			}
+			_ => Ok(false),
+		}
+	}
+}
+
+impl Connection {
+	/// Whether the connection is carried by a TCP flow of the server.
+	fn has_flow(&self) -> bool {
+		matches!(self.phase, Phase::Connecting | Phase::Dialing | Phase::Open)
+	}
+
+	fn new(stream: TcpStream, kind: Kind) -> Self {
+		Self {
+			stream,
+			kind,
+			phase: match kind {
+				Kind::Socks => Phase::Greeting,
+				Kind::Http => Phase::HttpRequest,
+				Kind::Forward(_) => Phase::Connecting,
+				Kind::Remote => Phase::Dialing,
+			},
+			received: Vec::new(),
+			remote: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+			window: Window::new(),
+			pending: Vec::new(),
+			paused: false,
+			finished: false,
+			opened: Instant::now(),
+			counters: Counters::default(),
+		}
+	}
+
+	/// Handle what the application sent so far during the handshake, returning its request
+	/// once it is complete.
+	fn handshake(&mut self, credentials: Option<&(String, String)>) -> Result<Option<socks::Request>, HandshakeError> {
+		loop {
+			let len = match self.phase {
+				Phase::HttpRequest => {
+					let len = match http::head_len(&self.received) {
+						Some(len) => len,
+						None => return Ok(None),
+					};
+					let target = http::Head::parse(&self.received[..len]).and_then(|h| h.request());
+					let address = match target {
+						// Same limit as a domain name in a SOCKS request.
+						Some(("CONNECT", target)) => http::split_authority(target)
+							.filter(|(host, _)| host.len() <= 255)
+							.map(|(host, port)| address(host, port)),
+						Some((method, _)) => {
+							let method = method.to_string();
+							self.reply(b"HTTP/1.1 405 Method Not Allowed\r\nAllow: CONNECT\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")?;
+							return Err(HandshakeError::Method(method));
+						}
+						None => None,
+					};
+					let address = match address {
+						Some(a) => a,
+						None => {
+							self.reply(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")?;
+							return Err(HandshakeError::InvalidHttp);
+						}
+					};
+					self.received.drain(..len);
+					return Ok(Some(socks::Request { command: socks::Command::Connect, address }));
+				}
+				Phase::Greeting => {
+					let (methods, len) = match socks::parse_greeting(&self.received)? {
+						Some(r) => r,
+						None => return Ok(None),
+					};
+					let method = match credentials {
+						Some(_) => socks::METHOD_PASSWORD,
+						None => socks::METHOD_NONE,
+					};
+					if !methods.contains(&method) {
+						self.reply(&socks::method(socks::METHOD_UNACCEPTABLE))?;
+						return Err(HandshakeError::NoMethod);
+					}
+					self.reply(&socks::method(method))?;
+					self.phase = if method == socks::METHOD_PASSWORD { Phase::Password } else { Phase::Request };
+					len
+				}
+				Phase::Password => {
+					let ((username, password), len) = match socks::parse_password(&self.received)? {
+						Some(r) => r,
+						None => return Ok(None),
+					};
+					let (u, p) = credentials.unwrap();
+					// Don't stop at the username to avoid leaking whether it was right through timing.
+					let ok = constant_time_eq(username, u.as_bytes()) & constant_time_eq(password, p.as_bytes());
+					self.reply(&socks::password_status(ok))?;
+					if !ok {
+						return Err(HandshakeError::BadPassword);
+					}
+					self.phase = Phase::Request;
+					len
+				}
+				Phase::Request => match socks::parse_request(&self.received) {
+					Ok(Some((request, len))) => {
+						self.received.drain(..len);
+						return Ok(Some(request));
+					}
+					Ok(None) => return Ok(None),
+					Err(e) => {
+						let _ = self.reply(&socks::reply(e.reply(), unspecified()));
+						return Err(e.into());
+					}
+				},
+				_ => return Ok(None),
+			};
+			self.received.drain(..len);
+		}
+	}
+
+	/// Tell the application whether the connection it asked for was established.
+	fn reply_connected(&mut self, error: Option<ConnectError>) -> Result<(), Error> {
+		match (self.kind, error) {
+			(Kind::Socks, None) => self.reply(&socks::reply(socks::Reply::Succeeded, unspecified())),
+			(Kind::Socks, Some(e)) => self.reply(&socks::reply(reply(e), unspecified())),
+			(Kind::Http, None) => self.reply(b"HTTP/1.1 200 Connection Established\r\n\r\n"),
+			(Kind::Http, Some(ConnectError::Denied)) => self.reply(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+			(Kind::Http, Some(ConnectError::Limited)) => self.reply(b"HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+			(Kind::Http, Some(ConnectError::TimedOut)) => self.reply(b"HTTP/1.1 504 Gateway Timeout\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+			(Kind::Http, Some(_)) => self.reply(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+			// Applications of forwards only notice the connection being closed.
+			(Kind::Forward(_) | Kind::Remote, _) => Ok(()),
+		}
+	}
+
+	/// Write a reply of the handshake. These are small enough to always fit in the socket
+	/// buffer.
+	fn reply(&mut self, data: &[u8]) -> Result<(), Error> {
+		match self.stream.write(data)? {
+			n if n == data.len() => Ok(()),
+			_ => Err(ErrorKind::WriteZero.into()),
+		}
+	}
+}
+
+/// The SOCKS reply for a failure to connect.
+fn reply(error: ConnectError) -> socks::Reply {
+	match error {
+		ConnectError::Failed => socks::Reply::Failure,
+		ConnectError::Refused => socks::Reply::ConnectionRefused,
+		ConnectError::Unreachable => socks::Reply::NetworkUnreachable,
+		ConnectError::NotResolved => socks::Reply::HostUnreachable,
+		ConnectError::TimedOut => socks::Reply::TtlExpired,
+		ConnectError::Denied => socks::Reply::NotAllowed,
+		ConnectError::Limited => socks::Reply::Failure,
+	}
+}
+
+impl UdpFlow {
+	fn new(target: UdpTarget) -> Self {
+		Self { target, opened: Instant::now(), counters: Counters::default() }
+	}
+
+	/// When a flow of a forward was last used. Flows of associations last as long as their
+	/// association.
+	fn last_used(&self) -> Option<Instant> {
+		match self.target {
+			UdpTarget::Association { .. } => None,
+			UdpTarget::Local { last_used, .. } | UdpTarget::Remote { last_used, .. } => Some(last_used),
+		}
+	}
+
+	/// The address of the other end of the flow, as far as the server knows it.
+	fn remote(&self) -> SocketAddrV4 {
+		match self.target {
+			UdpTarget::Local { remote, .. } => remote,
+			UdpTarget::Remote { peer, .. } => peer,
+			UdpTarget::Association { ref destination, .. } => remote(destination),
+		}
+	}
+}
+
+/// The address of a host given by name or as an IP address.
+fn address(host: &str, port: u16) -> socks::Address {
+	match host.parse() {
+		Ok(ip) => socks::Address::Ip(SocketAddr::new(ip, port)),
+		Err(_) => socks::Address::Domain(host.into(), port),
+	}
+}
+
+/// The remote address of the flow to `address`. Only the port is used if the server resolves
+/// the name or parses the address.
+fn remote(address: &socks::Address) -> SocketAddrV4 {
+	match address {
+		socks::Address::Ip(SocketAddr::V4(a)) => *a,
+		a => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, a.port()),
+	}
+}
+
+/// Send a datagram to `address`, which the server resolves or parses unless it is an IPv4
+/// address.
+fn send_datagram(stupid: &mut StupidClient, address: &socks::Address, local: u16, data: &[u8]) -> Result<(), RunError> {
+	match address {
+		socks::Address::Ip(SocketAddr::V4(a)) => stupid.send(StupidType::UDP, *a, local, data),
+		socks::Address::Ip(a) => stupid.send(StupidType::UdpName, remote(address), local, &stupid::name_datagram(&a.ip().to_string(), data)),
+		socks::Address::Domain(name, _) => stupid.send(StupidType::UdpName, remote(address), local, &stupid::name_datagram(name, data)),
+	}.map_err(RunError::Send)
+}
+
+fn unspecified() -> SocketAddr {
+	SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into()
+}
+
+/// The unspecified address of the family of `address`, to bind to.
+fn unspecified_ip(address: SocketAddr) -> std::net::IpAddr {
+	match address {
+		SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+		SocketAddr::V6(_) => std::net::Ipv6Addr::UNSPECIFIED.into(),
+	}
+}
+
+#[derive(Debug)]
+enum HandshakeError {
+	Parse(socks::ParseError),
+	Io(Error),
+	/// None of the authentication methods of the application are acceptable.
+	NoMethod,
+	BadPassword,
+	/// The application sent something other than a valid HTTP CONNECT request.
+	InvalidHttp,
+	/// The application sent an HTTP request with a method other than CONNECT.
+	Method(String),
+}
+
+impl From<socks::ParseError> for HandshakeError {
+	fn from(e: socks::ParseError) -> Self {
+		Self::Parse(e)
+	}
+}
+
+impl From<Error> for HandshakeError {
+	fn from(e: Error) -> Self {
+		Self::Io(e)
+	}
+}
+
+impl fmt::Display for HandshakeError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Parse(e) => write!(f, "invalid SOCKS message: {}", e),
+			Self::Io(e) => e.fmt(f),
+			Self::NoMethod => f.write_str("no acceptable authentication method"),
+			Self::BadPassword => f.write_str("wrong username or password"),
+			Self::InvalidHttp => f.write_str("invalid HTTP CONNECT request"),
+			Self::Method(m) => write!(f, "unsupported HTTP method {:?}", m),
+		}
+	}
+}
+
+impl fmt::Display for Kind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			Self::Socks => "SOCKS",
+			Self::Http => "HTTP",
+			Self::Forward(_) => "forward",
+			Self::Remote => "remote forward",
+		})
+	}
+}
@@ -0,0 +1,1322 @@
+//! Configuration of the server & client.
+//!
+//! Options are read from the command line and an optional TOML file, with arguments taking
+//! precedence. Server options go in a `[server]` table, client options in a `[client]` table.
+//! Options common to both, like logging, go in the table of the mode being run.
+//! Every option is validated before anything is opened.
+//!
+//! Named identities of clients are kept in a separate TOML file the server can reload, with a
+//! table per identity holding its `key` and optionally its own `acl` and limits, e.g.:
+//!
+//! ```toml
+//! [alice]
+//! key = "secret"
+//! acl = ["allow tcp 10.0.0.0/8 5432"]
+//! rate_limit = 1_000_000
+//! ```
+
+mod toml;
+
+use self::toml::{Table, Value};
+use crate::acl::Acl;
+use crate::egress::{self, Egress, Route};
+use crate::log;
+use crate::stupid::{Compression, Endpoint, Priorities, Protocol, Transport};
+use crate::upstream::Proxy;
+use core::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub enum Mode {
+	Server(ServerConfig),
+	Client(ClientConfig),
+}
+
+pub struct LogConfig {
+	pub level: log::Level,
+	pub format: log::Format,
+}
+
+pub struct KeepaliveConfig {
+	/// How often to send a ping.
+	pub interval: Duration,
+	/// How long to wait for any frame before considering the link dead.
+	pub timeout: Duration,
+}
+
+pub struct CompressionConfig {
+	/// The compression the client asks for, or the server allows.
+	pub algorithm: Compression,
+	/// Frames with less data than this are sent uncompressed.
+	pub threshold: usize,
+}
+
+/// Limits on what a single client may use of the server. The sessions of an identity share
+/// them. Every limit is optional.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LimitConfig {
+	/// Bytes per second relayed for a client, in both directions together.
+	pub rate: Option<usize>,
+	/// Bytes per second relayed for a single TCP connection, UDP or ICMP socket.
+	pub flow_rate: Option<usize>,
+	/// TCP connections open at once.
+	pub tcp: Option<usize>,
+	/// UDP and ICMP sockets and UDP peers of remote forwards open at once.
+	pub udp: Option<usize>,
+	/// TCP connections and UDP sockets opened per second.
+	pub connection_rate: Option<usize>,
+}
+
+/// A named client with its own key and policy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+	pub name: String,
+	pub key: String,
+	/// Rules checked before those of the server.
+	pub acl: Acl,
+	/// Limits replacing those of the server, where set.
+	pub limits: LimitConfig,
+	/// Egress options replacing those of the server, where set.
+	pub egress: Egress,
+	/// Routes checked before those of the server.
+	pub egress_routes: Vec<Route>,
+}
+
+/// A port forward, which listens on one side of the tunnel and connects every connection or
+/// UDP peer to a fixed destination from the other side.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Forward {
+	pub protocol: Protocol,
+	/// The address to listen on.
+	pub listen: SocketAddr,
+	/// The name or address to connect to.
+	pub host: String,
+	pub port: u16,
+}
+
+pub struct ServerConfig {
+	/// The address to accept clients on.
+	pub listen: SocketAddr,
+	/// The address to accept clients on over UDP, if any.
+	pub listen_udp: Option<SocketAddr>,
+	/// The address to accept clients on over WebSocket, if any.
+	pub listen_ws: Option<SocketAddr>,
+	/// The Unix socket to accept clients on, if any.
+	pub listen_unix: Option<PathBuf>,
+	/// Serve a single client on stdin & stdout instead of listening.
+	pub stdio: bool,
+	/// Keys clients may authenticate with without an identity. If empty and there is no
+	/// identities file any client is accepted.
+	pub keys: Vec<String>,
+	/// The file the identities are loaded from, if any.
+	pub identities_file: Option<PathBuf>,
+	pub identities: Vec<Identity>,
+	/// How long a UDP or ICMP socket may be idle before it is closed.
+	pub udp_timeout: Duration,
+	/// How long a TCP connection may be idle before it is closed.
+	pub tcp_timeout: Duration,
+	/// How long to keep the sockets of a disconnected client so it can resume its session.
+	pub session_timeout: Duration,
+	/// Listen on the addresses clients ask for with their remote forwards.
+	pub allow_remote_forwards: bool,
+	/// Which destinations clients may open flows to.
+	pub acl: Acl,
+	pub limits: LimitConfig,
+	/// How traffic to destinations leaves the server.
+	pub egress: Egress,
+	/// Other egress options for destinations in some networks.
+	pub egress_routes: Vec<Route>,
+	/// The SOCKS5 proxy to open TCP connections and UDP sockets to destinations through, if
+	/// any.
+	pub upstream_proxy: Option<Proxy>,
+	/// Weights of flows by destination port.
+	pub priorities: Priorities,
+	pub compression: CompressionConfig,
+	pub keepalive: KeepaliveConfig,
+	pub log: LogConfig,
+	/// The Unix socket to report statistics and accept commands on, if any.
+	pub control_socket: Option<PathBuf>,
+}
+
+pub struct ClientConfig {
+	/// The address of the server.
+	pub connect: SocketAddr,
+	/// How to carry frames to the server.
+	pub transport: Transport,
+	/// The HTTP proxy to reach the server through, for the WebSocket transport.
+	pub proxy: Option<SocketAddr>,
+	/// The path to request the WebSocket on.
+	pub websocket_path: String,
+	/// The Unix socket of the server, for the Unix transport.
+	pub socket: Option<PathBuf>,
+	/// The command connected to the server, for the command transport.
+	pub command: Option<String>,
+	/// The key to authenticate with.
+	pub key: String,
+	/// The name of the tun interface.
+	pub tun_name: String,
+	/// The MTU of the tun interface.
+	pub mtu: usize,
+	/// The /96 prefix IPv4 addresses are mapped into.
+	pub prefix: Ipv6Addr,
+	/// The address of the tun interface, which must be inside the prefix.
+	pub address: Ipv6Addr,
+	/// Verify TCP & UDP checksums of packets read from the tun.
+	pub verify_checksums: bool,
+	/// The address to accept SOCKS5 clients on instead of creating a tun, if any.
+	pub socks: Option<SocketAddr>,
+	/// The username SOCKS clients must authenticate with, if any.
+	pub socks_username: Option<String>,
+	/// The password SOCKS clients must authenticate with, if a username is set.
+	pub socks_password: Option<String>,
+	/// The address to accept HTTP CONNECT requests on instead of creating a tun, if any.
+	pub http_proxy: Option<SocketAddr>,
+	/// Forwards listening on the client, with the server connecting to their destination.
+	pub local_forwards: Vec<Forward>,
+	/// Forwards listening on the server, with the client connecting to their destination.
+	pub remote_forwards: Vec<Forward>,
+	/// How long a UDP peer of a forward may be idle before it is forgotten.
+	pub udp_timeout: Duration,
+	/// How long to wait for all fragments of a packet.
+	pub fragment_timeout: Duration,
+	/// How long to wait before reconnecting after losing the connection to the server. The
+	/// delay doubles after every failed attempt.
+	pub reconnect_delay: Duration,
+	/// The maximum delay between reconnection attempts.
+	pub max_reconnect_delay: Duration,
+	/// Weights of flows by destination port.
+	pub priorities: Priorities,
+	pub compression: CompressionConfig,
+	pub keepalive: KeepaliveConfig,
+	pub log: LogConfig,
+	/// The Unix socket to report statistics and accept commands on, if any.
+	pub control_socket: Option<PathBuf>,
+}
+
+struct Opt {
+	/// The key in the configuration file.
+	key: &'static str,
+	/// The command line flag, without leading dashes.
+	flag: &'static str,
+	/// A short description of the argument.
+	arg: &'static str,
+	default: Option<&'static str>,
+	help: &'static str,
+}
+
+/// Options shared by the server & client.
+const COMMON_OPTIONS: &[Opt] = &[
+	Opt { key: "log_level", flag: "log-level", arg: "LEVEL", default: Some("info"), help: "Least severe level to log: error, warn, info, debug or trace" },
+	Opt { key: "log_format", flag: "log-format", arg: "FORMAT", default: Some("text"), help: "Format of log records: text or json" },
+	Opt { key: "control_socket", flag: "control-socket", arg: "PATH", default: None, help: "Report statistics and accept commands on this Unix socket, which only the owner may connect to" },
+	Opt { key: "keepalive_interval", flag: "keepalive-interval", arg: "SECONDS", default: Some("15"), help: "Send a ping this often" },
+	Opt { key: "keepalive_timeout", flag: "keepalive-timeout", arg: "SECONDS", default: Some("60"), help: "Consider the link dead if nothing was received for this long" },
+	Opt { key: "priorities", flag: "priority", arg: "PORT=WEIGHT", default: None, help: "Give flows to a destination port a larger share of the link than the default weight of 1, e.g. 22=8. May be repeated" },
+	Opt { key: "compression_threshold", flag: "compression-threshold", arg: "BYTES", default: Some("256"), help: "Don't compress frames with less data than this" },
+];
+
+const SERVER_OPTIONS: &[Opt] = &[
+	Opt { key: "listen", flag: "listen", arg: "ADDRESS", default: Some("127.0.0.1:5434"), help: "Address to accept clients on" },
+	Opt { key: "listen_udp", flag: "listen-udp", arg: "ADDRESS", default: None, help: "Also accept clients over UDP on this address" },
+	Opt { key: "listen_ws", flag: "listen-ws", arg: "ADDRESS", default: None, help: "Also accept clients over WebSocket, i.e. plain HTTP, on this address" },
+	Opt { key: "listen_unix", flag: "listen-unix", arg: "PATH", default: None, help: "Also accept clients on this Unix socket" },
+	Opt { key: "stdio", flag: "stdio", arg: "BOOL", default: Some("false"), help: "Serve a single client on stdin & stdout instead of listening, e.g. as the remote command of SSH. Exits once the client is gone" },
+	Opt { key: "keys", flag: "key", arg: "KEY", default: None, help: "Key a client may authenticate with. May be repeated. If none are given any client is accepted" },
+	Opt { key: "identities", flag: "identities", arg: "PATH", default: None, help: "TOML file with a table per named identity, holding its key and optionally its own acl and limits, which take precedence over those of the server. Reloaded on SIGHUP, which ends the sessions of removed identities" },
+	Opt { key: "udp_timeout", flag: "udp-timeout", arg: "SECONDS", default: Some("60"), help: "Close UDP & ICMP sockets after being idle this long" },
+	Opt { key: "tcp_timeout", flag: "tcp-timeout", arg: "SECONDS", default: Some("7200"), help: "Close TCP connections after being idle this long" },
+	Opt { key: "session_timeout", flag: "session-timeout", arg: "SECONDS", default: Some("60"), help: "Keep the sockets of a disconnected client this long so it can resume its session" },
+	Opt { key: "allow_remote_forwards", flag: "allow-remote-forwards", arg: "BOOL", default: Some("false"), help: "Listen on the addresses clients ask for with --remote-forward" },
+	Opt { key: "acl", flag: "acl", arg: "RULE", default: None, help: "Allow or deny destinations, e.g. \"allow tcp 10.0.0.0/8 5432\" or \"deny any 0.0.0.0/0 25\". May be repeated; the first matching rule wins. Loopback, link-local and private networks are denied unless allowed" },
+	Opt { key: "rate_limit", flag: "rate-limit", arg: "BYTES", default: None, help: "Relay at most this many bytes per second for each client, in both directions together" },
+	Opt { key: "flow_rate_limit", flag: "flow-rate-limit", arg: "BYTES", default: None, help: "Relay at most this many bytes per second for each TCP connection, UDP or ICMP socket" },
+	Opt { key: "max_tcp_connections", flag: "max-tcp-connections", arg: "COUNT", default: None, help: "Most TCP connections a client may have open at once" },
+	Opt { key: "max_udp_sockets", flag: "max-udp-sockets", arg: "COUNT", default: None, help: "Most UDP & ICMP sockets a client may have open at once" },
+	Opt { key: "connection_rate_limit", flag: "connection-rate-limit", arg: "COUNT", default: None, help: "Most TCP connections and UDP sockets a client may open per second" },
+	Opt { key: "bind_address", flag: "bind-address", arg: "ADDRESS", default: None, help: "Source address of traffic to destinations of the same family" },
+	Opt { key: "bind_device", flag: "bind-device", arg: "INTERFACE", default: None, help: "Send traffic to destinations through this interface with SO_BINDTODEVICE. Needs CAP_NET_RAW" },
+	Opt { key: "fwmark", flag: "fwmark", arg: "MARK", default: None, help: "Set this firewall mark on traffic to destinations with SO_MARK, for policy routing. Needs CAP_NET_ADMIN" },
+	Opt { key: "egress_routes", flag: "egress-route", arg: "NETWORK OPTIONS", default: None, help: "Use other egress options for destinations in a network, e.g. \"10.0.0.0/8 address=10.0.0.2 device=eth1 mark=0x10\". May be repeated; the first matching route wins" },
+	Opt { key: "upstream_socks", flag: "upstream-socks", arg: "ADDRESS", default: None, help: "Open TCP connections and UDP sockets to destinations through this SOCKS5 proxy instead of directly. The egress options apply to the connections to the proxy. ICMP is still sent directly" },
+	Opt { key: "upstream_socks_username", flag: "upstream-socks-username", arg: "USERNAME", default: None, help: "Username to authenticate to the upstream SOCKS5 proxy with" },
+	Opt { key: "upstream_socks_password", flag: "upstream-socks-password", arg: "PASSWORD", default: None, help: "Password to authenticate to the upstream SOCKS5 proxy with, together with the username" },
+	Opt { key: "compression", flag: "compression", arg: "ALGORITHM", default: Some("deflate"), help: "Compression clients may ask for: none or deflate" },
+];
+
+/// The server options an identity can have its own value of.
+const IDENTITY_OPTIONS: &[&str] = &[
+	"acl", "rate_limit", "flow_rate_limit", "max_tcp_connections", "max_udp_sockets", "connection_rate_limit",
+	"bind_address", "bind_device", "fwmark", "egress_routes",
+];
+const IDENTITY_KEY: Opt = Opt { key: "key", flag: "key", arg: "KEY", default: None, help: "Key the identity authenticates with" };
+
+const CLIENT_OPTIONS: &[Opt] = &[
+	Opt { key: "connect", flag: "connect", arg: "ADDRESS", default: Some("127.0.0.1:5434"), help: "Address of the server" },
+	Opt { key: "transport", flag: "transport", arg: "TRANSPORT", default: Some("tcp"), help: "How to connect to the server: tcp, udp, ws, unix or command" },
+	Opt { key: "proxy", flag: "proxy", arg: "ADDRESS", default: None, help: "HTTP proxy to connect to the server through with CONNECT. Only for the ws transport" },
+	Opt { key: "websocket_path", flag: "websocket-path", arg: "PATH", default: Some("/"), help: "Path to request the WebSocket on" },
+	Opt { key: "socket", flag: "socket", arg: "PATH", default: None, help: "Unix socket of the server, for the unix transport" },
+	Opt { key: "command", flag: "command", arg: "COMMAND", default: None, help: "Shell command whose stdin & stdout are connected to the server, for the command transport, e.g. \"ssh host stupid_tunnel server --stdio\"" },
+	Opt { key: "key", flag: "key", arg: "KEY", default: Some(""), help: "Key to authenticate with" },
+	Opt { key: "tun_name", flag: "tun-name", arg: "NAME", default: Some("stupid_tunnel"), help: "Name of the tun interface" },
+	Opt { key: "mtu", flag: "mtu", arg: "BYTES", default: Some("1500"), help: "MTU of the tun interface" },
+	Opt { key: "prefix", flag: "prefix", arg: "PREFIX", default: Some("abcd:ef00::/96"), help: "IPv6 /96 prefix IPv4 addresses are mapped into" },
+	Opt { key: "address", flag: "address", arg: "ADDRESS", default: Some("abcd:ef00::1001"), help: "IPv6 address of the tun interface, inside the prefix" },
+	Opt { key: "verify_checksums", flag: "verify-checksums", arg: "BOOL", default: Some("true"), help: "Verify checksums of packets from the tun. Disable if checksums are offloaded" },
+	Opt { key: "socks", flag: "socks", arg: "ADDRESS", default: None, help: "Accept SOCKS5 clients on this address instead of creating a tun" },
+	Opt { key: "socks_username", flag: "socks-username", arg: "USERNAME", default: None, help: "Username SOCKS clients must authenticate with" },
+	Opt { key: "socks_password", flag: "socks-password", arg: "PASSWORD", default: None, help: "Password SOCKS clients must authenticate with, together with the username" },
+	Opt { key: "http_proxy", flag: "http-proxy", arg: "ADDRESS", default: None, help: "Accept HTTP CONNECT requests on this address instead of creating a tun" },
+	Opt { key: "local_forwards", flag: "local-forward", arg: "[BIND:]PORT:HOST:HOSTPORT[/udp]", default: None, help: "Listen on BIND:PORT, by default on 127.0.0.1, and connect every connection or UDP peer to HOST:HOSTPORT from the server, instead of creating a tun. May be repeated" },
+	Opt { key: "remote_forwards", flag: "remote-forward", arg: "[BIND:]PORT:HOST:HOSTPORT[/udp]", default: None, help: "Ask the server to listen on BIND:PORT, by default on 127.0.0.1, and connect every connection or UDP peer to HOST:HOSTPORT from the client, instead of creating a tun. May be repeated" },
+	Opt { key: "udp_timeout", flag: "udp-timeout", arg: "SECONDS", default: Some("60"), help: "Forget UDP peers of forwards after being idle this long" },
+	Opt { key: "fragment_timeout", flag: "fragment-timeout", arg: "SECONDS", default: Some("60"), help: "Drop fragmented packets not reassembled within this time" },
+	Opt { key: "reconnect_delay", flag: "reconnect-delay", arg: "SECONDS", default: Some("1"), help: "Wait this long before reconnecting to the server, doubling after every failed attempt" },
+	Opt { key: "max_reconnect_delay", flag: "max-reconnect-delay", arg: "SECONDS", default: Some("60"), help: "Maximum time to wait between reconnection attempts" },
+	Opt { key: "compression", flag: "compression", arg: "ALGORITHM", default: Some("none"), help: "Ask the server to compress frames in both directions: none or deflate" },
+];
+
+/// Parse the command line arguments, excluding the program name, and load the configuration.
+pub fn from_args(args: impl Iterator<Item = String>) -> Result<Mode, ConfigError> {
+	let mut mode = None;
+	let mut file = None;
+	let mut flags = Vec::<(String, String)>::new();
+
+	let mut args = args.peekable();
+	while let Some(arg) = args.next() {
+		let (flag, value) = match arg.strip_prefix("--") {
+			Some(f) => match f.split_once('=') {
+				Some((f, v)) => (f.to_string(), Some(v.to_string())),
+				None => (f.to_string(), None),
+			}
+			None if arg == "-h" => ("help".into(), None),
+			None if arg == "-c" => ("config".into(), None),
+			None if mode.is_none() && (arg == "server" || arg == "client") => {
+				mode = Some(arg);
+				continue;
+			}
+			None => return Err(ConfigError::Usage(format!("unexpected argument {:?}", arg))),
+		};
+		if flag == "help" {
+			return Err(ConfigError::Help);
+		}
+		// Boolean options may be given without a value to enable them.
+		let is_bool = [COMMON_OPTIONS, SERVER_OPTIONS, CLIENT_OPTIONS].iter().flat_map(|o| *o).any(|o| o.flag == flag && o.arg == "BOOL");
+		let value = match value {
+			None if is_bool && !matches!(args.peek().map(String::as_str), Some("true" | "false")) => Some("true".into()),
+			v => v,
+		};
+		let value = match value.or_else(|| args.next()) {
+			Some(v) => v,
+			None => return Err(ConfigError::Usage(format!("--{} requires an argument", flag))),
+		};
+		if flag == "config" {
+			file = Some(value);
+		} else {
+			flags.push((flag, value));
+		}
+	}
+
+	let (mode, options) = match mode.as_deref() {
+		Some("server") => ("server", SERVER_OPTIONS),
+		Some("client") => ("client", CLIENT_OPTIONS),
+		_ => return Err(ConfigError::Usage("expected \"server\" or \"client\"".into())),
+	};
+	let options = COMMON_OPTIONS.iter().chain(options).collect::<Vec<_>>();
+
+	if let Some((flag, _)) = flags.iter().find(|(f, _)| !options.iter().any(|o| o.flag == f)) {
+		return Err(ConfigError::Usage(format!("unknown option --{} for {}", flag, mode)));
+	}
+
+	let mut table = match &file {
+		Some(path) => {
+			let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+			toml::parse(&text).map_err(|e| ConfigError::Parse(path.clone(), e))?
+		}
+		None => Table::new(),
+	};
+	let table = match table.remove(mode) {
+		Some(Value::Table(t)) => t,
+		Some(v) => return Err(ConfigError::invalid(mode, format!("expected a table, found {}", v.type_name()))),
+		None => Table::new(),
+	};
+	for (k, v) in table.iter() {
+		if !options.iter().any(|o| o.key == k) {
+			return Err(ConfigError::invalid(&format!("{}.{}", mode, k), format!("unknown option ({})", v.type_name())));
+		}
+	}
+
+	let values = Values { section: mode.into(), options, table, flags };
+	Ok(match mode {
+		"server" => Mode::Server(ServerConfig::new(&values)?),
+		_ => Mode::Client(ClientConfig::new(&values)?),
+	})
+}
+
+/// Write a description of every option.
+pub fn help(name: &str, out: &mut impl io::Write) -> io::Result<()> {
+	writeln!(out, "Usage:")?;
+	writeln!(out, "  {} [OPTIONS] server", name)?;
+	writeln!(out, "  {} [OPTIONS] client", name)?;
+	writeln!(out)?;
+	writeln!(out, "Options:")?;
+	writeln!(out, "  -c, --config PATH")?;
+	writeln!(out, "          TOML file to read options from. Server options are read from the")?;
+	writeln!(out, "          [server] table and client options from the [client] table, using the")?;
+	writeln!(out, "          names in brackets. Command line options take precedence")?;
+	writeln!(out, "  -h, --help")?;
+	writeln!(out, "          Show this help")?;
+	for (section, options) in [("Common", COMMON_OPTIONS), ("Server", SERVER_OPTIONS), ("Client", CLIENT_OPTIONS)] {
+		writeln!(out)?;
+		writeln!(out, "{} options:", section)?;
+		for o in options {
+			writeln!(out, "      --{} {}  [{}]", o.flag, o.arg, o.key)?;
+			write!(out, "          {}", o.help)?;
+			match o.default {
+				Some("") | None => writeln!(out)?,
+				Some(d) => writeln!(out, " (default: {})", d)?,
+			}
+		}
+	}
+	Ok(())
+}
+
+impl ServerConfig {
+	fn new(values: &Values) -> Result<Self, ConfigError> {
+		let mut slf = Self {
+			listen: values.get("listen")?,
+			listen_udp: values.get("listen_udp")?,
+			listen_ws: values.get("listen_ws")?,
+			listen_unix: values.get("listen_unix")?,
+			stdio: values.get("stdio")?,
+			keys: values.get("keys")?,
+			identities_file: values.get("identities")?,
+			identities: Vec::new(),
+			udp_timeout: values.get("udp_timeout")?,
+			tcp_timeout: values.get("tcp_timeout")?,
+			session_timeout: values.get("session_timeout")?,
+			allow_remote_forwards: values.get("allow_remote_forwards")?,
+			acl: values.get("acl")?,
+			limits: LimitConfig::new(values)?,
+			egress: egress(values)?,
+			egress_routes: values.get("egress_routes")?,
+			upstream_proxy: upstream_proxy(values)?,
+			priorities: values.get("priorities")?,
+			compression: CompressionConfig::new(values)?,
+			keepalive: KeepaliveConfig::new(values)?,
+			log: LogConfig::new(values)?,
+			control_socket: values.get("control_socket")?,
+		};
+		if slf.stdio && (slf.listen_udp.is_some() || slf.listen_ws.is_some() || slf.listen_unix.is_some()) {
+			return Err(values.invalid("stdio", "can't be combined with listening on other addresses".into()));
+		}
+		if let Some(path) = &slf.identities_file {
+			slf.identities = load_identities(path)?;
+		}
+		Ok(slf)
+	}
+}
+
+fn egress(values: &Values) -> Result<Egress, ConfigError> {
+	let slf = Egress {
+		address: values.get("bind_address")?,
+		device: values.get("bind_device")?,
+		mark: values.get::<Option<Mark>>("fwmark")?.map(|m| m.0),
+	};
+	if slf.device.as_deref().is_some_and(|d| !egress::is_device(d)) {
+		return Err(values.invalid("bind_device", format!("must be between 1 and {} bytes long", egress::MAX_DEVICE_LEN)));
+	}
+	Ok(slf)
+}
+
+fn upstream_proxy(values: &Values) -> Result<Option<Proxy>, ConfigError> {
+	let address = values.get::<Option<SocketAddr>>("upstream_socks")?;
+	let username = values.get::<Option<String>>("upstream_socks_username")?;
+	let password = values.get::<Option<String>>("upstream_socks_password")?;
+	if username.is_some() != password.is_some() {
+		return Err(values.invalid("upstream_socks_password", "must be given together with upstream_socks_username".into()));
+	}
+	for (key, value) in [("upstream_socks_username", &username), ("upstream_socks_password", &password)] {
+		if value.as_ref().is_some_and(|v| !(1..=255).contains(&v.len())) {
+			return Err(values.invalid(key, "must be between 1 and 255 bytes long".into()));
+		}
+	}
+	match address {
+		Some(address) => Ok(Some(Proxy { address, credentials: username.zip(password) })),
+		None if username.is_some() => Err(values.invalid("upstream_socks_username", "requires upstream_socks".into())),
+		None => Ok(None),
+	}
+}
+
+/// Load the identities of clients from a file.
+pub fn load_identities(path: &Path) -> Result<Vec<Identity>, ConfigError> {
+	let file = path.display().to_string();
+	let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(file.clone(), e))?;
+	let table = toml::parse(&text).map_err(|e| ConfigError::Parse(file.clone(), e))?;
+	let options = SERVER_OPTIONS.iter()
+		.filter(|o| IDENTITY_OPTIONS.contains(&o.key))
+		.chain([&IDENTITY_KEY])
+		.collect::<Vec<_>>();
+	let mut identities = Vec::<Identity>::new();
+	for (name, value) in table {
+		let section = format!("{}: {}", file, name);
+		// Names go in log records as they are.
+		if !name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.@".contains(c)) {
+			return Err(ConfigError::invalid(&section, "names may only have letters, digits, '-', '_', '.' and '@'".into()));
+		}
+		let table = match value {
+			Value::Table(t) => t,
+			v => return Err(ConfigError::invalid(&section, format!("expected a table, found {}", v.type_name()))),
+		};
+		if let Some((k, v)) = table.iter().find(|(k, _)| !options.iter().any(|o| o.key == *k)) {
+			return Err(ConfigError::invalid(&format!("{}.{}", section, k), format!("unknown option ({})", v.type_name())));
+		}
+		let values = Values { section, options: options.clone(), table, flags: Vec::new() };
+		let key = values.get::<Option<String>>("key")?.ok_or_else(|| values.invalid("key", "is required".into()))?;
+		if let Some(other) = identities.iter().find(|i| i.key == key) {
+			return Err(values.invalid("key", format!("is also the key of {}", other.name)));
+		}
+		identities.push(Identity {
+			name,
+			key,
+			acl: values.get("acl")?,
+			limits: LimitConfig::new(&values)?,
+			egress: egress(&values)?,
+			egress_routes: values.get("egress_routes")?,
+		});
+	}
+	Ok(identities)
+}
+
+impl ClientConfig {
+	fn new(values: &Values) -> Result<Self, ConfigError> {
+		let slf = Self {
+			connect: values.get("connect")?,
+			transport: values.get("transport")?,
+			proxy: values.get("proxy")?,
+			websocket_path: values.get("websocket_path")?,
+			socket: values.get("socket")?,
+			command: values.get("command")?,
+			key: values.get("key")?,
+			tun_name: values.get("tun_name")?,
+			mtu: values.get("mtu")?,
+			prefix: values.get::<Prefix>("prefix")?.0,
+			address: values.get("address")?,
+			verify_checksums: values.get("verify_checksums")?,
+			socks: values.get("socks")?,
+			socks_username: values.get("socks_username")?,
+			socks_password: values.get("socks_password")?,
+			http_proxy: values.get("http_proxy")?,
+			local_forwards: values.get("local_forwards")?,
+			remote_forwards: values.get("remote_forwards")?,
+			udp_timeout: values.get("udp_timeout")?,
+			fragment_timeout: values.get("fragment_timeout")?,
+			reconnect_delay: values.get("reconnect_delay")?,
+			max_reconnect_delay: values.get("max_reconnect_delay")?,
+			priorities: values.get("priorities")?,
+			compression: CompressionConfig::new(values)?,
+			keepalive: KeepaliveConfig::new(values)?,
+			log: LogConfig::new(values)?,
+			control_socket: values.get("control_socket")?,
+		};
+		if slf.tun_name.is_empty() || slf.tun_name.len() >= 16 || slf.tun_name.contains(['/', '\0']) {
+			return Err(values.invalid("tun_name", "must be between 1 and 15 bytes long".into()));
+		}
+		if !(crate::fragment::MIN_MTU..=0xffff).contains(&slf.mtu) {
+			return Err(values.invalid("mtu", format!("must be between {} and 65535", crate::fragment::MIN_MTU)));
+		}
+		if slf.address.octets()[..12] != slf.prefix.octets()[..12] {
+			return Err(values.invalid("address", format!("must be inside the prefix {}/96", slf.prefix)));
+		}
+		if slf.proxy.is_some() && slf.transport != Transport::WebSocket {
+			return Err(values.invalid("proxy", "requires the ws transport".into()));
+		}
+		if slf.transport == Transport::Unix && slf.socket.is_none() {
+			return Err(values.invalid("socket", "is required by the unix transport".into()));
+		}
+		if slf.transport == Transport::Command && slf.command.as_deref().map_or(true, str::is_empty) {
+			return Err(values.invalid("command", "is required by the command transport".into()));
+		}
+		if !slf.websocket_path.starts_with('/') || slf.websocket_path.contains(char::is_whitespace) {
+			return Err(values.invalid("websocket_path", "must start with / and not contain whitespace".into()));
+		}
+		if slf.socks_username.is_some() != slf.socks_password.is_some() {
+			return Err(values.invalid("socks_password", "must be given together with socks_username".into()));
+		}
+		for (key, value) in [("socks_username", &slf.socks_username), ("socks_password", &slf.socks_password)] {
+			if value.as_ref().map_or(false, |v| !(1..=255).contains(&v.len())) {
+				return Err(values.invalid(key, "must be between 1 and 255 bytes long".into()));
+			}
+		}
+		if slf.remote_forwards.iter().any(|f| !f.listen.is_ipv4()) {
+			return Err(values.invalid("remote_forwards", "must listen on IPv4 addresses".into()));
+		}
+		if slf.local_forwards.len().max(slf.remote_forwards.len()) > usize::from(u16::MAX) {
+			return Err(values.invalid("local_forwards", "too many forwards".into()));
+		}
+		if slf.reconnect_delay.is_zero() {
+			return Err(values.invalid("reconnect_delay", "must be more than zero".into()));
+		}
+		if slf.max_reconnect_delay < slf.reconnect_delay {
+			return Err(values.invalid("max_reconnect_delay", "must be at least reconnect_delay".into()));
+		}
+		Ok(slf)
+	}
+
+	/// Whether applications connect to the client themselves instead of having their packets
+	/// routed to a tun.
+	pub fn is_proxy(&self) -> bool {
+		self.socks.is_some() || self.http_proxy.is_some() || !self.local_forwards.is_empty() || !self.remote_forwards.is_empty()
+	}
+
+	/// Where the server is and how to reach it.
+	pub fn endpoint(&self) -> Endpoint {
+		match self.transport {
+			Transport::Tcp => Endpoint::Tcp(self.connect),
+			Transport::Udp => Endpoint::Udp(self.connect),
+			Transport::WebSocket => Endpoint::WebSocket {
+				address: self.connect,
+				proxy: self.proxy,
+				path: self.websocket_path.clone(),
+			},
+			Transport::Unix => Endpoint::Unix(self.socket.clone().unwrap()),
+			Transport::Command => Endpoint::Command(self.command.clone().unwrap()),
+		}
+	}
+}
+
+impl KeepaliveConfig {
+	fn new(values: &Values) -> Result<Self, ConfigError> {
+		let slf = Self {
+			interval: values.get("keepalive_interval")?,
+			timeout: values.get("keepalive_timeout")?,
+		};
+		if slf.interval.is_zero() {
+			return Err(values.invalid("keepalive_interval", "must be more than zero".into()));
+		}
+		if slf.timeout <= slf.interval {
+			return Err(values.invalid("keepalive_timeout", "must be more than keepalive_interval".into()));
+		}
+		Ok(slf)
+	}
+}
+
+impl LimitConfig {
+	fn new(values: &Values) -> Result<Self, ConfigError> {
+		let slf = Self {
+			rate: values.get("rate_limit")?,
+			flow_rate: values.get("flow_rate_limit")?,
+			tcp: values.get("max_tcp_connections")?,
+			udp: values.get("max_udp_sockets")?,
+			connection_rate: values.get("connection_rate_limit")?,
+		};
+		for (key, value) in [("rate_limit", slf.rate), ("flow_rate_limit", slf.flow_rate), ("connection_rate_limit", slf.connection_rate)] {
+			if value == Some(0) {
+				return Err(values.invalid(key, "must be more than zero".into()));
+			}
+		}
+		Ok(slf)
+	}
+
+	/// These limits, with those that aren't set taken from `other`.
+	pub fn or(&self, other: &Self) -> Self {
+		Self {
+			rate: self.rate.or(other.rate),
+			flow_rate: self.flow_rate.or(other.flow_rate),
+			tcp: self.tcp.or(other.tcp),
+			udp: self.udp.or(other.udp),
+			connection_rate: self.connection_rate.or(other.connection_rate),
+		}
+	}
+}
+
+impl CompressionConfig {
+	fn new(values: &Values) -> Result<Self, ConfigError> {
+		Ok(Self {
+			algorithm: values.get("compression")?,
+			threshold: values.get("compression_threshold")?,
+		})
+	}
+}
+
+impl LogConfig {
+	fn new(values: &Values) -> Result<Self, ConfigError> {
+		Ok(Self {
+			level: values.get("log_level")?,
+			format: values.get("log_format")?,
+		})
+	}
+}
+
+struct Values {
+	section: String,
+	options: Vec<&'static Opt>,
+	table: Table,
+	flags: Vec<(String, String)>,
+}
+
+impl Values {
+	fn get<T: FromValue>(&self, key: &str) -> Result<T, ConfigError> {
+		let opt = self.options.iter().find(|o| o.key == key).expect("undefined option");
+		let mut flags = self.flags.iter().filter(|(f, _)| f == opt.flag).map(|(_, v)| v).peekable();
+		let parse_error = |v: &dyn fmt::Debug| ConfigError::Invalid {
+			key: format!("--{}", opt.flag),
+			message: format!("expected {}, found {:?}", T::EXPECTED, v),
+		};
+
+		if flags.peek().is_some() {
+			let mut value = None::<T>;
+			for v in flags {
+				let v = T::from_str(v).ok_or_else(|| parse_error(v))?;
+				value = Some(match value {
+					Some(value) if T::REPEATED => value.append(v),
+					_ => v,
+				});
+			}
+			return Ok(value.unwrap());
+		}
+		match self.table.get(key) {
+			Some(v) => T::from_value(v).ok_or_else(|| self.invalid(key, format!("expected {}, found {:?}", T::EXPECTED, v))),
+			None => match opt.default {
+				Some(d) => Ok(T::from_str(d).expect("invalid default")),
+				None => Ok(T::empty().expect("option has no default")),
+			}
+		}
+	}
+
+	fn invalid(&self, key: &str, message: String) -> ConfigError {
+		ConfigError::invalid(&format!("{}.{}", self.section, key), message)
+	}
+}
+
+trait FromValue: Sized {
+	/// Description of the expected value for error messages.
+	const EXPECTED: &'static str;
+	/// Whether the option may be given more than once on the command line.
+	const REPEATED: bool = false;
+
+	fn from_str(s: &str) -> Option<Self>;
+
+	fn from_value(value: &Value) -> Option<Self> {
+		match value {
+			Value::String(s) => Self::from_str(s),
+			_ => None,
+		}
+	}
+
+	fn append(self, _other: Self) -> Self {
+		unreachable!()
+	}
+
+	fn empty() -> Option<Self> {
+		None
+	}
+}
+
+impl FromValue for String {
+	const EXPECTED: &'static str = "a string";
+
+	fn from_str(s: &str) -> Option<Self> {
+		Some(s.into())
+	}
+}
+
+impl FromValue for Vec<String> {
+	const EXPECTED: &'static str = "a list of strings";
+	const REPEATED: bool = true;
+
+	fn from_str(s: &str) -> Option<Self> {
+		Some(vec![s.into()])
+	}
+
+	fn from_value(value: &Value) -> Option<Self> {
+		match value {
+			Value::Array(a) => a.iter().map(|v| match v {
+				Value::String(s) => Some(s.clone()),
+				_ => None,
+			}).collect(),
+			_ => None,
+		}
+	}
+
+	fn append(mut self, other: Self) -> Self {
+		self.extend(other);
+		self
+	}
+
+	fn empty() -> Option<Self> {
+		Some(Vec::new())
+	}
+}
+
+impl FromValue for SocketAddr {
+	const EXPECTED: &'static str = "an address with port";
+
+	fn from_str(s: &str) -> Option<Self> {
+		s.to_socket_addrs().ok()?.next()
+	}
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+	const EXPECTED: &'static str = T::EXPECTED;
+
+	fn from_str(s: &str) -> Option<Self> {
+		T::from_str(s).map(Some)
+	}
+
+	fn from_value(value: &Value) -> Option<Self> {
+		T::from_value(value).map(Some)
+	}
+
+	fn empty() -> Option<Self> {
+		Some(None)
+	}
+}
+
+impl FromValue for PathBuf {
+	const EXPECTED: &'static str = "a path";
+
+	fn from_str(s: &str) -> Option<Self> {
+		(!s.is_empty()).then(|| s.into())
+	}
+}
+
+impl FromValue for IpAddr {
+	const EXPECTED: &'static str = "an IP address";
+
+	fn from_str(s: &str) -> Option<Self> {
+		s.parse().ok()
+	}
+}
+
+/// A firewall mark, to accept it in hexadecimal too.
+struct Mark(u32);
+
+impl FromValue for Mark {
+	const EXPECTED: &'static str = "a number, optionally in hexadecimal with a 0x prefix";
+
+	fn from_str(s: &str) -> Option<Self> {
+		egress::parse_mark(s).map(Self)
+	}
+
+	fn from_value(value: &Value) -> Option<Self> {
+		match value {
+			Value::Integer(n) => (*n).try_into().ok().map(Self),
+			Value::String(s) => Self::from_str(s),
+			_ => None,
+		}
+	}
+}
+
+impl FromValue for Vec<Route> {
+	const EXPECTED: &'static str = "a list of NETWORK[/PREFIX] with address=IP, device=NAME or mark=N";
+	const REPEATED: bool = true;
+
+	fn from_str(s: &str) -> Option<Self> {
+		s.parse().ok().map(|r| vec![r])
+	}
+
+	fn from_value(value: &Value) -> Option<Self> {
+		match value {
+			Value::Array(a) => a.iter().map(|v| match v {
+				Value::String(s) => s.parse().ok(),
+				_ => None,
+			}).collect(),
+			_ => None,
+		}
+	}
+
+	fn append(mut self, other: Self) -> Self {
+		self.extend(other);
+		self
+	}
+
+	fn empty() -> Option<Self> {
+		Some(Vec::new())
+	}
+}
+
+impl FromValue for Ipv6Addr {
+	const EXPECTED: &'static str = "an IPv6 address";
+
+	fn from_str(s: &str) -> Option<Self> {
+		s.parse().ok()
+	}
+}
+
+impl FromValue for usize {
+	const EXPECTED: &'static str = "a positive integer";
+
+	fn from_str(s: &str) -> Option<Self> {
+		s.parse().ok()
+	}
+
+	fn from_value(value: &Value) -> Option<Self> {
+		match value {
+			Value::Integer(n) => (*n).try_into().ok(),
+			_ => None,
+		}
+	}
+}
+
+impl FromValue for bool {
+	const EXPECTED: &'static str = "true or false";
+
+	fn from_str(s: &str) -> Option<Self> {
+		s.parse().ok()
+	}
+
+	fn from_value(value: &Value) -> Option<Self> {
+		match value {
+			Value::Boolean(b) => Some(*b),
+			_ => None,
+		}
+	}
+}
+
+impl FromValue for Duration {
+	const EXPECTED: &'static str = "a positive amount of seconds";
+
+	fn from_str(s: &str) -> Option<Self> {
+		s.parse().ok().and_then(|s| Duration::try_from_secs_f64(s).ok())
+	}
+
+	fn from_value(value: &Value) -> Option<Self> {
+		match value {
+			Value::Integer(n) => (*n).try_into().ok().map(Duration::from_secs),
+			Value::Float(f) => Duration::try_from_secs_f64(*f).ok(),
+			_ => None,
+		}
+	}
+}
+
+impl FromValue for log::Level {
+	const EXPECTED: &'static str = "one of error, warn, info, debug or trace";
+
+	fn from_str(s: &str) -> Option<Self> {
+		s.parse().ok()
+	}
+}
+
+impl FromValue for Transport {
+	const EXPECTED: &'static str = "tcp, udp, ws, unix or command";
+
+	fn from_str(s: &str) -> Option<Self> {
+		s.parse().ok()
+	}
+}
+
+impl FromValue for Compression {
+	const EXPECTED: &'static str = "none or deflate";
+
+	fn from_str(s: &str) -> Option<Self> {
+		s.parse().ok()
+	}
+}
+
+impl FromValue for log::Format {
+	const EXPECTED: &'static str = "text or json";
+
+	fn from_str(s: &str) -> Option<Self> {
+		s.parse().ok()
+	}
+}
+
+impl FromValue for Priorities {
+	const EXPECTED: &'static str = "a list of PORT=WEIGHT with a weight between 1 and 1000";
+	const REPEATED: bool = true;
+
+	fn from_str(s: &str) -> Option<Self> {
+		let (port, weight) = s.split_once('=')?;
+		let weight = weight.trim().parse().ok().filter(|w| (1..=Self::MAX_WEIGHT).contains(w))?;
+		let mut p = Self::default();
+		p.set(port.trim().parse().ok()?, weight);
+		Some(p)
+	}
+
+	fn from_value(value: &Value) -> Option<Self> {
+		match value {
+			Value::Array(a) => a.iter().try_fold(Self::default(), |p, v| match v {
+				Value::String(s) => Some(p.append(Self::from_str(s)?)),
+				_ => None,
+			}),
+			_ => None,
+		}
+	}
+
+	fn append(mut self, other: Self) -> Self {
+		self.extend(other);
+		self
+	}
+
+	fn empty() -> Option<Self> {
+		Some(Self::default())
+	}
+}
+
+impl FromValue for Acl {
+	const EXPECTED: &'static str = "a list of ACTION PROTOCOL NETWORK[/PREFIX] [PORT[-PORT]]";
+	const REPEATED: bool = true;
+
+	fn from_str(s: &str) -> Option<Self> {
+		s.parse::<crate::acl::Rule>().ok().map(Self::from)
+	}
+
+	fn from_value(value: &Value) -> Option<Self> {
+		match value {
+			Value::Array(a) => a.iter().try_fold(Self::default(), |acl, v| match v {
+				Value::String(s) => Some(acl.append(Self::from_str(s)?)),
+				_ => None,
+			}),
+			_ => None,
+		}
+	}
+
+	fn append(mut self, other: Self) -> Self {
+		self.extend(other);
+		self
+	}
+
+	fn empty() -> Option<Self> {
+		Some(Self::default())
+	}
+}
+
+impl FromValue for Vec<Forward> {
+	const EXPECTED: &'static str = "a list of [BIND:]PORT:HOST:HOSTPORT with an optional /tcp or /udp";
+	const REPEATED: bool = true;
+
+	fn from_str(s: &str) -> Option<Self> {
+		let (s, protocol) = match s.rsplit_once('/') {
+			Some((s, "tcp")) => (s, Protocol::Tcp),
+			Some((s, "udp")) => (s, Protocol::Udp),
+			Some(_) => return None,
+			None => (s, Protocol::Tcp),
+		};
+		let (bind, port, host, host_port) = match split_colons(s)?[..] {
+			[port, host, host_port] => (IpAddr::from(Ipv4Addr::LOCALHOST), port, host, host_port),
+			[bind, port, host, host_port] => (bind.parse().ok()?, port, host, host_port),
+			_ => return None,
+		};
+		if host.is_empty() {
+			return None;
+		}
+		let listen = SocketAddr::new(bind, port.parse().ok()?);
+		Some(vec![Forward { protocol, listen, host: host.into(), port: host_port.parse().ok()? }])
+	}
+
+	fn from_value(value: &Value) -> Option<Self> {
+		match value {
+			Value::Array(a) => a.iter().try_fold(Vec::new(), |f, v| match v {
+				Value::String(s) => Some(f.append(Self::from_str(s)?)),
+				_ => None,
+			}),
+			_ => None,
+		}
+	}
+
+	fn append(mut self, other: Self) -> Self {
+		self.extend(other);
+		self
+	}
+
+	fn empty() -> Option<Self> {
+		Some(Vec::new())
+	}
+}
+
+/// Split a colon separated list in which IPv6 addresses are enclosed in brackets. The
+/// brackets are removed.
+fn split_colons(mut s: &str) -> Option<Vec<&str>> {
+	let mut parts = Vec::new();
+	loop {
+		let (part, rest) = match s.strip_prefix('[') {
+			Some(s) => s.split_once(']')?,
+			None => s.split_at(s.find(':').unwrap_or(s.len())),
+		};
+		parts.push(part);
+		match rest.strip_prefix(':') {
+			Some(rest) => s = rest,
+			None if rest.is_empty() => return Some(parts),
+			None => return None,
+		}
+	}
+}
+
+/// An IPv6 /96 prefix.
+struct Prefix(Ipv6Addr);
+
+impl FromValue for Prefix {
+	const EXPECTED: &'static str = "an IPv6 prefix ending in /96";
+
+	fn from_str(s: &str) -> Option<Self> {
+		let (addr, len) = s.split_once('/')?;
+		let addr = addr.parse::<Ipv6Addr>().ok()?;
+		(len == "96" && addr.octets()[12..] == [0; 4]).then(|| Self(addr))
+	}
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+	/// `--help` was passed.
+	Help,
+	Usage(String),
+	Io(String, io::Error),
+	Parse(String, toml::ParseError),
+	Invalid {
+		key: String,
+		message: String,
+	},
+}
+
+impl ConfigError {
+	fn invalid(key: &str, message: String) -> Self {
+		Self::Invalid { key: key.into(), message }
+	}
+}
+
+impl fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Help => f.write_str("help requested"),
+			Self::Usage(s) => f.write_str(s),
+			Self::Io(p, e) => write!(f, "{}: {}", p, e),
+			Self::Parse(p, e) => write!(f, "{}: {}", p, e),
+			Self::Invalid { key, message } => write!(f, "{}: {}", key, message),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn args(s: &str) -> Result<Mode, ConfigError> {
+		from_args(s.split_whitespace().map(String::from))
+	}
+
+	#[test]
+	fn defaults() {
+		match args("server").unwrap() {
+			Mode::Server(c) => {
+				assert_eq!(c.listen, "127.0.0.1:5434".parse().unwrap());
+				assert!(c.keys.is_empty());
+				assert_eq!(c.udp_timeout, Duration::from_secs(60));
+				assert_eq!(c.listen_udp, None);
+				assert_eq!(c.listen_ws, None);
+				assert!(!c.stdio);
+				assert_eq!(c.compression.algorithm, Compression::Deflate);
+				assert_eq!(c.compression.threshold, 256);
+				assert_eq!(c.limits, LimitConfig::default());
+				assert_eq!(c.upstream_proxy, None);
+			}
+			_ => panic!(),
+		}
+		match args("client").unwrap() {
+			Mode::Client(c) => {
+				assert_eq!(c.tun_name, "stupid_tunnel");
+				assert_eq!(c.mtu, 1500);
+				assert_eq!(c.prefix, "abcd:ef00::".parse::<Ipv6Addr>().unwrap());
+				assert!(c.verify_checksums);
+				assert_eq!(c.transport, Transport::Tcp);
+				assert_eq!(c.proxy, None);
+				assert_eq!(c.websocket_path, "/");
+				assert_eq!(c.log.level, log::Level::Info);
+				assert_eq!(c.compression.algorithm, Compression::None);
+			}
+			_ => panic!(),
+		}
+	}
+
+	#[test]
+	fn flags() {
+		match args("--key a server --key=b --listen [::1]:80 --udp-timeout 1.5 --log-level debug --log-format json --listen-udp [::]:81").unwrap() {
+			Mode::Server(c) => {
+				assert_eq!(c.log.level, log::Level::Debug);
+				assert_eq!(c.log.format, log::Format::Json);
+				assert_eq!(c.keys, ["a", "b"]);
+				assert_eq!(c.listen, "[::1]:80".parse().unwrap());
+				assert_eq!(c.udp_timeout, Duration::from_millis(1500));
+				assert_eq!(c.listen_udp, Some("[::]:81".parse().unwrap()));
+			}
+			_ => panic!(),
+		}
+		match args("client --transport ws --proxy 127.0.0.1:3128 --websocket-path /tunnel").unwrap() {
+			Mode::Client(c) => {
+				assert_eq!(c.transport, Transport::WebSocket);
+				assert_eq!(c.proxy, Some("127.0.0.1:3128".parse().unwrap()));
+				assert_eq!(c.websocket_path, "/tunnel");
+			}
+			_ => panic!(),
+		}
+		match args("server --priority 22=8 --priority 53=4 --priority 22=2").unwrap() {
+			Mode::Server(c) => {
+				assert_eq!(c.priorities.weight(22), 2);
+				assert_eq!(c.priorities.weight(53), 4);
+				assert_eq!(c.priorities.weight(80), 1);
+			}
+			_ => panic!(),
+		}
+		match args("server --rate-limit 1000000 --max-tcp-connections 0 --connection-rate-limit 10").unwrap() {
+			Mode::Server(c) => assert_eq!(c.limits, LimitConfig { rate: Some(1_000_000), tcp: Some(0), connection_rate: Some(10), ..Default::default() }),
+			_ => panic!(),
+		}
+		match args("server --upstream-socks 127.0.0.1:1080 --upstream-socks-username a --upstream-socks-password b").unwrap() {
+			Mode::Server(c) => {
+				let credentials = Some(("a".into(), "b".into()));
+				assert_eq!(c.upstream_proxy, Some(Proxy { address: "127.0.0.1:1080".parse().unwrap(), credentials }));
+			}
+			_ => panic!(),
+		}
+		match args("client --compression deflate --compression-threshold 1000").unwrap() {
+			Mode::Client(c) => {
+				assert_eq!(c.compression.algorithm, Compression::Deflate);
+				assert_eq!(c.compression.threshold, 1000);
+			}
+			_ => panic!(),
+		}
+		match args("--stdio server --log-level warn").unwrap() {
+			Mode::Server(c) => {
+				assert!(c.stdio);
+				assert_eq!(c.control_socket, None);
+			}
+			_ => panic!(),
+		}
+		match args("client --verify-checksums false --transport unix --socket /run/stupid.sock --control-socket /run/stupid-control.sock").unwrap() {
+			Mode::Client(c) => {
+				assert!(!c.verify_checksums);
+				assert_eq!(c.control_socket, Some(PathBuf::from("/run/stupid-control.sock")));
+				assert!(matches!(c.endpoint(), Endpoint::Unix(p) if p == PathBuf::from("/run/stupid.sock")));
+			}
+			_ => panic!(),
+		}
+		match args("client --socks 127.0.0.1:1080 --socks-username a --socks-password b --http-proxy 127.0.0.1:3128").unwrap() {
+			Mode::Client(c) => {
+				assert_eq!(c.socks, Some("127.0.0.1:1080".parse().unwrap()));
+				assert_eq!(c.socks_username.as_deref(), Some("a"));
+				assert_eq!(c.socks_password.as_deref(), Some("b"));
+				assert_eq!(c.http_proxy, Some("127.0.0.1:3128".parse().unwrap()));
+			}
+			_ => panic!(),
+		}
+		match args("client --local-forward 5432:db.internal:5432 --local-forward [::1]:53:[fd00::53]:53/udp --remote-forward 0.0.0.0:8080:localhost:80/tcp").unwrap() {
+			Mode::Client(c) => {
+				assert_eq!(c.local_forwards, [
+					Forward { protocol: Protocol::Tcp, listen: "127.0.0.1:5432".parse().unwrap(), host: "db.internal".into(), port: 5432 },
+					Forward { protocol: Protocol::Udp, listen: "[::1]:53".parse().unwrap(), host: "fd00::53".into(), port: 53 },
+				]);
+				assert_eq!(c.remote_forwards, [
+					Forward { protocol: Protocol::Tcp, listen: "0.0.0.0:8080".parse().unwrap(), host: "localhost".into(), port: 80 },
+				]);
+				assert!(c.is_proxy());
+			}
+			_ => panic!(),
+		}
+		let egress = ["server", "--bind-address", "192.0.2.1", "--bind-device", "eth1", "--fwmark", "0x10", "--egress-route", "10.0.0.0/8 device=wg0"].map(String::from);
+		match from_args(egress.into_iter()).unwrap() {
+			Mode::Server(c) => {
+				assert_eq!(c.egress, Egress { address: Some("192.0.2.1".parse().unwrap()), device: Some("eth1".into()), mark: Some(16) });
+				assert_eq!(c.egress_routes, ["10.0.0.0/8 device=wg0".parse().unwrap()]);
+			}
+			_ => panic!(),
+		}
+		let acl = ["server", "--acl", "allow tcp 127.0.0.1 22", "--acl=deny any 0.0.0.0/0 25"].map(String::from);
+		match from_args(acl.into_iter()).unwrap() {
+			Mode::Server(c) => {
+				assert!(c.acl.allows(crate::acl::Protocol::Tcp, "127.0.0.1:22".parse().unwrap()));
+				assert!(!c.acl.allows(crate::acl::Protocol::Tcp, "127.0.0.1:80".parse().unwrap()));
+				assert!(!c.acl.allows(crate::acl::Protocol::Udp, "1.1.1.1:25".parse().unwrap()));
+			}
+			_ => panic!(),
+		}
+	}
+
+	#[test]
+	fn file() {
+		let path = std::env::temp_dir().join(format!("stupid_tunnel_config_{}.toml", std::process::id()));
+		fs::write(&path, "[client]\nmtu = 9000\ntransport = 'udp'\ntun_name = 'tun0'\nprefix = 'fd00::/96'\naddress = 'fd00::1'\npriorities = ['22=8']\n").unwrap();
+		let path = path.to_str().unwrap();
+		match args(&format!("client -c {} --mtu 1280", path)).unwrap() {
+			Mode::Client(c) => {
+				assert_eq!(c.mtu, 1280);
+				assert_eq!(c.tun_name, "tun0");
+				assert_eq!(c.transport, Transport::Udp);
+				assert_eq!(c.address, "fd00::1".parse::<Ipv6Addr>().unwrap());
+				assert_eq!(c.priorities.weight(22), 8);
+			}
+			_ => panic!(),
+		}
+		fs::write(path, "[client]\nmtu = '9000'\n").unwrap();
+		assert!(matches!(args(&format!("client -c {}", path)), Err(ConfigError::Invalid { .. })));
+		fs::write(path, "[client]\nmtx = 9000\n").unwrap();
+		assert!(matches!(args(&format!("client -c {}", path)), Err(ConfigError::Invalid { .. })));
+		fs::remove_file(path).unwrap();
+	}
+
+	#[test]
+	fn identities() {
+		let path = std::env::temp_dir().join(format!("stupid_tunnel_identities_{}.toml", std::process::id()));
+		fs::write(&path, "[alice]\nkey = 'a'\nacl = ['allow tcp 10.0.0.0/8']\nrate_limit = 1000\nfwmark = 2\n\n[bob]\nkey = 'b'\n").unwrap();
+		let path_str = path.to_str().unwrap();
+		match args(&format!("server --identities {} --max-tcp-connections 10", path_str)).unwrap() {
+			Mode::Server(c) => {
+				assert_eq!(c.identities.len(), 2);
+				let alice = &c.identities[0];
+				assert_eq!((alice.name.as_str(), alice.key.as_str()), ("alice", "a"));
+				assert!(alice.acl.allows(crate::acl::Protocol::Tcp, "10.0.0.1:22".parse().unwrap()));
+				assert_eq!(alice.limits.or(&c.limits), LimitConfig { rate: Some(1000), tcp: Some(10), ..Default::default() });
+				assert_eq!(alice.egress.mark, Some(2));
+				assert_eq!(c.identities[1].limits, LimitConfig::default());
+			}
+			_ => panic!(),
+		}
+		for text in ["[alice]\nacl = []\n", "[alice]\nkey = 'a'\n[bob]\nkey = 'a'\n", "[alice]\nkey = 'a'\nkeys = ['b']\n", "['a b']\nkey = 'a'\n", "alice = 'a'\n"] {
+			fs::write(&path, text).unwrap();
+			assert!(matches!(load_identities(&path), Err(ConfigError::Invalid { .. })), "{:?}", text);
+		}
+		fs::remove_file(&path).unwrap();
+		assert!(matches!(load_identities(&path), Err(ConfigError::Io(..))));
+	}
+
+	#[test]
+	fn invalid() {
+		assert!(matches!(args("--help"), Err(ConfigError::Help)));
+		assert!(matches!(args(""), Err(ConfigError::Usage(_))));
+		assert!(matches!(args("server --mtu 1500"), Err(ConfigError::Usage(_))));
+		assert!(matches!(args("client --mtu"), Err(ConfigError::Usage(_))));
+		assert!(matches!(args("client --mtu 1000"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("client --tun-name 0123456789abcdef"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("client --prefix fd00::/64"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("client --address fd00::1"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("server --listen nope"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("client --transport sctp"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("client --proxy 127.0.0.1:3128"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("client --transport ws --websocket-path tunnel"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("client --transport unix"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("client --transport command"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("server --stdio --listen-ws 127.0.0.1:80"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("server --log-level loud"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("client --compression zstd"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("server --priority 22=0"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("server --acl allow"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("server --rate-limit 0"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("server --bind-device 0123456789abcdef"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("server --fwmark 0xfffffffff"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("server --egress-route 10.0.0.0/8"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("server --max-tcp-connections many"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("server --upstream-socks 127.0.0.1:1080 --upstream-socks-username a"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("server --upstream-socks-username a --upstream-socks-password b"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("client --priority 22"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("client --reconnect-delay 0"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("client --socks 127.0.0.1:1080 --socks-username a"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("client --local-forward 5432:db.internal"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("client --local-forward 5432:db.internal:5432/sctp"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("client --local-forward 5432::5432"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("client --remote-forward [::1]:80:localhost:80"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("server --keepalive-interval 60 --keepalive-timeout 60"), Err(ConfigError::Invalid { .. })));
+		assert!(matches!(args("client --reconnect-delay 5 --max-reconnect-delay 2"), Err(ConfigError::Invalid { .. })));
+	}
+}
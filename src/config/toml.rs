@@ -0,0 +1,426 @@
+//! Parser for the subset of [TOML](https://toml.io/en/v1.0.0) used by configuration files.
+//!
+//! Supported are tables, arrays of tables, dotted keys, basic & literal strings, integers,
+//! floats, booleans, arrays and inline tables. Dates and multi-line strings are not.
+
+use core::fmt;
+use std::collections::BTreeMap;
+
+pub type Table = BTreeMap<String, Value>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+	String(String),
+	Integer(i64),
+	Float(f64),
+	Boolean(bool),
+	Array(Vec<Value>),
+	Table(Table),
+}
+
+impl Value {
+	pub fn type_name(&self) -> &'static str {
+		match self {
+			Self::String(_) => "string",
+			Self::Integer(_) => "integer",
+			Self::Float(_) => "float",
+			Self::Boolean(_) => "boolean",
+			Self::Array(_) => "array",
+			Self::Table(_) => "table",
+		}
+	}
+}
+
+pub fn parse(text: &str) -> Result<Table, ParseError> {
+	let mut p = Parser { text: text.as_bytes(), pos: 0, line: 1 };
+	let mut root = Table::new();
+	// Path to the table any key/value pairs are added to.
+	let mut current = Vec::<String>::new();
+
+	loop {
+		p.skip_whitespace_and_comments(true);
+		match p.peek() {
+			None => break,
+			Some(b'[') => {
+				p.pos += 1;
+				let array = p.eat(b'[');
+				p.skip_whitespace();
+				current = p.key()?;
+				p.skip_whitespace();
+				p.expect(b']')?;
+				if array {
+					p.expect(b']')?;
+				}
+				let line = p.line;
+				let (last, path) = current.split_last().unwrap();
+				let parent = table_at(&mut root, path, line)?;
+				match parent.entry(last.clone()).or_insert_with(|| if array { Value::Array(Vec::new()) } else { Value::Table(Table::new()) }) {
+					Value::Array(a) if array => a.push(Value::Table(Table::new())),
+					Value::Table(_) if !array => (),
+					_ => return Err(p.error(ParseErrorKind::DuplicateKey(current.join(".")))),
+				}
+			}
+			Some(_) => {
+				let key = p.key()?;
+				p.skip_whitespace();
+				p.expect(b'=')?;
+				p.skip_whitespace();
+				let value = p.value()?;
+				let line = p.line;
+				let table = table_at(&mut root, &current, line)?;
+				insert(table, &key, value, line)?;
+			}
+		}
+		p.skip_whitespace_and_comments(false);
+		match p.peek() {
+			None => break,
+			Some(b'\n') => (),
+			Some(c) => return Err(p.error(ParseErrorKind::Unexpected(c as char))),
+		}
+	}
+
+	Ok(root)
+}
+
+/// Get the table at the given path, descending into the last element of arrays of tables.
+fn table_at<'a>(mut table: &'a mut Table, path: &[String], line: usize) -> Result<&'a mut Table, ParseError> {
+	for key in path {
+		let value = table.entry(key.clone()).or_insert_with(|| Value::Table(Table::new()));
+		table = match value {
+			Value::Table(t) => t,
+			Value::Array(a) => match a.last_mut() {
+				Some(Value::Table(t)) => t,
+				_ => return Err(ParseError { line, kind: ParseErrorKind::DuplicateKey(key.clone()) }),
+			}
+			_ => return Err(ParseError { line, kind: ParseErrorKind::DuplicateKey(key.clone()) }),
+		};
+	}
+	Ok(table)
+}
+
+fn insert(table: &mut Table, key: &[String], value: Value, line: usize) -> Result<(), ParseError> {
+	let (last, path) = key.split_last().unwrap();
+	let table = table_at(table, path, line)?;
+	if table.contains_key(last) {
+		return Err(ParseError { line, kind: ParseErrorKind::DuplicateKey(key.join(".")) });
+	}
+	table.insert(last.clone(), value);
+	Ok(())
+}
+
+struct Parser<'a> {
+	text: &'a [u8],
+	pos: usize,
+	line: usize,
+}
+
+impl Parser<'_> {
+	fn peek(&self) -> Option<u8> {
+		self.text.get(self.pos).copied()
+	}
+
+	fn eat(&mut self, c: u8) -> bool {
+		let eq = self.peek() == Some(c);
+		self.pos += usize::from(eq);
+		eq
+	}
+
+	fn expect(&mut self, c: u8) -> Result<(), ParseError> {
+		match self.peek() {
+			Some(d) if d == c => {
+				self.pos += 1;
+				Ok(())
+			}
+			Some(d) => Err(self.error(ParseErrorKind::Expected(c as char, d as char))),
+			None => Err(self.error(ParseErrorKind::UnexpectedEnd)),
+		}
+	}
+
+	fn skip_whitespace(&mut self) {
+		while let Some(b' ' | b'\t') = self.peek() {
+			self.pos += 1;
+		}
+	}
+
+	fn skip_whitespace_and_comments(&mut self, newlines: bool) {
+		loop {
+			match self.peek() {
+				Some(b' ' | b'\t' | b'\r') => self.pos += 1,
+				Some(b'\n') if newlines => {
+					self.pos += 1;
+					self.line += 1;
+				}
+				Some(b'#') => while !matches!(self.peek(), None | Some(b'\n')) {
+					self.pos += 1;
+				}
+				_ => break,
+			}
+		}
+	}
+
+	fn key(&mut self) -> Result<Vec<String>, ParseError> {
+		let mut key = Vec::new();
+		loop {
+			let part = match self.peek() {
+				Some(b'"') => self.basic_string()?,
+				Some(b'\'') => self.literal_string()?,
+				_ => {
+					let start = self.pos;
+					while let Some(b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_') = self.peek() {
+						self.pos += 1;
+					}
+					if start == self.pos {
+						return Err(match self.peek() {
+							Some(c) => self.error(ParseErrorKind::Unexpected(c as char)),
+							None => self.error(ParseErrorKind::UnexpectedEnd),
+						});
+					}
+					String::from_utf8(self.text[start..self.pos].to_vec()).unwrap()
+				}
+			};
+			key.push(part);
+			self.skip_whitespace();
+			if !self.eat(b'.') {
+				return Ok(key);
+			}
+			self.skip_whitespace();
+		}
+	}
+
+	fn value(&mut self) -> Result<Value, ParseError> {
+		match self.peek() {
+			Some(b'"') => self.basic_string().map(Value::String),
+			Some(b'\'') => self.literal_string().map(Value::String),
+			Some(b'[') => self.array(),
+			Some(b'{') => self.inline_table(),
+			Some(b't' | b'f') => {
+				for (s, v) in [("true", true), ("false", false)] {
+					if self.text[self.pos..].starts_with(s.as_bytes()) {
+						self.pos += s.len();
+						return Ok(Value::Boolean(v));
+					}
+				}
+				Err(self.error(ParseErrorKind::InvalidValue))
+			}
+			Some(b'0'..=b'9' | b'+' | b'-') => self.number(),
+			Some(c) => Err(self.error(ParseErrorKind::Unexpected(c as char))),
+			None => Err(self.error(ParseErrorKind::UnexpectedEnd)),
+		}
+	}
+
+	fn number(&mut self) -> Result<Value, ParseError> {
+		let start = self.pos;
+		while let Some(b'0'..=b'9' | b'a'..=b'z' | b'A'..=b'Z' | b'+' | b'-' | b'_' | b'.') = self.peek() {
+			self.pos += 1;
+		}
+		let s = core::str::from_utf8(&self.text[start..self.pos]).unwrap().replace('_', "");
+		let (sign, digits) = match s.as_bytes().first() {
+			Some(b'-') => (-1, &s[1..]),
+			Some(b'+') => (1, &s[1..]),
+			_ => (1, &s[..]),
+		};
+		let radix = [("0x", 16), ("0o", 8), ("0b", 2)]
+			.iter()
+			.find(|(p, _)| digits.starts_with(p));
+		let v = match radix {
+			Some((p, r)) => i64::from_str_radix(&digits[p.len()..], *r).ok().map(|n| Value::Integer(sign * n)),
+			None => s.parse().ok().map(Value::Integer).or_else(|| s.parse().ok().map(Value::Float)),
+		};
+		v.ok_or_else(|| self.error(ParseErrorKind::InvalidValue))
+	}
+
+	fn array(&mut self) -> Result<Value, ParseError> {
+		self.expect(b'[')?;
+		let mut array = Vec::new();
+		loop {
+			self.skip_whitespace_and_comments(true);
+			if self.eat(b']') {
+				return Ok(Value::Array(array));
+			}
+			array.push(self.value()?);
+			self.skip_whitespace_and_comments(true);
+			if !self.eat(b',') {
+				self.skip_whitespace_and_comments(true);
+				self.expect(b']')?;
+				return Ok(Value::Array(array));
+			}
+		}
+	}
+
+	fn inline_table(&mut self) -> Result<Value, ParseError> {
+		self.expect(b'{')?;
+		let mut table = Table::new();
+		self.skip_whitespace();
+		if self.eat(b'}') {
+			return Ok(Value::Table(table));
+		}
+		loop {
+			self.skip_whitespace();
+			let key = self.key()?;
+			self.skip_whitespace();
+			self.expect(b'=')?;
+			self.skip_whitespace();
+			let value = self.value()?;
+			insert(&mut table, &key, value, self.line)?;
+			self.skip_whitespace();
+			if !self.eat(b',') {
+				self.expect(b'}')?;
+				return Ok(Value::Table(table));
+			}
+		}
+	}
+
+	fn literal_string(&mut self) -> Result<String, ParseError> {
+		self.expect(b'\'')?;
+		let start = self.pos;
+		loop {
+			match self.peek() {
+				Some(b'\'') => break,
+				Some(b'\n') | None => return Err(self.error(ParseErrorKind::UnterminatedString)),
+				Some(_) => self.pos += 1,
+			}
+		}
+		let s = String::from_utf8(self.text[start..self.pos].to_vec())
+			.map_err(|_| self.error(ParseErrorKind::InvalidUtf8))?;
+		self.pos += 1;
+		Ok(s)
+	}
+
+	fn basic_string(&mut self) -> Result<String, ParseError> {
+		self.expect(b'"')?;
+		let mut s = Vec::new();
+		loop {
+			let c = match self.peek() {
+				Some(b'"') => break,
+				Some(b'\n') | None => return Err(self.error(ParseErrorKind::UnterminatedString)),
+				Some(c) => c,
+			};
+			self.pos += 1;
+			if c != b'\\' {
+				s.push(c);
+				continue;
+			}
+			let c = self.peek().ok_or_else(|| self.error(ParseErrorKind::UnterminatedString))?;
+			self.pos += 1;
+			let c = match c {
+				b'"' => '"',
+				b'\\' => '\\',
+				b'b' => '\x08',
+				b'f' => '\x0c',
+				b'n' => '\n',
+				b'r' => '\r',
+				b't' => '\t',
+				b'u' | b'U' => {
+					let n = if c == b'u' { 4 } else { 8 };
+					let hex = self.text.get(self.pos..self.pos + n)
+						.and_then(|h| core::str::from_utf8(h).ok())
+						.and_then(|h| u32::from_str_radix(h, 16).ok())
+						.and_then(char::from_u32)
+						.ok_or_else(|| self.error(ParseErrorKind::InvalidEscape))?;
+					self.pos += n;
+					hex
+				}
+				_ => return Err(self.error(ParseErrorKind::InvalidEscape)),
+			};
+			s.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+		}
+		self.pos += 1;
+		String::from_utf8(s).map_err(|_| self.error(ParseErrorKind::InvalidUtf8))
+	}
+
+	fn error(&self, kind: ParseErrorKind) -> ParseError {
+		ParseError { line: self.line, kind }
+	}
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+	pub line: usize,
+	pub kind: ParseErrorKind,
+}
+
+#[derive(Debug)]
+pub enum ParseErrorKind {
+	Unexpected(char),
+	Expected(char, char),
+	UnexpectedEnd,
+	UnterminatedString,
+	InvalidEscape,
+	InvalidUtf8,
+	InvalidValue,
+	DuplicateKey(String),
+}
+
+impl fmt::Display for ParseError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "line {}: ", self.line)?;
+		match &self.kind {
+			ParseErrorKind::Unexpected(c) => write!(f, "unexpected {:?}", c),
+			ParseErrorKind::Expected(e, c) => write!(f, "expected {:?}, found {:?}", e, c),
+			ParseErrorKind::UnexpectedEnd => f.write_str("unexpected end of file"),
+			ParseErrorKind::UnterminatedString => f.write_str("unterminated string"),
+			ParseErrorKind::InvalidEscape => f.write_str("invalid escape sequence"),
+			ParseErrorKind::InvalidUtf8 => f.write_str("invalid UTF-8"),
+			ParseErrorKind::InvalidValue => f.write_str("invalid value"),
+			ParseErrorKind::DuplicateKey(k) => write!(f, "duplicate key {:?}", k),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn parse_config() {
+		let t = parse(r#"
+			# comment
+			top = "level" # trailing comment
+
+			[server]
+			listen = '0.0.0.0:5434'
+			keys = [
+				"aé\"",
+				"b",
+			]
+			udp-timeout = 1_000
+			ratio = -0.5
+			limits = { tcp = 0x10, on = true }
+
+			[[server.rule]]
+			allow = false
+			[[server.rule]]
+			dotted.key = +3
+		"#).unwrap();
+
+		let s = |s: &str| Value::String(s.into());
+		assert_eq!(t["top"], s("level"));
+		let server = match &t["server"] {
+			Value::Table(t) => t,
+			v => panic!("{:?}", v),
+		};
+		assert_eq!(server["listen"], s("0.0.0.0:5434"));
+		assert_eq!(server["keys"], Value::Array(vec![s("aé\""), s("b")]));
+		assert_eq!(server["udp-timeout"], Value::Integer(1000));
+		assert_eq!(server["ratio"], Value::Float(-0.5));
+		let limits = Table::from([("tcp".into(), Value::Integer(16)), ("on".into(), Value::Boolean(true))]);
+		assert_eq!(server["limits"], Value::Table(limits));
+		let dotted = Table::from([("key".into(), Value::Integer(3))]);
+		let rules = vec![
+			Value::Table(Table::from([("allow".into(), Value::Boolean(false))])),
+			Value::Table(Table::from([("dotted".into(), Value::Table(dotted))])),
+		];
+		assert_eq!(server["rule"], Value::Array(rules));
+	}
+
+	#[test]
+	fn parse_errors() {
+		let line = |text| parse(text).unwrap_err().line;
+		assert_eq!(line("a = 1\na = 2"), 2);
+		assert_eq!(line("a = \"unterminated\nb = 1"), 1);
+		assert_eq!(line("\n\na = 1 b = 2"), 3);
+		assert_eq!(line("a = [1, 2"), 1);
+		assert_eq!(line("a = 1\n[a]"), 2);
+		assert_eq!(line("a = nope"), 1);
+	}
+}
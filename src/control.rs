@@ -0,0 +1,449 @@
+//! A Unix socket to inspect and control a running server or client, e.g. with
+//! `socat - UNIX-CONNECT:PATH`.
+//!
+//! Every line sent is a command. Its answer consists of any number of lines followed by `ok` or
+//! `error: MESSAGE`. Statistics are reported as one line per session or flow, starting with
+//! what it describes followed by `key=value` fields. Durations are given in seconds.
+
+use crate::acl::Protocol;
+use crate::log;
+use core::fmt::{self, Write as _};
+use core::str::FromStr;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::time::Duration;
+use mio::net::{UnixListener, UnixStream};
+use mio::{Interest, Registry, Token};
+
+/// The longest command accepted.
+const MAX_LINE: usize = 1024;
+/// The most answers kept for a connection. No more commands are run until they were written.
+const MAX_OUTPUT: usize = 1 << 20;
+
+const HELP: &str = "\
+stats                        report the sessions or connection and all flows
+kill [SESSION] PROTOCOL PORT close the tcp, udp or icmp flow with the local port
+drop SESSION                 end a session of the server
+log-level [LEVEL]            report or change the log level
+help                         list the commands
+";
+
+const KILL: &str = "kill [SESSION] PROTOCOL PORT";
+const DROP: &str = "drop SESSION";
+const LOG_LEVEL: &str = "log-level [error|warn|info|debug|trace]";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+	Stats,
+	/// Close a flow, which belongs to a session on the server.
+	Kill { session: Option<u32>, protocol: Protocol, port: u16 },
+	/// End a session, closing all of its flows.
+	Drop(u32),
+	/// Change the log level, or just report it.
+	LogLevel(Option<log::Level>),
+	Help,
+}
+
+impl FromStr for Command {
+	type Err = ParseError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let words = s.split_whitespace().collect::<Vec<_>>();
+		match words[..] {
+			["stats"] => Ok(Self::Stats),
+			["help"] => Ok(Self::Help),
+			["kill", ref args @ ..] => {
+				let (session, protocol, port) = match args {
+					[session, protocol, port] => (Some(*session), *protocol, *port),
+					[protocol, port] => (None, *protocol, *port),
+					_ => return Err(ParseError::Usage(KILL)),
+				};
+				let protocol = match protocol {
+					"tcp" => Protocol::Tcp,
+					"udp" => Protocol::Udp,
+					"icmp" => Protocol::Icmp,
+					_ => return Err(ParseError::Usage(KILL)),
+				};
+				let session = session.map(str::parse).transpose().map_err(|_| ParseError::Usage(KILL))?;
+				let port = port.parse().map_err(|_| ParseError::Usage(KILL))?;
+				Ok(Self::Kill { session, protocol, port })
+			}
+			["drop", session] => session.parse().map(Self::Drop).map_err(|_| ParseError::Usage(DROP)),
+			["log-level"] => Ok(Self::LogLevel(None)),
+			["log-level", level] => level.parse().map(|l| Self::LogLevel(Some(l))).map_err(|_| ParseError::Usage(LOG_LEVEL)),
+			["stats", ..] => Err(ParseError::Usage("stats")),
+			["help", ..] => Err(ParseError::Usage("help")),
+			["drop", ..] => Err(ParseError::Usage(DROP)),
+			["log-level", ..] => Err(ParseError::Usage(LOG_LEVEL)),
+			_ => Err(ParseError::Unknown(s.trim().into())),
+		}
+	}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+	Unknown(String),
+	/// A known command with the wrong arguments, with how it is used.
+	Usage(&'static str),
+}
+
+impl fmt::Display for ParseError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Unknown(c) => write!(f, "unknown command {:?}, try help", c),
+			Self::Usage(u) => write!(f, "usage: {}", u),
+		}
+	}
+}
+
+/// The data of a flow sent and received over the tunnel. The packets of a TCP flow are the
+/// frames carrying its data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counters {
+	pub sent_bytes: u64,
+	pub sent_packets: u64,
+	pub received_bytes: u64,
+	pub received_packets: u64,
+}
+
+impl Counters {
+	pub fn sent(&mut self, len: usize) {
+		self.sent_bytes += len as u64;
+		self.sent_packets += 1;
+	}
+
+	pub fn received(&mut self, len: usize) {
+		self.received_bytes += len as u64;
+		self.received_packets += 1;
+	}
+}
+
+/// A line of statistics being written.
+pub struct Line<'a>(&'a mut String);
+
+impl<'a> Line<'a> {
+	pub fn new(out: &'a mut String, kind: &str) -> Self {
+		out.push_str(kind);
+		Self(out)
+	}
+
+	pub fn field(self, key: &str, value: impl fmt::Display) -> Self {
+		let _ = write!(self.0, " {}={}", key, value);
+		self
+	}
+
+	/// Quote text that may contain spaces.
+	pub fn text(self, key: &str, value: &str) -> Self {
+		let _ = write!(self.0, " {}={:?}", key, value);
+		self
+	}
+
+	pub fn duration(self, key: &str, value: Duration) -> Self {
+		let _ = write!(self.0, " {}={:.3}", key, value.as_secs_f64());
+		self
+	}
+
+	pub fn counters(self, c: &Counters) -> Self {
+		self.field("sent-bytes", c.sent_bytes)
+			.field("sent-packets", c.sent_packets)
+			.field("received-bytes", c.received_bytes)
+			.field("received-packets", c.received_packets)
+	}
+
+	pub fn end(self) {
+		self.0.push('\n');
+	}
+}
+
+/// The control socket and the connections to it.
+pub struct Control {
+	listener: UnixListener,
+	/// The token of the listener. Connections use it with their number added.
+	token: usize,
+	connections: HashMap<u16, Connection>,
+	next_id: u16,
+}
+
+struct Connection {
+	stream: UnixStream,
+	/// What was received but doesn't make up a full line yet.
+	input: Vec<u8>,
+	/// Answers that haven't been written yet.
+	output: Vec<u8>,
+	/// Whether the connection is closed once the answers were written.
+	closing: bool,
+}
+
+impl Control {
+	/// Bind the control socket, which only the owner may connect to. Tokens from `token` up to
+	/// `token + 0xffff` are used.
+	pub fn bind(path: &Path, registry: &Registry, token: usize) -> Result<Self, Error> {
+		// Create the socket without access for others, rather than taking it away once they
+		// could have connected.
+		let mask = unsafe { libc::umask(0o177) };
+		let listener = bind_unix(path);
+		unsafe { libc::umask(mask) };
+		let mut listener = listener?;
+		registry.register(&mut listener, Token(token), Interest::READABLE)?;
+		Ok(Self { listener, token, connections: HashMap::new(), next_id: 1 })
+	}
+
+	/// Whether the token is one of the control socket.
+	pub fn owns(&self, token: Token) -> bool {
+		token.0 & !0xffff == self.token
+	}
+
+	/// Handle an event of the control socket, running the commands that were received with
+	/// `run`. It writes its report to the string, or returns why the command failed.
+	pub fn handle_event(&mut self, registry: &Registry, token: Token, mut run: impl FnMut(Command, &mut String) -> Result<(), String>) {
+		let id = (token.0 & 0xffff) as u16;
+		if id == 0 {
+			return self.accept(registry);
+		}
+		let conn = match self.connections.get_mut(&id) {
+			Some(c) => c,
+			None => return,
+		};
+		if let Err(e) = conn.handle(&mut run) {
+			debug!("closing control connection: {}", e);
+			self.connections.remove(&id);
+		} else if conn.closing && conn.output.is_empty() {
+			self.connections.remove(&id);
+		}
+	}
+
+	fn accept(&mut self, registry: &Registry) {
+		loop {
+			let mut stream = match self.listener.accept() {
+				Ok((s, _)) => s,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+				Err(e) => {
+					warn!("failed to accept control connection: {}", e);
+					return;
+				}
+			};
+			let id = match (0..u16::MAX).map(|i| self.next_id.wrapping_add(i)).find(|i| *i != 0 && !self.connections.contains_key(i)) {
+				Some(id) => id,
+				None => {
+					warn!("dropping control connection, too many are open");
+					continue;
+				}
+			};
+			self.next_id = id.wrapping_add(1);
+			if let Err(e) = registry.register(&mut stream, Token(self.token | usize::from(id)), Interest::READABLE | Interest::WRITABLE) {
+				warn!("failed to register control connection: {}", e);
+				continue;
+			}
+			debug!("accepted control connection");
+			self.connections.insert(id, Connection { stream, input: Vec::new(), output: Vec::new(), closing: false });
+		}
+	}
+}
+
+impl Connection {
+	/// Read commands, run them and write their answers as far as the socket takes them. No more
+	/// commands are read while answers are waiting to be written.
+	fn handle(&mut self, run: &mut impl FnMut(Command, &mut String) -> Result<(), String>) -> Result<(), Error> {
+		let mut buf = [0; MAX_LINE];
+		loop {
+			while self.output.len() < MAX_OUTPUT {
+				let end = match self.input.iter().position(|b| *b == b'\n') {
+					Some(end) => end,
+					None => break,
+				};
+				let line = self.input.drain(..=end).collect::<Vec<_>>();
+				self.execute(&String::from_utf8_lossy(&line), run);
+			}
+			if !self.closing && self.input.len() > MAX_LINE && !self.input.contains(&b'\n') {
+				self.output.extend_from_slice(b"error: command too long\n");
+				self.closing = true;
+				self.input.clear();
+			}
+			self.write()?;
+			// Read no more until the answers were taken.
+			if !self.output.is_empty() {
+				return Ok(());
+			}
+			if self.closing {
+				// Commands may have been held back for the answers before them.
+				if self.input.contains(&b'\n') {
+					continue;
+				}
+				return Ok(());
+			}
+			match self.stream.read(&mut buf) {
+				Ok(0) => {
+					self.closing = true;
+					// The last command may lack a newline.
+					if !self.input.is_empty() {
+						self.input.push(b'\n');
+					}
+				}
+				Ok(len) => self.input.extend_from_slice(&buf[..len]),
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+				Err(e) if e.kind() == ErrorKind::Interrupted => (),
+				Err(e) => return Err(e),
+			}
+		}
+	}
+
+	/// Write answers as far as the socket takes them.
+	fn write(&mut self) -> Result<(), Error> {
+		let mut written = 0;
+		while written < self.output.len() {
+			match self.stream.write(&self.output[written..]) {
+				Ok(0) => return Err(ErrorKind::WriteZero.into()),
+				Ok(n) => written += n,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+				Err(e) if e.kind() == ErrorKind::Interrupted => (),
+				Err(e) => return Err(e),
+			}
+		}
+		self.output.drain(..written);
+		Ok(())
+	}
+
+	fn execute(&mut self, line: &str, run: &mut impl FnMut(Command, &mut String) -> Result<(), String>) {
+		if line.trim().is_empty() {
+			return;
+		}
+		let mut out = String::new();
+		let result = match line.parse() {
+			Ok(Command::Help) => {
+				out.push_str(HELP);
+				Ok(())
+			}
+			Ok(Command::LogLevel(None)) => {
+				out.push_str(log::level().name());
+				out.push('\n');
+				Ok(())
+			}
+			Ok(Command::LogLevel(Some(level))) => {
+				log::set_level(level);
+				info!("log level changed to {}", level.name());
+				Ok(())
+			}
+			Ok(command) => run(command, &mut out),
+			Err(e) => Err(e.to_string()),
+		};
+		match result {
+			Ok(()) => out.push_str("ok\n"),
+			Err(e) => {
+				let _ = writeln!(out, "error: {}", e);
+			}
+		}
+		self.output.extend_from_slice(out.as_bytes());
+	}
+}
+
+/// Bind a Unix socket, replacing the socket left behind by a previous run.
+pub fn bind_unix(path: &Path) -> Result<UnixListener, Error> {
+	let stale = fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket())
+		&& std::os::unix::net::UnixStream::connect(path).is_err();
+	if stale {
+		fs::remove_file(path)?;
+	}
+	UnixListener::bind(path)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::os::unix::fs::PermissionsExt;
+
+	#[test]
+	fn commands() {
+		assert_eq!("stats".parse(), Ok(Command::Stats));
+		assert_eq!(" kill  3 tcp 1080\n".parse(), Ok(Command::Kill { session: Some(3), protocol: Protocol::Tcp, port: 1080 }));
+		assert_eq!("kill udp 53".parse(), Ok(Command::Kill { session: None, protocol: Protocol::Udp, port: 53 }));
+		assert_eq!("drop 7".parse(), Ok(Command::Drop(7)));
+		assert_eq!("log-level DEBUG".parse(), Ok(Command::LogLevel(Some(log::Level::Debug))));
+		assert_eq!("log-level".parse(), Ok(Command::LogLevel(None)));
+		assert_eq!("kill any 1".parse::<Command>(), Err(ParseError::Usage(KILL)));
+		assert_eq!("kill tcp 65536".parse::<Command>(), Err(ParseError::Usage(KILL)));
+		assert_eq!("drop".parse::<Command>(), Err(ParseError::Usage(DROP)));
+		assert_eq!("log-level loud".parse::<Command>(), Err(ParseError::Usage(LOG_LEVEL)));
+		assert_eq!("stats now".parse::<Command>(), Err(ParseError::Usage("stats")));
+		assert_eq!("reboot".parse::<Command>(), Err(ParseError::Unknown("reboot".into())));
+	}
+
+	#[test]
+	fn line() {
+		let mut out = String::new();
+		let mut counters = Counters::default();
+		counters.sent(100);
+		counters.received(1400);
+		counters.received(20);
+		Line::new(&mut out, "flow")
+			.field("port", 80)
+			.duration("age", Duration::from_millis(1500))
+			.text("error", "connection \"reset\"")
+			.counters(&counters)
+			.end();
+		assert_eq!(out, "flow port=80 age=1.500 error=\"connection \\\"reset\\\"\" sent-bytes=100 sent-packets=1 received-bytes=1420 received-packets=2\n");
+	}
+
+	#[test]
+	fn socket() {
+		let path = std::env::temp_dir().join(format!("stupid-control-{}.sock", std::process::id()));
+		let mut poll = mio::Poll::new().unwrap();
+		let mut control = Control::bind(&path, poll.registry(), 0x10_0000).unwrap();
+		assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+		let mut client = std::os::unix::net::UnixStream::connect(&path).unwrap();
+		client.write_all(b"stats\nkill 1 tcp 80\nbogus\nhelp").unwrap();
+		client.shutdown(std::net::Shutdown::Write).unwrap();
+		let mut events = mio::Events::with_capacity(16);
+		let mut commands = Vec::new();
+		let mut accepted = false;
+		// The connection is closed once all commands were answered.
+		while !accepted || !control.connections.is_empty() {
+			poll.poll(&mut events, Some(Duration::from_secs(5))).unwrap();
+			assert!(!events.is_empty());
+			for e in &events {
+				assert!(control.owns(e.token()));
+				accepted = true;
+				control.handle_event(poll.registry(), e.token(), |c, out| {
+					commands.push(c);
+					match c {
+						Command::Stats => {
+							Line::new(out, "session").field("id", 1).end();
+							Ok(())
+						}
+						_ => Err("no such flow".into()),
+					}
+				});
+			}
+		}
+		let mut answer = String::new();
+		client.read_to_string(&mut answer).unwrap();
+		assert_eq!(commands, [Command::Stats, Command::Kill { session: Some(1), protocol: Protocol::Tcp, port: 80 }]);
+		assert!(answer.starts_with("session id=1\nok\nerror: no such flow\nerror: unknown command \"bogus\", try help\nstats "), "{}", answer);
+		assert!(answer.ends_with("ok\n"));
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn unread_answers() {
+		let (stream, mut client) = std::os::unix::net::UnixStream::pair().unwrap();
+		stream.set_nonblocking(true).unwrap();
+		client.set_nonblocking(true).unwrap();
+		let stream = UnixStream::from_std(stream);
+		let mut conn = Connection { stream, input: Vec::new(), output: Vec::new(), closing: false };
+		let commands = b"help\n".repeat(100);
+		// The client never reads the answers, so the commands it sends pile up in the socket
+		// instead of their answers in the output.
+		let blocked = (0..10_000).any(|_| {
+			if let Err(e) = client.write(&commands) {
+				assert_eq!(e.kind(), ErrorKind::WouldBlock);
+				return true;
+			}
+			conn.handle(&mut |_, _| Ok(())).unwrap();
+			false
+		});
+		assert!(blocked);
+		assert!(conn.output.len() < MAX_OUTPUT + HELP.len() + 3);
+	}
+}
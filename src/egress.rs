@@ -0,0 +1,218 @@
+//! How traffic to destinations leaves the server: the source address, interface and firewall
+//! mark of the sockets opened for clients.
+//!
+//! Binding to an interface needs `CAP_NET_RAW` and setting a mark `CAP_NET_ADMIN`.
+
+use crate::acl::Network;
+use core::mem;
+use core::str::FromStr;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use mio::net::{TcpStream, UdpSocket};
+
+/// The longest interface name, without the terminating null byte.
+pub const MAX_DEVICE_LEN: usize = libc::IFNAMSIZ - 1;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Egress {
+	/// The source address, used for destinations of the same family.
+	pub address: Option<IpAddr>,
+	/// The interface to send through with `SO_BINDTODEVICE`.
+	pub device: Option<String>,
+	/// The firewall mark to set with `SO_MARK`.
+	pub mark: Option<u32>,
+}
+
+/// Another egress for destinations in a network, written as `NETWORK [address=IP]
+/// [device=NAME] [mark=N]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Route {
+	network: Network,
+	egress: Egress,
+}
+
+impl Egress {
+	/// This egress, with what isn't set taken from `other`.
+	pub fn or(&self, other: &Self) -> Self {
+		Self {
+			address: self.address.or(other.address),
+			device: self.device.clone().or_else(|| other.device.clone()),
+			mark: self.mark.or(other.mark),
+		}
+	}
+
+	/// The egress of the first route to the destination, with what it doesn't set taken from
+	/// this one.
+	pub fn route(&self, routes: &[Route], destination: IpAddr) -> Self {
+		match routes.iter().find(|r| r.network.contains(destination)) {
+			Some(r) => r.egress.or(self),
+			None => self.clone(),
+		}
+	}
+
+	/// The local address to bind a socket for the destination to.
+	pub fn source(&self, destination: SocketAddr) -> SocketAddr {
+		match (self.address, destination) {
+			(Some(a @ IpAddr::V4(_)), SocketAddr::V4(_)) | (Some(a @ IpAddr::V6(_)), SocketAddr::V6(_)) => SocketAddr::new(a, 0),
+			(_, SocketAddr::V4(_)) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+			(_, SocketAddr::V6(_)) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+		}
+	}
+
+	/// Bind a socket that isn't bound yet to the source address, if any, and set its interface
+	/// and mark.
+	pub fn bind(&self, fd: RawFd, destination: SocketAddr) -> Result<(), Error> {
+		self.set_options(fd)?;
+		let source = self.source(destination);
+		if source.ip().is_unspecified() {
+			return Ok(());
+		}
+		let (addr, len) = sockaddr(source);
+		let ret = unsafe { libc::bind(fd, &addr as *const _ as *const _, len) };
+		(ret >= 0).then_some(()).ok_or_else(Error::last_os_error)
+	}
+
+	/// Start connecting to the destination. The stream becomes writable once the connection
+	/// is established or failed.
+	pub fn connect_tcp(&self, destination: SocketAddr) -> Result<TcpStream, Error> {
+		let domain = if destination.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+		let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) };
+		if fd < 0 {
+			return Err(Error::last_os_error());
+		}
+		// SAFETY: nothing else owns the socket, which is closed when the stream is dropped.
+		let stream = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+		self.bind(fd, destination)?;
+		let (addr, len) = sockaddr(destination);
+		if unsafe { libc::connect(fd, &addr as *const _ as *const _, len) } < 0 {
+			let e = Error::last_os_error();
+			if e.raw_os_error() != Some(libc::EINPROGRESS) {
+				return Err(e);
+			}
+		}
+		Ok(TcpStream::from_std(stream))
+	}
+
+	/// Bind a UDP socket to send to the destination from.
+	pub fn bind_udp(&self, destination: SocketAddr) -> Result<UdpSocket, Error> {
+		let socket = UdpSocket::bind(self.source(destination))?;
+		self.set_options(socket.as_raw_fd())?;
+		Ok(socket)
+	}
+
+	fn set_options(&self, fd: RawFd) -> Result<(), Error> {
+		if let Some(device) = &self.device {
+			setsockopt(fd, libc::SO_BINDTODEVICE, device.as_bytes())?;
+		}
+		if let Some(mark) = self.mark {
+			setsockopt(fd, libc::SO_MARK, &mark.to_ne_bytes())?;
+		}
+		Ok(())
+	}
+}
+
+impl FromStr for Route {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut words = s.split_whitespace();
+		let network = words.next().ok_or(())?.parse()?;
+		let mut egress = Egress::default();
+		for word in words {
+			match word.split_once('=').ok_or(())? {
+				("address", a) => egress.address = Some(a.parse().map_err(|_| ())?),
+				("device", d) if is_device(d) => egress.device = Some(d.into()),
+				("mark", m) => egress.mark = Some(parse_mark(m).ok_or(())?),
+				_ => return Err(()),
+			}
+		}
+		if egress == Egress::default() {
+			return Err(());
+		}
+		Ok(Self { network, egress })
+	}
+}
+
+/// Whether the name can be that of an interface.
+pub fn is_device(name: &str) -> bool {
+	(1..=MAX_DEVICE_LEN).contains(&name.len()) && !name.contains(|c: char| c == '/' || c.is_whitespace())
+}
+
+/// Parse a firewall mark, either in decimal or in hexadecimal with a `0x` prefix.
+pub fn parse_mark(s: &str) -> Option<u32> {
+	match s.strip_prefix("0x") {
+		Some(hex) => u32::from_str_radix(hex, 16).ok(),
+		None => s.parse().ok(),
+	}
+}
+
+fn setsockopt(fd: RawFd, option: libc::c_int, value: &[u8]) -> Result<(), Error> {
+	let len = libc::socklen_t::try_from(value.len()).map_err(|_| Error::from(ErrorKind::InvalidInput))?;
+	let ret = unsafe { libc::setsockopt(fd, libc::SOL_SOCKET, option, value.as_ptr().cast(), len) };
+	(ret >= 0).then_some(()).ok_or_else(Error::last_os_error)
+}
+
+fn sockaddr(address: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+	// SAFETY: all zeroes is a valid sockaddr_storage.
+	let mut storage = unsafe { mem::zeroed::<libc::sockaddr_storage>() };
+	let len = match address {
+		SocketAddr::V4(a) => {
+			let sin = libc::sockaddr_in {
+				sin_family: libc::AF_INET as libc::sa_family_t,
+				sin_port: a.port().to_be(),
+				sin_addr: libc::in_addr { s_addr: u32::from_ne_bytes(a.ip().octets()) },
+				sin_zero: [0; 8],
+			};
+			// SAFETY: sockaddr_storage is large and aligned enough for any address.
+			unsafe { (&mut storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in>().write(sin) };
+			mem::size_of::<libc::sockaddr_in>()
+		}
+		SocketAddr::V6(a) => {
+			let sin6 = libc::sockaddr_in6 {
+				sin6_family: libc::AF_INET6 as libc::sa_family_t,
+				sin6_port: a.port().to_be(),
+				sin6_flowinfo: a.flowinfo(),
+				sin6_addr: libc::in6_addr { s6_addr: a.ip().octets() },
+				sin6_scope_id: a.scope_id(),
+			};
+			// SAFETY: as above.
+			unsafe { (&mut storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in6>().write(sin6) };
+			mem::size_of::<libc::sockaddr_in6>()
+		}
+	};
+	(storage, len as libc::socklen_t)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn routes() {
+		let default = Egress { address: Some("192.0.2.1".parse().unwrap()), device: None, mark: Some(1) };
+		let routes = ["10.0.0.0/8 device=wg0 mark=0x10", "2001:db8::/32 address=2001:db8::1"].map(|r| r.parse::<Route>().unwrap());
+		let e = default.route(&routes, "10.1.2.3".parse().unwrap());
+		assert_eq!(e, Egress { address: Some("192.0.2.1".parse().unwrap()), device: Some("wg0".into()), mark: Some(16) });
+		assert_eq!(default.route(&routes, "1.1.1.1".parse().unwrap()), default);
+		let e = default.route(&routes, "2001:db8::53".parse().unwrap());
+		assert_eq!(e.source("[2001:db8::53]:53".parse().unwrap()), "[2001:db8::1]:0".parse().unwrap());
+		// The source address is only used for destinations of the same family.
+		assert_eq!(e.source("1.1.1.1:53".parse().unwrap()), "0.0.0.0:0".parse().unwrap());
+		for r in ["10.0.0.0/8", "10.0.0.0/8 device=", "10.0.0.0/8 device=0123456789abcdef", "10.0.0.0/8 mark=x", "10.0.0.0/8 table=1", "nope mark=1"] {
+			assert_eq!(r.parse::<Route>(), Err(()), "{}", r);
+		}
+	}
+
+	#[test]
+	fn connect() {
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap();
+		let egress = Egress { address: Some("127.0.0.2".parse().unwrap()), ..Default::default() };
+		let _stream = egress.connect_tcp(address).unwrap();
+		let (_, peer) = listener.accept().unwrap();
+		assert_eq!(peer.ip(), "127.0.0.2".parse::<IpAddr>().unwrap());
+		let udp = egress.bind_udp(address).unwrap();
+		assert_eq!(udp.local_addr().unwrap().ip(), "127.0.0.2".parse::<IpAddr>().unwrap());
+	}
+}
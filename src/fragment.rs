@@ -0,0 +1,343 @@
+//! IPv6 fragment reassembly & fragmentation
+//!
+//! (RFC 8200 section 4.5)[https://datatracker.ietf.org/doc/html/rfc8200#section-4.5]
+
+use crate::ip::{FragmentHeader, IPv6Header};
+use core::ops::Range;
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use std::time::{Duration, Instant};
+
+/// The minimum MTU every IPv6 link must support.
+pub const MIN_MTU: usize = 1280;
+
+pub struct Reassembler {
+	packets: HashMap<Key, Partial>,
+	timeout: Duration,
+	max_bytes: usize,
+	bytes: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Key {
+	source: Ipv6Addr,
+	destination: Ipv6Addr,
+	identification: u32,
+}
+
+struct Partial {
+	/// The next header of the fragment with offset 0.
+	next_header: Option<u8>,
+	data: Vec<u8>,
+	received: Vec<Range<usize>>,
+	/// The total length, which is only known once the last fragment arrived.
+	length: Option<usize>,
+	start: Instant,
+}
+
+impl Reassembler {
+	/// How long to wait for all fragments to arrive, as recommended by RFC 8200.
+	pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+	/// The maximum amount of memory used by all incomplete packets combined.
+	pub const DEFAULT_MAX_BYTES: usize = 1 << 22;
+
+	pub fn new(timeout: Duration, max_bytes: usize) -> Self {
+		Self {
+			packets: HashMap::new(),
+			timeout,
+			max_bytes,
+			bytes: 0,
+		}
+	}
+
+	/// Add a fragment.
+	///
+	/// If all fragments have been received an IPv6 header describing the reassembled packet
+	/// and its payload is returned. The payload starts at the header indicated by the
+	/// fragment header and does not include the unfragmentable part.
+	pub fn insert(&mut self, now: Instant, ip: &IPv6Header, fragment: &FragmentHeader, data: &[u8]) -> Result<Option<(IPv6Header, Vec<u8>)>, ReassembleError> {
+		let start = usize::from(fragment.offset());
+		let end = start + data.len();
+
+		if fragment.more_fragments() && data.len() % 8 != 0 {
+			return Err(ReassembleError::BadLength);
+		}
+		if end > usize::from(u16::MAX) {
+			return Err(ReassembleError::TooLarge);
+		}
+
+		let reassembled = |next_header, data: Vec<u8>| {
+			let len = data.len().try_into().unwrap();
+			let ip = IPv6Header::new(len, next_header, ip.hop_limit, ip.source_address(), ip.destination_address());
+			(ip, data)
+		};
+
+		// Atomic fragments are handled independently of any other fragments (RFC 6946).
+		if start == 0 && !fragment.more_fragments() {
+			return Ok(Some(reassembled(fragment.next_header, data.to_vec())));
+		}
+
+		let key = Key {
+			source: ip.source_address(),
+			destination: ip.destination_address(),
+			identification: fragment.identification(),
+		};
+		let p = self.packets.entry(key).or_insert_with(|| Partial {
+			next_header: None,
+			data: Vec::new(),
+			received: Vec::new(),
+			length: None,
+			start: now,
+		});
+
+		// Fragments may be duplicated on the way, which is harmless if the copies are identical.
+		let duplicate = p.received.contains(&(start..end))
+			&& p.data[start..end] == *data
+			&& (start != 0 || p.next_header == Some(fragment.next_header))
+			&& fragment.more_fragments() == (p.length != Some(end));
+		if duplicate {
+			return Ok(None);
+		}
+
+		let check = || {
+			// Overlapping fragments are a common attack vector and must be dropped (RFC 5722).
+			if p.received.iter().any(|r| r.start < end && start < r.end) {
+				return Err(ReassembleError::Overlap);
+			}
+			match (fragment.more_fragments(), p.length) {
+				(true, Some(l)) if end > l => return Err(ReassembleError::Inconsistent),
+				(false, Some(_)) => return Err(ReassembleError::Inconsistent),
+				(false, None) if p.received.iter().any(|r| r.end > end) => return Err(ReassembleError::Inconsistent),
+				_ => (),
+			}
+			Ok(())
+		};
+		if let Err(e) = check() {
+			self.bytes -= p.data.len();
+			self.packets.remove(&key);
+			return Err(e);
+		}
+
+		if end > p.data.len() {
+			let grow = end - p.data.len();
+			if self.bytes + grow > self.max_bytes {
+				// The packet can't be completed without this fragment.
+				self.bytes -= p.data.len();
+				self.packets.remove(&key);
+				return Err(ReassembleError::MemoryLimit);
+			}
+			self.bytes += grow;
+			p.data.resize(end, 0);
+		}
+		p.data[start..end].copy_from_slice(data);
+		p.received.push(start..end);
+		if start == 0 {
+			p.next_header = Some(fragment.next_header);
+		}
+		if !fragment.more_fragments() {
+			p.length = Some(end);
+		}
+
+		let received = p.received.iter().map(|r| r.len()).sum::<usize>();
+		match (p.next_header, p.length) {
+			(Some(next_header), Some(length)) if length == received => {
+				let p = self.packets.remove(&key).unwrap();
+				self.bytes -= p.data.len();
+				Ok(Some(reassembled(next_header, p.data)))
+			}
+			_ => Ok(None),
+		}
+	}
+
+	/// Drop all packets for which not all fragments arrived in time.
+	pub fn expire(&mut self, now: Instant) {
+		let (timeout, bytes) = (self.timeout, &mut self.bytes);
+		self.packets.retain(|_, p| {
+			let keep = now.saturating_duration_since(p.start) < timeout;
+			(!keep).then(|| *bytes -= p.data.len());
+			keep
+		});
+	}
+
+	/// The time until the next incomplete packet expires, if any.
+	pub fn next_timeout(&self, now: Instant) -> Option<Duration> {
+		self.packets
+			.values()
+			.map(|p| (p.start + self.timeout).saturating_duration_since(now))
+			.min()
+	}
+}
+
+impl Default for Reassembler {
+	fn default() -> Self {
+		Self::new(Self::DEFAULT_TIMEOUT, Self::DEFAULT_MAX_BYTES)
+	}
+}
+
+/// Splits the payload of a packet into fragments that fit in the given MTU.
+pub struct Fragmenter<'a> {
+	ip: IPv6Header,
+	next_header: u8,
+	payload: &'a [u8],
+	offset: usize,
+	max_data: usize,
+	identification: u32,
+}
+
+impl<'a> Fragmenter<'a> {
+	pub fn new(ip: &IPv6Header, payload: &'a [u8], mtu: usize, identification: u32) -> Self {
+		assert!(mtu >= MIN_MTU, "MTU is too small");
+		let max_data = (mtu - ip.byte_len() - FragmentHeader::new(0, 0, false, 0).byte_len()) & !7;
+		Self {
+			ip: *ip,
+			next_header: ip.next_header,
+			payload,
+			offset: 0,
+			max_data,
+			identification,
+		}
+	}
+
+	/// Write the next fragment to the given buffer.
+	pub fn next_packet<'b>(&mut self, out: &'b mut [u8]) -> Option<&'b [u8]> {
+		let data = &self.payload[self.offset..];
+		if data.is_empty() {
+			return None;
+		}
+		let data = &data[..data.len().min(self.max_data)];
+		let more = self.offset + data.len() < self.payload.len();
+
+		let f = FragmentHeader::new(self.next_header, self.offset.try_into().unwrap(), more, self.identification);
+		let ip = *self.ip
+			.set_next_header(44)
+			.set_payload_length((f.byte_len() + data.len()).try_into().unwrap());
+
+		let len = ip.byte_len() + f.byte_len() + data.len();
+		assert!(out.len() >= len);
+
+		out[..ip.byte_len()].copy_from_slice(ip.as_ref());
+		out[ip.byte_len()..][..f.byte_len()].copy_from_slice(f.as_ref());
+		out[ip.byte_len()..][f.byte_len()..][..data.len()].copy_from_slice(data);
+
+		self.offset += data.len();
+		Some(&out[..len])
+	}
+}
+
+#[derive(Debug)]
+pub enum ReassembleError {
+	/// A fragment that isn't the last one is not a multiple of 8 bytes.
+	BadLength,
+	/// The reassembled packet would exceed the maximum payload size.
+	TooLarge,
+	/// The fragment overlaps with another fragment.
+	Overlap,
+	/// The fragment conflicts with the length indicated by the last fragment.
+	Inconsistent,
+	/// There is not enough memory left to store the fragment.
+	MemoryLimit,
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::ip::Header;
+
+	fn fragments(payload: &[u8], mtu: usize) -> Vec<Vec<u8>> {
+		let ip = IPv6Header::new(payload.len().try_into().unwrap(), 17, 64, Ipv6Addr::LOCALHOST, Ipv6Addr::LOCALHOST);
+		let mut f = Fragmenter::new(&ip, payload, mtu, 42);
+		let mut out = [0; 0x10000];
+		let mut v = Vec::new();
+		while let Some(p) = f.next_packet(&mut out) {
+			assert!(p.len() <= mtu);
+			v.push(p.to_vec());
+		}
+		v
+	}
+
+	fn insert(r: &mut Reassembler, now: Instant, packet: &[u8]) -> Result<Option<(IPv6Header, Vec<u8>)>, ReassembleError> {
+		let (ip, payload) = IPv6Header::from_raw(packet).unwrap();
+		match ip.extension_headers(payload).next() {
+			Some(Ok(Header::Fragment(f, data))) => r.insert(now, ip, &f, data),
+			h => panic!("{:?}", h),
+		}
+	}
+
+	#[test]
+	fn roundtrip() {
+		let payload = (0..5000).map(|i| i as u8).collect::<Vec<_>>();
+		let mut f = fragments(&payload, 1280);
+		assert_eq!(f.len(), 5);
+		f.reverse();
+
+		let (mut r, now) = (Reassembler::default(), Instant::now());
+		for p in &f[..4] {
+			assert!(insert(&mut r, now, p).unwrap().is_none());
+		}
+		let (ip, data) = insert(&mut r, now, &f[4]).unwrap().unwrap();
+		assert_eq!(ip.next_header, 17);
+		assert_eq!(usize::from(ip.payload_length()), payload.len());
+		assert_eq!(data, payload);
+		assert_eq!(r.bytes, 0);
+		assert!(r.packets.is_empty());
+	}
+
+	#[test]
+	fn overlap() {
+		let payload = [0; 3000];
+		let f = fragments(&payload, 1280);
+		let (mut r, now) = (Reassembler::default(), Instant::now());
+		assert!(insert(&mut r, now, &f[0]).unwrap().is_none());
+		// An identical copy is ignored.
+		assert!(insert(&mut r, now, &f[0]).unwrap().is_none());
+		assert_eq!(r.packets.len(), 1);
+		// Fragments of a larger MTU overlap the first one without being identical.
+		let other = fragments(&payload, 1500);
+		assert!(matches!(insert(&mut r, now, &other[0]), Err(ReassembleError::Overlap)));
+		assert!(r.packets.is_empty());
+		assert_eq!(r.bytes, 0);
+	}
+
+	#[test]
+	fn duplicate() {
+		let payload = (0..3000).map(|i| i as u8).collect::<Vec<_>>();
+		let f = fragments(&payload, 1280);
+		let (mut r, now) = (Reassembler::default(), Instant::now());
+		for p in [&f[2], &f[2], &f[0], &f[0], &f[2]] {
+			assert!(insert(&mut r, now, p).unwrap().is_none());
+		}
+		let (_, data) = insert(&mut r, now, &f[1]).unwrap().unwrap();
+		assert_eq!(data, payload);
+		assert!(r.packets.is_empty());
+	}
+
+	#[test]
+	fn timeout() {
+		let f = fragments(&[0; 3000], 1280);
+		let (mut r, now) = (Reassembler::new(Duration::from_secs(5), 1 << 16), Instant::now());
+		assert!(insert(&mut r, now, &f[0]).unwrap().is_none());
+		assert_eq!(r.next_timeout(now), Some(Duration::from_secs(5)));
+		r.expire(now + Duration::from_secs(4));
+		assert_eq!(r.packets.len(), 1);
+		r.expire(now + Duration::from_secs(5));
+		assert!(r.packets.is_empty());
+		assert_eq!(r.bytes, 0);
+		assert_eq!(r.next_timeout(now), None);
+	}
+
+	#[test]
+	fn memory_limit() {
+		let f = fragments(&[0; 3000], 1280);
+		let (mut r, now) = (Reassembler::new(Reassembler::DEFAULT_TIMEOUT, 2000), Instant::now());
+		assert!(insert(&mut r, now, &f[0]).unwrap().is_none());
+		assert!(matches!(insert(&mut r, now, &f[2]), Err(ReassembleError::MemoryLimit)));
+		assert!(r.packets.is_empty());
+		assert_eq!(r.bytes, 0);
+
+		// No empty packet is left behind by a first fragment that doesn't fit.
+		let mut r = Reassembler::new(Reassembler::DEFAULT_TIMEOUT, 1000);
+		assert!(matches!(insert(&mut r, now, &f[0]), Err(ReassembleError::MemoryLimit)));
+		assert!(r.packets.is_empty());
+		assert_eq!(r.next_timeout(now), None);
+	}
+}
@@ -0,0 +1,100 @@
+//! Just enough HTTP/1.1 to upgrade connections and to tunnel through proxies.
+
+use std::str;
+
+/// The maximum size of the head of a request or response.
+pub const MAX_HEAD: usize = 8192;
+
+/// The start line and header fields of a request or response.
+pub struct Head<'a> {
+	/// e.g. `GET / HTTP/1.1` or `HTTP/1.1 200 OK`.
+	pub start: &'a str,
+	fields: &'a str,
+}
+
+impl<'a> Head<'a> {
+	/// Parse a head, including the empty line that ends it.
+	pub fn parse(head: &'a [u8]) -> Option<Self> {
+		let head = str::from_utf8(head).ok()?.strip_suffix("\r\n\r\n")?;
+		let (start, fields) = head.split_once("\r\n").unwrap_or((head, ""));
+		Some(Self { start, fields })
+	}
+
+	/// The value of the first header field with the given name, which is case insensitive.
+	pub fn header(&self, name: &str) -> Option<&'a str> {
+		self.fields
+			.split("\r\n")
+			.filter_map(|l| l.split_once(':'))
+			.find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+			.map(|(_, v)| v.trim())
+	}
+
+	/// The method and target, if this is a request.
+	pub fn request(&self) -> Option<(&'a str, &'a str)> {
+		let mut parts = self.start.split(' ');
+		let (method, target, version) = (parts.next()?, parts.next()?, parts.next()?);
+		version.starts_with("HTTP/1.").then_some((method, target))
+	}
+
+	/// The status code, if this is a response.
+	pub fn status(&self) -> Option<u16> {
+		let mut parts = self.start.split(' ');
+		if !parts.next()?.starts_with("HTTP/1.") {
+			return None;
+		}
+		parts.next()?.parse().ok()
+	}
+}
+
+/// The length of the head at the start of `data`, if all of it is there.
+pub fn head_len(data: &[u8]) -> Option<usize> {
+	data.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4)
+}
+
+/// Split the `host:port` target of a CONNECT request. The brackets around IPv6 addresses are
+/// removed.
+pub fn split_authority(target: &str) -> Option<(&str, u16)> {
+	let (host, port) = target.rsplit_once(':')?;
+	let host = match host.strip_prefix('[') {
+		Some(h) => h.strip_suffix(']')?,
+		None if host.contains(':') => return None,
+		None => host,
+	};
+	if host.is_empty() {
+		return None;
+	}
+	Some((host, port.parse().ok()?))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn parse() {
+		let data = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nsec-websocket-accept:  abc= \r\n\r\nrest";
+		let len = head_len(data).unwrap();
+		assert_eq!(&data[len..], b"rest");
+		let head = Head::parse(&data[..len]).unwrap();
+		assert_eq!(head.start, "HTTP/1.1 101 Switching Protocols");
+		assert_eq!(head.status(), Some(101));
+		assert_eq!(head.header("Sec-WebSocket-Accept"), Some("abc="));
+		assert_eq!(head.header("connection"), None);
+		assert_eq!(Head::parse(b"GET / HTTP/1.1\r\n\r\n").unwrap().status(), None);
+		assert!(Head::parse(b"GET / HTTP/1.1\r\n").is_none());
+		let head = Head::parse(b"CONNECT example.org:443 HTTP/1.1\r\nHost: example.org:443\r\n\r\n").unwrap();
+		assert_eq!(head.request(), Some(("CONNECT", "example.org:443")));
+		assert_eq!(head.status(), None);
+	}
+
+	#[test]
+	fn authority() {
+		assert_eq!(split_authority("example.org:443"), Some(("example.org", 443)));
+		assert_eq!(split_authority("10.0.0.1:22"), Some(("10.0.0.1", 22)));
+		assert_eq!(split_authority("[::1]:80"), Some(("::1", 80)));
+		assert_eq!(split_authority("::1:80"), None);
+		assert_eq!(split_authority("example.org"), None);
+		assert_eq!(split_authority(":80"), None);
+		assert_eq!(split_authority("example.org:http"), None);
+	}
+}
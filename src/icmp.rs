@@ -1,7 +1,9 @@
 //! (RFC 4443)[https://datatracker.ietf.org/doc/html/rfc4443]
 
+use crate::Checksum;
 use core::fmt;
 use core::mem;
+use std::net::Ipv6Addr;
 
 #[derive(Clone, Copy)]
 #[repr(C)]
@@ -12,22 +14,108 @@ pub struct ICMPv6Header {
 }
 
 impl ICMPv6Header {
+	pub const ECHO_REQUEST: u8 = 128;
+	pub const ECHO_REPLY: u8 = 129;
+
+	pub fn ty(&self) -> u8 {
+		self.ty
+	}
+}
+
+impl fmt::Debug for ICMPv6Header {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct(stringify!(ICMPv6Header))
+			.field("type", &self.ty)
+			.field("code", &self.code)
+			.field("checksum", &u16::from_be_bytes(self.checksum))
+			.finish()
+	}
+}
+
+/// Echo request or reply message (RFC 4443 section 4)
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct ICMPv6Echo {
+	header: ICMPv6Header,
+	identifier: [u8; 2],
+	sequence_num: [u8; 2],
+}
+
+impl ICMPv6Echo {
 	pub fn from_raw(data: &[u8]) -> Result<(Self, &[u8]), RawHeaderError> {
 		if data.len() < mem::size_of::<Self>() {
 			return Err(RawHeaderError::Truncated);
 		}
 		// SAFETY: the data fits & is aligned
 		let (h, e) = data.split_at(mem::size_of::<Self>());
-		unsafe { Ok((*h.as_ptr().cast(), e)) }
+		let h = unsafe { *h.as_ptr().cast::<Self>() };
+		match h.header.ty {
+			ICMPv6Header::ECHO_REQUEST | ICMPv6Header::ECHO_REPLY => Ok((h, e)),
+			_ => Err(RawHeaderError::UnexpectedType),
+		}
+	}
+
+	pub fn new_reply_ipv6(source: Ipv6Addr, destination: Ipv6Addr, identifier: u16, sequence_num: u16, data: &[u8]) -> Result<Self, ChecksumError> {
+		let mut slf = Self {
+			header: ICMPv6Header {
+				ty: ICMPv6Header::ECHO_REPLY,
+				code: 0,
+				checksum: [0; 2],
+			},
+			identifier: identifier.to_be_bytes(),
+			sequence_num: sequence_num.to_be_bytes(),
+		};
+		slf.header.checksum = slf.checksum_ipv6(source, destination, data)?.to_be_bytes();
+		Ok(slf)
+	}
+
+	pub fn header(&self) -> &ICMPv6Header {
+		&self.header
+	}
+
+	from_be_fn!(identifier, u16);
+	from_be_fn!(sequence_num, u16);
+
+	/// Total length of the ICMPv6 message (header + data)
+	pub fn length(&self, data: &[u8]) -> Result<u16, ChecksumError> {
+		(mem::size_of_val(self) + data.len()).try_into().map_err(|_| ChecksumError::DataTooLarge)
+	}
+
+	/// Return the size of the header
+	pub fn byte_len(&self) -> usize {
+		mem::size_of_val(self)
+	}
+
+	fn checksum_ipv6(&self, source: Ipv6Addr, destination: Ipv6Addr, data: &[u8]) -> Result<u16, ChecksumError> {
+		let (src, dest) = (source.octets(), destination.octets());
+		let length = self.length(data)?.to_be_bytes();
+		let sum = Checksum::new()
+			.feed(&src)
+			.feed(&dest)
+			.feed(&[0, 58]) // zero, protocol
+			.feed(&length)
+			.feed(&[self.header.ty, self.header.code])
+			.feed(&self.header.checksum)
+			.feed(&self.identifier)
+			.feed(&self.sequence_num)
+			.feed(data)
+			.finish();
+		Ok(sum)
 	}
 }
 
-impl fmt::Debug for ICMPv6Header {
+impl AsRef<[u8; mem::size_of::<Self>()]> for ICMPv6Echo {
+	fn as_ref(&self) -> &[u8; mem::size_of::<Self>()] {
+		unsafe { &*(self as *const _ as *const _) }
+	}
+}
+
+impl fmt::Debug for ICMPv6Echo {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		f.debug_struct(stringify!(ICMPv6Header))
-			.field("type", &self.ty)
-			.field("code", &self.code)
-			.field("checksum", &u16::from_be_bytes(self.checksum))
+		f.debug_struct(stringify!(ICMPv6Echo))
+			.field("header", &self.header)
+			.field("identifier", &self.identifier())
+			.field("sequence_num", &self.sequence_num())
 			.finish()
 	}
 }
@@ -35,4 +123,34 @@ impl fmt::Debug for ICMPv6Header {
 #[derive(Debug)]
 pub enum RawHeaderError {
 	Truncated,
+	UnexpectedType,
+}
+
+#[derive(Debug)]
+pub enum ChecksumError {
+	DataTooLarge,
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn echo() {
+		let (src, dst) = ("fd00::1".parse().unwrap(), "fd00::2".parse().unwrap());
+		let echo = ICMPv6Echo::new_reply_ipv6(src, dst, 0x1234, 7, b"ping").unwrap();
+		let mut raw = echo.as_ref().to_vec();
+		raw.extend_from_slice(b"ping");
+
+		let (e, data) = ICMPv6Echo::from_raw(&raw).unwrap();
+		assert_eq!(e.header().ty(), ICMPv6Header::ECHO_REPLY);
+		assert_eq!((e.identifier(), e.sequence_num(), data), (0x1234, 7, &b"ping"[..]));
+		// Including the checksum field in the sum results in zero if it is correct.
+		assert_eq!(e.checksum_ipv6(src, dst, data).unwrap(), 0);
+
+		assert!(matches!(ICMPv6Echo::from_raw(&raw[..7]), Err(RawHeaderError::Truncated)));
+		// Neighbour solicitation
+		raw[0] = 135;
+		assert!(matches!(ICMPv6Echo::from_raw(&raw), Err(RawHeaderError::UnexpectedType)));
+	}
 }
@@ -21,7 +21,6 @@ impl IfReq {
 			return Err(NewIfReqError::NameTooLong);
 		}
 		n.iter_mut().zip(name.iter().chain(&[0])).for_each(|(w, r)| *w = *r);
-		dbg!(Self::FLAG_TUN | u16::from(no_packet_info) * Self::FLAG_NO_PI);
 		Ok(Self {
 			name: n,
 			data: Data {
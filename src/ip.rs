@@ -87,6 +87,16 @@ impl IPv6Header {
 	pub fn byte_len(&self) -> usize {
 		mem::size_of_val(self)
 	}
+
+	/// Walk the extension headers in the payload following this header.
+	///
+	/// The payload is truncated to [`Self::payload_length`] first.
+	pub fn extension_headers<'a>(&self, payload: &'a [u8]) -> ExtensionHeaders<'a> {
+		match payload.get(..usize::from(self.payload_length())) {
+			Some(data) => ExtensionHeaders { next_header: Some(self.next_header), data, error: None },
+			None => ExtensionHeaders { next_header: None, data: &[], error: Some(FromRawError::BadSize) },
+		}
+	}
 }
 
 impl fmt::Debug for IPv6Header {
@@ -114,8 +124,224 @@ impl Default for IPv6Header {
 	}
 }
 
+/// An IPv6 extension header or the upper-layer protocol following them.
+#[derive(Clone, Copy, Debug)]
+pub enum Header<'a> {
+	/// Hop-by-hop options, which are skipped.
+	HopByHop,
+	/// Routing header, which is skipped.
+	Routing,
+	/// Destination options, which are skipped.
+	DestinationOptions,
+	/// Fragment header and the fragment data.
+	///
+	/// This is always the last item as the data can only be interpreted after reassembly.
+	Fragment(FragmentHeader, &'a [u8]),
+	/// Upper-layer protocol number and its payload. This is always the last item.
+	Upper(u8, &'a [u8]),
+}
+
+/// Iterator over the extension headers of an IPv6 packet.
+pub struct ExtensionHeaders<'a> {
+	next_header: Option<u8>,
+	data: &'a [u8],
+	error: Option<FromRawError>,
+}
+
+impl<'a> ExtensionHeaders<'a> {
+	const HOP_BY_HOP: u8 = 0;
+	const ROUTING: u8 = 43;
+	const FRAGMENT: u8 = 44;
+	const DESTINATION_OPTIONS: u8 = 60;
+
+	/// Skip all extension headers and return the upper-layer protocol and payload.
+	///
+	/// Returns `None` if the packet is a fragment.
+	pub fn upper_layer(self) -> Result<Option<(u8, &'a [u8])>, FromRawError> {
+		for h in self {
+			match h? {
+				Header::Upper(protocol, payload) => return Ok(Some((protocol, payload))),
+				Header::Fragment(..) => return Ok(None),
+				_ => (),
+			}
+		}
+		Ok(None)
+	}
+
+	/// Skip a header made of options and return the next header.
+	fn skip_options(&mut self) -> Result<u8, FromRawError> {
+		let (next, len) = match self.data {
+			[next, len, ..] => (*next, (usize::from(*len) + 1) * 8),
+			_ => return Err(FromRawError::BadSize),
+		};
+		if self.data.len() < len {
+			return Err(FromRawError::BadSize);
+		}
+		self.data = &self.data[len..];
+		Ok(next)
+	}
+}
+
+impl<'a> Iterator for ExtensionHeaders<'a> {
+	type Item = Result<Header<'a>, FromRawError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if let Some(e) = self.error.take() {
+			return Some(Err(e));
+		}
+		let ty = self.next_header.take()?;
+		let header = match ty {
+			Self::HOP_BY_HOP | Self::ROUTING | Self::DESTINATION_OPTIONS => {
+				let next = match self.skip_options() {
+					Ok(r) => r,
+					Err(e) => return Some(Err(e)),
+				};
+				self.next_header = Some(next);
+				match ty {
+					Self::HOP_BY_HOP => Header::HopByHop,
+					Self::ROUTING => Header::Routing,
+					_ => Header::DestinationOptions,
+				}
+			}
+			Self::FRAGMENT => match FragmentHeader::from_raw(self.data) {
+				Ok((h, e)) => Header::Fragment(h, e),
+				Err(e) => return Some(Err(e)),
+			}
+			ty => Header::Upper(ty, self.data),
+		};
+		Some(Ok(header))
+	}
+}
+
+/// (RFC 8200 section 4.5)[https://datatracker.ietf.org/doc/html/rfc8200#section-4.5]
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct FragmentHeader {
+	pub next_header: u8,
+	_reserved: u8,
+	offset: [u8; 2], // 13 bit offset in 8-byte units, 2 bit reserved, 1 bit more fragments
+	identification: [u8; 4],
+}
+
+impl FragmentHeader {
+	pub fn new(next_header: u8, offset: u16, more_fragments: bool, identification: u32) -> Self {
+		debug_assert_eq!(offset % 8, 0, "offset is not a multiple of 8");
+		Self {
+			next_header,
+			_reserved: 0,
+			offset: (offset | u16::from(more_fragments)).to_be_bytes(),
+			identification: identification.to_be_bytes(),
+		}
+	}
+
+	pub fn from_raw(raw: &[u8]) -> Result<(Self, &[u8]), FromRawError> {
+		if raw.len() < mem::size_of::<Self>() {
+			return Err(FromRawError::BadSize);
+		}
+		let (h, e) = raw.split_at(mem::size_of::<Self>());
+		// SAFETY: it fits and it's properly aligned.
+		unsafe { Ok((*h.as_ptr().cast::<Self>(), e)) }
+	}
+
+	/// Offset of the fragment data in bytes.
+	pub fn offset(&self) -> u16 {
+		u16::from_be_bytes(self.offset) & !0b111
+	}
+
+	pub fn more_fragments(&self) -> bool {
+		self.offset[1] & 1 != 0
+	}
+
+	from_be_fn!(identification, u32);
+
+	pub fn byte_len(&self) -> usize {
+		mem::size_of_val(self)
+	}
+}
+
+impl fmt::Debug for FragmentHeader {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct(stringify!(FragmentHeader))
+			.field("next_header", &self.next_header)
+			.field("offset", &self.offset())
+			.field("more_fragments", &self.more_fragments())
+			.field("identification", &self.identification())
+			.finish()
+	}
+}
+
+impl AsRef<[u8; mem::size_of::<Self>()]> for FragmentHeader {
+	fn as_ref(&self) -> &[u8; mem::size_of::<Self>()] {
+		unsafe { &*(self as *const _ as *const _) }
+	}
+}
+
 #[derive(Debug)]
 pub enum FromRawError {
 	BadSize,
 	BadVersion(u8),
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn packet(next_header: u8, payload: &[u8]) -> Vec<u8> {
+		let ip = IPv6Header::new(payload.len().try_into().unwrap(), next_header, 64, Ipv6Addr::LOCALHOST, Ipv6Addr::LOCALHOST);
+		let mut p = ip.as_ref().to_vec();
+		p.extend_from_slice(payload);
+		p
+	}
+
+	#[test]
+	fn extension_headers() {
+		let mut payload = vec![60, 0, 1, 4, 0, 0, 0, 0]; // hop-by-hop, PadN
+		payload.extend_from_slice(&[17, 1, 1, 12]); // destination options, PadN
+		payload.extend_from_slice(&[0; 12]);
+		payload.extend_from_slice(b"udp data");
+		let p = packet(0, &payload);
+
+		let (ip, payload) = IPv6Header::from_raw(&p).unwrap();
+		let mut it = ip.extension_headers(payload);
+		assert!(matches!(it.next(), Some(Ok(Header::HopByHop))));
+		assert!(matches!(it.next(), Some(Ok(Header::DestinationOptions))));
+		assert!(matches!(it.next(), Some(Ok(Header::Upper(17, b"udp data")))));
+		assert!(it.next().is_none());
+	}
+
+	#[test]
+	fn extension_headers_fragment() {
+		let f = FragmentHeader::new(17, 1232, true, 0xdeadbeef);
+		let mut payload = f.as_ref().to_vec();
+		payload.extend_from_slice(b"fragment");
+		let p = packet(44, &payload);
+
+		let (ip, payload) = IPv6Header::from_raw(&p).unwrap();
+		let mut it = ip.extension_headers(payload);
+		match it.next() {
+			Some(Ok(Header::Fragment(f, b"fragment"))) => {
+				assert_eq!(f.next_header, 17);
+				assert_eq!(f.offset(), 1232);
+				assert!(f.more_fragments());
+				assert_eq!(f.identification(), 0xdeadbeef);
+			}
+			h => panic!("{:?}", h),
+		}
+		assert!(it.next().is_none());
+		assert!(matches!(ip.extension_headers(payload).upper_layer(), Ok(None)));
+	}
+
+	#[test]
+	fn extension_headers_truncated() {
+		let p = packet(0, &[6, 1, 0, 0, 0, 0, 0, 0]);
+		let (ip, payload) = IPv6Header::from_raw(&p).unwrap();
+		let mut it = ip.extension_headers(payload);
+		assert!(matches!(it.next(), Some(Err(FromRawError::BadSize))));
+		assert!(it.next().is_none());
+
+		let mut p = packet(6, b"tcp");
+		p.truncate(p.len() - 1);
+		let (ip, payload) = IPv6Header::from_raw(&p).unwrap();
+		assert!(matches!(ip.extension_headers(payload).upper_layer(), Err(FromRawError::BadSize)));
+	}
+}
@@ -0,0 +1,69 @@
+//! Token buckets limiting how fast a client may use the server.
+
+use std::time::{Duration, Instant};
+
+/// Allows taking `rate` tokens per second on average, and up to a second's worth at once
+/// after having been idle.
+///
+/// More may be taken than is available as long as any token is left, so data can be
+/// relayed in whole reads. The debt is paid off before more can be taken.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+	rate: f64,
+	tokens: f64,
+	last: Instant,
+}
+
+impl TokenBucket {
+	/// A full bucket.
+	pub fn new(rate: usize, now: Instant) -> Self {
+		let rate = rate as f64;
+		Self { rate, tokens: rate, last: now }
+	}
+
+	/// Whether any token is left.
+	pub fn ready(&mut self, now: Instant) -> bool {
+		self.refill(now);
+		self.tokens > 0.0
+	}
+
+	pub fn take(&mut self, amount: usize) {
+		self.tokens -= amount as f64;
+	}
+
+	/// The time until a whole token will be left, in milliseconds as that's what timeouts
+	/// are waited for with.
+	pub fn next_ready(&self, now: Instant) -> Duration {
+		let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+		let missing = 1.0 - (self.tokens + elapsed * self.rate);
+		Duration::from_millis((missing.max(0.0) / self.rate * 1000.0).ceil() as u64)
+	}
+
+	fn refill(&mut self, now: Instant) {
+		let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+		self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+		self.last = now;
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn bucket() {
+		let now = Instant::now();
+		let mut b = TokenBucket::new(1000, now);
+		assert!(b.ready(now));
+		b.take(1500);
+		assert!(!b.ready(now));
+		assert_eq!(b.next_ready(now), Duration::from_millis(501));
+		assert!(!b.ready(now + Duration::from_millis(499)));
+		assert!(b.ready(now + Duration::from_millis(501)));
+		// Idle time doesn't add up beyond a second's worth.
+		let later = now + Duration::from_secs(10);
+		assert!(b.ready(later));
+		b.take(1000);
+		assert!(!b.ready(later));
+	}
+}
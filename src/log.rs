@@ -1,7 +1,243 @@
+//! Leveled logging to standard error.
+//!
+//! Records are written as a single line, either as plain text or as a JSON object. The level
+//! and format can be changed at any time.
+
+use core::fmt::{self, Write as _};
+use std::collections::BTreeSet;
+use std::io::Write as _;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+	Error = 0,
+	Warn = 1,
+	Info = 2,
+	Debug = 3,
+	Trace = 4,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+	Text,
+	Json,
+}
+
+impl Level {
+	const ALL: [Self; 5] = [Self::Error, Self::Warn, Self::Info, Self::Debug, Self::Trace];
+
+	pub fn name(&self) -> &'static str {
+		["error", "warn", "info", "debug", "trace"][*self as usize]
+	}
+}
+
+impl FromStr for Level {
+	type Err = InvalidLevel;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Self::ALL.iter().copied().find(|l| l.name().eq_ignore_ascii_case(s)).ok_or(InvalidLevel)
+	}
+}
+
+#[derive(Debug)]
+pub struct InvalidLevel;
+
+impl FromStr for Format {
+	type Err = InvalidFormat;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"text" => Ok(Self::Text),
+			"json" => Ok(Self::Json),
+			_ => Err(InvalidFormat),
+		}
+	}
+}
+
+#[derive(Debug)]
+pub struct InvalidFormat;
+
+pub fn set_level(level: Level) {
+	LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> Level {
+	Level::ALL[usize::from(LEVEL.load(Ordering::Relaxed))]
+}
+
+pub fn enabled(level: Level) -> bool {
+	level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn set_format(format: Format) {
+	JSON.store(format == Format::Json, Ordering::Relaxed);
+}
+
+/// Information about the session or flow a record relates to.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Context {
+	pub session: Option<u64>,
+	/// The name of the identity the client of the session authenticated as.
+	pub identity: Option<&'static str>,
+	pub local_port: Option<u16>,
+	pub remote: Option<SocketAddr>,
+}
+
+impl Context {
+	pub const NONE: Self = Self { session: None, identity: None, local_port: None, remote: None };
+
+	pub fn session(session: u64) -> Self {
+		Self { session: Some(session), ..Self::NONE }
+	}
+
+	/// The name must not need escaping in JSON, see [`intern`].
+	pub fn identity(self, identity: &'static str) -> Self {
+		Self { identity: Some(identity), ..self }
+	}
+
+	pub fn flow(self, local_port: u16, remote: impl Into<SocketAddr>) -> Self {
+		Self { local_port: Some(local_port), remote: Some(remote.into()), ..self }
+	}
+}
+
+/// Get a copy of a name that lives as long as the program, to put in a [`Context`]. Copies are
+/// reused, so interning the same names again doesn't take more memory.
+pub fn intern(name: &str) -> &'static str {
+	static NAMES: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+	let mut names = NAMES.lock().unwrap();
+	match names.get(name) {
+		Some(n) => n,
+		None => {
+			let n = Box::leak(name.into());
+			names.insert(n);
+			n
+		}
+	}
+}
+
+pub fn write(level: Level, context: &Context, args: fmt::Arguments) {
+	let line = format(level, context, args, JSON.load(Ordering::Relaxed), SystemTime::now());
+	// Ignore errors, there is nowhere else to report them.
+	let _ = std::io::stderr().write_all(line.as_bytes());
+}
+
+fn format(level: Level, context: &Context, args: fmt::Arguments, json: bool, time: SystemTime) -> String {
+	let time = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+	let mut s = String::new();
+	if json {
+		let message = args.to_string();
+		let _ = write!(s, r#"{{"time":{}.{:03},"level":"{}""#, time.as_secs(), time.subsec_millis(), level.name());
+		context.session.map(|v| write!(s, r#","session":{}"#, v));
+		context.identity.map(|v| write!(s, r#","identity":"{}""#, v));
+		context.local_port.map(|v| write!(s, r#","local_port":{}"#, v));
+		context.remote.map(|v| write!(s, r#","remote":"{}""#, v));
+		s.push_str(r#","message":""#);
+		for c in message.chars() {
+			let _ = match c {
+				'"' => s.write_str("\\\""),
+				'\\' => s.write_str("\\\\"),
+				'\n' => s.write_str("\\n"),
+				c if c.is_control() => write!(s, "\\u{:04x}", u32::from(c)),
+				c => s.write_char(c),
+			};
+		}
+		s.push_str("\"}\n");
+	} else {
+		let _ = write!(s, "{}.{:03} [{}]", time.as_secs(), time.subsec_millis(), level.name().to_ascii_uppercase());
+		context.session.map(|v| write!(s, " session={}", v));
+		context.identity.map(|v| write!(s, " identity={}", v));
+		context.local_port.map(|v| write!(s, " local={}", v));
+		context.remote.map(|v| write!(s, " remote={}", v));
+		let _ = writeln!(s, " {}", args);
+	}
+	s
+}
+
+/// Log a record at the given level, optionally with a [`Context`]:
+///
+/// ```ignore
+/// log!(Level::Info, "connected to {}", address);
+/// log!(Level::Info, ctx: context, "connected to {}", address);
+/// ```
+#[macro_export]
+macro_rules! log {
+	($level:expr, ctx: $ctx:expr, $($arg:tt)*) => {{
+		let level = $level;
+		if $crate::log::enabled(level) {
+			$crate::log::write(level, &$ctx, format_args!($($arg)*));
+		}
+	}};
+	($level:expr, $($arg:tt)*) => {
+		log!($level, ctx: $crate::log::Context::NONE, $($arg)*)
+	};
+}
+
+#[macro_export]
+macro_rules! error {
+	($($arg:tt)*) => { log!($crate::log::Level::Error, $($arg)*) }
+}
+
+#[macro_export]
+macro_rules! warn {
+	($($arg:tt)*) => { log!($crate::log::Level::Warn, $($arg)*) }
+}
+
+#[macro_export]
+macro_rules! info {
+	($($arg:tt)*) => { log!($crate::log::Level::Info, $($arg)*) }
+}
+
 #[macro_export]
 macro_rules! debug {
-	($($arg:tt)*) => {{
-		eprint!("[DEBUG] ");
-		eprintln!($($arg)*);
-	}}
+	($($arg:tt)*) => { log!($crate::log::Level::Debug, $($arg)*) }
+}
+
+#[macro_export]
+macro_rules! trace {
+	($($arg:tt)*) => { log!($crate::log::Level::Trace, $($arg)*) }
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::time::Duration;
+
+	#[test]
+	fn text() {
+		let time = SystemTime::UNIX_EPOCH + Duration::from_millis(1_500);
+		let ctx = Context::session(3).flow(1234, "1.2.3.4:80".parse::<SocketAddr>().unwrap());
+		let s = format(Level::Debug, &ctx, format_args!("hello {}", 42), false, time);
+		assert_eq!(s, "1.500 [DEBUG] session=3 local=1234 remote=1.2.3.4:80 hello 42\n");
+		let s = format(Level::Warn, &Context::NONE, format_args!("hi"), false, time);
+		assert_eq!(s, "1.500 [WARN] hi\n");
+		let s = format(Level::Info, &Context::session(3).identity(intern("alice")), format_args!("hi"), false, time);
+		assert_eq!(s, "1.500 [INFO] session=3 identity=alice hi\n");
+		assert!(core::ptr::eq(intern("alice"), intern(&String::from("alice"))));
+	}
+
+	#[test]
+	fn json() {
+		let time = SystemTime::UNIX_EPOCH + Duration::from_millis(2_007);
+		let ctx = Context::session(3).flow(1234, "1.2.3.4:80".parse::<SocketAddr>().unwrap());
+		let s = format(Level::Info, &ctx, format_args!("a \"quote\"\n\\{}", '\x01'), true, time);
+		assert_eq!(
+			s,
+			r#"{"time":2.007,"level":"info","session":3,"local_port":1234,"remote":"1.2.3.4:80","message":"a \"quote\"\n\\\u0001"}"#.to_string() + "\n",
+		);
+	}
+
+	#[test]
+	fn level() {
+		assert_eq!("WARN".parse::<Level>().unwrap(), Level::Warn);
+		assert!("verbose".parse::<Level>().is_err());
+		assert!(Level::Error < Level::Trace);
+	}
 }
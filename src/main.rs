@@ -14,16 +14,28 @@ macro_rules! from_be_fn {
 
 mod checksum;
 mod client;
+mod config;
+mod control;
+mod fragment;
+mod http;
 mod icmp;
 mod ip;
+mod ping;
 mod udp;
 mod server;
+mod acl;
+mod egress;
+mod limit;
+mod upstream;
+mod resolve;
+mod socks;
 mod stupid;
 mod tcp;
 mod tun;
 mod ifreq;
 
 use std::env;
+use std::io;
 use std::net;
 
 use checksum::Checksum;
@@ -34,26 +46,47 @@ fn main() -> ! {
 
 	let _ = args.next(); // Skip name
 
-	match args.next().as_deref() {
-		Some("server") => {
-			let server = server::Server {
-				address: net::SocketAddr::new(net::IpAddr::V4(net::Ipv4Addr::LOCALHOST), 5434),
-			};
-			server.run().unwrap();
+	let mode = match config::from_args(args) {
+		Ok(mode) => mode,
+		Err(config::ConfigError::Help) => {
+			let _ = config::help(&program_name(), &mut io::stdout());
+			std::process::exit(0);
 		}
-		Some("client") => {
-			let client = client::Client::new();
-			client.run().unwrap()
+		Err(e @ config::ConfigError::Usage(_)) => {
+			eprintln!("error: {}", e);
+			eprintln!("Try '{} --help' for more information.", program_name());
+			std::process::exit(1);
+		}
+		Err(e) => {
+			eprintln!("error: {}", e);
+			std::process::exit(1);
+		}
+	};
+
+	let log = match &mode {
+		config::Mode::Server(c) => &c.log,
+		config::Mode::Client(c) => &c.log,
+	};
+	log::set_level(log.level);
+	log::set_format(log.format);
+
+	match mode {
+		config::Mode::Server(config) => {
+			let server = server::Server::new(config);
+			match server.run() {
+				Ok(()) => std::process::exit(0),
+				Err(e) => error!("{}", e),
+			}
+		}
+		config::Mode::Client(config) => {
+			let client = client::Client::new(config);
+			let Err(e) = client.run();
+			error!("{}", e);
 		}
-		_ => show_help(),
 	}
+	std::process::exit(1);
 }
 
-fn show_help() -> ! {
-	let name = env::args().next();
-	let name = name.as_deref().unwrap_or("stupid_tunnel");
-	eprintln!("Usage:");
-	eprintln!("  {} server", name);
-	eprintln!("  {} client", name);
-	std::process::exit(1);
+fn program_name() -> String {
+	env::args().next().unwrap_or_else(|| "stupid_tunnel".into())
 }
@@ -0,0 +1,147 @@
+//! Unprivileged ICMP echo sockets (`SOCK_DGRAM` with `IPPROTO_ICMP`).
+//!
+//! The kernel fills in the identifier and checksum of outgoing requests and only passes
+//! replies matching the identifier back, so no raw sockets (and hence no root) are needed.
+//! The group of the process must be in `net.ipv4.ping_group_range` though.
+
+use core::mem;
+use std::io::{Error, ErrorKind};
+use std::net::Ipv4Addr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use mio::{Registry, Token, Interest};
+use mio::unix::SourceFd;
+
+pub struct PingSocket {
+	fd: RawFd,
+}
+
+impl PingSocket {
+	const ECHO_REQUEST: u8 = 8;
+	const ECHO_REPLY: u8 = 0;
+	const HEADER_SIZE: usize = 8;
+
+	pub fn new() -> Result<Self, Error> {
+		let fd = unsafe {
+			libc::socket(
+				libc::AF_INET,
+				libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+				libc::IPPROTO_ICMP,
+			)
+		};
+		(fd >= 0).then(|| Self { fd }).ok_or_else(Error::last_os_error)
+	}
+
+	/// Send an echo request with the given sequence number and payload.
+	pub fn send_echo(&self, address: Ipv4Addr, sequence_num: u16, data: &[u8]) -> Result<(), Error> {
+		let mut out = [0; 0x10000];
+		let len = Self::HEADER_SIZE + data.len();
+		if len > out.len() {
+			return Err(ErrorKind::InvalidInput.into());
+		}
+		// Type, code, checksum & identifier are left zero for the kernel to fill in.
+		out[0] = Self::ECHO_REQUEST;
+		out[6..8].copy_from_slice(&sequence_num.to_be_bytes());
+		out[Self::HEADER_SIZE..len].copy_from_slice(data);
+
+		let addr = sockaddr(address);
+		let ret = unsafe {
+			libc::sendto(
+				self.fd,
+				out.as_ptr().cast(),
+				len,
+				0,
+				&addr as *const _ as *const _,
+				mem::size_of_val(&addr) as libc::socklen_t,
+			)
+		};
+		(ret >= 0).then(|| ()).ok_or_else(Error::last_os_error)
+	}
+
+	/// Receive an echo reply, returning the source address, sequence number and payload.
+	pub fn receive_echo<'a>(&self, buf: &'a mut [u8]) -> Result<(Ipv4Addr, u16, &'a [u8]), Error> {
+		let mut addr = sockaddr(Ipv4Addr::UNSPECIFIED);
+		let mut addr_len = mem::size_of_val(&addr) as libc::socklen_t;
+		let ret = unsafe {
+			libc::recvfrom(
+				self.fd,
+				buf.as_mut_ptr().cast(),
+				buf.len(),
+				0,
+				&mut addr as *mut _ as *mut _,
+				&mut addr_len,
+			)
+		};
+		if ret < 0 {
+			return Err(Error::last_os_error());
+		}
+		let buf = &buf[..ret as usize];
+
+		if buf.len() < Self::HEADER_SIZE || buf[0] != Self::ECHO_REPLY {
+			return Err(ErrorKind::InvalidData.into());
+		}
+		let sequence_num = u16::from_be_bytes([buf[6], buf[7]]);
+		let address = Ipv4Addr::from(addr.sin_addr.s_addr.to_ne_bytes());
+		Ok((address, sequence_num, &buf[Self::HEADER_SIZE..]))
+	}
+}
+
+impl Drop for PingSocket {
+	fn drop(&mut self) {
+		unsafe { libc::close(self.fd) };
+	}
+}
+
+impl mio::event::Source for PingSocket {
+	fn register(&mut self, registry: &Registry, token: Token, interest: Interest) -> Result<(), Error> {
+		SourceFd(&self.fd).register(registry, token, interest)
+	}
+
+	fn reregister(&mut self, registry: &Registry, token: Token, interest: Interest) -> Result<(), Error> {
+		SourceFd(&self.fd).reregister(registry, token, interest)
+	}
+
+	fn deregister(&mut self, registry: &Registry) -> Result<(), Error> {
+		SourceFd(&self.fd).deregister(registry)
+	}
+}
+
+impl AsRawFd for PingSocket {
+	fn as_raw_fd(&self) -> RawFd {
+		self.fd
+	}
+}
+
+fn sockaddr(address: Ipv4Addr) -> libc::sockaddr_in {
+	libc::sockaddr_in {
+		sin_family: libc::AF_INET as libc::sa_family_t,
+		sin_port: 0,
+		sin_addr: libc::in_addr { s_addr: u32::from_ne_bytes(address.octets()) },
+		sin_zero: [0; 8],
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::time::{Duration, Instant};
+
+	#[test]
+	fn echo() {
+		let socket = match PingSocket::new() {
+			Ok(s) => s,
+			// The group isn't in net.ipv4.ping_group_range.
+			Err(e) if e.kind() == ErrorKind::PermissionDenied => return,
+			Err(e) => panic!("{}", e),
+		};
+		socket.send_echo(Ipv4Addr::LOCALHOST, 7, b"ping").unwrap();
+		let mut buf = [0; 64];
+		let deadline = Instant::now() + Duration::from_secs(5);
+		loop {
+			match socket.receive_echo(&mut buf) {
+				Ok(reply) => break assert_eq!(reply, (Ipv4Addr::LOCALHOST, 7, &b"ping"[..])),
+				Err(e) if e.kind() == ErrorKind::WouldBlock && Instant::now() < deadline => std::thread::sleep(Duration::from_millis(10)),
+				Err(e) => panic!("{}", e),
+			}
+		}
+	}
+}
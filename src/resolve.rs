@@ -0,0 +1,90 @@
+//! Resolves names on worker threads, so the event loop never waits for DNS.
+
+use std::io::Error;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use mio::{Registry, Token, Waker};
+
+/// Hands names to the workers. Answers carry the key the name was given with and are taken
+/// from [`Answers`] once its token is ready.
+pub struct Resolver<K> {
+	requests: mpsc::Sender<(K, String, u16)>,
+}
+
+pub struct Answers<K> {
+	answers: mpsc::Receiver<(K, Result<Vec<SocketAddr>, Error>)>,
+}
+
+/// How many names are resolved at once.
+const WORKERS: usize = 4;
+
+/// Start the workers, which make `token` ready whenever an answer arrives. There may only be
+/// one per [`mio::Poll`].
+pub fn start<K: Send + 'static>(registry: &Registry, token: Token) -> Result<(Resolver<K>, Answers<K>), Error> {
+	let waker = Arc::new(Waker::new(registry, token)?);
+	let (requests, queue) = mpsc::channel::<(K, String, u16)>();
+	let (done, answers) = mpsc::channel();
+	let queue = Arc::new(Mutex::new(queue));
+	for _ in 0..WORKERS {
+		let (queue, done, waker) = (queue.clone(), done.clone(), waker.clone());
+		thread::Builder::new().name("resolver".into()).spawn(move || loop {
+			// Ends once the resolver is dropped.
+			let (key, name, port) = match queue.lock().unwrap().recv() {
+				Ok(r) => r,
+				Err(_) => return,
+			};
+			let result = (name.as_str(), port).to_socket_addrs().map(Iterator::collect);
+			if done.send((key, result)).is_err() {
+				return;
+			}
+			let _ = waker.wake();
+		})?;
+	}
+	Ok((Resolver { requests }, Answers { answers }))
+}
+
+impl<K> Resolver<K> {
+	/// Start resolving `name`, with `port` as the port of the addresses.
+	pub fn resolve(&self, key: K, name: &str, port: u16) {
+		// The workers only stop once the resolver is dropped.
+		let _ = self.requests.send((key, name.into(), port));
+	}
+}
+
+impl<K> Clone for Resolver<K> {
+	fn clone(&self) -> Self {
+		Self { requests: self.requests.clone() }
+	}
+}
+
+impl<K> Answers<K> {
+	/// The answers that arrived so far, with the key each name was given with.
+	pub fn take(&self) -> impl Iterator<Item = (K, Result<Vec<SocketAddr>, Error>)> + '_ {
+		self.answers.try_iter()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::time::Duration;
+
+	#[test]
+	fn resolve() {
+		let mut poll = mio::Poll::new().unwrap();
+		let (resolver, answers) = start(poll.registry(), Token(1)).unwrap();
+		resolver.resolve(7, "127.0.0.1", 80);
+		resolver.resolve(8, "not a name", 80);
+		let mut events = mio::Events::with_capacity(4);
+		let mut got = Vec::new();
+		while got.len() < 2 {
+			poll.poll(&mut events, Some(Duration::from_secs(10))).unwrap();
+			assert!(events.iter().any(|e| e.token() == Token(1)));
+			got.extend(answers.take());
+		}
+		got.sort_by_key(|(k, _)| *k);
+		assert_eq!(got[0].1.as_ref().unwrap(), &[SocketAddr::from(([127, 0, 0, 1], 80))]);
+		assert!(got[1].1.is_err());
+	}
+}
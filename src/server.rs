@@ -1,173 +1,2427 @@
 use crate::*;
-use crate::stupid::{StupidDataHeader, StupidType};
+use crate::acl::{self, Acl};
+use crate::config::Identity;
+use crate::control::{self, Control, Counters, Line};
+use crate::egress::{Egress, Route};
+use crate::limit::TokenBucket;
+use crate::log::Context;
+use crate::ping::PingSocket;
+use crate::resolve::{self, Resolver};
+use crate::socks::{self, Command};
+use crate::stupid::{Compression, ConnectError, DatagramLink, Dead, Decompressor, Frame, FrameReader, FrameWriter, Keepalive, Pipes, Protocol, ReceiveError, Scheduler, SessionToken, StreamTransport, StupidDataHeader, StupidType, WebSocket, Window, WindowError, ACCEPTED_PORTS, constant_time_eq, split_name_datagram};
+use crate::upstream::{self, Handshake, Proxy};
+use core::fmt;
+use core::mem;
+use std::cell::{Cell, RefCell};
 use std::collections::hash_map::{HashMap, Entry};
-use std::io::{Error, Read, Write};
-use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
-use std::time::Instant;
-use mio::net::{UdpSocket, TcpListener, TcpStream};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, Ipv4Addr};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use mio::net::{UdpSocket, TcpListener, TcpStream, UnixListener};
+use mio::Registry;
+
+/// Tokens consist of the session ID, the kind of event and the local port of the flow.
+const SESSION_SHIFT: u32 = 32;
+const EVENT_MASK: usize = 0xff_0000;
+const PORT_MASK: usize = 0xffff;
+
+const CLIENT_EVENT: usize = 0x00_0000;
+const UDP_EVENT: usize = 0x10_0000;
+const TCP_EVENT: usize = 0x20_0000;
+const ICMP_EVENT: usize = 0x30_0000;
+const LISTEN_EVENT: usize = 0x40_0000;
+const LISTEN_UDP_EVENT: usize = 0x50_0000;
+/// The port is the number of the remote forward instead.
+const FORWARD_EVENT: usize = 0x60_0000;
+/// The connection to the upstream proxy a UDP socket is associated over.
+const ASSOCIATION_EVENT: usize = 0x70_0000;
+/// The port is the number of the connection to the control socket, or 0 for the socket itself.
+const CONTROL_EVENT: usize = 0x80_0000;
+/// Names of flows were resolved.
+const RESOLVE_EVENT: usize = 0x90_0000;
+
+fn token(session: u32, kind: usize, port: u16) -> mio::Token {
+	mio::Token((session as usize) << SESSION_SHIFT | kind | usize::from(port))
+}
+
+/// Set on SIGHUP to reload the identities. The signal interrupts waiting for events.
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_reload(_: libc::c_int) {
+	RELOAD.store(true, Ordering::Relaxed);
+}
 
 pub struct Server {
-	pub address: net::SocketAddr,
+	config: config::ServerConfig,
+	sessions: HashMap<u32, Session>,
+	next_session: u32,
+	/// The sessions of clients connected over UDP, by address and connection ID.
+	peers: HashMap<(SocketAddr, u32), u32>,
+	/// Resolves the names of flows for all sessions, once the server runs.
+	resolver: Option<Resolver<(u32, u64)>>,
+	/// What the sessions of each identity use of its limits, while it has any.
+	usage: HashMap<String, Weak<RefCell<Usage>>>,
 }
 
 impl Server {
-	pub fn run(self) -> Result<!, RunError> {
+	pub fn new(config: config::ServerConfig) -> Self {
+		Self { config, sessions: HashMap::new(), next_session: 0, peers: HashMap::new(), resolver: None, usage: HashMap::new() }
+	}
 
-		debug!("Starting server");
-		let mut server = TcpListener::bind(self.address).map_err(RunError::Bind)?;
+	/// Run the server. This only returns if serving a single client on stdin & stdout, once
+	/// that client is gone.
+	pub fn run(mut self) -> Result<(), RunError> {
+		let mut poll = mio::Poll::new().map_err(RunError::Poll)?;
+		let (resolver, answers) = resolve::start(poll.registry(), mio::Token(RESOLVE_EVENT)).map_err(RunError::Poll)?;
+		self.resolver = Some(resolver);
+
+		let mut listeners = Vec::new();
+		if !self.config.stdio {
+			listeners.push(Listener::Tcp(TcpListener::bind(self.config.listen).map_err(RunError::Bind)?));
+			info!("listening on {}", self.config.listen);
+		}
+		if let Some(address) = self.config.listen_ws {
+			listeners.push(Listener::WebSocket(TcpListener::bind(address).map_err(RunError::Bind)?));
+			info!("listening on {} over WebSocket", address);
+		}
+		if let Some(path) = &self.config.listen_unix {
+			listeners.push(Listener::Unix(control::bind_unix(path).map_err(RunError::Bind)?, path.clone()));
+			info!("listening on Unix socket {}", path.display());
+		}
+		let mut control = match &self.config.control_socket {
+			Some(path) => {
+				let control = Control::bind(path, poll.registry(), CONTROL_EVENT).map_err(RunError::Bind)?;
+				info!("accepting commands on control socket {}", path.display());
+				Some(control)
+			}
+			None => None,
+		};
+		for (i, l) in listeners.iter_mut().enumerate() {
+			l.register(poll.registry(), token(0, LISTEN_EVENT, i.try_into().unwrap()))
+				.map_err(RunError::Poll)?;
+		}
+		let udp = match self.config.listen_udp {
+			Some(address) => {
+				let mut socket = UdpSocket::bind(address).map_err(RunError::Bind)?;
+				info!("listening on {} over UDP", address);
+				poll.registry()
+					.register(&mut socket, mio::Token(LISTEN_UDP_EVENT), mio::Interest::READABLE)
+					.map_err(RunError::Poll)?;
+				Some(Rc::new(socket))
+			}
+			None => None,
+		};
+		if let Some(path) = &self.config.identities_file {
+			info!("loaded {} identities from {}", self.config.identities.len(), path.display());
+			// SAFETY: the handler only stores to an atomic.
+			unsafe { libc::signal(libc::SIGHUP, request_reload as extern "C" fn(libc::c_int) as libc::sighandler_t) };
+		}
+		if self.config.stdio {
+			let stdio = Pipes::stdio().map_err(RunError::Stdio)?;
+			let id = self.add_client(Box::new(stdio), poll.registry(), Instant::now()).map_err(RunError::Poll)?;
+			info!(ctx: Context::session(id.into()), "serving client on stdin & stdout");
+		}
+		let mut events = mio::Events::with_capacity(1024);
 
-		let mut poll = mio::Poll::new().unwrap();
-		let reg = poll.registry();
-		reg.register(&mut server, mio::Token(0), mio::Interest::READABLE).unwrap();
-		let mut events = mio::Events::with_capacity(1);
-		
 		loop {
-			debug!("Waiting for client");
-			poll.poll(&mut events, None).unwrap();
+			trace!("sessions: {}", self.sessions.len());
+
+			let timeout = self.next_timeout(Instant::now());
+			match poll.poll(&mut events, timeout) {
+				// e.g. after being suspended and resumed.
+				Err(e) if e.kind() == ErrorKind::Interrupted => (),
+				r => r.map_err(RunError::Poll)?,
+			}
+			let now = Instant::now(); // Inie tinie bit more efficient;
+
+			if RELOAD.swap(false, Ordering::Relaxed) {
+				self.reload_identities(now);
+			}
 
 			for e in &events {
-				if e.token() == mio::Token(0) {
-					let (stream, _) = server.accept().map_err(RunError::Accept)?;
-					debug!("Accepted client");
-					let _ = self.handle_client(stream);
+				let t = e.token().0;
+				let (id, ty, port) = ((t >> SESSION_SHIFT) as u32, t & EVENT_MASK, (t & PORT_MASK) as u16);
+				match ty {
+					LISTEN_EVENT => self.accept(&listeners[usize::from(port)], poll.registry(), now),
+					LISTEN_UDP_EVENT => self.receive_datagrams(udp.as_ref().unwrap(), poll.registry(), now),
+					CLIENT_EVENT => self.handle_client(poll.registry(), id, now),
+					CONTROL_EVENT => control.as_mut().unwrap().handle_event(poll.registry(), e.token(), |c, out| self.control(c, out, now)),
+					RESOLVE_EVENT => {
+						for ((id, lookup), result) in answers.take() {
+							self.resolved(poll.registry(), id, lookup, result, now);
+						}
+					}
+					_ => self.handle_flow(poll.registry(), id, ty, port, now),
 				}
 			}
-		}
-	}
 
-	fn handle_client(&self, mut client: TcpStream) -> Result<!, Error> {
+			self.expire(poll.registry(), now);
 
-		const CLIENT_EVENT: usize = 0x00_0000;
-		const UDP_EVENT: usize = 0x10_0000;
-		const TCP_EVENT: usize = 0x20_0000;
-		const EVENT_MASK: usize = !0xffff;
+			// Nobody else can connect to resume the session.
+			if self.config.stdio && self.sessions.values().all(|s| s.client.is_none()) {
+				info!("client on stdin & stdout is gone");
+				return Ok(());
+			}
+		}
+	}
 
-		let mut poll = mio::Poll::new().unwrap();
-		let reg = poll.registry();
-		reg.register(&mut client, mio::Token(CLIENT_EVENT), mio::Interest::READABLE).unwrap();
+	fn accept(&mut self, listener: &Listener, registry: &Registry, now: Instant) {
+		loop {
+			let (stream, peer) = match listener.accept() {
+				Ok(r) => r,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+				Err(e) => {
+					warn!("failed to accept client: {}", e);
+					return;
+				}
+			};
+			match self.add_client(stream, registry, now) {
+				Ok(id) => info!(ctx: Context::session(id.into()), "accepted client {}", peer),
+				Err(e) => warn!("failed to register client: {}", e),
+			}
+		}
+	}
 
-		let mut events = mio::Events::with_capacity(1024);
+	/// Start a session for a client connected with a stream transport.
+	fn add_client(&mut self, mut stream: Box<dyn StreamTransport>, registry: &Registry, now: Instant) -> Result<u32, Error> {
+		let id = self.next_id();
+		registry.register(&mut stream, token(id, CLIENT_EVENT, 0), mio::Interest::READABLE | mio::Interest::WRITABLE)?;
+		let client = Connection::Stream(stream, FrameReader::new(), FrameWriter::new());
+		self.sessions.insert(id, Session::new(id, client, &self.config, self.resolver.clone().unwrap(), now));
+		Ok(id)
+	}
 
+	/// Handle all datagrams of clients connected over UDP. A client is accepted when the first
+	/// datagram of a connection arrives.
+	fn receive_datagrams(&mut self, socket: &Rc<UdpSocket>, registry: &Registry, now: Instant) {
 		let mut buf = [0; 0x10000];
-		let mut out = [0; 0x10000];
+		loop {
+			let (len, address) = match socket.recv_from(&mut buf) {
+				Ok(r) => r,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+				Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+				Err(e) => {
+					warn!("failed to receive datagram: {}", e);
+					return;
+				}
+			};
+			let datagram = &buf[..len];
+			let connection = match DatagramLink::connection_id(datagram) {
+				Some(c) => c,
+				None => continue,
+			};
+			let id = match self.peers.get(&(address, connection)) {
+				Some(id) => *id,
+				// The first frame must be a valid hello, so nothing is kept for clients that
+				// don't authenticate.
+				None => match DatagramLink::initial_frame(datagram).map(|f| check_hello(&self.config, f)) {
+					Some(Ok(())) => {
+						let id = self.next_id();
+						info!(ctx: Context::session(id.into()), "accepted client {} over UDP", address);
+						let client = Connection::Datagram(socket.clone(), address, DatagramLink::new(connection, now));
+						self.sessions.insert(id, Session::new(id, client, &self.config, self.resolver.clone().unwrap(), now));
+						self.peers.insert((address, connection), id);
+						id
+					}
+					Some(Err(e)) => {
+						debug!("ignoring datagram of {}: {}", address, e);
+						continue;
+					}
+					None => continue,
+				},
+			};
 
-		let mut udp_socks = HashMap::<u16, (UdpSocket, Instant)>::new();
-		let mut tcp_socks = HashMap::<u16, (TcpStream, Instant)>::new();
+			if let Some(Connection::Datagram(_, _, link)) = &mut self.sessions.get_mut(&id).unwrap().client {
+				link.receive(datagram, now);
+			}
+			self.handle_client(registry, id, now);
+		}
+	}
 
+	fn handle_client(&mut self, registry: &Registry, mut id: u32, now: Instant) {
 		loop {
+			let session = match self.sessions.get_mut(&id) {
+				Some(s) => s,
+				None => return,
+			};
+			let hello = match session.handle_client(&self.config, registry, now) {
+				Ok(Some(h)) => h,
+				Ok(None) => return,
+				Err(e) => return self.disconnect(id, e, now),
+			};
 
-			debug!("TCP sockets: {}", tcp_socks.len());
-			debug!("UDP sockets: {}", udp_socks.len());
-			poll.poll(&mut events, None).unwrap();
-			let now = Instant::now(); // Inie tinie bit more efficient;
+			// Don't let an all zero token match a session that hasn't been welcomed yet.
+			let resume = (hello.token != SessionToken::default())
+				.then(|| self.sessions.iter().find(|(i, s)| {
+					**i != id
+						&& s.token.map_or(false, |t| constant_time_eq(&t, &hello.token))
+						&& s.identity.as_ref().map(|i| &i.name) == hello.identity.as_ref().map(|i| &i.name)
+				}))
+				.flatten()
+				.map(|(i, _)| *i);
 
-			for e in &events {
-				let (ty, local_port) = (e.token().0 & EVENT_MASK, e.token().0 & !EVENT_MASK);
-				match ty {
-					CLIENT_EVENT => {
-						let len = client.read(&mut buf)?;
-						if len == 0 {
-							return Err(Error::new(std::io::ErrorKind::NotConnected, ""));
-						}
+			let result = match resume {
+				Some(target) => {
+					let mut client = self.sessions.remove(&id).and_then(|s| s.client).unwrap();
+					if let Err(e) = client.register(registry, token(target, CLIENT_EVENT, 0)) {
+						warn!(ctx: Context::session(id.into()), "failed to register client: {}", e);
+						return;
+					}
+					id = target;
+					let session = self.sessions.get_mut(&id).unwrap();
+					if let Some(peer) = session.client.as_ref().and_then(Connection::peer) {
+						self.peers.remove(&peer);
+					}
+					if let Some(peer) = client.peer() {
+						self.peers.insert(peer, id);
+					}
+					info!(ctx: session.ctx, "client resumed session");
+					session.attach(registry, client, hello.compression, now)
+				}
+				None => {
+					let usage = self.usage(hello.identity.as_ref(), now);
+					let session = self.sessions.get_mut(&id).unwrap();
+					session.set_identity(hello.identity, usage, &self.config, now);
+					info!(ctx: session.ctx, "authenticated client");
+					session.token = Some(random_token());
+					session.keepalive.reset(now);
+					session.welcome(hello.compression)
+				}
+			};
+			if let Err(e) = result {
+				return self.disconnect(id, e, now);
+			}
+		}
+	}
 
-						let (sh, data, _) = stupid::StupidDataHeader::from_raw(&buf[..len]).unwrap();
-
-						match sh.ty() {
-							Ok(stupid::StupidType::UDP) => {
-								match udp_socks.entry(sh.local()) {
-									Entry::Occupied(mut e) => {
-										let (udp, last_used) = e.get_mut();
-										udp.send(data).unwrap();
-										*last_used = now;
-									}
-									Entry::Vacant(e) => {
-										let addr = SocketAddrV4::new([0; 4].into(), 0);
-										let mut udp = UdpSocket::bind(addr.into()).unwrap();
-										udp.connect(sh.remote().into()).unwrap();
-										udp.send(data).unwrap();
-										let token = mio::Token(UDP_EVENT | usize::from(sh.local()));
-										poll.registry()
-											.register(&mut udp, token, mio::Interest::READABLE)
-											.unwrap();
-										e.insert((udp, now));
-									}
-								}
-							}
-							Ok(stupid::StupidType::TcpConnect) => {
-								debug!("connecting TCP {} -> {}", sh.local(), sh.remote());
-								let mut tcp = TcpStream::connect(sh.remote().into()).unwrap();
-								tcp.write(data).unwrap();
-								let token = mio::Token(TCP_EVENT | usize::from(sh.local()));
-								poll.registry()
-									.register(&mut tcp, token, mio::Interest::READABLE)
-									.unwrap();
-								tcp_socks.insert(sh.local(), (tcp, now));
-							}
-							Ok(stupid::StupidType::TCP) => {
-								debug!("closing TCP {} -> {}", sh.local(), sh.remote());
-								let (tcp, last_used) = tcp_socks.get_mut(&sh.local()).unwrap();
-								tcp.write(data).unwrap();
-								*last_used = now;
-							}
-							Ok(stupid::StupidType::TcpFinish) => {
-								debug!("closed TCP {} -> {}", sh.local(), sh.remote());
-								tcp_socks.remove(&sh.local());
-							}
-							Err(_) => todo!(),
-						}
+	fn handle_flow(&mut self, registry: &Registry, id: u32, ty: usize, port: u16, now: Instant) {
+		let session = match self.sessions.get_mut(&id) {
+			Some(s) if s.client.is_some() => s,
+			// Any data is handled once the client reconnects.
+			_ => return,
+		};
+		let result = match ty {
+			UDP_EVENT => session.handle_udp(port, now),
+			TCP_EVENT => session.handle_tcp(registry, port, now),
+			ICMP_EVENT => session.handle_icmp(port, now),
+			FORWARD_EVENT => session.handle_forward(registry, port, now),
+			ASSOCIATION_EVENT => session.handle_association(port),
+			_ => unreachable!(),
+		};
+		if let Err(e) = result {
+			self.disconnect(id, e, now);
+		}
+	}
+
+	/// Open the flow a name was resolved for, if its session is still there.
+	fn resolved(&mut self, registry: &Registry, id: u32, lookup: u64, result: Result<Vec<SocketAddr>, Error>, now: Instant) {
+		let result = match self.sessions.get_mut(&id) {
+			Some(session) => session.resolved(registry, lookup, result, now),
+			None => return,
+		};
+		if let Err(e) = result {
+			self.disconnect(id, e, now);
+		}
+	}
+
+	/// Handle the loss of the connection to a client. The session is kept for a while if the
+	/// client authenticated.
+	fn disconnect(&mut self, id: u32, error: SessionError, now: Instant) {
+		let session = self.sessions.get_mut(&id).unwrap();
+		if let Some(peer) = session.client.as_ref().and_then(Connection::peer) {
+			self.peers.remove(&peer);
+		}
+		if session.token.is_none() {
+			info!(ctx: session.ctx, "session ended: {}", error);
+			self.sessions.remove(&id);
+		} else {
+			info!(ctx: session.ctx, "client disconnected: {}", error);
+			session.detach();
+			session.since = now;
+			session.disconnects += 1;
+			session.last_error = Some(error.to_string());
+		}
+	}
+
+	/// The time until the next session or idle socket expires or a ping is due, if any.
+	fn next_timeout(&self, now: Instant) -> Option<Duration> {
+		self.sessions
+			.values()
+			.map(|s| match (&s.client, s.token) {
+				(Some(_), Some(_)) => s.next_timeout(&self.config, now),
+				_ => (s.since + self.config.session_timeout).saturating_duration_since(now),
+			})
+			.min()
+	}
+
+	/// Remove sessions that have been disconnected or unauthenticated for too long, close
+	/// idle sockets and resume flows waiting for bandwidth.
+	fn expire(&mut self, registry: &Registry, now: Instant) {
+		let timeout = self.config.session_timeout;
+		let peers = &mut self.peers;
+		self.sessions.retain(|_, s| {
+			let keep = (s.client.is_some() && s.token.is_some()) || now.saturating_duration_since(s.since) < timeout;
+			if !keep {
+				info!(ctx: s.ctx, "session expired");
+				if let Some(peer) = s.client.as_ref().and_then(Connection::peer) {
+					peers.remove(&peer);
+				}
+			}
+			keep
+		});
+		let mut errors = Vec::new();
+		for (id, s) in self.sessions.iter_mut().filter(|(_, s)| s.client.is_some()) {
+			if let Err(e) = s.expire(&self.config, registry, now) {
+				errors.push((*id, e));
+			}
+		}
+		for (id, e) in errors {
+			self.disconnect(id, e, now);
+		}
+	}
+
+	/// Load the identities again, ending the sessions of identities that were removed or had
+	/// their key changed and applying the new policies to the others.
+	fn reload_identities(&mut self, now: Instant) {
+		let path = match &self.config.identities_file {
+			Some(p) => p,
+			None => return,
+		};
+		match config::load_identities(path) {
+			Ok(identities) => self.config.identities = identities,
+			Err(e) => {
+				warn!("failed to reload identities: {}", e);
+				return;
+			}
+		}
+		info!("reloaded {} identities from {}", self.config.identities.len(), path.display());
+		let (config, peers) = (&self.config, &mut self.peers);
+		self.sessions.retain(|_, s| {
+			let current = match &s.identity {
+				Some(i) => i,
+				None => return true,
+			};
+			match config.identities.iter().find(|i| i.name == current.name && i.key == current.key) {
+				Some(i) => {
+					s.set_identity(Some(i.clone()), s.usage.clone(), config, now);
+					true
+				}
+				None => {
+					info!(ctx: s.ctx, "identity revoked, ending session");
+					if let Some(peer) = s.client.as_ref().and_then(Connection::peer) {
+						peers.remove(&peer);
 					}
-					UDP_EVENT => {
-						let (udp, last_used) = udp_socks.get_mut(&local_port.try_into().unwrap()).unwrap();
-						let (len, addr) = udp.recv_from(&mut buf).unwrap();
-						let addr = match addr {
-							SocketAddr::V4(a) => a,
-							_ => unreachable!(),
-						};
-
-						let data = &buf[..len];
-						let h = StupidDataHeader::new(StupidType::UDP, addr, local_port.try_into().unwrap(), len.try_into().unwrap());
-
-						out[..h.byte_len()].copy_from_slice(h.as_ref());
-						out[h.byte_len()..][..data.len()].copy_from_slice(data);
-						let out = &out[..h.byte_len() + data.len()];
-						client.write_all(out).unwrap();
-
-						*last_used = now;
-					}
-					TCP_EVENT => {
-						let (tcp, last_used) = tcp_socks.get_mut(&local_port.try_into().unwrap()).unwrap();
-						let addr = tcp.peer_addr().unwrap();
-						let addr = match addr {
-							SocketAddr::V4(a) => a,
-							_ => unreachable!(),
-						};
-
-						let len = tcp.read(&mut buf).unwrap();
-						if len > 0 {
-							let data = &buf[..len];
-							let h = StupidDataHeader::new(StupidType::TCP, addr, local_port.try_into().unwrap(), len.try_into().unwrap());
-
-							out[..h.byte_len()].copy_from_slice(h.as_ref());
-							out[h.byte_len()..][..data.len()].copy_from_slice(data);
-							let out = &out[..h.byte_len() + data.len()];
-							client.write_all(out).unwrap();
-
-							*last_used = now;
-						} else {
-							debug!("closing TCP connection {} -> {}", local_port, addr);
-							let h = StupidDataHeader::new(StupidType::TcpFinish, addr, local_port.try_into().unwrap(), len.try_into().unwrap());
-							client.write_all(h.as_ref()).unwrap();
-						}
+					false
+				}
+			}
+		});
+	}
+
+	/// Run a command received on the control socket.
+	fn control(&mut self, command: control::Command, out: &mut String, now: Instant) -> Result<(), String> {
+		match command {
+			control::Command::Stats => {
+				let mut ids = self.sessions.keys().copied().collect::<Vec<_>>();
+				ids.sort_unstable();
+				for id in ids {
+					self.sessions[&id].report(now, out);
+				}
+				Ok(())
+			}
+			control::Command::Kill { session, protocol, port } => {
+				let id = session.ok_or("the session of the flow is required")?;
+				let session = self.sessions.get_mut(&id).ok_or_else(|| format!("no session {}", id))?;
+				match session.kill(protocol, port) {
+					Ok(true) => Ok(()),
+					Ok(false) => Err(format!("no {} flow {} in session {}", protocol, port, id)),
+					Err(e) => {
+						self.disconnect(id, e, now);
+						Ok(())
 					}
-					_ => unreachable!(),
 				}
 			}
+			control::Command::Drop(id) => {
+				let session = self.sessions.remove(&id).ok_or_else(|| format!("no session {}", id))?;
+				info!(ctx: session.ctx, "session dropped on request");
+				if let Some(peer) = session.client.as_ref().and_then(Connection::peer) {
+					self.peers.remove(&peer);
+				}
+				Ok(())
+			}
+			// Handled by the control socket itself.
+			control::Command::LogLevel(_) | control::Command::Help => unreachable!(),
+		}
+	}
+
+	/// The usage shared by the sessions of an identity, or a new one for a session without an
+	/// identity.
+	fn usage(&mut self, identity: Option<&Identity>, now: Instant) -> Rc<RefCell<Usage>> {
+		let identity = match identity {
+			Some(i) => i,
+			None => return Rc::new(RefCell::new(Usage::new(&self.config.limits, now))),
+		};
+		if let Some(usage) = self.usage.get(&identity.name).and_then(Weak::upgrade) {
+			return usage;
 		}
+		self.usage.retain(|_, u| u.strong_count() > 0);
+		let usage = Rc::new(RefCell::new(Usage::new(&identity.limits.or(&self.config.limits), now)));
+		self.usage.insert(identity.name.clone(), Rc::downgrade(&usage));
+		usage
+	}
+
+	fn next_id(&mut self) -> u32 {
+		while self.sessions.contains_key(&self.next_session) {
+			self.next_session = self.next_session.wrapping_add(1);
+		}
+		let id = self.next_session;
+		self.next_session = id.wrapping_add(1);
+		id
 	}
 }
 
-#[derive(Debug)]
-pub enum RunError {
-	Bind(Error),
-	Accept(Error),
+/// A socket clients connect to with a stream transport.
+enum Listener {
+	Tcp(TcpListener),
+	WebSocket(TcpListener),
+	Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+	/// Accept a client, returning its connection and a description of where it came from.
+	fn accept(&self) -> Result<(Box<dyn StreamTransport>, String), Error> {
+		Ok(match self {
+			Self::Tcp(l) => l.accept().map(|(s, a)| (Box::new(s) as _, a.to_string()))?,
+			Self::WebSocket(l) => l.accept().map(|(s, a)| (Box::new(WebSocket::server(s)) as _, format!("{} over WebSocket", a)))?,
+			Self::Unix(l, path) => l.accept().map(|(s, _)| (Box::new(s) as _, format!("on Unix socket {}", path.display())))?,
+		})
+	}
+
+	fn register(&mut self, registry: &Registry, token: mio::Token) -> Result<(), Error> {
+		match self {
+			Self::Tcp(l) | Self::WebSocket(l) => registry.register(l, token, mio::Interest::READABLE),
+			Self::Unix(l, _) => registry.register(l, token, mio::Interest::READABLE),
+		}
+	}
+}
+
+/// The connection to a client.
+enum Connection {
+	Stream(Box<dyn StreamTransport>, FrameReader, FrameWriter),
+	/// Datagrams sent from the given address over the shared UDP socket.
+	Datagram(Rc<UdpSocket>, SocketAddr, DatagramLink),
+}
+
+impl Connection {
+	/// Get the next frame of the client, if a complete one is available.
+	fn receive<'a>(&mut self, buf: &'a mut [u8], now: Instant) -> Result<Option<(StupidDataHeader, &'a [u8])>, ReceiveError> {
+		match self {
+			Self::Stream(stream, reader, _) => reader.read(stream, buf),
+			Self::Datagram(socket, address, link) => match link.read(buf) {
+				Some(f) => Ok(Some(f)),
+				None => {
+					// Acknowledge everything that was received.
+					link.poll(now);
+					link.transmit(|d| socket.send_to(d, *address)).map_err(ReceiveError::Io)?;
+					Ok(None)
+				}
+			},
+		}
+	}
+
+	/// Send queued frames until the connection would block.
+	fn flush(&mut self, queue: &mut Scheduler) -> Result<(), Error> {
+		match self {
+			Self::Stream(stream, _, writer) => writer.write(stream, queue),
+			Self::Datagram(socket, address, link) => {
+				while link.has_capacity() {
+					match queue.pop() {
+						Some(f) => link.send(&f.data, f.reliable, Instant::now())?,
+						None => break,
+					}
+				}
+				link.transmit(|d| socket.send_to(d, *address))
+			}
+		}
+	}
+
+	/// Retransmit lost frames, if any.
+	fn poll(&mut self, now: Instant) -> Result<(), Error> {
+		match self {
+			Self::Stream(..) => Ok(()),
+			Self::Datagram(socket, address, link) => {
+				link.poll(now);
+				link.transmit(|d| socket.send_to(d, *address))
+			}
+		}
+	}
+
+	fn next_timeout(&self, now: Instant) -> Option<Duration> {
+		match self {
+			Self::Stream(..) => None,
+			Self::Datagram(_, _, link) => link.next_timeout(now),
+		}
+	}
+
+	/// The maximum amount of data to send in a single TCP frame.
+	fn max_stream_data(&self) -> usize {
+		match self {
+			Self::Stream(..) => StupidDataHeader::MAX_DATA_LENGTH,
+			Self::Datagram(..) => DatagramLink::MAX_STREAM_DATA,
+		}
+	}
+
+	/// How many frames were sent again, if the client is connected over UDP.
+	fn retransmissions(&self) -> Option<u64> {
+		match self {
+			Self::Stream(..) => None,
+			Self::Datagram(_, _, link) => Some(link.retransmissions()),
+		}
+	}
+
+	/// Take the frame that was only partly written, if any.
+	fn take_unsent(&mut self) -> Option<Frame> {
+		match self {
+			Self::Stream(_, _, writer) => writer.take_unsent(),
+			Self::Datagram(..) => None,
+		}
+	}
+
+	/// The address and connection ID datagrams of the client are routed by.
+	fn peer(&self) -> Option<(SocketAddr, u32)> {
+		match self {
+			Self::Stream(..) => None,
+			Self::Datagram(_, address, link) => Some((*address, link.id())),
+		}
+	}
+
+	fn register(&mut self, registry: &Registry, token: mio::Token) -> Result<(), Error> {
+		match self {
+			Self::Stream(stream, ..) => registry.reregister(stream, token, mio::Interest::READABLE | mio::Interest::WRITABLE),
+			// The socket is shared by all clients.
+			Self::Datagram(..) => Ok(()),
+		}
+	}
+}
+
+/// A socket for a single flow of a client.
+struct Flow<S> {
+	socket: S,
+	/// The address of the other end of the flow.
+	remote: SocketAddrV4,
+	opened: Instant,
+	last_used: Instant,
+	counters: Counters,
+	/// Limits the data relayed for the flow, if its rate is limited.
+	bandwidth: Option<TokenBucket>,
+	/// Whether relaying stopped until the session or flow has bandwidth again.
+	throttled: bool,
+	/// When the client was last told that datagrams of the flow were dropped for lack of
+	/// bandwidth.
+	limit_reported: Option<Instant>,
+	/// Counts the flow as open against the limits of the identity.
+	_slot: Slot,
+}
+
+/// What the sessions of an identity use of its limits. All of them share it, so a client can't
+/// raise the limits by opening more sessions. Sessions without an identity each have their own.
+struct Usage {
+	limits: config::LimitConfig,
+	/// Limits the data relayed, if its rate is limited.
+	bandwidth: Option<TokenBucket>,
+	/// Limits how fast new flows are opened, if their rate is limited.
+	new_flows: Option<TokenBucket>,
+	/// The TCP connections open.
+	tcp: Rc<Cell<usize>>,
+	/// The UDP & ICMP sockets and UDP peers of remote forwards open.
+	datagram: Rc<Cell<usize>>,
+}
+
+impl Usage {
+	fn new(limits: &config::LimitConfig, now: Instant) -> Self {
+		Self {
+			limits: limits.clone(),
+			bandwidth: limits.rate.map(|r| TokenBucket::new(r, now)),
+			new_flows: limits.connection_rate.map(|r| TokenBucket::new(r, now)),
+			tcp: Rc::default(),
+			datagram: Rc::default(),
+		}
+	}
+
+	/// Apply new limits, starting over with the buckets whose rate changed.
+	fn set_limits(&mut self, limits: config::LimitConfig, now: Instant) {
+		if limits.rate != self.limits.rate {
+			self.bandwidth = limits.rate.map(|r| TokenBucket::new(r, now));
+		}
+		if limits.connection_rate != self.limits.connection_rate {
+			self.new_flows = limits.connection_rate.map(|r| TokenBucket::new(r, now));
+		}
+		self.limits = limits;
+	}
+
+	/// The count of open flows `quota` limits, and the most allowed if limited.
+	fn open(&self, quota: Quota) -> (&Rc<Cell<usize>>, Option<usize>) {
+		match quota {
+			Quota::Tcp => (&self.tcp, self.limits.tcp),
+			Quota::Udp => (&self.datagram, self.limits.udp),
+			Quota::Rate | Quota::Lookups => unreachable!(),
+		}
+	}
+
+	/// Whether no more flows `quota` limits may be opened.
+	fn full(&self, quota: Quota) -> bool {
+		let (open, max) = self.open(quota);
+		max.is_some_and(|max| open.get() >= max)
+	}
+
+	/// A new flow counted against `quota`, with the bandwidth limit of a single flow.
+	fn flow<S>(&self, socket: S, remote: SocketAddrV4, quota: Quota, now: Instant) -> Flow<S> {
+		let mut flow = Flow::new(socket, remote, Slot::new(self.open(quota).0), now);
+		flow.bandwidth = self.limits.flow_rate.map(|r| TokenBucket::new(r, now));
+		flow
+	}
+}
+
+/// Counts a flow as open for as long as it exists.
+struct Slot(Rc<Cell<usize>>);
+
+impl Slot {
+	fn new(open: &Rc<Cell<usize>>) -> Self {
+		open.set(open.get() + 1);
+		Self(open.clone())
+	}
+}
+
+impl Drop for Slot {
+	fn drop(&mut self) {
+		self.0.set(self.0.get() - 1);
+	}
+}
+
+/// How often the client is told at most that datagrams of a flow were dropped.
+const LIMITED_INTERVAL: Duration = Duration::from_secs(1);
+
+impl<S> Flow<S> {
+	fn new(socket: S, remote: SocketAddrV4, slot: Slot, now: Instant) -> Self {
+		Self { socket, remote, opened: now, last_used: now, counters: Counters::default(), bandwidth: None, throttled: false, limit_reported: None, _slot: slot }
+	}
+
+	/// Whether to tell the client about a datagram that was dropped for lack of bandwidth.
+	fn tell_limited(&mut self, now: Instant) -> bool {
+		let tell = self.limit_reported.is_none_or(|t| now.saturating_duration_since(t) >= LIMITED_INTERVAL);
+		if tell {
+			self.limit_reported = Some(now);
+		}
+		tell
+	}
+
+	/// Write a line about the flow for the control socket.
+	fn report(&self, session: u32, protocol: &str, port: u16, now: Instant, out: &mut String) {
+		Line::new(out, "flow")
+			.field("session", session)
+			.field("protocol", protocol)
+			.field("port", port)
+			.field("remote", self.remote)
+			.duration("age", now.saturating_duration_since(self.opened))
+			.duration("idle", now.saturating_duration_since(self.last_used))
+			.counters(&self.counters)
+			.end();
+	}
+}
+
+/// The connection of a TCP flow to its destination, or of a peer of a remote forward.
+struct Upstream {
+	stream: TcpStream,
+	/// Whether the connection has been established and the client told about it. For a peer
+	/// of a remote forward, whether the client connected its end.
+	connected: bool,
+	/// The handshake with the upstream proxy, if the stream is connected to it instead of the
+	/// destination and the proxy didn't connect yet.
+	handshake: Option<Handshake>,
+	/// Whether the connection was accepted for a remote forward.
+	accepted: bool,
+	window: Window,
+	/// Data of the client that hasn't been written to the stream yet.
+	pending: Vec<u8>,
+	/// Whether the stream is only registered for writable events, as the client has no credit
+	/// left to receive more.
+	paused: bool,
+	/// Set once the client finished the flow, which is closed once the pending data is written.
+	finished: bool,
+}
+
+/// The socket of a UDP flow, associated with the upstream proxy if any.
+struct UdpUpstream {
+	socket: UdpSocket,
+	/// Where the datagrams go, which the remote address of the flow only names if the client
+	/// sent an address.
+	destination: SocketAddr,
+	/// The name the client sent the datagrams to, if it named the destination.
+	name: Option<String>,
+	association: Option<Association>,
+}
+
+impl Flow<UdpUpstream> {
+	/// Whether datagrams the client sent to `remote`, or to the host `name` if any, belong to
+	/// the flow. They can't go elsewhere, as only the first destination was checked.
+	fn sends_to(&self, remote: SocketAddrV4, name: Option<&str>) -> bool {
+		self.remote == remote && self.socket.name.as_deref() == name
+	}
+}
+
+/// The socket of an ICMP flow, with the egress it was bound for.
+struct IcmpUpstream {
+	socket: PingSocket,
+	egress: Egress,
+}
+
+/// A UDP association with the upstream proxy, which lasts as long as the connection it was
+/// requested over. Datagrams go through the relay of the proxy with a SOCKS header.
+struct Association {
+	control: TcpStream,
+	/// The handshake, until the proxy replied with the address of its relay.
+	handshake: Option<Handshake>,
+	/// Datagrams of the client waiting for the relay.
+	queued: Vec<Vec<u8>>,
+}
+
+/// The most datagrams kept while waiting for the relay of a UDP association.
+const MAX_QUEUED_DATAGRAMS: usize = 16;
+
+impl UdpUpstream {
+	/// Send a datagram to the destination, which is queued while the association isn't
+	/// established yet.
+	fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+		let association = match &mut self.association {
+			Some(a) => a,
+			None => return self.socket.send(data).map(drop),
+		};
+		if association.handshake.is_some() {
+			if association.queued.len() < MAX_QUEUED_DATAGRAMS {
+				association.queued.push(data.to_vec());
+			}
+			return Ok(());
+		}
+		let mut datagram = Vec::with_capacity(data.len() + 22);
+		socks::datagram_header(&socks::Address::Ip(self.destination), &mut datagram);
+		datagram.extend_from_slice(data);
+		self.socket.send(&datagram).map(drop)
+	}
+
+	/// Receive a datagram of the destination, truncated to the buffer like a direct one.
+	fn recv(&self, buf: &mut [u8]) -> Result<usize, Error> {
+		let association = match &self.association {
+			Some(a) => a,
+			None => return self.socket.recv(buf),
+		};
+		let mut datagram = [0; 0x10000];
+		loop {
+			let len = self.socket.recv(&mut datagram)?;
+			// Until the socket is connected to the relay anyone could send to it.
+			if association.handshake.is_some() {
+				continue;
+			}
+			if let Ok((_, data)) = socks::parse_datagram(&datagram[..len]) {
+				let len = data.len().min(buf.len());
+				buf[..len].copy_from_slice(&data[..len]);
+				return Ok(len);
+			}
+		}
+	}
+}
+
+/// A socket listening for a remote forward of a client.
+struct Forward {
+	socket: ForwardSocket,
+	/// The local ports of the flows of UDP peers by their address.
+	peers: HashMap<SocketAddrV4, u16>,
+}
+
+enum ForwardSocket {
+	Tcp(TcpListener),
+	Udp(UdpSocket),
+}
+
+/// A name being resolved for a flow, which is opened once the answer arrives.
+struct Lookup {
+	/// Tells the answer apart from that of an earlier lookup for the same port.
+	id: u64,
+	remote: SocketAddrV4,
+	name: String,
+	/// Datagrams of the client waiting for the answer.
+	queued: Vec<Vec<u8>>,
+}
+
+/// The most names resolved at once for a session.
+const MAX_LOOKUPS: usize = 64;
+
+/// What a client asked for in its hello.
+struct Hello {
+	/// The session to resume, or all zeroes for a new session.
+	token: SessionToken,
+	/// The compression to use, which the server allows.
+	compression: Compression,
+	/// The identity the client authenticated as, if any.
+	identity: Option<Identity>,
+}
+
+struct Session {
+	id: u32,
+	ctx: Context,
+	/// The token the client can resume the session with, assigned once it authenticated.
+	token: Option<SessionToken>,
+	/// The identity the client authenticated as, if any.
+	identity: Option<Identity>,
+	client: Option<Connection>,
+	/// When the client connected or disconnected.
+	since: Instant,
+	started: Instant,
+	/// How often the client disconnected, and why it did the last time.
+	disconnects: u64,
+	last_error: Option<String>,
+	/// Flows that failed to open or were closed because of an error.
+	flow_errors: u64,
+	keepalive: Keepalive,
+	/// Frames waiting to be sent to the client, which are kept while it is away.
+	queue: Scheduler,
+	compression_threshold: usize,
+	decompressor: Decompressor,
+	udp_socks: HashMap<u16, Flow<UdpUpstream>>,
+	tcp_socks: HashMap<u16, Flow<Upstream>>,
+	icmp_socks: HashMap<u16, Flow<IcmpUpstream>>,
+	allow_remote_forwards: bool,
+	acl: Acl,
+	usage: Rc<RefCell<Usage>>,
+	egress: Egress,
+	egress_routes: Vec<Route>,
+	/// The proxy to open flows through, if any.
+	upstream_proxy: Option<Proxy>,
+	/// Listeners of the remote forwards of the client, by the number the client gave them.
+	forwards: HashMap<u16, Forward>,
+	/// Flows of UDP peers of remote forwards, with the number of the forward.
+	accepted_udp: HashMap<u16, Flow<u16>>,
+	next_accepted_port: u16,
+	resolver: Resolver<(u32, u64)>,
+	/// Names being resolved for flows, by their protocol and port.
+	lookups: HashMap<(Protocol, u16), Lookup>,
+	next_lookup: u64,
+}
+
+impl Session {
+	fn new(id: u32, client: Connection, config: &config::ServerConfig, resolver: Resolver<(u32, u64)>, now: Instant) -> Self {
+		Self {
+			id,
+			ctx: Context::session(id.into()),
+			token: None,
+			identity: None,
+			client: Some(client),
+			since: now,
+			started: now,
+			disconnects: 0,
+			last_error: None,
+			flow_errors: 0,
+			keepalive: Keepalive::new(config.keepalive.interval, config.keepalive.timeout, now),
+			queue: Scheduler::new(config.priorities.clone()),
+			compression_threshold: config.compression.threshold,
+			decompressor: Decompressor::new(),
+			udp_socks: HashMap::new(),
+			tcp_socks: HashMap::new(),
+			icmp_socks: HashMap::new(),
+			allow_remote_forwards: config.allow_remote_forwards,
+			acl: config.acl.clone(),
+			usage: Rc::new(RefCell::new(Usage::new(&config.limits, now))),
+			egress: config.egress.clone(),
+			egress_routes: config.egress_routes.clone(),
+			upstream_proxy: config.upstream_proxy.clone(),
+			forwards: HashMap::new(),
+			accepted_udp: HashMap::new(),
+			next_accepted_port: *ACCEPTED_PORTS.start(),
+			resolver,
+			lookups: HashMap::new(),
+			next_lookup: 0,
+		}
+	}
+
+	/// Handle all frames the client sent.
+	///
+	/// If the client has not authenticated yet only the hello is read and returned.
+	fn handle_client(&mut self, config: &config::ServerConfig, registry: &Registry, now: Instant) -> Result<Option<Hello>, SessionError> {
+		self.flush()?;
+		let mut buf = [0; 0x10000];
+		loop {
+			let client = match &mut self.client {
+				Some(c) => c,
+				None => return Ok(None),
+			};
+			let frame = client.receive(&mut buf, now).map_err(SessionError::Receive)?.map(|(h, d)| (h, d.len()));
+			let (sh, data) = match self.decompressor.frame(frame, &mut buf) {
+				Ok(Some(f)) => f,
+				// Send the window updates for all data that was written.
+				Ok(None) => return self.flush().map(|()| None),
+				Err(e) => return Err(SessionError::Receive(ReceiveError::Decompress(e))),
+			};
+			self.keepalive.received(now);
+
+			if self.token.is_none() {
+				return parse_hello(config, &sh, data).map(Some);
+			}
+
+			self.handle_frame(registry, now, &sh, data)?;
+		}
+	}
+
+	/// Apply the policy of the identity the client authenticated as on top of that of the
+	/// server, counting the session against the `usage` of the identity.
+	fn set_identity(&mut self, identity: Option<Identity>, usage: Rc<RefCell<Usage>>, config: &config::ServerConfig, now: Instant) {
+		let (mut acl, limits, egress, mut routes) = match &identity {
+			Some(i) => (i.acl.clone(), i.limits.or(&config.limits), i.egress.or(&config.egress), i.egress_routes.clone()),
+			None => (Acl::default(), config.limits.clone(), config.egress.clone(), Vec::new()),
+		};
+		acl.extend(config.acl.clone());
+		routes.extend_from_slice(&config.egress_routes);
+		self.acl = acl;
+		self.egress = egress;
+		self.egress_routes = routes;
+		usage.borrow_mut().set_limits(limits, now);
+		self.usage = usage;
+		self.ctx = Context::session(self.id.into());
+		if let Some(i) = &identity {
+			self.ctx = self.ctx.identity(log::intern(&i.name));
+		}
+		self.identity = identity;
+	}
+
+	/// Tell the client which session it is in and which compression to use.
+	fn welcome(&mut self, compression: Compression) -> Result<(), SessionError> {
+		self.queue.set_compression(compression, self.compression_threshold);
+		let any = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+		// Frames queued while the client was away must not precede the welcome.
+		self.queue.push_first(StupidType::Welcome, any, compression.into(), &self.token.unwrap());
+		self.flush()
+	}
+
+	/// Queue a frame for the client and send as much as the connection takes.
+	fn send(&mut self, ty: StupidType, remote: SocketAddrV4, local: u16, data: &[u8]) -> Result<(), SessionError> {
+		self.queue.push(ty, remote, local, data);
+		self.flush()
+	}
+
+	/// Send queued frames until the connection to the client would block.
+	fn flush(&mut self) -> Result<(), SessionError> {
+		match &mut self.client {
+			Some(c) => c.flush(&mut self.queue).map_err(SessionError::Send),
+			None => Ok(()),
+		}
+	}
+
+	/// Drop the connection to the client. A frame that was only partly written to it is sent
+	/// again in full over the next one.
+	fn detach(&mut self) {
+		if let Some(frame) = self.client.take().and_then(|mut c| c.take_unsent()) {
+			self.queue.requeue(frame);
+		}
+	}
+
+	/// Continue the session over a new connection, replacing the old one if any.
+	fn attach(&mut self, registry: &Registry, client: Connection, compression: Compression, now: Instant) -> Result<(), SessionError> {
+		self.detach();
+		self.client = Some(client);
+		self.since = now;
+		self.keepalive.reset(now);
+		// Data of TCP flows may have been lost with the previous connection, so the client
+		// reset them when it lost it.
+		if !self.tcp_socks.is_empty() {
+			debug!(ctx: self.ctx, "closing {} TCP flows of the previous connection", self.tcp_socks.len());
+			self.tcp_socks.clear();
+		}
+		self.lookups.retain(|(p, _), _| *p != Protocol::Tcp);
+		self.queue.clear_tcp();
+		self.welcome(compression)?;
+		// Data that arrived while the client was away hasn't been handled yet.
+		for port in self.udp_socks.keys().copied().collect::<Vec<_>>() {
+			self.handle_association(port)?;
+			self.handle_udp(port, now)?;
+		}
+		for port in self.icmp_socks.keys().copied().collect::<Vec<_>>() {
+			self.handle_icmp(port, now)?;
+		}
+		for forward in self.forwards.keys().copied().collect::<Vec<_>>() {
+			self.handle_forward(registry, forward, now)?;
+		}
+		Ok(())
+	}
+
+	fn handle_frame(&mut self, registry: &Registry, now: Instant, sh: &StupidDataHeader, data: &[u8]) -> Result<(), SessionError> {
+		let flow = self.ctx.flow(sh.local(), sh.remote());
+		let ty = match sh.ty() {
+			Ok(ty) => ty,
+			Err(e) => {
+				warn!(ctx: flow, "ignoring frame with unknown type {}", e.0);
+				return Ok(());
+			}
+		};
+
+		let (local, remote) = (sh.local(), sh.remote());
+		let result = match ty {
+			StupidType::UDP if self.accepted_udp.contains_key(&local) => self.send_accepted_udp(now, local, data),
+			StupidType::UDP => self.send_udp(registry, now, local, remote, data),
+			StupidType::UdpName => self.send_udp_name(registry, now, local, remote, data),
+			StupidType::TcpConnect if self.tcp_socks.get(&local).is_some_and(|f| f.socket.accepted && !f.socket.connected) => {
+				debug!(ctx: flow, "client connected TCP");
+				self.tcp_socks.get_mut(&local).unwrap().socket.connected = true;
+				// Read what the peer sent in the meantime.
+				return self.handle_tcp(registry, local, now);
+			}
+			StupidType::TcpConnect => self.connect_tcp(registry, now, local, remote, remote.into(), data),
+			StupidType::TcpConnectName => self.connect_tcp_name(registry, now, local, remote, data),
+			StupidType::TCP => self.send_tcp(now, local, data),
+			StupidType::TcpFinish => {
+				match self.tcp_socks.get_mut(&local) {
+					Some(f) if f.socket.pending.is_empty() => {
+						self.tcp_socks.remove(&local);
+						debug!(ctx: flow, "closed TCP");
+					}
+					Some(f) => f.socket.finished = true,
+					None => drop(self.lookups.remove(&(Protocol::Tcp, local))),
+				}
+				Ok(())
+			}
+			StupidType::WindowUpdate => match self.grant_tcp(registry, local, data) {
+				// Read what arrived while the client had no credit left.
+				Ok(()) => return self.handle_tcp(registry, local, now),
+				Err(e) => Err(e),
+			},
+			StupidType::IcmpEchoRequest => self.send_echo(registry, now, local, remote, data),
+			StupidType::IcmpEchoReply => {
+				debug!(ctx: flow, "ignoring echo reply from client");
+				Ok(())
+			}
+			StupidType::Ping => return self.send(StupidType::Pong, remote, local, data),
+			StupidType::Pong => {
+				if let Some(rtt) = self.keepalive.pong(data, now) {
+					trace!(ctx: self.ctx, "round-trip time to client: {:?}", rtt);
+				}
+				if let Some(sent) = self.queue.compression_stats() {
+					trace!(ctx: self.ctx, "compressed {} sent, {} received", sent, self.decompressor.stats());
+				}
+				Ok(())
+			}
+			StupidType::Listen => return self.listen(registry, local, remote, data),
+			StupidType::Hello | StupidType::Welcome | StupidType::Accept | StupidType::Reject => {
+				debug!(ctx: flow, "ignoring unexpected {:?} from client", ty);
+				Ok(())
+			}
+		};
+
+		match result {
+			Ok(()) => Ok(()),
+			Err(e) => self.flow_failed(ty, local, remote, e),
+		}
+	}
+
+	/// Close the flow a frame of type `ty` failed for and tell the client why.
+	fn flow_failed(&mut self, ty: StupidType, local: u16, remote: SocketAddrV4, error: FlowError) -> Result<(), SessionError> {
+		debug!(ctx: self.ctx.flow(local, remote), "closing flow: {}", error);
+		self.flow_errors += 1;
+		match ty {
+			StupidType::UDP | StupidType::UdpName => drop(self.udp_socks.remove(&local)),
+			StupidType::IcmpEchoRequest => drop(self.icmp_socks.remove(&local)),
+			StupidType::TcpConnect | StupidType::TcpConnectName => return self.fail_tcp(local, remote, error.connect_error()),
+			_ => return self.close_tcp(local, remote),
+		}
+		self.send(StupidType::Reject, remote, local, &[error.connect_error() as u8])
+	}
+
+	/// Open the flow a name was resolved for, unless the client closed it in the meantime.
+	fn resolved(&mut self, registry: &Registry, lookup: u64, result: Result<Vec<SocketAddr>, Error>, now: Instant) -> Result<(), SessionError> {
+		let (protocol, local) = match self.lookups.iter().find(|(_, l)| l.id == lookup) {
+			Some((key, _)) => *key,
+			None => return Ok(()),
+		};
+		let Lookup { remote, name, queued, .. } = self.lookups.remove(&(protocol, local)).unwrap();
+		let ctx = self.ctx.flow(local, remote);
+		let result = match result.map(|a| a.into_iter().next()) {
+			Ok(Some(address)) => {
+				debug!(ctx: ctx, "resolved {} to {}", name, address);
+				match protocol {
+					Protocol::Tcp => self.connect_tcp(registry, now, local, remote, address, &[]),
+					Protocol::Udp => self.open_udp(registry, now, local, remote, address, Some(&name))
+						.and_then(|()| queued.iter().try_for_each(|d| self.send_udp_datagram(now, local, d))),
+				}
+			}
+			Ok(None) => {
+				debug!(ctx: ctx, "{} has no addresses", name);
+				Err(FlowError::Resolve)
+			}
+			Err(e) => {
+				debug!(ctx: ctx, "failed to resolve {}: {}", name, e);
+				Err(FlowError::Resolve)
+			}
+		};
+		match (result, protocol) {
+			(Ok(()), _) => self.flush(),
+			(Err(e), Protocol::Tcp) => self.flow_failed(StupidType::TcpConnectName, local, remote, e),
+			(Err(e), Protocol::Udp) => self.flow_failed(StupidType::UdpName, local, remote, e),
+		}
+	}
+
+	/// Start resolving `name` for a flow, which is opened once the answer arrives.
+	fn lookup(&mut self, protocol: Protocol, local: u16, remote: SocketAddrV4, name: &str, queued: Vec<Vec<u8>>) -> Result<(), FlowError> {
+		if self.lookups.len() >= MAX_LOOKUPS {
+			info!(ctx: self.ctx.flow(local, remote), "refused flow: {}", Quota::Lookups);
+			return Err(FlowError::Quota(Quota::Lookups));
+		}
+		debug!(ctx: self.ctx.flow(local, remote), "resolving {}", name);
+		let id = self.next_lookup;
+		self.next_lookup += 1;
+		self.resolver.resolve((self.id, id), name, remote.port());
+		self.lookups.insert((protocol, local), Lookup { id, remote, name: name.into(), queued });
+		Ok(())
+	}
+
+	fn send_udp(&mut self, registry: &Registry, now: Instant, local: u16, remote: SocketAddrV4, data: &[u8]) -> Result<(), FlowError> {
+		match self.udp_socks.get(&local) {
+			Some(flow) if !flow.sends_to(remote, None) => return self.reject_datagram(local, remote),
+			Some(_) => (),
+			None if self.lookups.contains_key(&(Protocol::Udp, local)) => return self.reject_datagram(local, remote),
+			None => self.open_udp(registry, now, local, remote, remote.into(), None)?,
+		}
+		self.send_udp_datagram(now, local, data)
+	}
+
+	/// Send a datagram to the host the client named, resolving the name before the first one.
+	fn send_udp_name(&mut self, registry: &Registry, now: Instant, local: u16, remote: SocketAddrV4, data: &[u8]) -> Result<(), FlowError> {
+		let (name, data) = split_name_datagram(data).ok_or(FlowError::Resolve)?;
+		if let Some(flow) = self.udp_socks.get(&local) {
+			if !flow.sends_to(remote, Some(name)) {
+				return self.reject_datagram(local, remote);
+			}
+			return self.send_udp_datagram(now, local, data);
+		}
+		if let Some(lookup) = self.lookups.get_mut(&(Protocol::Udp, local)) {
+			if lookup.remote != remote || lookup.name != name {
+				return self.reject_datagram(local, remote);
+			}
+			if lookup.queued.len() < MAX_QUEUED_DATAGRAMS {
+				lookup.queued.push(data.to_vec());
+			}
+			return Ok(());
+		}
+		match name.parse::<IpAddr>() {
+			Ok(ip) => {
+				self.open_udp(registry, now, local, remote, SocketAddr::new(ip, remote.port()), Some(name))?;
+				self.send_udp_datagram(now, local, data)
+			}
+			Err(_) => self.lookup(Protocol::Udp, local, remote, name, vec![data.to_vec()]),
+		}
+	}
+
+	/// Drop a datagram the client sent elsewhere than its flow goes. The flow stays open.
+	fn reject_datagram(&mut self, local: u16, remote: SocketAddrV4) -> Result<(), FlowError> {
+		debug!(ctx: self.ctx.flow(local, remote), "dropping datagram to another destination than its flow");
+		self.flow_errors += 1;
+		self.queue.push(StupidType::Reject, remote, local, &[ConnectError::Failed as u8]);
+		Ok(())
+	}
+
+	/// Open the socket of a UDP flow to `address`, which the client sent to `name` if any.
+	fn open_udp(&mut self, registry: &Registry, now: Instant, local: u16, remote: SocketAddrV4, address: SocketAddr, name: Option<&str>) -> Result<(), FlowError> {
+		self.acl_check(local, remote, acl::Protocol::Udp, address)?;
+		self.quota_check(local, remote, Quota::Udp, now)?;
+		debug!(ctx: self.ctx.flow(local, remote), "opening UDP socket");
+		let egress = self.egress.route(&self.egress_routes, address.ip());
+		let (mut socket, association) = match &self.upstream_proxy {
+			Some(proxy) => {
+				// The relay is only known once the proxy replies.
+				let mut control = egress.connect_tcp(proxy.address)?;
+				let token = token(self.id, ASSOCIATION_EVENT, local);
+				registry.register(&mut control, token, mio::Interest::READABLE | mio::Interest::WRITABLE)?;
+				let socket = egress.bind_udp(proxy.address)?;
+				// Tell the proxy where datagrams come from, as far as is known.
+				let handshake = Some(Handshake::new(proxy, Command::UdpAssociate, socket.local_addr()?));
+				(socket, Some(Association { control, handshake, queued: Vec::new() }))
+			}
+			None => {
+				let socket = egress.bind_udp(address)?;
+				socket.connect(address)?;
+				(socket, None)
+			}
+		};
+		let token = token(self.id, UDP_EVENT, local);
+		registry.register(&mut socket, token, mio::Interest::READABLE)?;
+		let udp = UdpUpstream { socket, destination: address, name: name.map(String::from), association };
+		self.udp_socks.insert(local, self.usage.borrow().flow(udp, remote, Quota::Udp, now));
+		Ok(())
+	}
+
+	/// Send a datagram of the client over the socket of its flow.
+	fn send_udp_datagram(&mut self, now: Instant, local: u16, data: &[u8]) -> Result<(), FlowError> {
+		let flow = self.udp_socks.get_mut(&local).ok_or(FlowError::Unknown)?;
+		if !has_bandwidth(&mut self.usage.borrow_mut().bandwidth, &mut flow.bandwidth, now) {
+			trace!(ctx: self.ctx.flow(local, flow.remote), "dropping datagram, bandwidth limit reached");
+			if flow.tell_limited(now) {
+				self.queue.push(StupidType::Reject, flow.remote, local, &[ConnectError::Limited as u8]);
+			}
+			return Ok(());
+		}
+		flow.socket.send(data)?;
+		use_bandwidth(&mut self.usage.borrow_mut().bandwidth, &mut flow.bandwidth, data.len());
+		flow.counters.received(data.len());
+		flow.last_used = now;
+		Ok(())
+	}
+
+	/// Start connecting to `address`. The client is told once the connection is established.
+	fn connect_tcp(&mut self, registry: &Registry, now: Instant, local: u16, remote: SocketAddrV4, address: SocketAddr, data: &[u8]) -> Result<(), FlowError> {
+		self.acl_check(local, remote, acl::Protocol::Tcp, address)?;
+		self.quota_check(local, remote, Quota::Tcp, now)?;
+		debug!(ctx: self.ctx.flow(local, remote), "connecting TCP");
+		let egress = self.egress.route(&self.egress_routes, address.ip());
+		let (mut stream, handshake) = match &self.upstream_proxy {
+			Some(proxy) => (egress.connect_tcp(proxy.address)?, Some(Handshake::new(proxy, Command::Connect, address))),
+			None => (egress.connect_tcp(address)?, None),
+		};
+		let token = token(self.id, TCP_EVENT, local);
+		registry.register(&mut stream, token, mio::Interest::READABLE | mio::Interest::WRITABLE)?;
+		let upstream = Upstream { stream, connected: false, handshake, accepted: false, window: Window::new(), pending: Vec::new(), paused: false, finished: false };
+		self.tcp_socks.insert(local, self.usage.borrow().flow(upstream, remote, Quota::Tcp, now));
+		if !data.is_empty() {
+			self.send_tcp(now, local, data)?;
+		}
+		Ok(())
+	}
+
+	/// Connect to the address a client sent as text, or resolve the name it asked to connect
+	/// to and connect to the first address once the answer arrives.
+	fn connect_tcp_name(&mut self, registry: &Registry, now: Instant, local: u16, remote: SocketAddrV4, name: &[u8]) -> Result<(), FlowError> {
+		let name = std::str::from_utf8(name).map_err(|_| FlowError::Resolve)?;
+		match name.parse::<IpAddr>() {
+			Ok(ip) => self.connect_tcp(registry, now, local, remote, SocketAddr::new(ip, remote.port()), &[]),
+			Err(_) => self.lookup(Protocol::Tcp, local, remote, name, Vec::new()),
+		}
+	}
+
+	/// Check whether the access control list allows opening a flow to `address`.
+	fn acl_check(&self, local: u16, remote: SocketAddrV4, protocol: acl::Protocol, address: SocketAddr) -> Result<(), FlowError> {
+		if self.acl.allows(protocol, address) {
+			return Ok(());
+		}
+		info!(ctx: self.ctx.flow(local, remote), "denied {} to {}", protocol, address);
+		Err(FlowError::Denied)
+	}
+
+	/// Check whether the client may open another flow, counting it against the rate of new
+	/// flows.
+	fn quota_check(&mut self, local: u16, remote: SocketAddrV4, quota: Quota, now: Instant) -> Result<(), FlowError> {
+		let mut usage = self.usage.borrow_mut();
+		let quota = if usage.full(quota) {
+			quota
+		} else if !usage.new_flows.as_mut().is_none_or(|b| b.ready(now)) {
+			Quota::Rate
+		} else {
+			if let Some(b) = &mut usage.new_flows {
+				b.take(1);
+			}
+			return Ok(());
+		};
+		info!(ctx: self.ctx.flow(local, remote), "refused flow: {}", quota);
+		Err(FlowError::Quota(quota))
+	}
+
+	fn send_tcp(&mut self, now: Instant, local: u16, data: &[u8]) -> Result<(), FlowError> {
+		let flow = self.tcp_socks.get_mut(&local).ok_or(FlowError::Unknown)?;
+		trace!(ctx: self.ctx.flow(local, flow.remote), "sending {} bytes over TCP", data.len());
+		flow.socket.window.received(data.len())?;
+		flow.socket.pending.extend_from_slice(data);
+		flow.counters.received(data.len());
+		flow.last_used = now;
+		self.write_tcp(local, now)?;
+		Ok(())
+	}
+
+	/// Write data of the client to the upstream socket as far as it and the bandwidth limits
+	/// take it, granting the client credit for what was written. The window update is queued
+	/// but not sent.
+	fn write_tcp(&mut self, local: u16, now: Instant) -> Result<(), Error> {
+		let flow = match self.tcp_socks.get_mut(&local) {
+			Some(f) => f,
+			None => return Ok(()),
+		};
+		if !flow.socket.connected {
+			// The data is written once the connection is established.
+			return Ok(());
+		}
+		if !flow.socket.pending.is_empty() && !has_bandwidth(&mut self.usage.borrow_mut().bandwidth, &mut flow.bandwidth, now) {
+			flow.throttled = true;
+			return Ok(());
+		}
+		let upstream = &mut flow.socket;
+		let mut written = 0;
+		while written < upstream.pending.len() {
+			match upstream.stream.write(&upstream.pending[written..]) {
+				Ok(0) => return Err(ErrorKind::WriteZero.into()),
+				Ok(n) => written += n,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+				Err(e) if e.kind() == ErrorKind::Interrupted => (),
+				Err(e) => return Err(e),
+			}
+		}
+		upstream.pending.drain(..written);
+		use_bandwidth(&mut self.usage.borrow_mut().bandwidth, &mut flow.bandwidth, written);
+		if let Some(update) = upstream.window.consumed(written) {
+			self.queue.push(StupidType::WindowUpdate, flow.remote, local, &update);
+		}
+		if upstream.finished && upstream.pending.is_empty() {
+			debug!(ctx: self.ctx.flow(local, flow.remote), "closed TCP");
+			self.tcp_socks.remove(&local);
+		}
+		Ok(())
+	}
+
+	/// Handle a window update of the client, reading the upstream socket again if it was paused.
+	fn grant_tcp(&mut self, registry: &Registry, local: u16, data: &[u8]) -> Result<(), FlowError> {
+		// The flow may have been closed while the update was underway.
+		let upstream = match self.tcp_socks.get_mut(&local) {
+			Some(f) => &mut f.socket,
+			None => return Ok(()),
+		};
+		upstream.window.update(data)?;
+		if upstream.paused {
+			let token = token(self.id, TCP_EVENT, local);
+			registry.reregister(&mut upstream.stream, token, mio::Interest::READABLE | mio::Interest::WRITABLE)?;
+			upstream.paused = false;
+		}
+		Ok(())
+	}
+
+	fn send_echo(&mut self, registry: &Registry, now: Instant, local: u16, remote: SocketAddrV4, data: &[u8]) -> Result<(), FlowError> {
+		// A flow is only an identifier, each echo may go elsewhere. ICMP has no ports, the port
+		// of the flow is the sequence number.
+		self.acl_check(local, remote, acl::Protocol::Icmp, SocketAddrV4::new(*remote.ip(), 0).into())?;
+		if !self.icmp_socks.contains_key(&local) {
+			self.quota_check(local, remote, Quota::Udp, now)?;
+		}
+		let destination = SocketAddr::from(SocketAddrV4::new(*remote.ip(), 0));
+		let egress = self.egress.route(&self.egress_routes, destination.ip());
+		let flow = match self.icmp_socks.entry(local) {
+			Entry::Occupied(e) if e.get().socket.egress == egress => e.into_mut(),
+			entry => {
+				// Echoes that leave another way than the last ones need a socket bound for it.
+				let mut icmp = PingSocket::new()?;
+				egress.bind(icmp.as_raw_fd(), destination)?;
+				let token = token(self.id, ICMP_EVENT, local);
+				registry.register(&mut icmp, token, mio::Interest::READABLE)?;
+				let icmp = IcmpUpstream { socket: icmp, egress };
+				match entry {
+					Entry::Occupied(e) => {
+						let flow = e.into_mut();
+						flow.socket = icmp;
+						flow
+					}
+					Entry::Vacant(e) => e.insert(self.usage.borrow().flow(icmp, remote, Quota::Udp, now)),
+				}
+			}
+		};
+		if !has_bandwidth(&mut self.usage.borrow_mut().bandwidth, &mut flow.bandwidth, now) {
+			trace!(ctx: self.ctx.flow(local, remote), "dropping echo request, bandwidth limit reached");
+			if flow.tell_limited(now) {
+				self.queue.push(StupidType::Reject, remote, local, &[ConnectError::Limited as u8]);
+			}
+			return Ok(());
+		}
+		let (addr, seq) = (*remote.ip(), remote.port());
+		flow.socket.socket.send_echo(addr, seq, data)?;
+		use_bandwidth(&mut self.usage.borrow_mut().bandwidth, &mut flow.bandwidth, data.len());
+		flow.counters.received(data.len());
+		flow.remote = remote;
+		flow.last_used = now;
+		Ok(())
+	}
+
+	fn handle_udp(&mut self, local_port: u16, now: Instant) -> Result<(), SessionError> {
+		let mut buf = [0; StupidDataHeader::MAX_DATA_LENGTH];
+		loop {
+			// The socket may have been closed before this event was handled.
+			let flow = match self.udp_socks.get_mut(&local_port) {
+				Some(f) => f,
+				None => return Ok(()),
+			};
+			if !has_bandwidth(&mut self.usage.borrow_mut().bandwidth, &mut flow.bandwidth, now) {
+				// Leave the datagrams in the socket until there is bandwidth again.
+				flow.throttled = true;
+				return Ok(());
+			}
+			let len = match flow.socket.recv(&mut buf) {
+				Ok(len) => len,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+				Err(e) => {
+					debug!(ctx: self.ctx.flow(local_port, flow.remote), "closing flow: {}", e);
+					self.udp_socks.remove(&local_port);
+					self.flow_errors += 1;
+					return Ok(());
+				}
+			};
+			flow.last_used = now;
+			flow.counters.sent(len);
+			use_bandwidth(&mut self.usage.borrow_mut().bandwidth, &mut flow.bandwidth, len);
+			let remote = flow.remote;
+			self.send(StupidType::UDP, remote, local_port, &buf[..len])?;
+		}
+	}
+
+	fn handle_tcp(&mut self, registry: &Registry, local_port: u16, now: Instant) -> Result<(), SessionError> {
+		let flow = match self.tcp_socks.get_mut(&local_port) {
+			Some(f) => f,
+			None => return Ok(()),
+		};
+		let (remote, ctx) = (flow.remote, self.ctx.flow(local_port, flow.remote));
+		if !flow.socket.connected && flow.socket.accepted {
+			// Leave the data of the peer in the socket until the client connected its end.
+			return Ok(());
+		}
+		if !flow.socket.connected {
+			// The stream becomes writable once the connection is established or failed, and
+			// then readable as the upstream proxy replies, if any.
+			let upstream = &mut flow.socket;
+			let result = match (is_connected(&upstream.stream), &mut upstream.handshake) {
+				(Ok(true), Some(handshake)) => handshake.resume(&mut upstream.stream).map(|bound| bound.is_some()),
+				(result, _) => result.map_err(upstream::Error::Io),
+			};
+			match result {
+				Ok(true) => {
+					debug!(ctx: ctx, "connected TCP");
+					upstream.connected = true;
+					upstream.handshake = None;
+					self.send(StupidType::TcpConnect, remote, local_port, &[])?;
+				}
+				Ok(false) => return Ok(()),
+				Err(e) => {
+					debug!(ctx: ctx, "failed to connect TCP: {}", e);
+					self.flow_errors += 1;
+					return self.fail_tcp(local_port, remote, e.connect_error());
+				}
+			}
+		}
+		if let Err(e) = self.write_tcp(local_port, now) {
+			let remote = self.tcp_socks[&local_port].remote;
+			debug!(ctx: self.ctx.flow(local_port, remote), "closing flow: {}", e);
+			self.flow_errors += 1;
+			return self.close_tcp(local_port, remote);
+		}
+		let mut buf = [0; StupidDataHeader::MAX_DATA_LENGTH];
+		let max = self.client.as_ref().map_or(buf.len(), Connection::max_stream_data);
+		loop {
+			let flow = match self.tcp_socks.get_mut(&local_port) {
+				Some(f) => f,
+				None => return self.flush(),
+			};
+			let (remote, ctx) = (flow.remote, self.ctx.flow(local_port, flow.remote));
+			let upstream = &mut flow.socket;
+			let max = max.min(upstream.window.credit());
+			if max == 0 {
+				// Leave the data in the socket until the client granted more credit.
+				if !upstream.paused {
+					trace!(ctx: ctx, "pausing TCP, no credit left");
+					let token = token(self.id, TCP_EVENT, local_port);
+					if let Err(e) = registry.reregister(&mut upstream.stream, token, mio::Interest::WRITABLE) {
+						debug!(ctx: ctx, "closing flow: {}", e);
+						self.flow_errors += 1;
+						return self.close_tcp(local_port, remote);
+					}
+					upstream.paused = true;
+				}
+				return self.flush();
+			}
+			if !has_bandwidth(&mut self.usage.borrow_mut().bandwidth, &mut flow.bandwidth, now) {
+				// Leave the data in the socket until there is bandwidth again.
+				flow.throttled = true;
+				return self.flush();
+			}
+			match upstream.stream.read(&mut buf[..max]) {
+				Ok(0) => {
+					debug!(ctx: ctx, "TCP connection closed by remote");
+					return self.close_tcp(local_port, remote);
+				}
+				Ok(len) => {
+					if let Err(e) = upstream.window.sent(len) {
+						debug!(ctx: ctx, "closing flow: {}", e);
+						self.flow_errors += 1;
+						return self.close_tcp(local_port, remote);
+					}
+					flow.last_used = now;
+					flow.counters.sent(len);
+					use_bandwidth(&mut self.usage.borrow_mut().bandwidth, &mut flow.bandwidth, len);
+					self.send(StupidType::TCP, remote, local_port, &buf[..len])?;
+				}
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return self.flush(),
+				Err(e) if e.kind() == ErrorKind::Interrupted => (),
+				Err(e) => {
+					debug!(ctx: ctx, "closing flow: {}", e);
+					self.flow_errors += 1;
+					return self.close_tcp(local_port, remote);
+				}
+			}
+		}
+	}
+
+	/// Continue setting up the UDP association of a flow, or close the flow once the proxy
+	/// ends the association.
+	fn handle_association(&mut self, local_port: u16) -> Result<(), SessionError> {
+		let flow = match self.udp_socks.get_mut(&local_port) {
+			Some(f) => f,
+			None => return Ok(()),
+		};
+		let (remote, ctx) = (flow.remote, self.ctx.flow(local_port, flow.remote));
+		let udp = &mut flow.socket;
+		let association = match &mut udp.association {
+			Some(a) => a,
+			None => return Ok(()),
+		};
+		let error = match &mut association.handshake {
+			Some(handshake) => {
+				let result = match is_connected(&association.control) {
+					Ok(true) => handshake.resume(&mut association.control),
+					Ok(false) => Ok(None),
+					Err(e) => Err(upstream::Error::Io(e)),
+				};
+				let result = match result {
+					Ok(Some(relay)) => associate(udp, relay).map_err(upstream::Error::Io),
+					Ok(None) => return Ok(()),
+					Err(e) => Err(e),
+				};
+				match result {
+					Ok(()) => {
+						debug!(ctx: ctx, "associated UDP socket with upstream proxy");
+						return Ok(());
+					}
+					Err(e) => {
+						debug!(ctx: ctx, "failed to associate UDP socket: {}", e);
+						e.connect_error()
+					}
+				}
+			}
+			// The proxy isn't supposed to send anything, the connection just has to stay open.
+			None => loop {
+				match association.control.read(&mut [0; 64]) {
+					Ok(0) => {
+						debug!(ctx: ctx, "upstream proxy ended UDP association");
+						break ConnectError::Failed;
+					}
+					Ok(_) => (),
+					Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+					Err(e) if e.kind() == ErrorKind::Interrupted => (),
+					Err(e) => {
+						debug!(ctx: ctx, "UDP association failed: {}", e);
+						break ConnectError::from(&e);
+					}
+				}
+			},
+		};
+		self.udp_socks.remove(&local_port);
+		self.flow_errors += 1;
+		self.send(StupidType::Reject, remote, local_port, &[error as u8])
+	}
+
+	fn handle_icmp(&mut self, local_port: u16, now: Instant) -> Result<(), SessionError> {
+		let mut buf = [0; StupidDataHeader::MAX_DATA_LENGTH];
+		loop {
+			let flow = match self.icmp_socks.get_mut(&local_port) {
+				Some(f) => f,
+				None => return Ok(()),
+			};
+			if !has_bandwidth(&mut self.usage.borrow_mut().bandwidth, &mut flow.bandwidth, now) {
+				// Leave the replies in the socket until there is bandwidth again.
+				flow.throttled = true;
+				return Ok(());
+			}
+			let (addr, seq, data) = match flow.socket.socket.receive_echo(&mut buf) {
+				Ok(r) => r,
+				Err(e) if e.kind() == ErrorKind::InvalidData => continue,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+				Err(e) => {
+					debug!(ctx: self.ctx.flow(local_port, flow.remote), "failed to receive echo reply: {}", e);
+					return Ok(());
+				}
+			};
+			flow.last_used = now;
+			flow.counters.sent(data.len());
+			use_bandwidth(&mut self.usage.borrow_mut().bandwidth, &mut flow.bandwidth, data.len());
+			let addr = SocketAddrV4::new(addr, seq);
+			self.send(StupidType::IcmpEchoReply, addr, local_port, data)?;
+		}
+	}
+
+	/// Listen for a remote forward of the client and tell it whether that worked.
+	fn listen(&mut self, registry: &Registry, forward: u16, address: SocketAddrV4, data: &[u8]) -> Result<(), SessionError> {
+		// A previous listener of the forward would be in the way.
+		self.forwards.remove(&forward);
+		let result = match data.first().map(|p| Protocol::try_from(*p)) {
+			_ if !self.allow_remote_forwards => Err(Error::new(ErrorKind::PermissionDenied, "remote forwards are not allowed")),
+			Some(Ok(protocol)) => self.bind_forward(registry, forward, address, protocol),
+			_ => Err(Error::new(ErrorKind::InvalidInput, "unknown protocol")),
+		};
+		let error = match result {
+			Ok(()) => String::new(),
+			Err(e) => {
+				warn!(ctx: self.ctx, "failed to listen on {} for remote forward {}: {}", address, forward, e);
+				e.to_string()
+			}
+		};
+		self.send(StupidType::Listen, address, forward, error.as_bytes())
+	}
+
+	fn bind_forward(&mut self, registry: &Registry, forward: u16, address: SocketAddrV4, protocol: Protocol) -> Result<(), Error> {
+		let token = token(self.id, FORWARD_EVENT, forward);
+		let socket = match protocol {
+			Protocol::Tcp => {
+				let mut listener = TcpListener::bind(address.into())?;
+				registry.register(&mut listener, token, mio::Interest::READABLE)?;
+				ForwardSocket::Tcp(listener)
+			}
+			Protocol::Udp => {
+				let mut socket = UdpSocket::bind(address.into())?;
+				registry.register(&mut socket, token, mio::Interest::READABLE)?;
+				ForwardSocket::Udp(socket)
+			}
+		};
+		info!(ctx: self.ctx, "listening on {} over {} for remote forward {}", address, protocol, forward);
+		self.forwards.insert(forward, Forward { socket, peers: HashMap::new() });
+		Ok(())
+	}
+
+	/// Accept all connections or receive all datagrams of peers of a remote forward.
+	fn handle_forward(&mut self, registry: &Registry, forward: u16, now: Instant) -> Result<(), SessionError> {
+		let mut buf = [0; StupidDataHeader::MAX_DATA_LENGTH];
+		loop {
+			let listener = match self.forwards.get(&forward) {
+				Some(l) => l,
+				None => return Ok(()),
+			};
+			let result = match &listener.socket {
+				ForwardSocket::Tcp(l) => l.accept().map(|(stream, peer)| (Some(stream), 0, peer)),
+				ForwardSocket::Udp(s) => s.recv_from(&mut buf).map(|(len, peer)| (None, len, peer)),
+			};
+			let (stream, len, peer) = match result {
+				Ok((stream, len, SocketAddr::V4(peer))) => (stream, len, peer),
+				Ok(_) => continue,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+				Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+				Err(e) => {
+					debug!(ctx: self.ctx, "failed to accept peer of remote forward {}: {}", forward, e);
+					return Ok(());
+				}
+			};
+			let local = match listener.peers.get(&peer) {
+				Some(local) => *local,
+				None if stream.is_none() && self.usage.borrow().full(Quota::Udp) => {
+					debug!(ctx: self.ctx, "dropping UDP peer {} of remote forward {}: {}", peer, forward, Quota::Udp);
+					continue;
+				}
+				None if stream.is_some() && self.usage.borrow().full(Quota::Tcp) => {
+					debug!(ctx: self.ctx, "dropping TCP peer {} of remote forward {}: {}", peer, forward, Quota::Tcp);
+					continue;
+				}
+				None => match self.accept_port() {
+					Some(local) => local,
+					None => {
+						debug!(ctx: self.ctx, "dropping peer {} of remote forward {}, no ports left", peer, forward);
+						continue;
+					}
+				},
+			};
+			let ctx = self.ctx.flow(local, peer);
+			match stream {
+				Some(mut stream) => {
+					let token = token(self.id, TCP_EVENT, local);
+					if let Err(e) = registry.register(&mut stream, token, mio::Interest::READABLE | mio::Interest::WRITABLE) {
+						debug!(ctx: ctx, "failed to register peer of remote forward: {}", e);
+						continue;
+					}
+					debug!(ctx: ctx, "accepted TCP peer of remote forward {}", forward);
+					let upstream = Upstream { stream, connected: false, handshake: None, accepted: true, window: Window::new(), pending: Vec::new(), paused: false, finished: false };
+					self.tcp_socks.insert(local, self.usage.borrow().flow(upstream, peer, Quota::Tcp, now));
+					self.send(StupidType::Accept, peer, local, &forward.to_be_bytes())?;
+				}
+				None => {
+					if let Entry::Vacant(e) = self.accepted_udp.entry(local) {
+						debug!(ctx: ctx, "accepted UDP peer of remote forward {}", forward);
+						e.insert(self.usage.borrow().flow(forward, peer, Quota::Udp, now));
+						self.forwards.get_mut(&forward).unwrap().peers.insert(peer, local);
+						self.send(StupidType::Accept, peer, local, &forward.to_be_bytes())?;
+					}
+					let flow = self.accepted_udp.get_mut(&local).unwrap();
+					flow.last_used = now;
+					// Datagrams of all peers arrive on the same socket, so they can't be left in it.
+					if !has_bandwidth(&mut self.usage.borrow_mut().bandwidth, &mut flow.bandwidth, now) {
+						trace!(ctx: ctx, "dropping datagram, bandwidth limit reached");
+						continue;
+					}
+					flow.counters.sent(len);
+					use_bandwidth(&mut self.usage.borrow_mut().bandwidth, &mut flow.bandwidth, len);
+					self.send(StupidType::UDP, peer, local, &buf[..len])?;
+				}
+			}
+		}
+	}
+
+	/// Send a datagram of the client to a UDP peer of a remote forward.
+	fn send_accepted_udp(&mut self, now: Instant, local: u16, data: &[u8]) -> Result<(), FlowError> {
+		let flow = self.accepted_udp.get_mut(&local).ok_or(FlowError::Unknown)?;
+		if !has_bandwidth(&mut self.usage.borrow_mut().bandwidth, &mut flow.bandwidth, now) {
+			trace!(ctx: self.ctx.flow(local, flow.remote), "dropping datagram, bandwidth limit reached");
+			if flow.tell_limited(now) {
+				self.queue.push(StupidType::Reject, flow.remote, local, &[ConnectError::Limited as u8]);
+			}
+			return Ok(());
+		}
+		match self.forwards.get(&flow.socket).map(|f| &f.socket) {
+			Some(ForwardSocket::Udp(s)) => s.send_to(data, flow.remote.into())?,
+			_ => return Err(FlowError::Unknown),
+		};
+		use_bandwidth(&mut self.usage.borrow_mut().bandwidth, &mut flow.bandwidth, data.len());
+		flow.counters.received(data.len());
+		flow.last_used = now;
+		Ok(())
+	}
+
+	/// Pick a local port for a flow accepted for a remote forward.
+	fn accept_port(&mut self) -> Option<u16> {
+		for _ in ACCEPTED_PORTS {
+			let port = self.next_accepted_port;
+			self.next_accepted_port = port.checked_add(1).unwrap_or(*ACCEPTED_PORTS.start());
+			if !self.tcp_socks.contains_key(&port) && !self.accepted_udp.contains_key(&port) {
+				return Some(port);
+			}
+		}
+		None
+	}
+
+	/// Close a flow on request, returning whether it exists.
+	fn kill(&mut self, protocol: acl::Protocol, port: u16) -> Result<bool, SessionError> {
+		let remote = match protocol {
+			acl::Protocol::Tcp => self.tcp_socks.get(&port).map(|f| f.remote),
+			acl::Protocol::Udp => self.udp_socks.remove(&port).map(|f| f.remote).or_else(|| {
+				let flow = self.accepted_udp.remove(&port)?;
+				if let Some(forward) = self.forwards.get_mut(&flow.socket) {
+					forward.peers.remove(&flow.remote);
+				}
+				Some(flow.remote)
+			}),
+			acl::Protocol::Icmp => self.icmp_socks.remove(&port).map(|f| f.remote),
+		};
+		let remote = match remote {
+			Some(r) => r,
+			None => return Ok(false),
+		};
+		info!(ctx: self.ctx.flow(port, remote), "closing {} flow on request", protocol);
+		match protocol {
+			acl::Protocol::Tcp => self.close_tcp(port, remote)?,
+			acl::Protocol::Udp => self.send(StupidType::Reject, remote, port, &[ConnectError::Failed as u8])?,
+			// The client just stops getting replies.
+			acl::Protocol::Icmp => (),
+		}
+		Ok(true)
+	}
+
+	/// Write a line about the session for the control socket, followed by one per flow.
+	fn report(&self, now: Instant, out: &mut String) {
+		let mut line = Line::new(out, "session").field("id", self.id);
+		if let Some(i) = &self.identity {
+			line = line.text("identity", &i.name);
+		}
+		let state = match (&self.client, self.token) {
+			(None, _) => "disconnected",
+			(Some(_), None) => "authenticating",
+			(Some(_), Some(_)) => "connected",
+		};
+		line = line.field("state", state)
+			.duration("since", now.saturating_duration_since(self.since))
+			.duration("age", now.saturating_duration_since(self.started));
+		if let Some(rtt) = self.keepalive.rtt() {
+			line = line.duration("rtt", rtt.latest).duration("smoothed-rtt", rtt.smoothed).duration("min-rtt", rtt.min);
+		}
+		if let Some(r) = self.client.as_ref().and_then(Connection::retransmissions) {
+			line = line.field("retransmissions", r);
+		}
+		line = line.field("queued", self.queue.queued())
+			.field("tcp", self.tcp_socks.len())
+			.field("udp", self.udp_socks.len() + self.accepted_udp.len())
+			.field("icmp", self.icmp_socks.len())
+			.field("disconnects", self.disconnects)
+			.field("flow-errors", self.flow_errors);
+		if let Some(e) = &self.last_error {
+			line = line.text("last-error", e);
+		}
+		line.end();
+		for (port, f) in sorted(&self.tcp_socks) {
+			f.report(self.id, "tcp", port, now, out);
+		}
+		for (port, f) in sorted(&self.udp_socks) {
+			f.report(self.id, "udp", port, now, out);
+		}
+		for (port, f) in sorted(&self.accepted_udp) {
+			f.report(self.id, "udp", port, now, out);
+		}
+		for (port, f) in sorted(&self.icmp_socks) {
+			f.report(self.id, "icmp", port, now, out);
+		}
+	}
+
+	/// Close a TCP connection and tell the client about it.
+	fn close_tcp(&mut self, local_port: u16, remote: SocketAddrV4) -> Result<(), SessionError> {
+		self.tcp_socks.remove(&local_port);
+		self.send(StupidType::TcpFinish, remote, local_port, &[])
+	}
+
+	/// Close a TCP connection that couldn't be established and tell the client why.
+	fn fail_tcp(&mut self, local_port: u16, remote: SocketAddrV4, error: ConnectError) -> Result<(), SessionError> {
+		self.tcp_socks.remove(&local_port);
+		self.send(StupidType::TcpFinish, remote, local_port, &[error as u8])
+	}
+
+	/// The time until the next ping, retransmission, idle socket expiry or throttled flow
+	/// having bandwidth again.
+	fn next_timeout(&self, config: &config::ServerConfig, now: Instant) -> Duration {
+		let throttled = self.tcp_socks.values().filter(|f| f.throttled).map(|f| &f.bandwidth)
+			.chain(self.udp_socks.values().filter(|f| f.throttled).map(|f| &f.bandwidth))
+			.chain(self.icmp_socks.values().filter(|f| f.throttled).map(|f| &f.bandwidth))
+			.map(|b| bandwidth_wait(&self.usage.borrow().bandwidth, b, now))
+			.min();
+		let udp_expiry = self.udp_socks.values().map(|f| f.last_used)
+			.chain(self.icmp_socks.values().map(|f| f.last_used))
+			.chain(self.accepted_udp.values().map(|f| f.last_used))
+			.min()
+			.map(|t| t + config.udp_timeout);
+		let tcp_expiry = self.tcp_socks.values().map(|f| f.last_used)
+			.min()
+			.map(|t| t + config.tcp_timeout);
+		udp_expiry.into_iter()
+			.chain(tcp_expiry)
+			.map(|t| t.saturating_duration_since(now))
+			.chain(throttled)
+			.chain(self.client.as_ref().and_then(|c| c.next_timeout(now)))
+			.fold(self.keepalive.next_timeout(now), Duration::min)
+	}
+
+	/// Ping the client if needed, check whether it is still responding, retransmit lost frames,
+	/// resume throttled flows and close all sockets that have been idle for too long.
+	fn expire(&mut self, config: &config::ServerConfig, registry: &Registry, now: Instant) -> Result<(), SessionError> {
+		if let Some(client) = &mut self.client {
+			client.poll(now).map_err(SessionError::Send)?;
+		}
+		if self.token.is_some() {
+			match self.keepalive.poll(now) {
+				Ok(Some(ping)) => {
+					let any = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+					self.send(StupidType::Ping, any, 0, &ping)?;
+				}
+				Ok(None) => (),
+				Err(Dead) => return Err(SessionError::Dead),
+			}
+		}
+		let resumed = self.tcp_socks.iter()
+			.filter(|(_, f)| f.throttled && bandwidth_wait(&self.usage.borrow().bandwidth, &f.bandwidth, now).is_zero())
+			.map(|(p, _)| *p)
+			.collect::<Vec<_>>();
+		for port in resumed {
+			self.tcp_socks.get_mut(&port).unwrap().throttled = false;
+			self.handle_tcp(registry, port, now)?;
+		}
+		let resumed = self.udp_socks.iter()
+			.filter(|(_, f)| f.throttled && bandwidth_wait(&self.usage.borrow().bandwidth, &f.bandwidth, now).is_zero())
+			.map(|(p, _)| *p)
+			.collect::<Vec<_>>();
+		for port in resumed {
+			self.udp_socks.get_mut(&port).unwrap().throttled = false;
+			self.handle_udp(port, now)?;
+		}
+		let resumed = self.icmp_socks.iter()
+			.filter(|(_, f)| f.throttled && bandwidth_wait(&self.usage.borrow().bandwidth, &f.bandwidth, now).is_zero())
+			.map(|(p, _)| *p)
+			.collect::<Vec<_>>();
+		for port in resumed {
+			self.icmp_socks.get_mut(&port).unwrap().throttled = false;
+			self.handle_icmp(port, now)?;
+		}
+		let expired = self.tcp_socks.iter()
+			.filter(|(_, f)| now.saturating_duration_since(f.last_used) >= config.tcp_timeout)
+			.map(|(p, f)| (*p, f.remote))
+			.collect::<Vec<_>>();
+		for (local_port, remote) in expired {
+			debug!(ctx: self.ctx.flow(local_port, remote), "closing idle TCP connection");
+			self.close_tcp(local_port, remote)?;
+		}
+		let timeout = config.udp_timeout;
+		self.udp_socks.retain(|_, f| now.saturating_duration_since(f.last_used) < timeout);
+		self.icmp_socks.retain(|_, f| now.saturating_duration_since(f.last_used) < timeout);
+		let forwards = &mut self.forwards;
+		self.accepted_udp.retain(|_, f| {
+			let keep = now.saturating_duration_since(f.last_used) < timeout;
+			if !keep {
+				if let Some(forward) = forwards.get_mut(&f.socket) {
+					forward.peers.remove(&f.remote);
+				}
+			}
+			keep
+		});
+		Ok(())
+	}
+}
+
+/// Parse the hello a client must start with.
+fn parse_hello(config: &config::ServerConfig, sh: &StupidDataHeader, data: &[u8]) -> Result<Hello, SessionError> {
+	let token_len = mem::size_of::<SessionToken>();
+	match sh.ty() {
+		Ok(StupidType::Hello) if data.len() >= token_len => {
+			let identity = authenticate(config, &data[token_len..])?.cloned();
+			// Fall back to no compression if the client asks for something else.
+			let compression = Compression::try_from(sh.local())
+				.ok()
+				.filter(|c| *c == config.compression.algorithm)
+				.unwrap_or(Compression::None);
+			Ok(Hello { token: data[..token_len].try_into().unwrap(), compression, identity })
+		}
+		_ => Err(SessionError::Unauthenticated),
+	}
+}
+
+/// Check whether a frame is a hello that authenticates the client.
+fn check_hello(config: &config::ServerConfig, frame: &[u8]) -> Result<(), SessionError> {
+	let (sh, data, _) = StupidDataHeader::from_raw(frame).map_err(|_| SessionError::Unauthenticated)?;
+	parse_hello(config, &sh, data).map(drop)
+}
+
+/// Check the key sent in the hello of a client, returning the identity it belongs to if any.
+fn authenticate<'a>(config: &'a config::ServerConfig, key: &[u8]) -> Result<Option<&'a Identity>, SessionError> {
+	// Don't stop at the first match to avoid leaking which key matched through timing.
+	let identity = config.identities
+		.iter()
+		.fold(None, |found, i| if constant_time_eq(i.key.as_bytes(), key) { Some(i) } else { found });
+	let anonymous = config.keys
+		.iter()
+		.fold(false, |ok, k| constant_time_eq(k.as_bytes(), key) | ok);
+	match identity {
+		Some(i) => Ok(Some(i)),
+		None if anonymous || (config.keys.is_empty() && config.identities_file.is_none()) => Ok(None),
+		None => Err(SessionError::Unauthenticated),
+	}
+}
+
+/// Whether a stream that is connecting has been connected, or why it failed.
+fn is_connected(stream: &TcpStream) -> Result<bool, Error> {
+	match stream.take_error() {
+		Ok(Some(e)) | Err(e) => Err(e),
+		Ok(None) => match stream.peer_addr() {
+			Ok(_) => Ok(true),
+			Err(e) if e.kind() == ErrorKind::NotConnected => Ok(false),
+			Err(e) => Err(e),
+		},
+	}
+}
+
+/// Connect the socket of a UDP flow to the relay the upstream proxy set up for it and send
+/// the datagrams that were waiting for it.
+fn associate(udp: &mut UdpUpstream, relay: SocketAddr) -> Result<(), Error> {
+	let association = udp.association.as_mut().unwrap();
+	// Proxies often reply with an unspecified address, meaning their own.
+	let relay = match relay.ip().is_unspecified() {
+		true => SocketAddr::new(association.control.peer_addr()?.ip(), relay.port()),
+		false => relay,
+	};
+	udp.socket.connect(relay)?;
+	association.handshake = None;
+	for datagram in mem::take(&mut association.queued) {
+		udp.send(&datagram)?;
+	}
+	Ok(())
+}
+
+/// The flows of a session by port, in order.
+fn sorted<S>(flows: &HashMap<u16, Flow<S>>) -> Vec<(u16, &Flow<S>)> {
+	let mut flows = flows.iter().map(|(p, f)| (*p, f)).collect::<Vec<_>>();
+	flows.sort_unstable_by_key(|(p, _)| *p);
+	flows
+}
+
+fn random_token() -> SessionToken {
+	let mut token = SessionToken::default();
+	stupid::fill_random(&mut token);
+	token
+}
+
+#[derive(Debug)]
+pub enum RunError {
+	Bind(Error),
+	Poll(Error),
+	Stdio(Error),
+}
+
+impl fmt::Display for RunError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Bind(e) => write!(f, "failed to bind: {}", e),
+			Self::Poll(e) => write!(f, "failed to poll: {}", e),
+			Self::Stdio(e) => write!(f, "failed to use stdin & stdout: {}", e),
+		}
+	}
+}
+
+/// An error that ends the connection with a client.
+#[derive(Debug)]
+enum SessionError {
+	/// The first frame was not a hello with a valid key.
+	Unauthenticated,
+	Receive(ReceiveError),
+	Send(Error),
+	/// Nothing was received from the client within the keepalive timeout.
+	Dead,
+}
+
+impl fmt::Display for SessionError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Unauthenticated => f.write_str("client failed to authenticate"),
+			Self::Receive(e) => write!(f, "failed to receive from client: {}", e),
+			Self::Send(e) => write!(f, "failed to send to client: {}", e),
+			Self::Dead => f.write_str("client stopped responding"),
+		}
+	}
+}
+
+/// A limit on the flows of a client.
+#[derive(Clone, Copy, Debug)]
+enum Quota {
+	Tcp,
+	Udp,
+	Rate,
+	/// Too many names are being resolved.
+	Lookups,
+}
+
+impl fmt::Display for Quota {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			Self::Tcp => "too many TCP connections",
+			Self::Udp => "too many UDP & ICMP sockets",
+			Self::Rate => "opening flows too fast",
+			Self::Lookups => "resolving too many names",
+		})
+	}
+}
+
+/// Whether the bandwidth limits of both a session and one of its flows allow relaying more
+/// data.
+fn has_bandwidth(session: &mut Option<TokenBucket>, flow: &mut Option<TokenBucket>, now: Instant) -> bool {
+	session.as_mut().is_none_or(|b| b.ready(now)) && flow.as_mut().is_none_or(|b| b.ready(now))
+}
+
+/// The time until both a session and one of its flows have bandwidth again.
+fn bandwidth_wait(session: &Option<TokenBucket>, flow: &Option<TokenBucket>, now: Instant) -> Duration {
+	session.iter()
+		.chain(flow)
+		.map(|b| b.next_ready(now))
+		.fold(Duration::ZERO, Duration::max)
+}
+
+/// Count data relayed for a flow against the bandwidth limits.
+fn use_bandwidth(session: &mut Option<TokenBucket>, flow: &mut Option<TokenBucket>, amount: usize) {
+	for b in session.iter_mut().chain(flow.iter_mut()) {
+		b.take(amount);
+	}
+}
+
+/// An error that only closes a single flow.
+#[derive(Debug)]
+enum FlowError {
+	/// The client referred to a flow that doesn't exist.
+	Unknown,
+	/// The name to connect to couldn't be resolved.
+	Resolve,
+	/// The access control list doesn't allow the destination.
+	Denied,
+	Quota(Quota),
+	Window(WindowError),
+	Io(Error),
+}
+
+impl FlowError {
+	/// What to tell the client if the error happened while connecting.
+	fn connect_error(&self) -> ConnectError {
+		match self {
+			Self::Resolve => ConnectError::NotResolved,
+			Self::Denied => ConnectError::Denied,
+			Self::Quota(_) => ConnectError::Limited,
+			Self::Io(e) => e.into(),
+			_ => ConnectError::Failed,
+		}
+	}
+}
+
+impl From<Error> for FlowError {
+	fn from(e: Error) -> Self {
+		Self::Io(e)
+	}
+}
+
+impl From<WindowError> for FlowError {
+	fn from(e: WindowError) -> Self {
+		Self::Window(e)
+	}
+}
+
+impl fmt::Display for FlowError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Unknown => f.write_str("unknown flow"),
+			Self::Resolve => f.write_str("failed to resolve name"),
+			Self::Denied => f.write_str("destination not allowed"),
+			Self::Quota(q) => q.fmt(f),
+			Self::Window(e) => e.fmt(f),
+			Self::Io(e) => e.fmt(f),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use mio::net::UnixStream;
+
+	/// The configuration of a server started with `options`.
+	fn config(options: &[&str]) -> config::ServerConfig {
+		let args = options.iter().copied().chain(["server"]).map(String::from);
+		match config::from_args(args) {
+			Ok(config::Mode::Server(c)) => c,
+			_ => panic!("invalid options {:?}", options),
+		}
+	}
+
+	/// A session of a client that authenticated without an identity, with the other end of
+	/// its connection.
+	fn session(config: &config::ServerConfig, poll: &mio::Poll) -> (Session, UnixStream) {
+		let (stream, peer) = UnixStream::pair().unwrap();
+		let (resolver, _) = resolve::start(poll.registry(), mio::Token(RESOLVE_EVENT)).unwrap();
+		let client = Connection::Stream(Box::new(stream), FrameReader::new(), FrameWriter::new());
+		let mut session = Session::new(0, client, config, resolver, Instant::now());
+		session.token = Some(random_token());
+		(session, peer)
+	}
+
+	#[test]
+	fn echo_acl() {
+		let poll = mio::Poll::new().unwrap();
+		let config = config(&["--acl", "allow icmp 127.0.0.1/32"]);
+		let (mut session, _peer) = session(&config, &poll);
+		let now = Instant::now();
+		let result = session.send_echo(poll.registry(), now, 8, "127.0.0.2:1".parse().unwrap(), b"ping");
+		assert!(matches!(result, Err(FlowError::Denied)));
+		match session.send_echo(poll.registry(), now, 7, "127.0.0.1:1".parse().unwrap(), b"ping") {
+			Ok(()) => (),
+			// The group isn't in net.ipv4.ping_group_range.
+			Err(FlowError::Io(e)) if e.kind() == ErrorKind::PermissionDenied => return,
+			Err(e) => panic!("{}", e),
+		}
+		// The identifier is the same, the destination isn't allowed.
+		let result = session.send_echo(poll.registry(), now, 7, "127.0.0.2:2".parse().unwrap(), b"ping");
+		assert!(matches!(result, Err(FlowError::Denied)));
+	}
+
+	#[test]
+	fn udp_destination() {
+		let poll = mio::Poll::new().unwrap();
+		let config = config(&["--acl", "allow udp 127.0.0.1/32"]);
+		let (mut session, _peer) = session(&config, &poll);
+		session.client = None;
+		let destination = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+		destination.set_nonblocking(true).unwrap();
+		let (now, remote) = (Instant::now(), address(&destination));
+		session.send_udp(poll.registry(), now, 1, remote, b"query").unwrap();
+		// The destination isn't allowed, and the datagram doesn't go to that of the flow either.
+		let other = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 2), remote.port());
+		session.send_udp(poll.registry(), now, 1, other, b"other").unwrap();
+		let mut buf = [0; 16];
+		assert_eq!(destination.recv(&mut buf).unwrap(), 5);
+		assert_eq!(&buf[..5], b"query");
+		assert_eq!(destination.recv(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+		let reject = session.queue.pop().unwrap();
+		assert_eq!(reject.ty as u8, StupidType::Reject as u8);
+		assert!(session.udp_socks.contains_key(&1));
+
+		// The same goes for flows to a named host.
+		let any = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, remote.port());
+		let named = |name| crate::stupid::name_datagram(name, b"query");
+		session.send_udp_name(poll.registry(), now, 2, any, &named("127.0.0.1")).unwrap();
+		session.send_udp_name(poll.registry(), now, 2, any, &named("localhost")).unwrap();
+		assert_eq!(destination.recv(&mut buf).unwrap(), 5);
+		assert_eq!(destination.recv(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+		assert_eq!(session.queue.pop().unwrap().ty as u8, StupidType::Reject as u8);
+	}
+
+	#[test]
+	fn echo_routes() {
+		let poll = mio::Poll::new().unwrap();
+		let config = config(&["--acl", "allow icmp 127.0.0.0/8", "--egress-route", "127.0.0.2/32 address=127.0.0.1"]);
+		let (mut session, _peer) = session(&config, &poll);
+		let now = Instant::now();
+		match session.send_echo(poll.registry(), now, 7, "127.0.0.1:1".parse().unwrap(), b"ping") {
+			Ok(()) => (),
+			// The group isn't in net.ipv4.ping_group_range.
+			Err(FlowError::Io(e)) if e.kind() == ErrorKind::PermissionDenied => return,
+			Err(e) => panic!("{}", e),
+		}
+		assert_eq!(session.icmp_socks[&7].socket.egress, Egress::default());
+		// The identifier is the same, the destination is routed elsewhere.
+		session.send_echo(poll.registry(), now, 7, "127.0.0.2:2".parse().unwrap(), b"ping").unwrap();
+		assert_eq!(session.icmp_socks[&7].socket.egress.address, Some("127.0.0.1".parse().unwrap()));
+	}
+
+	/// The address of a loopback socket.
+	fn address(socket: &std::net::UdpSocket) -> SocketAddrV4 {
+		match socket.local_addr().unwrap() {
+			SocketAddr::V4(a) => a,
+			SocketAddr::V6(_) => unreachable!(),
+		}
+	}
+
+	#[test]
+	fn udp_quota() {
+		let poll = mio::Poll::new().unwrap();
+		let config = config(&["--acl", "allow any 127.0.0.1/32", "--max-udp-sockets", "1"]);
+		let (mut session, _peer) = session(&config, &poll);
+		let destination = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+		let (now, remote) = (Instant::now(), address(&destination));
+		session.send_udp(poll.registry(), now, 1, remote, b"query").unwrap();
+		session.send_udp(poll.registry(), now, 1, remote, b"query").unwrap();
+		let result = session.send_udp(poll.registry(), now, 2, remote, b"query");
+		assert!(matches!(result, Err(FlowError::Quota(Quota::Udp))));
+		// ICMP identifiers count against the same limit.
+		let result = session.send_echo(poll.registry(), now, 3, remote, b"ping");
+		assert!(matches!(result, Err(FlowError::Quota(Quota::Udp))));
+	}
+
+	#[test]
+	fn rate_quota() {
+		let poll = mio::Poll::new().unwrap();
+		let config = config(&["--acl", "allow any 127.0.0.1/32", "--connection-rate-limit", "1"]);
+		let (mut session, _peer) = session(&config, &poll);
+		let destination = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+		let (now, remote) = (Instant::now(), address(&destination));
+		session.send_udp(poll.registry(), now, 1, remote, b"query").unwrap();
+		let result = session.send_udp(poll.registry(), now, 2, remote, b"query");
+		assert!(matches!(result, Err(FlowError::Quota(Quota::Rate))));
+		let later = now + Duration::from_secs(1);
+		session.send_udp(poll.registry(), later, 2, remote, b"query").unwrap();
+	}
+
+	#[test]
+	fn forward_quota() {
+		let poll = mio::Poll::new().unwrap();
+		let config = config(&["--allow-remote-forwards", "true", "--max-tcp-connections", "1"]);
+		let (mut session, _peer) = session(&config, &poll);
+		session.client = None;
+		session.bind_forward(poll.registry(), 1, "127.0.0.1:0".parse().unwrap(), Protocol::Tcp).unwrap();
+		let listen = match &session.forwards[&1].socket {
+			ForwardSocket::Tcp(l) => l.local_addr().unwrap(),
+			ForwardSocket::Udp(_) => unreachable!(),
+		};
+		let _first = std::net::TcpStream::connect(listen).unwrap();
+		let mut second = std::net::TcpStream::connect(listen).unwrap();
+		session.handle_forward(poll.registry(), 1, Instant::now()).unwrap();
+		// The peer over the limit of TCP connections is closed rather than accepted.
+		assert_eq!(session.tcp_socks.len(), 1);
+		let accepted = std::iter::from_fn(|| session.queue.pop()).filter(|f| f.ty as u8 == StupidType::Accept as u8).count();
+		assert_eq!(accepted, 1);
+		second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+		match second.read(&mut [0; 1]) {
+			Ok(0) => (),
+			Err(e) => assert_eq!(e.kind(), ErrorKind::ConnectionReset),
+			Ok(_) => unreachable!(),
+		}
+	}
+
+	#[test]
+	fn throttling() {
+		let poll = mio::Poll::new().unwrap();
+		let config = config(&["--acl", "allow any 127.0.0.1/32", "--flow-rate-limit", "100"]);
+		let (mut session, _peer) = session(&config, &poll);
+		// Leave frames in the queue.
+		session.client = None;
+		let destination = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+		destination.set_nonblocking(true).unwrap();
+		let now = Instant::now();
+		// A datagram may take more than is left, the ones after it are dropped and the client
+		// is told once.
+		for _ in 0..3 {
+			session.send_udp(poll.registry(), now, 1, address(&destination), &[0; 200]).unwrap();
+		}
+		let reject = session.queue.pop().unwrap();
+		assert_eq!(reject.ty as u8, StupidType::Reject as u8);
+		let (_, data, _) = StupidDataHeader::from_raw(&reject.data).unwrap();
+		assert_eq!(ConnectError::from_data(data), Some(ConnectError::Limited));
+		assert!(session.queue.pop().is_none());
+		let mut buf = [0; 512];
+		assert_eq!(destination.recv_from(&mut buf).unwrap().0, 200);
+		assert_eq!(destination.recv_from(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+		// Replies wait in the socket until the flow has bandwidth again.
+		let source = session.udp_socks[&1].socket.socket.local_addr().unwrap();
+		destination.send_to(b"reply", source).unwrap();
+		session.handle_udp(1, now).unwrap();
+		assert!(session.udp_socks[&1].throttled);
+		assert!(session.queue.pop().is_none());
+		assert!(session.next_timeout(&config, now) < Duration::from_secs(2));
+		session.expire(&config, poll.registry(), now + Duration::from_secs(2)).unwrap();
+		assert!(!session.udp_socks[&1].throttled);
+		let frames = std::iter::from_fn(|| session.queue.pop()).collect::<Vec<_>>();
+		let reply = frames.iter().find(|f| f.ty as u8 == StupidType::UDP as u8).unwrap();
+		assert_eq!(StupidDataHeader::from_raw(&reply.data).unwrap().1, b"reply");
+	}
+
+	/// Add a session of the identity `name` to `server`, as if its client authenticated.
+	fn add_session(server: &mut Server, poll: &mio::Poll, name: &str, now: Instant) -> u32 {
+		let (stream, _) = UnixStream::pair().unwrap();
+		let id = server.add_client(Box::new(stream), poll.registry(), now).unwrap();
+		let identity = server.config.identities.iter().find(|i| i.name == name).cloned();
+		let usage = server.usage(identity.as_ref(), now);
+		let session = server.sessions.get_mut(&id).unwrap();
+		session.set_identity(identity, usage, &server.config, now);
+		session.token = Some(random_token());
+		// Leave frames in the queue, as the other end is gone.
+		session.client = None;
+		id
+	}
+
+	#[test]
+	fn identities() {
+		let path = std::env::temp_dir().join(format!("stupid_tunnel_server_identities_{}.toml", std::process::id()));
+		std::fs::write(&path, "[alice]\nkey = 'a'\nmax_udp_sockets = 1\n\n[bob]\nkey = 'b'\n").unwrap();
+		let poll = mio::Poll::new().unwrap();
+		let mut server = Server::new(config(&["--acl", "allow any 127.0.0.1/32", "--identities", path.to_str().unwrap()]));
+		server.resolver = Some(resolve::start(poll.registry(), mio::Token(RESOLVE_EVENT)).unwrap().0);
+		let now = Instant::now();
+		let (first, second) = (add_session(&mut server, &poll, "alice", now), add_session(&mut server, &poll, "alice", now));
+		let bob = add_session(&mut server, &poll, "bob", now);
+		let destination = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+		let (registry, remote) = (poll.registry(), address(&destination));
+
+		// The sessions of an identity share its limits.
+		server.sessions.get_mut(&first).unwrap().send_udp(registry, now, 1, remote, b"query").unwrap();
+		let result = server.sessions.get_mut(&second).unwrap().send_udp(registry, now, 1, remote, b"query");
+		assert!(matches!(result, Err(FlowError::Quota(Quota::Udp))));
+		server.sessions.get_mut(&bob).unwrap().send_udp(registry, now, 1, remote, b"query").unwrap();
+
+		std::fs::write(&path, "[alice]\nkey = 'a'\nmax_udp_sockets = 2\n").unwrap();
+		server.reload_identities(now);
+		std::fs::remove_file(&path).unwrap();
+		assert!(!server.sessions.contains_key(&bob));
+		server.sessions.get_mut(&second).unwrap().send_udp(registry, now, 1, remote, b"query").unwrap();
+		let result = server.sessions.get_mut(&second).unwrap().send_udp(registry, now, 2, remote, b"query");
+		assert!(matches!(result, Err(FlowError::Quota(Quota::Udp))));
+		// The flows of sessions that ended no longer count.
+		server.sessions.remove(&first);
+		server.sessions.get_mut(&second).unwrap().send_udp(registry, now, 2, remote, b"query").unwrap();
+	}
 }
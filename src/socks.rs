@@ -0,0 +1,436 @@
+//! Just enough SOCKS5 (RFC 1928) and its username/password authentication (RFC 1929) to
+//! serve CONNECT and UDP ASSOCIATE requests, and to make them to another server.
+//!
+//! Parsers return `Ok(None)` if the message isn't complete yet, and otherwise the message
+//! with the amount of bytes it took.
+
+use core::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+
+pub const VERSION: u8 = 5;
+/// The version of the username/password subnegotiation.
+const PASSWORD_VERSION: u8 = 1;
+
+pub const METHOD_NONE: u8 = 0;
+pub const METHOD_PASSWORD: u8 = 2;
+/// Sent instead of a method if none of the methods of the client are acceptable.
+pub const METHOD_UNACCEPTABLE: u8 = 0xff;
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+	Connect,
+	Bind,
+	UdpAssociate,
+}
+
+/// The destination of a request or datagram.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Address {
+	Ip(SocketAddr),
+	Domain(String, u16),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+	pub command: Command,
+	pub address: Address,
+}
+
+/// The reply of a server to a request.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Response {
+	pub reply: Reply,
+	/// The address the server bound to.
+	pub bound: Address,
+}
+
+/// The outcome of a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Reply {
+	Succeeded = 0,
+	Failure = 1,
+	NotAllowed = 2,
+	NetworkUnreachable = 3,
+	HostUnreachable = 4,
+	ConnectionRefused = 5,
+	TtlExpired = 6,
+	CommandNotSupported = 7,
+	AddressTypeNotSupported = 8,
+}
+
+/// Parse the methods the client offers to authenticate with.
+pub fn parse_greeting(data: &[u8]) -> Result<Option<(&[u8], usize)>, ParseError> {
+	let (version, n) = match data {
+		[v, n, ..] => (*v, usize::from(*n)),
+		_ => return Ok(None),
+	};
+	check_version(version, VERSION)?;
+	Ok(data.get(2..2 + n).map(|m| (m, 2 + n)))
+}
+
+/// The reply to a greeting, with the chosen method.
+pub fn method(method: u8) -> [u8; 2] {
+	[VERSION, method]
+}
+
+/// A username and password.
+pub type Credentials<'a> = (&'a [u8], &'a [u8]);
+
+/// Parse the username and password the client authenticates with.
+pub fn parse_password(data: &[u8]) -> Result<Option<(Credentials<'_>, usize)>, ParseError> {
+	let (version, rest) = match data.split_first() {
+		Some(r) => r,
+		None => return Ok(None),
+	};
+	check_version(*version, PASSWORD_VERSION)?;
+	let (username, rest) = match length_prefixed(rest) {
+		Some(r) => r,
+		None => return Ok(None),
+	};
+	let (password, _) = match length_prefixed(rest) {
+		Some(r) => r,
+		None => return Ok(None),
+	};
+	Ok(Some(((username, password), 3 + username.len() + password.len())))
+}
+
+/// The reply to a username and password.
+pub fn password_status(ok: bool) -> [u8; 2] {
+	[PASSWORD_VERSION, if ok { 0 } else { 1 }]
+}
+
+/// The greeting of a client, offering methods to authenticate with.
+pub fn greeting(methods: &[u8]) -> Vec<u8> {
+	let mut out = vec![VERSION, methods.len().try_into().expect("too many methods")];
+	out.extend_from_slice(methods);
+	out
+}
+
+/// Parse the method the server chose.
+pub fn parse_method(data: &[u8]) -> Result<Option<(u8, usize)>, ParseError> {
+	match data {
+		[v, m, ..] => check_version(*v, VERSION).map(|()| Some((*m, 2))),
+		_ => Ok(None),
+	}
+}
+
+/// The username and password to authenticate with, which must be at most 255 bytes long.
+pub fn password(username: &[u8], password: &[u8]) -> Vec<u8> {
+	let mut out = vec![PASSWORD_VERSION];
+	for s in [username, password] {
+		out.push(s.len().try_into().expect("credential is too long"));
+		out.extend_from_slice(s);
+	}
+	out
+}
+
+/// Parse whether the server accepted the username and password.
+pub fn parse_password_status(data: &[u8]) -> Result<Option<(bool, usize)>, ParseError> {
+	match data {
+		[v, status, ..] => check_version(*v, PASSWORD_VERSION).map(|()| Some((*status == 0, 2))),
+		_ => Ok(None),
+	}
+}
+
+/// A request of a client.
+pub fn request(command: Command, address: &Address) -> Vec<u8> {
+	let command = match command {
+		Command::Connect => 1,
+		Command::Bind => 2,
+		Command::UdpAssociate => 3,
+	};
+	let mut out = vec![VERSION, command, 0];
+	address.write(&mut out);
+	out
+}
+
+/// Parse a request. Unknown commands are reported once the request is complete, so the
+/// client can be told.
+pub fn parse_request(data: &[u8]) -> Result<Option<(Request, usize)>, ParseError> {
+	let (version, command) = match data {
+		[v, c, _, ..] => (*v, *c),
+		_ => return Ok(None),
+	};
+	check_version(version, VERSION)?;
+	let (address, len) = match Address::parse(&data[3..])? {
+		Some(r) => r,
+		None => return Ok(None),
+	};
+	let command = match command {
+		1 => Command::Connect,
+		2 => Command::Bind,
+		3 => Command::UdpAssociate,
+		c => return Err(ParseError::Command(c)),
+	};
+	Ok(Some((Request { command, address }, 3 + len)))
+}
+
+/// The reply to a request, with the address the server bound to.
+pub fn reply(reply: Reply, bound: SocketAddr) -> Vec<u8> {
+	let mut out = vec![VERSION, reply as u8, 0];
+	Address::Ip(bound).write(&mut out);
+	out
+}
+
+/// Parse the reply to a request.
+pub fn parse_reply(data: &[u8]) -> Result<Option<(Response, usize)>, ParseError> {
+	let (version, reply) = match data {
+		[v, r, _, ..] => (*v, Reply::from(*r)),
+		_ => return Ok(None),
+	};
+	check_version(version, VERSION)?;
+	Ok(Address::parse(&data[3..])?.map(|(bound, len)| (Response { reply, bound }, 3 + len)))
+}
+
+/// How many more bytes a reply needs at least, so it can be read without reading past it.
+pub fn missing_reply(data: &[u8]) -> usize {
+	let len = match data {
+		[_, _, _, ATYP_IPV4, ..] => 3 + 1 + 4 + 2,
+		[_, _, _, ATYP_IPV6, ..] => 3 + 1 + 16 + 2,
+		[_, _, _, ATYP_DOMAIN, n, ..] => 3 + 2 + usize::from(*n) + 2,
+		_ => 3 + 2,
+	};
+	len.saturating_sub(data.len())
+}
+
+/// Parse the header of a datagram relayed for a UDP association, returning the destination
+/// and the data. Fragments are rejected.
+pub fn parse_datagram(data: &[u8]) -> Result<(Address, &[u8]), ParseError> {
+	let (fragment, rest) = match data {
+		[0, 0, f, rest @ ..] => (*f, rest),
+		_ => return Err(ParseError::Malformed),
+	};
+	if fragment != 0 {
+		return Err(ParseError::Fragmented);
+	}
+	let (address, len) = Address::parse(rest)?.ok_or(ParseError::Malformed)?;
+	Ok((address, &rest[len..]))
+}
+
+/// Write the header of a datagram relayed for a UDP association, with the address it came
+/// from, or the destination if sent to the relay of a server.
+pub fn datagram_header(address: &Address, out: &mut Vec<u8>) {
+	out.extend_from_slice(&[0, 0, 0]);
+	address.write(out);
+}
+
+impl From<u8> for Reply {
+	fn from(reply: u8) -> Self {
+		match reply {
+			0 => Self::Succeeded,
+			2 => Self::NotAllowed,
+			3 => Self::NetworkUnreachable,
+			4 => Self::HostUnreachable,
+			5 => Self::ConnectionRefused,
+			6 => Self::TtlExpired,
+			7 => Self::CommandNotSupported,
+			8 => Self::AddressTypeNotSupported,
+			_ => Self::Failure,
+		}
+	}
+}
+
+impl fmt::Display for Reply {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			Self::Succeeded => "succeeded",
+			Self::Failure => "general failure",
+			Self::NotAllowed => "not allowed by ruleset",
+			Self::NetworkUnreachable => "network unreachable",
+			Self::HostUnreachable => "host unreachable",
+			Self::ConnectionRefused => "connection refused",
+			Self::TtlExpired => "TTL expired",
+			Self::CommandNotSupported => "command not supported",
+			Self::AddressTypeNotSupported => "address type not supported",
+		})
+	}
+}
+
+impl Address {
+	fn parse(data: &[u8]) -> Result<Option<(Self, usize)>, ParseError> {
+		let (ty, rest) = match data.split_first() {
+			Some(r) => r,
+			None => return Ok(None),
+		};
+		let len = match *ty {
+			ATYP_IPV4 => 4,
+			ATYP_IPV6 => 16,
+			ATYP_DOMAIN => match rest.first() {
+				Some(n) => 1 + usize::from(*n),
+				None => return Ok(None),
+			},
+			t => return Err(ParseError::AddressType(t)),
+		};
+		let (address, port) = match rest.get(..len + 2) {
+			Some(r) => r.split_at(len),
+			None => return Ok(None),
+		};
+		let port = u16::from_be_bytes([port[0], port[1]]);
+		let address = match *ty {
+			ATYP_IPV4 => Self::Ip((Ipv4Addr::from(<[u8; 4]>::try_from(address).unwrap()), port).into()),
+			ATYP_IPV6 => Self::Ip((Ipv6Addr::from(<[u8; 16]>::try_from(address).unwrap()), port).into()),
+			_ => {
+				let name = str::from_utf8(&address[1..]).map_err(|_| ParseError::Malformed)?;
+				Self::Domain(name.into(), port)
+			}
+		};
+		Ok(Some((address, 1 + len + 2)))
+	}
+
+	/// Append the address type, address and port.
+	fn write(&self, out: &mut Vec<u8>) {
+		let port = match self {
+			Self::Ip(SocketAddr::V4(a)) => {
+				out.push(ATYP_IPV4);
+				out.extend_from_slice(&a.ip().octets());
+				a.port()
+			}
+			Self::Ip(SocketAddr::V6(a)) => {
+				out.push(ATYP_IPV6);
+				out.extend_from_slice(&a.ip().octets());
+				a.port()
+			}
+			Self::Domain(name, port) => {
+				out.push(ATYP_DOMAIN);
+				out.push(name.len().try_into().expect("domain name is too long"));
+				out.extend_from_slice(name.as_bytes());
+				*port
+			}
+		};
+		out.extend_from_slice(&port.to_be_bytes());
+	}
+
+	pub fn port(&self) -> u16 {
+		match self {
+			Self::Ip(a) => a.port(),
+			Self::Domain(_, port) => *port,
+		}
+	}
+}
+
+impl fmt::Display for Address {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Ip(a) => a.fmt(f),
+			Self::Domain(name, port) => write!(f, "{}:{}", name, port),
+		}
+	}
+}
+
+fn check_version(version: u8, expected: u8) -> Result<(), ParseError> {
+	(version == expected).then_some(()).ok_or(ParseError::Version(version))
+}
+
+/// Split off a string prefixed with its length as a single byte.
+fn length_prefixed(data: &[u8]) -> Option<(&[u8], &[u8])> {
+	let (len, rest) = data.split_first()?;
+	let len = usize::from(*len);
+	(rest.len() >= len).then(|| rest.split_at(len))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+	Version(u8),
+	Command(u8),
+	AddressType(u8),
+	/// The datagram is a fragment, which isn't supported.
+	Fragmented,
+	Malformed,
+}
+
+impl ParseError {
+	/// What to reply to a request that failed to parse.
+	pub fn reply(&self) -> Reply {
+		match self {
+			Self::Command(_) => Reply::CommandNotSupported,
+			Self::AddressType(_) => Reply::AddressTypeNotSupported,
+			_ => Reply::Failure,
+		}
+	}
+}
+
+impl fmt::Display for ParseError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Version(v) => write!(f, "unsupported version {}", v),
+			Self::Command(c) => write!(f, "unsupported command {}", c),
+			Self::AddressType(t) => write!(f, "unsupported address type {}", t),
+			Self::Fragmented => f.write_str("fragmented datagram"),
+			Self::Malformed => f.write_str("malformed message"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn greeting() {
+		assert_eq!(parse_greeting(&[5, 2, 0]), Ok(None));
+		assert_eq!(parse_greeting(&[5, 2, 0, 2, 9]), Ok(Some((&[0, 2][..], 4))));
+		assert_eq!(parse_greeting(&[4, 1, 0]), Err(ParseError::Version(4)));
+		assert_eq!(parse_password(&[1, 1, b'a', 2, b'b']), Ok(None));
+		assert_eq!(parse_password(&[1, 1, b'a', 2, b'b', b'c']), Ok(Some(((&b"a"[..], &b"bc"[..]), 6))));
+	}
+
+	#[test]
+	fn request() {
+		let (r, len) = parse_request(&[5, 1, 0, 1, 10, 0, 0, 1, 0, 80, 0xff]).unwrap().unwrap();
+		assert_eq!(len, 10);
+		assert_eq!(r, Request { command: Command::Connect, address: Address::Ip("10.0.0.1:80".parse().unwrap()) });
+		let mut data = vec![5, 3, 0, 3, 11];
+		data.extend_from_slice(b"example.org");
+		assert_eq!(parse_request(&data), Ok(None));
+		data.extend_from_slice(&[1, 187]);
+		let (r, _) = parse_request(&data).unwrap().unwrap();
+		assert_eq!(r, Request { command: Command::UdpAssociate, address: Address::Domain("example.org".into(), 443) });
+		let mut data = vec![5, 1, 0, 4];
+		data.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+		data.extend_from_slice(&[0, 22]);
+		assert_eq!(parse_request(&data).unwrap().unwrap().0.address, Address::Ip("[::1]:22".parse().unwrap()));
+		assert_eq!(parse_request(&[5, 9, 0, 1, 1, 1, 1, 1, 0, 0]), Err(ParseError::Command(9)));
+		assert_eq!(parse_request(&[5, 1, 0, 2, 1, 1]), Err(ParseError::AddressType(2)));
+	}
+
+	#[test]
+	fn client() {
+		assert_eq!(super::greeting(&[METHOD_NONE, METHOD_PASSWORD]), [5, 2, 0, 2]);
+		assert_eq!(parse_method(&[5]), Ok(None));
+		assert_eq!(parse_method(&[5, 2]), Ok(Some((METHOD_PASSWORD, 2))));
+		assert_eq!(password(b"a", b"bc"), [1, 1, b'a', 2, b'b', b'c']);
+		assert_eq!(parse_password_status(&[1, 1]), Ok(Some((false, 2))));
+		let r = super::request(Command::UdpAssociate, &Address::Ip("0.0.0.0:0".parse().unwrap()));
+		assert_eq!(r, [5, 3, 0, 1, 0, 0, 0, 0, 0, 0]);
+		let reply = reply(Reply::ConnectionRefused, "[::1]:1080".parse().unwrap());
+		for len in 0..reply.len() {
+			assert_eq!(parse_reply(&reply[..len]), Ok(None));
+			assert!(missing_reply(&reply[..len]) > 0 && len + missing_reply(&reply[..len]) <= reply.len());
+		}
+		assert_eq!(missing_reply(&reply), 0);
+		let response = Response { reply: Reply::ConnectionRefused, bound: Address::Ip("[::1]:1080".parse().unwrap()) };
+		assert_eq!(parse_reply(&reply), Ok(Some((response, reply.len()))));
+		assert_eq!(parse_reply(&[5, 0, 0, 3, 1, b'a', 0]), Ok(None));
+		assert_eq!(missing_reply(&[5, 0, 0, 3, 1, b'a', 0]), 1);
+	}
+
+	#[test]
+	fn datagram() {
+		let mut data = Vec::new();
+		datagram_header(&Address::Ip("1.2.3.4:53".parse().unwrap()), &mut data);
+		data.extend_from_slice(b"query");
+		assert_eq!(data[..10], [0, 0, 0, 1, 1, 2, 3, 4, 0, 53]);
+		let (address, payload) = parse_datagram(&data).unwrap();
+		assert_eq!(address, Address::Ip("1.2.3.4:53".parse().unwrap()));
+		assert_eq!(payload, b"query");
+		assert_eq!(parse_datagram(&data[..6]), Err(ParseError::Malformed));
+		data[2] = 1;
+		assert_eq!(parse_datagram(&data), Err(ParseError::Fragmented));
+	}
+}
@@ -1,49 +1,274 @@
 use super::*;
-use std::io::{Error, Read, Write};
-use std::net::{SocketAddr, SocketAddrV4};
-use mio::net::TcpStream;
+use std::io::{Error, ErrorKind};
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use mio::net::{TcpStream, UdpSocket, UnixStream};
 use mio::{Registry, Token, Interest};
 
 pub struct StupidClient {
-	server: TcpStream,
+	endpoint: Endpoint,
+	key: Vec<u8>,
+	server: Option<Server>,
+	/// The session assigned by the server, which is resumed when reconnecting.
+	session: Option<SessionToken>,
+	keepalive: Keepalive,
+	/// Frames waiting to be sent, which are kept while reconnecting except those of TCP flows.
+	queue: Scheduler,
+	/// The compression to ask the server for.
+	compression: Compression,
+	compression_threshold: usize,
+	decompressor: Decompressor,
+}
+
+/// Where the server is and how to reach it.
+pub enum Endpoint {
+	Tcp(SocketAddr),
+	Udp(SocketAddr),
+	WebSocket {
+		address: SocketAddr,
+		/// The HTTP proxy to connect through, if any.
+		proxy: Option<SocketAddr>,
+		path: String,
+	},
+	Unix(PathBuf),
+	/// A shell command connected to the server.
+	Command(String),
+}
+
+/// The connection to the server.
+enum Server {
+	Stream(Box<dyn StreamTransport>, FrameReader, FrameWriter),
+	Datagram(UdpSocket, DatagramLink),
 }
 
 impl StupidClient {
-	pub fn new(address: SocketAddr) -> Result<Self, Error> {
-		let server = TcpStream::connect(address)?;
-		Ok(Self { server })
+	/// The amount of data that may be queued before [`Self::has_room`] returns `false`.
+	const MAX_QUEUE: usize = 1 << 20;
+
+	/// Create a client for the given server. [`Self::connect`] must be called before use.
+	pub fn new(endpoint: Endpoint, key: &[u8], keepalive: Keepalive, priorities: Priorities, compression: Compression, compression_threshold: usize) -> Self {
+		Self {
+			endpoint,
+			key: key.into(),
+			server: None,
+			session: None,
+			keepalive,
+			queue: Scheduler::new(priorities),
+			compression,
+			compression_threshold,
+			decompressor: Decompressor::new(),
+		}
 	}
 
+	/// Connect to the server, asking to resume the current session if there is any.
+	pub fn connect(&mut self, registry: &Registry, token: Token) -> Result<(), Error> {
+		let now = Instant::now();
+		let mut server = match &self.endpoint {
+			// The hello waits in the queue until the connection is established.
+			Endpoint::Tcp(address) => Server::Stream(Box::new(TcpStream::connect(*address)?), FrameReader::new(), FrameWriter::new()),
+			Endpoint::Udp(address) => {
+				let any = match address {
+					SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+					SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+				};
+				let server = UdpSocket::bind(any)?;
+				server.connect(*address)?;
+				let mut id = [0; 4];
+				fill_random(&mut id);
+				Server::Datagram(server, DatagramLink::new(u32::from_le_bytes(id), now))
+			}
+			Endpoint::WebSocket { address, proxy, path } => {
+				let server = TcpStream::connect(proxy.unwrap_or(*address))?;
+				let server = WebSocket::client(server, &address.to_string(), path, proxy.is_some());
+				Server::Stream(Box::new(server), FrameReader::new(), FrameWriter::new())
+			}
+			Endpoint::Unix(path) => Server::Stream(Box::new(UnixStream::connect(path)?), FrameReader::new(), FrameWriter::new()),
+			Endpoint::Command(command) => Server::Stream(Box::new(Pipes::spawn(command)?), FrameReader::new(), FrameWriter::new()),
+		};
+		match &mut server {
+			Server::Stream(server, ..) => registry.register(server, token, Interest::READABLE | Interest::WRITABLE)?,
+			Server::Datagram(server, _) => registry.register(server, token, Interest::READABLE)?,
+		}
+		self.server = Some(server);
+		self.keepalive.reset(now);
+		// The server may not support it, so wait for the welcome.
+		self.queue.set_compression(Compression::None, 0);
+
+		// Frames queued for the previous connection must not precede the hello.
+		let hello = [&self.session.unwrap_or_default()[..], &self.key].concat();
+		let any = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+		self.queue.push_first(StupidType::Hello, any, self.compression.into(), &hello);
+		self.flush()
+	}
+
+	/// Close the connection to the server. The session is kept, but not the frames of TCP
+	/// flows, which the front end resets as data of them may have been lost. Other frames
+	/// that were only partly written are sent again in full.
+	pub fn disconnect(&mut self) {
+		if let Some(Server::Stream(_, _, mut writer)) = self.server.take() {
+			if let Some(frame) = writer.take_unsent() {
+				self.queue.requeue(frame);
+			}
+		}
+		self.queue.clear_tcp();
+	}
+
+	pub fn endpoint(&self) -> &Endpoint {
+		&self.endpoint
+	}
+
+	pub fn is_connected(&self) -> bool {
+		self.server.is_some()
+	}
+
+	pub fn keepalive(&mut self) -> &mut Keepalive {
+		&mut self.keepalive
+	}
+
+	/// The maximum amount of data to send in a single TCP frame.
+	pub fn max_stream_data(&self) -> usize {
+		match self.endpoint {
+			Endpoint::Udp(_) => DatagramLink::MAX_STREAM_DATA,
+			_ => StupidDataHeader::MAX_DATA_LENGTH,
+		}
+	}
+
+	/// Handle the welcome of the server.
+	pub fn welcome(&mut self, h: &StupidDataHeader, data: &[u8]) -> Result<Welcome, InvalidWelcome> {
+		let token = SessionToken::try_from(data).map_err(|_| InvalidWelcome)?;
+		let compression = match Compression::try_from(h.local()) {
+			Ok(c) if c == Compression::None || c == self.compression => c,
+			_ => return Err(InvalidWelcome),
+		};
+		self.queue.set_compression(compression, self.compression_threshold);
+		let welcome = match self.session {
+			None => Welcome::New,
+			Some(t) if t == token => Welcome::Resumed,
+			Some(_) => Welcome::Replaced,
+		};
+		self.session = Some(token);
+		Ok(welcome)
+	}
+
+	/// Queue a frame and send as much as the connection takes.
 	pub fn send(&mut self, ty: StupidType, remote: SocketAddrV4, local: u16, data: &[u8]) -> Result<(), Error> {
-		let len = data.len().try_into().unwrap();
-		let dh = StupidDataHeader::new(ty, remote, local, len);
+		if self.server.is_none() {
+			return Err(ErrorKind::NotConnected.into());
+		}
+		self.queue.push(ty, remote, local, data);
+		self.flush()
+	}
 
-		let mut out = [0; 0x10000];
-		out[..dh.byte_len()].copy_from_slice(dh.as_ref());
-		out[dh.byte_len()..][..data.len()].copy_from_slice(data);
-		let out = &out[..dh.byte_len() + data.len()];
+	/// Send queued frames until the connection would block.
+	pub fn flush(&mut self) -> Result<(), Error> {
+		match &mut self.server {
+			Some(Server::Stream(server, _, writer)) => writer.write(server, &mut self.queue),
+			Some(Server::Datagram(server, link)) => {
+				while link.has_capacity() {
+					match self.queue.pop() {
+						Some(f) => link.send(&f.data, f.reliable, Instant::now())?,
+						None => break,
+					}
+				}
+				link.transmit(|d| server.send(d))
+			}
+			None => Ok(()),
+		}
+	}
 
-		self.server.write_all(out)
+	/// Whether there is room to queue more frames.
+	pub fn has_room(&self) -> bool {
+		self.queue.queued() < Self::MAX_QUEUE
 	}
 
-	pub fn receive<'a>(&mut self, buf: &'a mut [u8]) -> Result<(StupidDataHeader, &'a [u8], &'a [u8]), Error> {
-		let len = self.server.read(buf)?;
-		assert_ne!(len, 0);
-		let buf = &buf[..len];
-		Ok(StupidDataHeader::from_raw(&buf).unwrap())
+	/// Receive the next frame, if a complete one is available.
+	pub fn receive<'a>(&mut self, buf: &'a mut [u8]) -> Result<Option<(StupidDataHeader, &'a [u8])>, ReceiveError> {
+		let frame = match &mut self.server {
+			Some(Server::Stream(server, reader, _)) => reader.read(server, buf)?.map(|(h, d)| (h, d.len())),
+			Some(Server::Datagram(server, link)) => {
+				loop {
+					match server.recv(buf) {
+						Ok(len) => link.receive(&buf[..len], Instant::now()),
+						Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+						Err(e) if e.kind() == ErrorKind::Interrupted => (),
+						Err(e) => return Err(ReceiveError::Io(e)),
+					}
+				}
+				// Acknowledge everything that was received.
+				link.poll(Instant::now());
+				link.transmit(|d| server.send(d)).map_err(ReceiveError::Io)?;
+				link.read(buf).map(|(h, d)| (h, d.len()))
+			}
+			None => return Ok(None),
+		};
+		if frame.is_some() {
+			self.keepalive.received(Instant::now());
+		}
+		self.decompressor.frame(frame, buf).map_err(ReceiveError::Decompress)
+	}
+
+	/// The amount of data sent and received before and after compression, if compression is
+	/// enabled.
+	pub fn compression_stats(&self) -> Option<(CompressionStats, CompressionStats)> {
+		self.queue.compression_stats().map(|s| (s, self.decompressor.stats()))
 	}
-}
 
-impl mio::event::Source for StupidClient {
-	fn register(&mut self, registry: &Registry, token: Token, interest: Interest) -> Result<(), Error> {
-		self.server.register(registry, token, interest)
+	/// The amount of data waiting to be sent.
+	pub fn queued(&self) -> usize {
+		self.queue.queued()
 	}
 
-	fn reregister(&mut self, registry: &Registry, token: Token, interest: Interest) -> Result<(), Error> {
-		self.server.reregister(registry, token, interest)
+	/// How many frames were sent again over the current connection, if it is over UDP.
+	pub fn retransmissions(&self) -> Option<u64> {
+		match &self.server {
+			Some(Server::Datagram(_, link)) => Some(link.retransmissions()),
+			_ => None,
+		}
 	}
 
-	fn deregister(&mut self, registry: &Registry) -> Result<(), Error> {
-		self.server.deregister(registry)
+	/// Retransmit lost frames, if any.
+	pub fn poll(&mut self, now: Instant) -> Result<(), Error> {
+		match &mut self.server {
+			Some(Server::Datagram(server, link)) => {
+				link.poll(now);
+				link.transmit(|d| server.send(d))
+			}
+			_ => Ok(()),
+		}
+	}
+
+	/// The time until [`Self::poll`] needs to be called again, if at all.
+	pub fn next_timeout(&self, now: Instant) -> Option<Duration> {
+		match &self.server {
+			Some(Server::Datagram(_, link)) => link.next_timeout(now),
+			_ => None,
+		}
+	}
+}
+
+/// The session the server put the client in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Welcome {
+	/// The first session of the client.
+	New,
+	Resumed,
+	/// The previous session is gone, together with all of its flows.
+	Replaced,
+}
+
+#[derive(Debug)]
+pub struct InvalidWelcome;
+
+impl fmt::Display for Endpoint {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Tcp(address) => write!(f, "{} over TCP", address),
+			Self::Udp(address) => write!(f, "{} over UDP", address),
+			Self::WebSocket { address, proxy: None, .. } => write!(f, "{} over WebSocket", address),
+			Self::WebSocket { address, proxy: Some(proxy), .. } => write!(f, "{} over WebSocket through {}", address, proxy),
+			Self::Unix(path) => write!(f, "Unix socket {}", path.display()),
+			Self::Command(command) => write!(f, "command {:?}", command),
+		}
 	}
 }
@@ -0,0 +1,216 @@
+use super::*;
+use flate2::{Compress, Decompress, FlushCompress, FlushDecompress, Status};
+
+/// How the data of frames is compressed.
+///
+/// Every frame is compressed on its own, as frames of different flows are reordered and
+/// unreliable frames may be lost. Compressed frames are marked in their header, so the
+/// receiving side doesn't need to know whether compression is enabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+	None = 0,
+	/// Raw deflate, without zlib header.
+	Deflate = 1,
+}
+
+/// Compresses the data of frames sent to the other side.
+pub struct Compressor {
+	deflate: Compress,
+	/// Frames with less data than this are sent as is.
+	threshold: usize,
+	buf: Box<[u8]>,
+	stats: CompressionStats,
+}
+
+/// Decompresses the data of frames received from the other side.
+pub struct Decompressor {
+	inflate: Decompress,
+	buf: Box<[u8]>,
+	stats: CompressionStats,
+}
+
+/// The amount of data before and after compression, including data that wasn't compressed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+	pub raw: u64,
+	pub compressed: u64,
+}
+
+impl Compressor {
+	pub fn new(threshold: usize) -> Self {
+		Self {
+			// Favour speed, as the link is usually slow but not that slow.
+			deflate: Compress::new(flate2::Compression::fast(), false),
+			threshold,
+			buf: vec![0; StupidDataHeader::MAX_DATA_LENGTH].into(),
+			stats: CompressionStats::default(),
+		}
+	}
+
+	/// Compress data, if it is large enough and compression makes it smaller.
+	pub fn compress(&mut self, data: &[u8]) -> Option<&[u8]> {
+		self.stats.raw += data.len() as u64;
+		if data.is_empty() || data.len() < self.threshold {
+			self.stats.compressed += data.len() as u64;
+			return None;
+		}
+		self.deflate.reset();
+		// Leave no room for output that isn't smaller than the input.
+		let out = &mut self.buf[..data.len() - 1];
+		let len = match self.deflate.compress(data, out, FlushCompress::Finish) {
+			Ok(Status::StreamEnd) => self.deflate.total_out() as usize,
+			_ => {
+				self.stats.compressed += data.len() as u64;
+				return None;
+			}
+		};
+		self.stats.compressed += len as u64;
+		Some(&self.buf[..len])
+	}
+
+	pub fn stats(&self) -> CompressionStats {
+		self.stats
+	}
+}
+
+impl Decompressor {
+	pub fn new() -> Self {
+		Self {
+			inflate: Decompress::new(false),
+			buf: vec![0; StupidDataHeader::MAX_DATA_LENGTH].into(),
+			stats: CompressionStats::default(),
+		}
+	}
+
+	/// Decompress the data of a frame in place. `buf` holds `len` bytes of compressed data and
+	/// must be large enough for any frame. Returns the length of the decompressed data.
+	pub fn decompress(&mut self, buf: &mut [u8], len: usize) -> Result<usize, DecompressError> {
+		self.inflate.reset(false);
+		let out_len = match self.inflate.decompress(&buf[..len], &mut self.buf, FlushDecompress::Finish) {
+			Ok(Status::StreamEnd) if self.inflate.total_in() as usize == len => self.inflate.total_out() as usize,
+			_ => return Err(DecompressError),
+		};
+		buf[..out_len].copy_from_slice(&self.buf[..out_len]);
+		self.stats.raw += out_len as u64;
+		self.stats.compressed += len as u64;
+		Ok(out_len)
+	}
+
+	/// Decompress a frame that was read to the start of `buf`, if it is compressed.
+	pub fn frame<'a>(&mut self, frame: Option<(StupidDataHeader, usize)>, buf: &'a mut [u8]) -> Result<Option<(StupidDataHeader, &'a [u8])>, DecompressError> {
+		Ok(match frame {
+			Some((h, len)) if h.is_compressed() => {
+				let len = self.decompress(buf, len)?;
+				Some((h, &buf[..len]))
+			}
+			Some((h, len)) => {
+				self.stats.raw += len as u64;
+				self.stats.compressed += len as u64;
+				Some((h, &buf[..len]))
+			}
+			None => None,
+		})
+	}
+
+	pub fn stats(&self) -> CompressionStats {
+		self.stats
+	}
+}
+
+impl CompressionStats {
+	/// The size after compression relative to the size before.
+	pub fn ratio(&self) -> f64 {
+		match self.raw {
+			0 => 1.0,
+			raw => self.compressed as f64 / raw as f64,
+		}
+	}
+}
+
+impl fmt::Display for CompressionStats {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} bytes as {} ({:.1}%)", self.raw, self.compressed, self.ratio() * 100.0)
+	}
+}
+
+impl From<Compression> for u16 {
+	fn from(c: Compression) -> Self {
+		c as u16
+	}
+}
+
+impl TryFrom<u16> for Compression {
+	type Error = ();
+
+	fn try_from(n: u16) -> Result<Self, Self::Error> {
+		match n {
+			0 => Ok(Self::None),
+			1 => Ok(Self::Deflate),
+			_ => Err(()),
+		}
+	}
+}
+
+impl FromStr for Compression {
+	type Err = InvalidCompression;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"none" => Ok(Self::None),
+			"deflate" => Ok(Self::Deflate),
+			_ => Err(InvalidCompression),
+		}
+	}
+}
+
+#[derive(Debug)]
+pub struct InvalidCompression;
+
+/// The compressed data of a frame is malformed or too large.
+#[derive(Debug)]
+pub struct DecompressError;
+
+impl fmt::Display for DecompressError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("invalid compressed frame")
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn round_trip() {
+		let (mut c, mut d) = (Compressor::new(64), Decompressor::new());
+		let text = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(100);
+		let mut buf = vec![0; StupidDataHeader::MAX_DATA_LENGTH];
+		let compressed = c.compress(&text).unwrap();
+		assert!(compressed.len() < text.len() / 10);
+		buf[..compressed.len()].copy_from_slice(compressed);
+		let len = d.decompress(&mut buf, compressed.len()).unwrap();
+		assert_eq!(&buf[..len], &text[..]);
+		assert_eq!(c.stats(), d.stats());
+
+		// Small frames and those that don't get smaller are sent as is.
+		assert_eq!(c.compress(b"hello"), None);
+		let mut random = [0; 1000];
+		fill_random(&mut random);
+		assert_eq!(c.compress(&random), None);
+		assert_eq!(c.stats().raw, text.len() as u64 + 1005);
+	}
+
+	#[test]
+	fn invalid() {
+		let mut d = Decompressor::new();
+		let mut buf = vec![0xff; StupidDataHeader::MAX_DATA_LENGTH];
+		assert!(d.decompress(&mut buf, 100).is_err());
+		// More than fits in a frame.
+		let mut c = Compress::new(flate2::Compression::fast(), false);
+		let mut bomb = Vec::with_capacity(1000);
+		c.compress_vec(&[0; 0x20000], &mut bomb, FlushCompress::Finish).unwrap();
+		let len = bomb.len();
+		buf[..len].copy_from_slice(&bomb);
+		assert!(d.decompress(&mut buf, len).is_err());
+	}
+}
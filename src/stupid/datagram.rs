@@ -0,0 +1,433 @@
+use super::*;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+
+/// Carries frames in datagrams, one frame per datagram.
+///
+/// Frames of flows that tolerate loss, like UDP, are sent as is. All other frames are
+/// numbered, acknowledged and retransmitted if lost, so they arrive once and in order. This
+/// keeps a lossy path from stalling every flow like a single TCP connection would.
+///
+/// Every datagram starts with a header of little endian fields:
+///
+/// ```text
+/// connection: u32, kind: u8, sequence: u32, acknowledge: u32
+/// ```
+///
+/// `kind` is 0 for unreliable frames, 1 for reliable frames and 2 for acknowledgements
+/// without a frame. `acknowledge` is the sequence number of the next reliable frame expected
+/// from the other side.
+pub struct DatagramLink {
+	id: u32,
+	/// The sequence number of the first frame in `in_flight`.
+	acknowledged: u32,
+	/// Reliable frames that were sent but not acknowledged yet.
+	in_flight: VecDeque<Unacknowledged>,
+	/// Reliable frames that don't fit in the window yet.
+	waiting: VecDeque<Vec<u8>>,
+	duplicate_acks: u8,
+	/// The sequence number following the last frame sent when a loss was detected. Until it
+	/// is acknowledged every acknowledgement is followed by a retransmission, as multiple
+	/// frames may be lost.
+	recover: Option<u32>,
+	/// When the first frame in flight was last (re)sent or acknowledged.
+	timer: Instant,
+	retransmit_timeout: Duration,
+	/// How often the retransmit timeout doubled since the last acknowledgement.
+	backoff: u32,
+	/// The smoothed round-trip time and its variation.
+	rtt: Option<(Duration, Duration)>,
+	next_receive: u32,
+	out_of_order: HashMap<u32, Vec<u8>>,
+	/// Received frames that haven't been read yet.
+	received: VecDeque<Vec<u8>>,
+	ack_pending: bool,
+	/// Datagrams ready to be sent.
+	outbox: VecDeque<Vec<u8>>,
+	/// How many frames were sent again.
+	retransmissions: u64,
+}
+
+struct Unacknowledged {
+	frame: Vec<u8>,
+	sent: Instant,
+	retransmitted: bool,
+}
+
+const UNRELIABLE: u8 = 0;
+const RELIABLE: u8 = 1;
+const ACKNOWLEDGE: u8 = 2;
+
+const HEADER_LEN: usize = 13;
+
+/// The maximum amount of reliable frames in flight.
+const WINDOW: usize = 64;
+
+const INITIAL_RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(200);
+const MAX_RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl DatagramLink {
+	/// The largest UDP payload that can be sent over IPv4.
+	pub const MAX_DATAGRAM: usize = 0xffff - 20 - 8;
+	/// Stream data, i.e. of TCP flows, is split into frames of at most this size so the
+	/// datagrams don't need to be fragmented on most paths.
+	pub const MAX_STREAM_DATA: usize = 1200;
+
+	pub fn new(id: u32, now: Instant) -> Self {
+		Self {
+			id,
+			acknowledged: 0,
+			in_flight: VecDeque::new(),
+			waiting: VecDeque::new(),
+			duplicate_acks: 0,
+			recover: None,
+			timer: now,
+			retransmit_timeout: INITIAL_RETRANSMIT_TIMEOUT,
+			backoff: 0,
+			rtt: None,
+			next_receive: 0,
+			out_of_order: HashMap::new(),
+			received: VecDeque::new(),
+			ack_pending: false,
+			outbox: VecDeque::new(),
+			retransmissions: 0,
+		}
+	}
+
+	/// The connection a datagram belongs to.
+	pub fn connection_id(datagram: &[u8]) -> Option<u32> {
+		(datagram.len() >= HEADER_LEN).then(|| u32::from_le_bytes(datagram[..4].try_into().unwrap()))
+	}
+
+	/// The first reliable frame of a connection, if the datagram carries it.
+	pub fn initial_frame(datagram: &[u8]) -> Option<&[u8]> {
+		(datagram.len() > HEADER_LEN && datagram[4] == RELIABLE && datagram[5..9] == [0; 4]).then(|| &datagram[HEADER_LEN..])
+	}
+
+	pub fn id(&self) -> u32 {
+		self.id
+	}
+
+	pub fn retransmissions(&self) -> u64 {
+		self.retransmissions
+	}
+
+	/// Queue a frame. Unreliable frames that don't fit in a datagram are dropped.
+	pub fn send(&mut self, frame: &[u8], reliable: bool, now: Instant) -> Result<(), Error> {
+		if frame.len() > Self::MAX_DATAGRAM - HEADER_LEN {
+			return match reliable {
+				true => Err(ErrorKind::InvalidInput.into()),
+				false => Ok(()),
+			};
+		}
+		if reliable {
+			self.waiting.push_back(frame.into());
+			self.fill_window(now);
+		} else {
+			self.emit(UNRELIABLE, 0, frame);
+		}
+		Ok(())
+	}
+
+	/// Whether the window has room for more reliable frames. More frames can still be sent
+	/// but will have to wait.
+	pub fn has_capacity(&self) -> bool {
+		self.waiting.is_empty()
+	}
+
+	/// Handle a received datagram. Malformed datagrams and those of other connections are
+	/// ignored.
+	pub fn receive(&mut self, datagram: &[u8], now: Instant) {
+		if Self::connection_id(datagram) != Some(self.id) {
+			return;
+		}
+		let kind = datagram[4];
+		let sequence = u32::from_le_bytes(datagram[5..9].try_into().unwrap());
+		let acknowledge = u32::from_le_bytes(datagram[9..13].try_into().unwrap());
+		let frame = &datagram[HEADER_LEN..];
+
+		self.acknowledge(acknowledge, kind == ACKNOWLEDGE, now);
+
+		match kind {
+			UNRELIABLE => self.received.push_back(frame.into()),
+			RELIABLE => {
+				// Acknowledge duplicates too, the previous acknowledgement may have been lost.
+				self.ack_pending = true;
+				let ahead = sequence.wrapping_sub(self.next_receive);
+				if ahead == 0 {
+					self.received.push_back(frame.into());
+					self.next_receive = self.next_receive.wrapping_add(1);
+					while let Some(f) = self.out_of_order.remove(&self.next_receive) {
+						self.received.push_back(f);
+						self.next_receive = self.next_receive.wrapping_add(1);
+					}
+				} else if ahead < WINDOW as u32 {
+					self.out_of_order.insert(sequence, frame.into());
+				}
+			}
+			_ => (),
+		}
+	}
+
+	/// Get the next received frame, if any. The data of the frame is copied to `out`.
+	pub fn read<'a>(&mut self, out: &'a mut [u8]) -> Option<(StupidDataHeader, &'a [u8])> {
+		while let Some(frame) = self.received.pop_front() {
+			if let Ok((h, data, _)) = StupidDataHeader::from_raw(&frame) {
+				let out = &mut out[..data.len()];
+				out.copy_from_slice(data);
+				return Some((h, out));
+			}
+		}
+		None
+	}
+
+	/// Retransmit lost frames and acknowledge received frames.
+	pub fn poll(&mut self, now: Instant) {
+		if !self.in_flight.is_empty() && now >= self.timer + self.retransmit_timeout() {
+			self.backoff = (self.backoff + 1).min(16);
+			self.retransmit(now);
+		}
+		if self.ack_pending {
+			self.emit(ACKNOWLEDGE, 0, &[]);
+		}
+	}
+
+	/// The time until [`Self::poll`] needs to be called again, if at all.
+	pub fn next_timeout(&self, now: Instant) -> Option<Duration> {
+		if self.ack_pending {
+			Some(Duration::ZERO)
+		} else {
+			(!self.in_flight.is_empty())
+				.then(|| (self.timer + self.retransmit_timeout()).saturating_duration_since(now))
+		}
+	}
+
+	/// Send all queued datagrams with the given function.
+	///
+	/// Datagrams that would block are dropped like any other lost datagram.
+	pub fn transmit(&mut self, mut send: impl FnMut(&[u8]) -> Result<usize, Error>) -> Result<(), Error> {
+		while let Some(d) = self.outbox.pop_front() {
+			match send(&d) {
+				Ok(_) => (),
+				Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(())
+	}
+
+	fn acknowledge(&mut self, acknowledge: u32, pure: bool, now: Instant) {
+		let count = acknowledge.wrapping_sub(self.acknowledged) as usize;
+		if count == 0 {
+			// The other side keeps receiving frames after a missing one.
+			if pure && !self.in_flight.is_empty() {
+				self.duplicate_acks += 1;
+				if self.duplicate_acks == 3 {
+					self.retransmit(now);
+				}
+			}
+			return;
+		}
+		if count > self.in_flight.len() {
+			return;
+		}
+
+		// Don't measure with retransmitted frames, as it's unknown which copy was acknowledged,
+		// nor while recovering, as frames may have been held up by a lost one.
+		let sample = self.in_flight.drain(..count)
+			.last()
+			.filter(|f| !f.retransmitted && self.recover.is_none())
+			.map(|f| now.saturating_duration_since(f.sent));
+		self.acknowledged = acknowledge;
+		self.duplicate_acks = 0;
+		self.backoff = 0;
+		self.timer = now;
+
+		// As described in RFC 6298
+		if let Some(r) = sample {
+			let (srtt, rttvar) = match self.rtt {
+				None => (r, r / 2),
+				Some((srtt, rttvar)) => {
+					let delta = if srtt > r { srtt - r } else { r - srtt };
+					((srtt * 7 + r) / 8, (rttvar * 3 + delta) / 4)
+				}
+			};
+			self.rtt = Some((srtt, rttvar));
+			self.retransmit_timeout = (srtt + rttvar * 4).clamp(MIN_RETRANSMIT_TIMEOUT, MAX_RETRANSMIT_TIMEOUT);
+		}
+
+		if let Some(recover) = self.recover {
+			if (recover.wrapping_sub(acknowledge) as i32) > 0 {
+				self.retransmit(now);
+			} else {
+				self.recover = None;
+			}
+		}
+
+		self.fill_window(now);
+	}
+
+	fn retransmit_timeout(&self) -> Duration {
+		(self.retransmit_timeout * (1 << self.backoff)).min(MAX_RETRANSMIT_TIMEOUT)
+	}
+
+	fn fill_window(&mut self, now: Instant) {
+		while self.in_flight.len() < WINDOW {
+			let frame = match self.waiting.pop_front() {
+				Some(f) => f,
+				None => break,
+			};
+			if self.in_flight.is_empty() {
+				self.timer = now;
+			}
+			let sequence = self.acknowledged.wrapping_add(self.in_flight.len() as u32);
+			self.emit(RELIABLE, sequence, &frame);
+			self.in_flight.push_back(Unacknowledged { frame, sent: now, retransmitted: false });
+		}
+	}
+
+	/// Send the oldest unacknowledged frame again.
+	fn retransmit(&mut self, now: Instant) {
+		if self.recover.is_none() {
+			self.recover = Some(self.acknowledged.wrapping_add(self.in_flight.len() as u32));
+		}
+		let datagram = self.datagram(RELIABLE, self.acknowledged, &self.in_flight[0].frame);
+		self.push(datagram);
+		let f = &mut self.in_flight[0];
+		f.sent = now;
+		f.retransmitted = true;
+		self.timer = now;
+		self.retransmissions += 1;
+	}
+
+	fn emit(&mut self, kind: u8, sequence: u32, frame: &[u8]) {
+		let datagram = self.datagram(kind, sequence, frame);
+		self.push(datagram);
+	}
+
+	fn push(&mut self, datagram: Vec<u8>) {
+		// Every datagram acknowledges all received frames.
+		self.ack_pending = false;
+		self.outbox.push_back(datagram);
+	}
+
+	fn datagram(&self, kind: u8, sequence: u32, frame: &[u8]) -> Vec<u8> {
+		let mut d = Vec::with_capacity(HEADER_LEN + frame.len());
+		d.extend_from_slice(&self.id.to_le_bytes());
+		d.push(kind);
+		d.extend_from_slice(&sequence.to_le_bytes());
+		d.extend_from_slice(&self.next_receive.to_le_bytes());
+		d.extend_from_slice(frame);
+		d
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn frame(n: u16) -> Vec<u8> {
+		let h = StupidDataHeader::new(StupidType::TCP, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 80), n, 2);
+		[&h.as_ref()[..], &n.to_le_bytes()[..]].concat()
+	}
+
+	fn deliver(from: &mut DatagramLink, to: &mut DatagramLink, now: Instant, mut drop: impl FnMut(usize) -> bool) {
+		let mut datagrams = Vec::new();
+		from.transmit(|d| {
+			datagrams.push(d.to_vec());
+			Ok(d.len())
+		}).unwrap();
+		for (i, d) in datagrams.iter().enumerate() {
+			if !drop(i) {
+				to.receive(d, now);
+			}
+		}
+	}
+
+	fn read_all(link: &mut DatagramLink) -> Vec<u16> {
+		let mut buf = [0; 16];
+		let mut v = Vec::new();
+		while let Some((h, _)) = link.read(&mut buf) {
+			v.push(h.local());
+		}
+		v
+	}
+
+	#[test]
+	fn retransmit() {
+		let now = Instant::now();
+		let (mut a, mut b) = (DatagramLink::new(7, now), DatagramLink::new(7, now));
+		for n in 0..10 {
+			a.send(&frame(n), true, now).unwrap();
+		}
+		a.send(&frame(100), false, now).unwrap();
+		deliver(&mut a, &mut b, now, |i| i == 3);
+		assert_eq!(read_all(&mut b), [0, 1, 2, 100]);
+
+		// Only the first 3 frames are acknowledged.
+		b.poll(now);
+		deliver(&mut b, &mut a, now, |_| false);
+		assert_eq!(a.in_flight.len(), 7);
+
+		let later = now + MIN_RETRANSMIT_TIMEOUT;
+		assert_eq!(a.next_timeout(now), Some(MIN_RETRANSMIT_TIMEOUT));
+		a.poll(now);
+		assert!(a.outbox.is_empty());
+		a.poll(later);
+		deliver(&mut a, &mut b, later, |_| false);
+		assert_eq!(read_all(&mut b), [3, 4, 5, 6, 7, 8, 9]);
+		assert_eq!(a.retransmissions(), 1);
+
+		b.poll(later);
+		deliver(&mut b, &mut a, later, |_| false);
+		assert!(a.in_flight.is_empty());
+		assert_eq!(a.next_timeout(later), None);
+	}
+
+	#[test]
+	fn window() {
+		let now = Instant::now();
+		let (mut a, mut b) = (DatagramLink::new(1, now), DatagramLink::new(1, now));
+		for n in 0..WINDOW as u16 + 10 {
+			a.send(&frame(n), true, now).unwrap();
+		}
+		assert!(!a.has_capacity());
+		assert_eq!(a.outbox.len(), WINDOW);
+
+		// Duplicates are ignored.
+		let d = a.outbox[0].clone();
+		deliver(&mut a, &mut b, now, |_| false);
+		b.receive(&d, now);
+		assert_eq!(read_all(&mut b).len(), WINDOW);
+
+		b.poll(now);
+		deliver(&mut b, &mut a, now, |_| false);
+		assert!(a.has_capacity());
+		deliver(&mut a, &mut b, now, |_| false);
+		assert_eq!(read_all(&mut b), (WINDOW as u16..WINDOW as u16 + 10).collect::<Vec<_>>());
+
+		// Datagrams of other connections are ignored.
+		let mut c = DatagramLink::new(2, now);
+		c.send(&frame(0), true, now).unwrap();
+		deliver(&mut c, &mut b, now, |_| false);
+		assert!(read_all(&mut b).is_empty());
+	}
+
+	#[test]
+	fn initial_frame() {
+		let now = Instant::now();
+		let mut a = DatagramLink::new(3, now);
+		a.send(&frame(100), false, now).unwrap();
+		a.send(&frame(0), true, now).unwrap();
+		a.send(&frame(1), true, now).unwrap();
+		let mut datagrams = Vec::new();
+		a.transmit(|d| {
+			datagrams.push(d.to_vec());
+			Ok(d.len())
+		}).unwrap();
+		let initial = datagrams.iter().map(|d| DatagramLink::initial_frame(d)).collect::<Vec<_>>();
+		assert_eq!(initial, [None, Some(&frame(0)[..]), None]);
+	}
+}
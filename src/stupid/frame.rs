@@ -0,0 +1,232 @@
+use super::*;
+use std::io::{Error, ErrorKind, Read, Write};
+
+/// Splits a byte stream into frames.
+///
+/// A single read may return only part of a frame or several frames at once, so data is
+/// buffered until a complete frame is available.
+pub struct FrameReader {
+	buf: Box<[u8]>,
+	start: usize,
+	end: usize,
+}
+
+impl FrameReader {
+	const CAPACITY: usize = mem::size_of::<StupidDataHeader>() + StupidDataHeader::MAX_DATA_LENGTH;
+
+	pub fn new() -> Self {
+		Self {
+			buf: vec![0; Self::CAPACITY].into(),
+			start: 0,
+			end: 0,
+		}
+	}
+
+	/// Get the next complete frame, reading from the stream if necessary. The data of the
+	/// frame is copied to `out`.
+	///
+	/// Returns `None` if the stream has no more data available right now.
+	pub fn read<'a>(&mut self, stream: &mut impl Read, out: &'a mut [u8]) -> Result<Option<(StupidDataHeader, &'a [u8])>, ReceiveError> {
+		loop {
+			if let Ok((h, data, _)) = StupidDataHeader::from_raw(&self.buf[self.start..self.end]) {
+				let out = &mut out[..data.len()];
+				out.copy_from_slice(data);
+				self.start += h.byte_len() + data.len();
+				return Ok(Some((h, out)));
+			}
+
+			// Move the partial frame to the front to make room for the rest of it.
+			self.buf.copy_within(self.start..self.end, 0);
+			self.end -= self.start;
+			self.start = 0;
+
+			match stream.read(&mut self.buf[self.end..]) {
+				Ok(0) => return Err(ReceiveError::Closed),
+				Ok(n) => self.end += n,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+				Err(e) if e.kind() == ErrorKind::Interrupted => (),
+				Err(e) => return Err(ReceiveError::Io(e)),
+			}
+		}
+	}
+}
+
+/// Writes frames taken from a [`Scheduler`] to a byte stream.
+///
+/// A single write may take only part of a frame, so the rest of it is kept until the stream
+/// is writable again. Only then is the next frame taken, which may belong to another flow.
+pub struct FrameWriter {
+	frame: Option<Frame>,
+	/// The amount of the frame that has been written.
+	start: usize,
+}
+
+impl FrameWriter {
+	pub fn new() -> Self {
+		Self { frame: None, start: 0 }
+	}
+
+	/// Write frames until the stream would block or no frames are left.
+	pub fn write(&mut self, stream: &mut impl Write, scheduler: &mut Scheduler) -> Result<(), Error> {
+		loop {
+			let frame = match &self.frame {
+				Some(f) => f,
+				None => match scheduler.pop() {
+					Some(f) => {
+						self.start = 0;
+						self.frame.insert(f)
+					}
+					None => return match stream.flush() {
+						Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+						r => r,
+					},
+				},
+			};
+			match stream.write(&frame.data[self.start..]) {
+				Ok(0) => return Err(ErrorKind::WriteZero.into()),
+				Ok(n) => {
+					self.start += n;
+					if self.start == frame.data.len() {
+						self.frame = None;
+					}
+				}
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+				Err(e) if e.kind() == ErrorKind::Interrupted => (),
+				Err(e) => return Err(e),
+			}
+		}
+	}
+
+	/// Take the frame that was only partly written, so it can be sent in full over another
+	/// connection.
+	pub fn take_unsent(&mut self) -> Option<Frame> {
+		self.frame.take()
+	}
+}
+
+#[derive(Debug)]
+pub enum ReceiveError {
+	/// The other side closed the connection.
+	Closed,
+	Io(Error),
+	Decompress(DecompressError),
+}
+
+impl fmt::Display for ReceiveError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Closed => f.write_str("connection closed"),
+			Self::Io(e) => e.fmt(f),
+			Self::Decompress(e) => e.fmt(f),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Returns the given chunks one read at a time, with a `WouldBlock` in between each.
+	struct Chunks(Vec<Vec<u8>>, bool);
+
+	impl Read for Chunks {
+		fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+			self.1 = !self.1;
+			match self.0.first_mut() {
+				_ if !self.1 => Err(ErrorKind::WouldBlock.into()),
+				None => Ok(0),
+				Some(c) => {
+					let n = c.len().min(buf.len());
+					buf[..n].copy_from_slice(&c[..n]);
+					c.drain(..n);
+					c.is_empty().then(|| self.0.remove(0));
+					Ok(n)
+				}
+			}
+		}
+	}
+
+	fn frame(local: u16, data: &[u8]) -> Vec<u8> {
+		let remote = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 80);
+		let h = StupidDataHeader::new(StupidType::TCP, remote, local, data.len().try_into().unwrap());
+		[&h.as_ref()[..], data].concat()
+	}
+
+	#[test]
+	fn split_and_merged() {
+		let big = vec![7; StupidDataHeader::MAX_DATA_LENGTH];
+		let stream = [frame(1, b"hello"), frame(2, b""), frame(3, &big), frame(4, b"bye")].concat();
+		let chunks = [&stream[..3], &stream[3..20], &stream[20..30_000], &stream[30_000..]];
+		let mut stream = Chunks(chunks.iter().map(|c| c.to_vec()).collect(), false);
+
+		let mut r = FrameReader::new();
+		let mut out = [0; 0x10000];
+		let mut frames = Vec::new();
+		loop {
+			match r.read(&mut stream, &mut out) {
+				Ok(Some((h, data))) => frames.push((h.local(), data.to_vec())),
+				Ok(None) => (),
+				Err(ReceiveError::Closed) => break,
+				Err(e) => panic!("{:?}", e),
+			}
+		}
+		assert_eq!(frames, [(1, b"hello".to_vec()), (2, Vec::new()), (3, big), (4, b"bye".to_vec())]);
+	}
+
+	/// Takes at most 1000 bytes per write, with a `WouldBlock` in between each.
+	struct Trickle(Vec<u8>, bool);
+
+	impl Write for Trickle {
+		fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+			self.1 = !self.1;
+			if !self.1 {
+				return Err(ErrorKind::WouldBlock.into());
+			}
+			let n = buf.len().min(1000);
+			self.0.extend_from_slice(&buf[..n]);
+			Ok(n)
+		}
+
+		fn flush(&mut self) -> Result<(), Error> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn partial_writes() {
+		let remote = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 80);
+		let big = vec![7; 5000];
+		let mut scheduler = Scheduler::new(Priorities::default());
+		scheduler.push(StupidType::TCP, remote, 1, &big);
+		scheduler.push(StupidType::TCP, remote, 1, b"hello");
+		let (mut w, mut stream) = (FrameWriter::new(), Trickle(Vec::new(), false));
+		w.write(&mut stream, &mut scheduler).unwrap();
+		assert_eq!(stream.0.len(), 1000);
+		// A frame queued while another is being written waits for it.
+		scheduler.push(StupidType::TCP, remote, 2, b"bye");
+		while scheduler.queued() > 0 || w.frame.is_some() {
+			w.write(&mut stream, &mut scheduler).unwrap();
+		}
+		assert_eq!(stream.0, [frame(1, &big), frame(1, b"hello"), frame(2, b"bye")].concat());
+	}
+
+	#[test]
+	fn unsent() {
+		let remote = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 80);
+		let big = vec![7; 5000];
+		let mut scheduler = Scheduler::new(Priorities::default());
+		scheduler.push(StupidType::TCP, remote, 1, &big);
+		scheduler.push(StupidType::TCP, remote, 1, b"hello");
+		let (mut w, mut stream) = (FrameWriter::new(), Trickle(Vec::new(), false));
+		w.write(&mut stream, &mut scheduler).unwrap();
+		assert_eq!(stream.0.len(), 1000);
+
+		// The connection is lost, so the frame is sent in full over the next one.
+		scheduler.requeue(w.take_unsent().unwrap());
+		let (mut w, mut stream) = (FrameWriter::new(), Trickle(Vec::new(), false));
+		while scheduler.queued() > 0 || w.frame.is_some() {
+			w.write(&mut stream, &mut scheduler).unwrap();
+		}
+		assert_eq!(stream.0, [frame(1, &big), frame(1, b"hello")].concat());
+	}
+}
@@ -0,0 +1,137 @@
+use std::time::{Duration, Instant};
+
+/// Decides when to send pings and whether the other side is still alive.
+///
+/// Each side pings the other at a fixed interval, which also keeps NAT mappings alive. The
+/// link is considered dead if nothing at all was received within the timeout.
+pub struct Keepalive {
+	interval: Duration,
+	timeout: Duration,
+	last_received: Instant,
+	next_ping: Instant,
+	/// The sequence number of the next ping.
+	sequence: u64,
+	/// The sequence number and send time of the last ping.
+	outstanding: Option<(u64, Instant)>,
+	rtt: Option<Rtt>,
+}
+
+/// Round-trip time measured with pings.
+#[derive(Clone, Copy, Debug)]
+pub struct Rtt {
+	pub latest: Duration,
+	/// Smoothed as described in [RFC 6298](https://datatracker.ietf.org/doc/html/rfc6298).
+	pub smoothed: Duration,
+	pub min: Duration,
+}
+
+impl Keepalive {
+	pub fn new(interval: Duration, timeout: Duration, now: Instant) -> Self {
+		Self {
+			interval,
+			timeout,
+			last_received: now,
+			next_ping: now + interval,
+			sequence: 0,
+			outstanding: None,
+			rtt: None,
+		}
+	}
+
+	/// Start over for a new connection. The round-trip time is kept.
+	pub fn reset(&mut self, now: Instant) {
+		self.last_received = now;
+		self.next_ping = now + self.interval;
+		self.outstanding = None;
+	}
+
+	/// Note that a frame has been received.
+	pub fn received(&mut self, now: Instant) {
+		self.last_received = now;
+	}
+
+	/// Handle a pong, returning the round-trip time if it belongs to the last ping.
+	pub fn pong(&mut self, data: &[u8], now: Instant) -> Option<Duration> {
+		let sequence = u64::from_le_bytes(data.try_into().ok()?);
+		let (expected, sent) = self.outstanding?;
+		if sequence != expected {
+			return None;
+		}
+		self.outstanding = None;
+
+		let rtt = now.saturating_duration_since(sent);
+		self.rtt = Some(match self.rtt {
+			None => Rtt { latest: rtt, smoothed: rtt, min: rtt },
+			Some(r) => Rtt {
+				latest: rtt,
+				smoothed: (r.smoothed * 7 + rtt) / 8,
+				min: r.min.min(rtt),
+			},
+		});
+		Some(rtt)
+	}
+
+	/// Check whether the other side is alive and get the data of a ping if one is due.
+	pub fn poll(&mut self, now: Instant) -> Result<Option<[u8; 8]>, Dead> {
+		if now.saturating_duration_since(self.last_received) >= self.timeout {
+			return Err(Dead);
+		}
+		if now < self.next_ping {
+			return Ok(None);
+		}
+		self.next_ping = now + self.interval;
+		self.outstanding = Some((self.sequence, now));
+		self.sequence += 1;
+		Ok(Some((self.sequence - 1).to_le_bytes()))
+	}
+
+	/// The time until [`Self::poll`] needs to be called again.
+	pub fn next_timeout(&self, now: Instant) -> Duration {
+		self.next_ping
+			.min(self.last_received + self.timeout)
+			.saturating_duration_since(now)
+	}
+
+	pub fn rtt(&self) -> Option<Rtt> {
+		self.rtt
+	}
+}
+
+/// Nothing was received from the other side in time.
+#[derive(Debug)]
+pub struct Dead;
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	const S: Duration = Duration::from_secs(1);
+
+	#[test]
+	fn ping_pong() {
+		let now = Instant::now();
+		let mut k = Keepalive::new(10 * S, 30 * S, now);
+		assert_eq!(k.poll(now).unwrap(), None);
+		assert_eq!(k.next_timeout(now), 10 * S);
+
+		let ping = k.poll(now + 10 * S).unwrap().unwrap();
+		assert_eq!(k.pong(&ping, now + 12 * S), Some(2 * S));
+		// Duplicate
+		assert_eq!(k.pong(&ping, now + 13 * S), None);
+
+		let ping = k.poll(now + 20 * S).unwrap().unwrap();
+		assert_eq!(k.pong(&ping, now + 30 * S), Some(10 * S));
+		let rtt = k.rtt().unwrap();
+		assert_eq!((rtt.latest, rtt.min, rtt.smoothed), (10 * S, 2 * S, 3 * S));
+	}
+
+	#[test]
+	fn dead() {
+		let now = Instant::now();
+		let mut k = Keepalive::new(10 * S, 30 * S, now);
+		k.received(now + 5 * S);
+		assert!(k.poll(now + 34 * S).is_ok());
+		assert_eq!(k.next_timeout(now + 34 * S), S);
+		assert!(k.poll(now + 35 * S).is_err());
+	}
+}
@@ -1,18 +1,145 @@
 mod client;
+mod compress;
+mod datagram;
+mod frame;
+mod keepalive;
+mod pipes;
+mod scheduler;
+mod websocket;
+mod window;
 
 use core::mem;
 use core::fmt;
 use std::net::{Ipv4Addr, SocketAddrV4};
+use std::io::{self, ErrorKind, Read, Write};
+use std::str::FromStr;
+use mio::event::Source;
 
-pub use client::StupidClient;
+pub use client::{Endpoint, StupidClient, Welcome};
+pub use compress::{Compression, CompressionStats, Compressor, Decompressor, DecompressError};
+pub use datagram::DatagramLink;
+pub use frame::{FrameReader, FrameWriter, ReceiveError};
+pub use keepalive::{Dead, Keepalive, Rtt};
+pub use pipes::Pipes;
+pub use scheduler::{Frame, Priorities, Scheduler};
+pub use websocket::WebSocket;
+pub use window::{Window, WindowError};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 #[repr(u8)]
 pub enum StupidType {
 	TCP = 0,
+	/// A datagram of a UDP flow. All datagrams of a flow must go to the remote address of the
+	/// first one; the server rejects the others.
 	UDP = 1,
+	/// Opens a TCP flow to the remote address. The server sends it back once connected.
 	TcpConnect = 2,
+	/// Closes a TCP flow. If the server failed or refused to connect the data is a
+	/// [`ConnectError`].
 	TcpFinish = 3,
+	/// ICMP echo request. The local port is the identifier, the remote port the sequence number.
+	IcmpEchoRequest = 4,
+	/// ICMP echo reply, with the same fields as [`Self::IcmpEchoRequest`].
+	IcmpEchoReply = 5,
+	/// First frame sent by the client. The data is the [`SessionToken`] of the session to
+	/// resume, or all zeroes for a new session, followed by the key to authenticate with. The
+	/// local port is the [`Compression`] the client would like to use.
+	Hello = 6,
+	/// Reply of the server to a hello, with the [`SessionToken`] of the session as data. If it
+	/// differs from the token in the hello the old session is gone. The local port is the
+	/// [`Compression`] both sides may use from now on.
+	Welcome = 7,
+	/// Sent periodically by both sides, with 8 bytes of data to be echoed in the pong.
+	Ping = 8,
+	Pong = 9,
+	/// Grants the other side more credit for a TCP flow, see [`Window`]. The data is the amount
+	/// of bytes as a 32 bit little-endian integer.
+	WindowUpdate = 10,
+	/// Like [`Self::TcpConnect`], but to the host named by the data, which the server
+	/// resolves. Only the port of the remote address is used.
+	TcpConnectName = 11,
+	/// Asks the server to listen on the remote address for a remote forward. The local port
+	/// identifies the forward and the data is the [`Protocol`]. The server sends it back with
+	/// no data once listening, or with a description of the error.
+	Listen = 12,
+	/// Tells the client the listener of a remote forward accepted a connection, or received
+	/// a datagram from a new peer. The remote address is the peer, the local port the new
+	/// flow, which is in [`ACCEPTED_PORTS`], and the data the forward as a 16 bit big-endian
+	/// integer. The client sends [`Self::TcpConnect`] back once it connected a TCP flow.
+	Accept = 13,
+	/// Tells the client the server refused or closed a UDP or ICMP flow, or dropped its
+	/// datagrams over a bandwidth limit or to another destination, with a [`ConnectError`] as
+	/// data. TCP flows are finished with the error instead.
+	Reject = 14,
+	/// Like [`Self::UDP`], but to the host named at the start of the data, see
+	/// [`name_datagram`]. The server resolves the name when it opens the flow, and later
+	/// datagrams must name the same host. Only the port of the remote address is used, and
+	/// the server replies with [`Self::UDP`] frames from
+	/// that remote address.
+	UdpName = 15,
+}
+
+/// The local ports the server picks for flows it accepted for remote forwards. A client with
+/// remote forwards picks the ports of its own flows below these.
+pub const ACCEPTED_PORTS: core::ops::RangeInclusive<u16> = 0x8000..=0xffff;
+
+/// The protocol of a forward.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Protocol {
+	Tcp = 0,
+	Udp = 1,
+}
+
+/// How frames are carried between the client and the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+	/// A single TCP connection.
+	Tcp,
+	/// UDP datagrams, see [`DatagramLink`].
+	Udp,
+	/// A WebSocket over HTTP, optionally through a proxy, see [`WebSocket`].
+	WebSocket,
+	/// A Unix domain socket.
+	Unix,
+	/// The stdin & stdout of a command, e.g. `ssh host stupid_tunnel server --stdio`.
+	Command,
+}
+
+/// A transport that carries frames as a reliable byte stream, like TCP.
+///
+/// Neither reads nor writes may block. The stream is read until it would block after it
+/// becomes readable, and writes that would block are retried once it becomes writable.
+pub trait StreamTransport: Read + Write + Source {}
+
+impl<T: Read + Write + Source> StreamTransport for T {}
+
+impl FromStr for Transport {
+	type Err = InvalidTransport;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"tcp" => Ok(Self::Tcp),
+			"udp" => Ok(Self::Udp),
+			"ws" => Ok(Self::WebSocket),
+			"unix" => Ok(Self::Unix),
+			"command" => Ok(Self::Command),
+			_ => Err(InvalidTransport),
+		}
+	}
+}
+
+#[derive(Debug)]
+pub struct InvalidTransport;
+
+/// Identifies a session, so a client can resume it after reconnecting.
+pub type SessionToken = [u8; 16];
+
+impl StupidType {
+	/// Whether frames of this type must arrive, in order. Frames of UDP & ICMP flows and
+	/// pings may be lost like the packets they carry.
+	pub fn is_reliable(self) -> bool {
+		!matches!(self, Self::UDP | Self::UdpName | Self::IcmpEchoRequest | Self::IcmpEchoReply | Self::Ping | Self::Pong)
+	}
 }
 
 impl From<StupidType> for u8 {
@@ -30,12 +157,125 @@ impl TryFrom<u8> for StupidType {
 			Self::UDP,
 			Self::TcpConnect,
 			Self::TcpFinish,
-		].get(usize::from(n)).copied().ok_or(InvalidType)
+			Self::IcmpEchoRequest,
+			Self::IcmpEchoReply,
+			Self::Hello,
+			Self::Welcome,
+			Self::Ping,
+			Self::Pong,
+			Self::WindowUpdate,
+			Self::TcpConnectName,
+			Self::Listen,
+			Self::Accept,
+			Self::Reject,
+			Self::UdpName,
+		].get(usize::from(n)).copied().ok_or(InvalidType(n))
 	}
 }
 
 #[derive(Debug)]
-pub struct InvalidType;
+pub struct InvalidType(pub u8);
+
+/// The data of a [`StupidType::UdpName`]: the length of the name as a byte, the name and the
+/// datagram.
+pub fn name_datagram(name: &str, data: &[u8]) -> Vec<u8> {
+	[&[name.len().try_into().unwrap()][..], name.as_bytes(), data].concat()
+}
+
+/// Split the data of a [`StupidType::UdpName`] into the name and the datagram.
+pub fn split_name_datagram(data: &[u8]) -> Option<(&str, &[u8])> {
+	let (len, rest) = data.split_first()?;
+	let (name, data) = rest.split_at_checked(usize::from(*len))?;
+	Some((str::from_utf8(name).ok()?, data))
+}
+
+impl TryFrom<u8> for Protocol {
+	type Error = ();
+
+	fn try_from(n: u8) -> Result<Self, Self::Error> {
+		match n {
+			0 => Ok(Self::Tcp),
+			1 => Ok(Self::Udp),
+			_ => Err(()),
+		}
+	}
+}
+
+impl fmt::Display for Protocol {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			Self::Tcp => "TCP",
+			Self::Udp => "UDP",
+		})
+	}
+}
+
+/// Why the server failed or refused to open a flow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectError {
+	Failed = 1,
+	Refused = 2,
+	Unreachable = 3,
+	TimedOut = 4,
+	/// The name in a [`StupidType::TcpConnectName`] couldn't be resolved.
+	NotResolved = 5,
+	/// The access control list of the server doesn't allow the destination.
+	Denied = 6,
+	/// The client reached one of its quotas on the server.
+	Limited = 7,
+}
+
+impl ConnectError {
+	/// Get the reason from the data of a [`StupidType::TcpFinish`] or [`StupidType::Reject`], if
+	/// any.
+	pub fn from_data(data: &[u8]) -> Option<Self> {
+		Some(match data.first()? {
+			2 => Self::Refused,
+			3 => Self::Unreachable,
+			4 => Self::TimedOut,
+			5 => Self::NotResolved,
+			6 => Self::Denied,
+			7 => Self::Limited,
+			_ => Self::Failed,
+		})
+	}
+}
+
+impl From<&io::Error> for ConnectError {
+	fn from(e: &io::Error) -> Self {
+		match e.kind() {
+			ErrorKind::ConnectionRefused => Self::Refused,
+			ErrorKind::NetworkUnreachable | ErrorKind::HostUnreachable => Self::Unreachable,
+			ErrorKind::TimedOut => Self::TimedOut,
+			_ => Self::Failed,
+		}
+	}
+}
+
+impl fmt::Display for ConnectError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			Self::Failed => "connection failed",
+			Self::Refused => "connection refused",
+			Self::Unreachable => "destination unreachable",
+			Self::TimedOut => "connection timed out",
+			Self::NotResolved => "name not resolved",
+			Self::Denied => "destination not allowed",
+			Self::Limited => "quota exceeded",
+		})
+	}
+}
+
+/// Fill a buffer with random bytes suitable for tokens.
+pub fn fill_random(buf: &mut [u8]) {
+	let ret = unsafe { libc::getrandom(buf.as_mut_ptr().cast(), buf.len(), 0) };
+	assert_eq!(ret, buf.len() as isize, "getrandom failed");
+}
+
+/// Compare two byte strings in time independent of their contents.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |d, (x, y)| d | (x ^ y)) == 0
+}
 
 #[derive(Clone, Copy)]
 #[repr(C)]
@@ -48,6 +288,11 @@ pub struct StupidDataHeader {
 }
 
 impl StupidDataHeader {
+	/// The maximum amount of data a single frame can carry.
+	pub const MAX_DATA_LENGTH: usize = u16::MAX as usize;
+	/// Set in the type if the data is compressed, see [`Compression`].
+	const COMPRESSED: u8 = 0x80;
+
 	pub fn from_raw(data: &[u8]) -> Result<(Self, &[u8], &[u8]), FromRawError> {
 		if data.len() < mem::size_of::<Self>() {
 			return Err(FromRawError::Truncated);
@@ -55,9 +300,12 @@ impl StupidDataHeader {
 
 		let (h, d) = data.split_at(mem::size_of::<Self>());
 		let h = unsafe { *h.as_ptr().cast::<Self>() };
+		if d.len() < usize::from(h.data_length()) {
+			return Err(FromRawError::Truncated);
+		}
 		let (d, e) = d.split_at(h.data_length().into());
 
-		unsafe { Ok((h, d, e)) }
+		Ok((h, d, e))
 	}
 
 	pub fn new(ty: StupidType, remote: SocketAddrV4, local: u16, data_length: u16) -> Self {
@@ -79,7 +327,16 @@ impl StupidDataHeader {
 	}
 
 	pub fn ty(&self) -> Result<StupidType, InvalidType> {
-		self.ty.try_into()
+		(self.ty & !Self::COMPRESSED).try_into()
+	}
+
+	pub fn is_compressed(&self) -> bool {
+		self.ty & Self::COMPRESSED != 0
+	}
+
+	/// Mark the data as compressed.
+	pub fn set_compressed(&mut self) {
+		self.ty |= Self::COMPRESSED;
 	}
 
 	pub fn data_length(&self) -> u16 {
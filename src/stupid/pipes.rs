@@ -0,0 +1,135 @@
+use std::fs::File;
+use std::io::{Error, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::process::{Child, Command, Stdio};
+use mio::event::Source;
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+
+/// A stream made of two file descriptors, one to read from and one to write to, such as
+/// stdin & stdout or the pipes of a child process.
+///
+/// Neither reads nor writes block.
+pub struct Pipes {
+	read: File,
+	write: File,
+	/// The process on the other end, which is killed when the pipes are dropped.
+	child: Option<Child>,
+}
+
+impl Pipes {
+	/// Use stdin & stdout of this process.
+	pub fn stdio() -> Result<Self, Error> {
+		// Duplicate the descriptors so stdin & stdout stay open when the pipes are dropped.
+		let dup = |fd| match unsafe { libc::dup(fd) } {
+			-1 => Err(Error::last_os_error()),
+			fd => Ok(unsafe { File::from_raw_fd(fd) }),
+		};
+		Self::new(dup(libc::STDIN_FILENO)?, dup(libc::STDOUT_FILENO)?, None)
+	}
+
+	/// Run a shell command and use its stdin & stdout. Its stderr is inherited.
+	pub fn spawn(command: &str) -> Result<Self, Error> {
+		let mut child = Command::new("/bin/sh")
+			.args(["-c", command])
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.spawn()?;
+		let read = unsafe { File::from_raw_fd(child.stdout.take().unwrap().into_raw_fd()) };
+		let write = unsafe { File::from_raw_fd(child.stdin.take().unwrap().into_raw_fd()) };
+		Self::new(read, write, Some(child))
+	}
+
+	fn new(read: File, write: File, child: Option<Child>) -> Result<Self, Error> {
+		for fd in [read.as_raw_fd(), write.as_raw_fd()] {
+			let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+			if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1 {
+				return Err(Error::last_os_error());
+			}
+		}
+		Ok(Self { read, write, child })
+	}
+}
+
+impl Read for Pipes {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+		self.read.read(buf)
+	}
+}
+
+impl Write for Pipes {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+		self.write.write(buf)
+	}
+
+	fn flush(&mut self) -> Result<(), Error> {
+		self.write.flush()
+	}
+}
+
+impl Source for Pipes {
+	fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<(), Error> {
+		self.each(interests, |fd, i| SourceFd(&fd).register(registry, token, i))
+	}
+
+	fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<(), Error> {
+		self.each(interests, |fd, i| SourceFd(&fd).reregister(registry, token, i))
+	}
+
+	fn deregister(&mut self, registry: &Registry) -> Result<(), Error> {
+		SourceFd(&self.read.as_raw_fd()).deregister(registry)?;
+		SourceFd(&self.write.as_raw_fd()).deregister(registry)
+	}
+}
+
+impl Pipes {
+	/// Register the descriptor that is read from for readable events and the one that is
+	/// written to for writable events.
+	fn each(&self, interests: Interest, mut f: impl FnMut(RawFd, Interest) -> Result<(), Error>) -> Result<(), Error> {
+		if interests.is_readable() {
+			f(self.read.as_raw_fd(), Interest::READABLE)?;
+		}
+		if interests.is_writable() {
+			f(self.write.as_raw_fd(), Interest::WRITABLE)?;
+		}
+		Ok(())
+	}
+}
+
+impl Drop for Pipes {
+	fn drop(&mut self) {
+		if let Some(child) = &mut self.child {
+			let _ = child.kill();
+			let _ = child.wait();
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::io::ErrorKind;
+
+	#[test]
+	fn spawn() {
+		let mut pipes = Pipes::spawn("cat").unwrap();
+		let mut buf = [0; 0x10000];
+		assert_eq!(pipes.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+		// Much more than fits in a pipe, so writing has to wait for cat to catch up.
+		let data = (0..1_000_000).map(|i| i as u8).collect::<Vec<_>>();
+		let (mut written, mut received) = (0, Vec::new());
+		while received.len() < data.len() {
+			match pipes.write(&data[written..]) {
+				Ok(n) => written += n,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+				Err(e) => panic!("{}", e),
+			}
+			match pipes.read(&mut buf) {
+				Ok(n) => received.extend_from_slice(&buf[..n]),
+				Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::yield_now(),
+				Err(e) => panic!("{}", e),
+			}
+		}
+		assert_eq!(received, data);
+	}
+}
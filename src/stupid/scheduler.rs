@@ -0,0 +1,327 @@
+use super::*;
+use std::collections::{HashMap, VecDeque};
+
+/// Decides in which order queued frames are sent, so a bulk transfer can't starve the other
+/// flows sharing the link.
+///
+/// Frames are queued per flow and flows with queued frames take turns with deficit round
+/// robin: on its turn a flow may send [`QUANTUM`] bytes times its weight, plus whatever it
+/// couldn't use on earlier turns because its next frame was larger. Frames that belong to no
+/// flow, like pings, are sent before all others.
+///
+/// Frames are only taken from the scheduler once the link can send them right away, so
+/// frames queued later can still overtake frames of busier flows.
+///
+/// The data of frames of flows is compressed when they are queued, if compression is enabled.
+pub struct Scheduler {
+	priorities: Priorities,
+	compressor: Option<Compressor>,
+	flows: HashMap<Flow, Queue>,
+	/// Flows with queued frames, in the order they take turns. The first one has the turn.
+	active: VecDeque<Flow>,
+	control: VecDeque<Frame>,
+	/// The amount of data queued in total, in bytes.
+	queued: usize,
+}
+
+/// The amount of data a flow with a weight of 1 may send per turn.
+const QUANTUM: usize = 1500;
+
+/// Identifies the flow a frame belongs to by its protocol and local port.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Flow {
+	Tcp(u16),
+	Udp(u16),
+	/// An ICMP echo flow, by identifier.
+	Icmp(u16),
+}
+
+/// A frame, including header, ready to be sent.
+pub struct Frame {
+	pub ty: StupidType,
+	pub data: Vec<u8>,
+	/// Whether the frame must arrive, see [`StupidType::is_reliable`].
+	pub reliable: bool,
+}
+
+/// How large a share of the link flows get by the port they are destined to. Flows to other
+/// ports, and ICMP flows, have a weight of 1.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Priorities(HashMap<u16, u16>);
+
+struct Queue {
+	frames: VecDeque<Frame>,
+	/// The amount of data queued, in bytes.
+	queued: usize,
+	/// The amount of data the flow may still send before its turn is over.
+	deficit: usize,
+	/// Whether the flow has been given its quantum for the current turn.
+	has_turn: bool,
+	weight: usize,
+}
+
+impl Scheduler {
+	/// The amount of data a flow may have queued. Unreliable frames beyond it are dropped.
+	pub const MAX_FLOW_QUEUE: usize = 1 << 18;
+
+	pub fn new(priorities: Priorities) -> Self {
+		Self {
+			priorities,
+			compressor: None,
+			flows: HashMap::new(),
+			active: VecDeque::new(),
+			control: VecDeque::new(),
+			queued: 0,
+		}
+	}
+
+	/// Queue a frame. Unreliable frames are dropped if their flow has too much queued.
+	pub fn push(&mut self, ty: StupidType, remote: SocketAddrV4, local: u16, data: &[u8]) {
+		let flow = Flow::new(ty, local);
+		let compressed = self.compressor.as_mut().filter(|_| flow.is_some()).and_then(|c| c.compress(data));
+		let mut h = StupidDataHeader::new(ty, remote, local, compressed.unwrap_or(data).len().try_into().unwrap());
+		if compressed.is_some() {
+			h.set_compressed();
+		}
+		let frame = Frame { ty, data: [&h.as_ref()[..], compressed.unwrap_or(data)].concat(), reliable: ty.is_reliable() };
+		let flow = match flow {
+			Some(f) => f,
+			None => {
+				self.queued += frame.data.len();
+				self.control.push_back(frame);
+				return;
+			}
+		};
+		if !frame.reliable && !self.has_room(flow) {
+			return;
+		}
+		let priorities = &self.priorities;
+		let queue = self.flows.entry(flow).or_insert_with(|| {
+			let weight = match flow {
+				Flow::Icmp(_) => 1,
+				_ => priorities.weight(remote.port()),
+			};
+			Queue { frames: VecDeque::new(), queued: 0, deficit: 0, has_turn: false, weight }
+		});
+		if queue.frames.is_empty() {
+			self.active.push_back(flow);
+		}
+		self.queued += frame.data.len();
+		queue.queued += frame.data.len();
+		queue.frames.push_back(frame);
+	}
+
+	/// Queue a frame ahead of all others, like the hello that must start a connection.
+	pub fn push_first(&mut self, ty: StupidType, remote: SocketAddrV4, local: u16, data: &[u8]) {
+		let h = StupidDataHeader::new(ty, remote, local, data.len().try_into().unwrap());
+		self.requeue(Frame { ty, data: [&h.as_ref()[..], data].concat(), reliable: ty.is_reliable() });
+	}
+
+	/// Queue a frame that was taken before ahead of all others, as it wasn't sent in full.
+	pub fn requeue(&mut self, frame: Frame) {
+		self.queued += frame.data.len();
+		self.control.push_front(frame);
+	}
+
+	/// Drop the frames of all TCP flows, including their window updates, as the flows are
+	/// reset.
+	pub fn clear_tcp(&mut self) {
+		let queued = &mut self.queued;
+		self.control.retain(|f| {
+			let keep = !matches!(f.ty, StupidType::WindowUpdate) && !matches!(Flow::new(f.ty, 0), Some(Flow::Tcp(_)));
+			if !keep {
+				*queued -= f.data.len();
+			}
+			keep
+		});
+		self.flows.retain(|flow, queue| {
+			let keep = !matches!(flow, Flow::Tcp(_));
+			if !keep {
+				*queued -= queue.queued;
+			}
+			keep
+		});
+		self.active.retain(|f| !matches!(f, Flow::Tcp(_)));
+	}
+
+	/// Take the next frame to send, if any.
+	pub fn pop(&mut self) -> Option<Frame> {
+		if let Some(frame) = self.control.pop_front() {
+			self.queued -= frame.data.len();
+			return Some(frame);
+		}
+		loop {
+			let flow = *self.active.front()?;
+			let queue = self.flows.get_mut(&flow).unwrap();
+			if !queue.has_turn {
+				queue.has_turn = true;
+				queue.deficit += QUANTUM * queue.weight;
+			}
+			let len = queue.frames.front().unwrap().data.len();
+			if len > queue.deficit {
+				// Save the deficit for the next turn.
+				queue.has_turn = false;
+				self.active.rotate_left(1);
+				continue;
+			}
+			let frame = queue.frames.pop_front().unwrap();
+			queue.deficit -= len;
+			queue.queued -= len;
+			self.queued -= len;
+			if queue.frames.is_empty() {
+				// An idle flow can't save up for a burst later.
+				self.flows.remove(&flow);
+				self.active.pop_front();
+			}
+			return Some(frame);
+		}
+	}
+
+	/// Whether a flow has room for more frames.
+	pub fn has_room(&self, flow: Flow) -> bool {
+		self.flows.get(&flow).map_or(true, |q| q.queued < Self::MAX_FLOW_QUEUE)
+	}
+
+	/// The amount of data queued in total, in bytes.
+	pub fn queued(&self) -> usize {
+		self.queued
+	}
+
+	/// Compress the data of frames queued from now on, or stop compressing. Frames with less
+	/// data than `threshold` are never compressed.
+	pub fn set_compression(&mut self, compression: Compression, threshold: usize) {
+		match compression {
+			Compression::None => self.compressor = None,
+			Compression::Deflate => drop(self.compressor.get_or_insert_with(|| Compressor::new(threshold))),
+		}
+	}
+
+	/// The amount of data of frames before and after compression, if compression is enabled.
+	pub fn compression_stats(&self) -> Option<CompressionStats> {
+		self.compressor.as_ref().map(Compressor::stats)
+	}
+}
+
+impl Flow {
+	/// The flow frames of the given type belong to, if any.
+	pub fn new(ty: StupidType, local: u16) -> Option<Self> {
+		match ty {
+			StupidType::TCP | StupidType::TcpConnect | StupidType::TcpConnectName | StupidType::TcpFinish => Some(Self::Tcp(local)),
+			StupidType::UDP | StupidType::UdpName | StupidType::Reject => Some(Self::Udp(local)),
+			StupidType::IcmpEchoRequest | StupidType::IcmpEchoReply => Some(Self::Icmp(local)),
+			// Window updates are for the opposite direction, so they shouldn't wait for data.
+			// Accepts must arrive before the data of the flow, which control frames always do.
+			StupidType::Hello | StupidType::Welcome | StupidType::Ping | StupidType::Pong | StupidType::WindowUpdate | StupidType::Listen | StupidType::Accept => None,
+		}
+	}
+}
+
+impl Priorities {
+	/// The largest weight a port can be given.
+	pub const MAX_WEIGHT: u16 = 1000;
+
+	/// Give flows to a port the given weight, which must be between 1 and [`Self::MAX_WEIGHT`].
+	pub fn set(&mut self, port: u16, weight: u16) {
+		assert!((1..=Self::MAX_WEIGHT).contains(&weight), "invalid weight");
+		self.0.insert(port, weight);
+	}
+
+	pub fn weight(&self, port: u16) -> usize {
+		self.0.get(&port).copied().unwrap_or(1).into()
+	}
+
+	/// Add the weights of another set, replacing those of the same ports.
+	pub fn extend(&mut self, other: Self) {
+		self.0.extend(other.0);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn remote(port: u16) -> SocketAddrV4 {
+		SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)
+	}
+
+	fn local(frame: &Frame) -> u16 {
+		StupidDataHeader::from_raw(&frame.data).unwrap().0.local()
+	}
+
+	#[test]
+	fn interactive_overtakes_bulk() {
+		let mut s = Scheduler::new(Priorities::default());
+		let big = vec![0; StupidDataHeader::MAX_DATA_LENGTH];
+		for _ in 0..3 {
+			s.push(StupidType::TCP, remote(80), 1, &big);
+		}
+		s.push(StupidType::TCP, remote(22), 2, b"ls\n");
+		s.push(StupidType::Ping, remote(0), 0, &[0; 8]);
+
+		assert!(s.pop().map_or(false, |f| !f.reliable));
+		assert_eq!(s.pop().map(|f| local(&f)), Some(2));
+		let rest = std::iter::from_fn(|| s.pop()).map(|f| local(&f)).collect::<Vec<_>>();
+		assert_eq!(rest, [1, 1, 1]);
+		assert_eq!(s.queued(), 0);
+	}
+
+	#[test]
+	fn weights() {
+		let mut p = Priorities::default();
+		p.set(22, 3);
+		let mut s = Scheduler::new(p);
+		for _ in 0..100 {
+			s.push(StupidType::TCP, remote(80), 1, &[0; 989]);
+			s.push(StupidType::TCP, remote(22), 2, &[0; 989]);
+		}
+		let first = std::iter::from_fn(|| s.pop()).take(96).map(|f| local(&f)).collect::<Vec<_>>();
+		let bulk = first.iter().filter(|l| **l == 1).count();
+		assert_eq!((bulk, first.len() - bulk), (24, 72));
+	}
+
+	#[test]
+	fn drop_unreliable() {
+		let mut s = Scheduler::new(Priorities::default());
+		let big = vec![0; StupidDataHeader::MAX_DATA_LENGTH];
+		let mut pushed = 0;
+		while s.has_room(Flow::Udp(1)) {
+			s.push(StupidType::UDP, remote(53), 1, &big);
+			pushed += 1;
+		}
+		s.push(StupidType::UDP, remote(53), 1, b"dropped");
+		// Reliable frames are never dropped.
+		s.push(StupidType::TCP, remote(80), 1, &big);
+		let popped = std::iter::from_fn(|| s.pop()).collect::<Vec<_>>();
+		assert_eq!(popped.len(), pushed + 1);
+		assert!(popped.iter().all(|f| f.data.len() == 11 + big.len()));
+	}
+
+	#[test]
+	fn compress_flows_only() {
+		let mut s = Scheduler::new(Priorities::default());
+		s.set_compression(Compression::Deflate, 100);
+		let text = b"hello ".repeat(100);
+		s.push(StupidType::Ping, remote(0), 0, &text);
+		s.push(StupidType::TCP, remote(80), 1, &text);
+		s.push(StupidType::TCP, remote(80), 1, b"hello");
+		let popped = std::iter::from_fn(|| s.pop())
+			.map(|f| StupidDataHeader::from_raw(&f.data).unwrap().0.is_compressed())
+			.collect::<Vec<_>>();
+		assert_eq!(popped, [false, true, false]);
+		assert!(s.compression_stats().unwrap().ratio() < 0.5);
+	}
+
+	#[test]
+	fn clear_tcp() {
+		let mut s = Scheduler::new(Priorities::default());
+		s.push(StupidType::TCP, remote(80), 1, b"data");
+		s.push(StupidType::WindowUpdate, remote(80), 1, &[0; 4]);
+		s.push(StupidType::UDP, remote(53), 2, b"query");
+		s.push(StupidType::Ping, remote(0), 0, &[0; 8]);
+		s.push_first(StupidType::Hello, remote(0), 0, b"key");
+		s.clear_tcp();
+		let popped = std::iter::from_fn(|| s.pop()).map(|f| f.ty as u8).collect::<Vec<_>>();
+		assert_eq!(popped, [StupidType::Hello as u8, StupidType::Ping as u8, StupidType::UDP as u8]);
+		assert_eq!(s.queued(), 0);
+	}
+}
@@ -0,0 +1,529 @@
+use super::*;
+use crate::http;
+use std::io::{Error, ErrorKind, Read, Write};
+use mio::{Interest, Registry, Token};
+
+/// Carries frames in binary WebSocket messages ([RFC 6455]), so the tunnel can pass through
+/// networks and proxies that only allow HTTP.
+///
+/// Message boundaries have no meaning: the payload of all messages forms the same byte stream
+/// as a plain TCP connection, so a [`FrameReader`] can read from a `WebSocket` directly.
+///
+/// Both sides upgrade the connection as part of reading: the client handles the answer to
+/// its upgrade request, and to the CONNECT request before it if it goes through an HTTP
+/// proxy, and the server answers the upgrade request. Writes fail with `WouldBlock` until the
+/// connection is upgraded, so the client can start writing frames right after connecting.
+///
+/// Each write is sent as a single message. Messages are written in full before the next one is
+/// accepted, so if the stream would block the rest is kept and written by the next write or
+/// flush.
+///
+/// [RFC 6455]: https://datatracker.ietf.org/doc/html/rfc6455
+pub struct WebSocket<S> {
+	stream: S,
+	/// Whether this is the client side, which masks everything it sends.
+	client: bool,
+	/// Whether the upgrade request of the client has been answered.
+	upgraded: bool,
+	/// The requests of the client that haven't been answered yet.
+	request: Option<Handshake>,
+	/// Set once the other side sent a close frame.
+	closed: bool,
+	/// Headers and control frames that haven't been handled yet.
+	buf: Box<[u8]>,
+	start: usize,
+	end: usize,
+	/// The amount of payload left in the current data frame.
+	remaining: u64,
+	mask: [u8; 4],
+	/// The offset of the next payload byte in the current data frame.
+	offset: usize,
+	/// Data that has yet to be written, starting with the rest of a partially written frame.
+	out: Vec<u8>,
+}
+
+/// What the client waits for before the connection is upgraded.
+struct Handshake {
+	/// The key the server must prove it understood the upgrade with.
+	key: String,
+	/// The upgrade request, held back until the HTTP proxy connected if there is one.
+	upgrade: Option<Vec<u8>>,
+}
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+const FIN: u8 = 0x80;
+const MASKED: u8 = 0x80;
+
+/// Appended to the key of the client to prove the server understood the upgrade.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+impl<S: Read + Write> WebSocket<S> {
+	/// Wrap a stream as the client, requesting `path` from `host`, through the HTTP proxy at
+	/// the other end of the stream if `proxied` is set. The stream may still be connecting.
+	pub fn client(stream: S, host: &str, path: &str, proxied: bool) -> Self {
+		let mut key = [0; 16];
+		fill_random(&mut key);
+		let key = base64(&key);
+		let upgrade = format!(
+			"GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+			path, host, key,
+		).into_bytes();
+		let mut ws = Self::new(stream, true);
+		match proxied {
+			true => {
+				ws.out = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", host).into_bytes();
+				ws.request = Some(Handshake { key, upgrade: Some(upgrade) });
+			}
+			false => {
+				ws.out = upgrade;
+				ws.request = Some(Handshake { key, upgrade: None });
+			}
+		}
+		ws
+	}
+
+	/// Wrap a stream accepted by the server, which still has to be upgraded.
+	pub fn server(stream: S) -> Self {
+		Self::new(stream, false)
+	}
+
+	fn new(stream: S, client: bool) -> Self {
+		Self {
+			stream,
+			client,
+			upgraded: false,
+			request: None,
+			closed: false,
+			buf: vec![0; http::MAX_HEAD].into(),
+			start: 0,
+			end: 0,
+			remaining: 0,
+			mask: [0; 4],
+			offset: 0,
+			out: Vec::new(),
+		}
+	}
+
+	/// Queue a frame and write as much as possible.
+	fn write_frame(&mut self, opcode: u8, data: &[u8]) -> Result<(), Error> {
+		let masked = if self.client { MASKED } else { 0 };
+		let frame = &mut self.out;
+		frame.push(FIN | opcode);
+		match data.len() {
+			n @ 0..=125 => frame.push(masked | n as u8),
+			n @ 126..=0xffff => {
+				frame.push(masked | 126);
+				frame.extend((n as u16).to_be_bytes());
+			}
+			n => {
+				frame.push(masked | 127);
+				frame.extend((n as u64).to_be_bytes());
+			}
+		}
+		if self.client {
+			let mut mask = [0; 4];
+			fill_random(&mut mask);
+			frame.extend(mask);
+			let start = frame.len();
+			frame.extend(data);
+			apply_mask(&mut frame[start..], mask, 0);
+		} else {
+			frame.extend(data);
+		}
+		self.write_out()
+	}
+
+	/// Write as much of the queued data as possible.
+	fn write_out(&mut self) -> Result<(), Error> {
+		while !self.out.is_empty() {
+			match self.stream.write(&self.out) {
+				Ok(0) => return Err(ErrorKind::WriteZero.into()),
+				Ok(n) => drop(self.out.drain(..n)),
+				Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+				Err(e) if e.kind() == ErrorKind::Interrupted => (),
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(())
+	}
+
+	/// Read more data into the buffer. Returns `false` if the stream was closed.
+	fn fill(&mut self) -> Result<bool, Error> {
+		self.buf.copy_within(self.start..self.end, 0);
+		self.end -= self.start;
+		self.start = 0;
+		if self.end == self.buf.len() {
+			return Err(invalid("header too large"));
+		}
+		let n = self.stream.read(&mut self.buf[self.end..])?;
+		self.end += n;
+		Ok(n > 0)
+	}
+
+	/// Answer the upgrade request of the client, which is the first `len` bytes.
+	fn upgrade(&mut self, len: usize) -> Result<(), Error> {
+		let accept = http::Head::parse(&self.buf[..len])
+			.filter(|h| h.start.starts_with("GET "))
+			.filter(|h| h.header("upgrade").map_or(false, |u| u.eq_ignore_ascii_case("websocket")))
+			.filter(|h| h.header("sec-websocket-version") == Some("13"))
+			.and_then(|h| h.header("sec-websocket-key"))
+			.map(accept_key);
+		match accept {
+			Some(accept) => {
+				let response = format!(
+					"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+					accept,
+				);
+				self.out.extend(response.as_bytes());
+				self.write_out()?;
+				self.start = len;
+				self.upgraded = true;
+				Ok(())
+			}
+			None => {
+				let _ = self.stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+				Err(invalid("invalid upgrade request"))
+			}
+		}
+	}
+
+	/// Continue the upgrade with what was received. Returns `false` if the stream was closed.
+	fn handshake(&mut self) -> Result<bool, Error> {
+		while !self.upgraded {
+			match http::head_len(&self.buf[self.start..self.end]) {
+				Some(len) if self.client => self.answered(len)?,
+				Some(len) => self.upgrade(len)?,
+				None if !self.fill()? => return Ok(false),
+				None => (),
+			}
+		}
+		Ok(true)
+	}
+
+	/// Handle the answer of the proxy or the server to a request of the client, which is the
+	/// next `len` bytes.
+	fn answered(&mut self, len: usize) -> Result<(), Error> {
+		let request = self.request.as_mut().unwrap();
+		let head = http::Head::parse(&self.buf[self.start..][..len]);
+		self.start += len;
+		if let Some(upgrade) = request.upgrade.take() {
+			let head = head.ok_or_else(|| invalid("invalid response from proxy"))?;
+			if !matches!(head.status(), Some(200..=299)) {
+				return Err(Error::new(ErrorKind::Other, format!("proxy refused to connect: {}", head.start)));
+			}
+			self.out.extend(upgrade);
+			return self.write_out();
+		}
+		let head = head.ok_or_else(|| invalid("invalid response to upgrade"))?;
+		if head.status() != Some(101) {
+			return Err(Error::new(ErrorKind::Other, format!("server refused upgrade: {}", head.start)));
+		}
+		if head.header("sec-websocket-accept") != Some(&accept_key(&request.key)) {
+			return Err(invalid("server sent the wrong accept key"));
+		}
+		self.request = None;
+		self.upgraded = true;
+		Ok(())
+	}
+
+	/// Handle the next frame header, and the payload if it is a control frame. Returns `false`
+	/// if more data is needed.
+	fn next_frame(&mut self) -> Result<bool, Error> {
+		let b = &self.buf[self.start..self.end];
+		if b.len() < 2 {
+			return Ok(false);
+		}
+		let (fin, opcode, masked) = (b[0] & FIN != 0, b[0] & 0xf, b[1] & MASKED != 0);
+		if b[0] & 0x70 != 0 {
+			return Err(invalid("reserved bits are set"));
+		}
+		// Only the client masks its frames.
+		if masked == self.client {
+			return Err(invalid("frame is masked incorrectly"));
+		}
+		let (len, mut at) = match b[1] & 0x7f {
+			126 if b.len() >= 4 => (u16::from_be_bytes([b[2], b[3]]).into(), 4),
+			127 if b.len() >= 10 => (u64::from_be_bytes(b[2..10].try_into().unwrap()), 10),
+			126 | 127 => return Ok(false),
+			n => (u64::from(n), 2),
+		};
+		let mut mask = [0; 4];
+		if masked {
+			if b.len() < at + 4 {
+				return Ok(false);
+			}
+			mask.copy_from_slice(&b[at..at + 4]);
+			at += 4;
+		}
+
+		match opcode {
+			CONTINUATION | BINARY => {
+				self.start += at;
+				self.remaining = len;
+				self.mask = mask;
+				self.offset = 0;
+			}
+			CLOSE | PING | PONG => {
+				if !fin || len > 125 {
+					return Err(invalid("invalid control frame"));
+				}
+				let len = len as usize;
+				if b.len() < at + len {
+					return Ok(false);
+				}
+				let mut payload = b[at..at + len].to_vec();
+				apply_mask(&mut payload, mask, 0);
+				self.start += at + len;
+				match opcode {
+					PING => self.write_frame(PONG, &payload)?,
+					CLOSE => {
+						// The other side may be gone already.
+						let _ = self.write_frame(CLOSE, &payload[..len.min(2)]);
+						self.closed = true;
+					}
+					_ => (),
+				}
+			}
+			TEXT => return Err(invalid("text messages are not supported")),
+			_ => return Err(invalid("unknown opcode")),
+		}
+		Ok(true)
+	}
+}
+
+impl<S: Read + Write> Read for WebSocket<S> {
+	/// Read the payload of data frames, handling the upgrade and control frames in between.
+	fn read(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+		if !self.handshake()? {
+			return Ok(0);
+		}
+		loop {
+			if self.closed {
+				return Ok(0);
+			}
+			if self.remaining > 0 {
+				let max = out.len().min(self.remaining.try_into().unwrap_or(usize::MAX));
+				let n = if self.start < self.end {
+					let n = max.min(self.end - self.start);
+					out[..n].copy_from_slice(&self.buf[self.start..][..n]);
+					self.start += n;
+					n
+				} else {
+					match self.stream.read(&mut out[..max])? {
+						0 => return Ok(0),
+						n => n,
+					}
+				};
+				apply_mask(&mut out[..n], self.mask, self.offset);
+				self.offset += n;
+				self.remaining -= n as u64;
+				return Ok(n);
+			}
+			if !self.next_frame()? && !self.fill()? {
+				return Ok(0);
+			}
+		}
+	}
+}
+
+impl<S: Read + Write> Write for WebSocket<S> {
+	/// Send all data as a single binary message, once the previous one has been written.
+	fn write(&mut self, data: &[u8]) -> Result<usize, Error> {
+		self.write_out()?;
+		if !self.out.is_empty() || !self.upgraded {
+			return Err(ErrorKind::WouldBlock.into());
+		}
+		self.write_frame(BINARY, data).map(|()| data.len())
+	}
+
+	fn flush(&mut self) -> Result<(), Error> {
+		self.write_out()?;
+		if !self.out.is_empty() || !self.upgraded {
+			return Err(ErrorKind::WouldBlock.into());
+		}
+		self.stream.flush()
+	}
+}
+
+impl<S: Source> Source for WebSocket<S> {
+	fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<(), Error> {
+		self.stream.register(registry, token, interests)
+	}
+
+	fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<(), Error> {
+		self.stream.reregister(registry, token, interests)
+	}
+
+	fn deregister(&mut self, registry: &Registry) -> Result<(), Error> {
+		self.stream.deregister(registry)
+	}
+}
+
+/// Mask or unmask data starting at the given offset in the payload.
+fn apply_mask(data: &mut [u8], mask: [u8; 4], offset: usize) {
+	for (i, b) in data.iter_mut().enumerate() {
+		*b ^= mask[(offset + i) % 4];
+	}
+}
+
+fn accept_key(key: &str) -> String {
+	base64(&sha1([key, ACCEPT_GUID].concat().as_bytes()))
+}
+
+fn invalid(message: &str) -> Error {
+	Error::new(ErrorKind::InvalidData, message)
+}
+
+fn base64(data: &[u8]) -> String {
+	const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+	let mut s = String::with_capacity((data.len() + 2) / 3 * 4);
+	for c in data.chunks(3) {
+		let n = c.iter().enumerate().fold(0, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+		for i in 0..4 {
+			match i <= c.len() {
+				true => s.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize].into()),
+				false => s.push('='),
+			}
+		}
+	}
+	s
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+	let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+	let mut message = data.to_vec();
+	message.push(0x80);
+	while message.len() % 64 != 56 {
+		message.push(0);
+	}
+	message.extend((data.len() as u64 * 8).to_be_bytes());
+
+	for block in message.chunks(64) {
+		let mut w = [0u32; 80];
+		for (w, b) in w.iter_mut().zip(block.chunks(4)) {
+			*w = u32::from_be_bytes(b.try_into().unwrap());
+		}
+		for i in 16..80 {
+			w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+		}
+		let [mut a, mut b, mut c, mut d, mut e] = h;
+		for (i, w) in w.iter().enumerate() {
+			let (f, k) = match i {
+				0..=19 => ((b & c) | (!b & d), 0x5a827999),
+				20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+				40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+				_ => (b ^ c ^ d, 0xca62c1d6),
+			};
+			let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*w);
+			e = d;
+			d = c;
+			c = b.rotate_left(30);
+			b = a;
+			a = t;
+		}
+		for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+			*h = h.wrapping_add(v);
+		}
+	}
+
+	let mut out = [0; 20];
+	for (o, h) in out.chunks_mut(4).zip(h) {
+		o.copy_from_slice(&h.to_be_bytes());
+	}
+	out
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+	use std::thread;
+
+	#[test]
+	fn accept() {
+		// The example of RFC 6455.
+		assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+		assert_eq!(base64(b"ab"), "YWI=");
+		assert_eq!(base64(b"a"), "YQ==");
+	}
+
+	/// A plain HTTP proxy that handles a single CONNECT request.
+	fn proxy() -> SocketAddr {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap();
+		thread::spawn(move || {
+			let (mut client, _) = listener.accept().unwrap();
+			let mut head = Vec::new();
+			while !head.ends_with(b"\r\n\r\n") {
+				let mut byte = [0];
+				client.read_exact(&mut byte).unwrap();
+				head.push(byte[0]);
+			}
+			let head = http::Head::parse(&head).unwrap();
+			let target = head.start.strip_prefix("CONNECT ").unwrap().split(' ').next().unwrap();
+			let server = TcpStream::connect(target).unwrap();
+			client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+			let copy = |mut from: TcpStream, mut to: TcpStream| thread::spawn(move || {
+				let _ = std::io::copy(&mut from, &mut to);
+				let _ = to.shutdown(Shutdown::Write);
+			});
+			copy(client.try_clone().unwrap(), server.try_clone().unwrap());
+			copy(server, client);
+		});
+		address
+	}
+
+	fn frame(local: u16, data: &[u8]) -> Vec<u8> {
+		let remote = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 80);
+		let h = StupidDataHeader::new(StupidType::TCP, remote, local, data.len().try_into().unwrap());
+		[&h.as_ref()[..], data].concat()
+	}
+
+	fn read_all(ws: &mut WebSocket<TcpStream>) -> Vec<(u16, Vec<u8>)> {
+		let (mut reader, mut out, mut frames) = (FrameReader::new(), [0; 0x10000], Vec::new());
+		loop {
+			match reader.read(ws, &mut out) {
+				Ok(Some((h, data))) => frames.push((h.local(), data.to_vec())),
+				Err(ReceiveError::Closed) => return frames,
+				r => panic!("{:?}", r.map(|_| ())),
+			}
+		}
+	}
+
+	#[test]
+	fn through_proxy() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let target = listener.local_addr().unwrap().to_string();
+		let server = thread::spawn(move || {
+			let mut ws = WebSocket::server(listener.accept().unwrap().0);
+			let frames = read_all(&mut ws);
+			for (local, data) in &frames {
+				ws.write_all(&frame(*local, data)).unwrap();
+			}
+			ws.stream.shutdown(Shutdown::Write).unwrap();
+			frames
+		});
+
+		let mut ws = WebSocket::client(TcpStream::connect(proxy()).unwrap(), &target, "/tunnel", true);
+		// Frames are held back until the connection is upgraded.
+		assert_eq!(ws.write(&frame(1, b"early")).unwrap_err().kind(), ErrorKind::WouldBlock);
+		assert!(ws.handshake().unwrap());
+		let big = vec![7; StupidDataHeader::MAX_DATA_LENGTH];
+		let sent = [(1, b"hello".to_vec()), (2, Vec::new()), (3, big)];
+		for (local, data) in &sent {
+			ws.write_all(&frame(*local, data)).unwrap();
+		}
+		// A ping in between must be answered without disturbing the data.
+		ws.write_frame(PING, b"ping").unwrap();
+		ws.stream.shutdown(Shutdown::Write).unwrap();
+
+		assert_eq!(server.join().unwrap(), sent);
+		assert_eq!(read_all(&mut ws), sent);
+	}
+}
@@ -0,0 +1,128 @@
+use super::*;
+
+/// Credit-based flow control of a single TCP flow, so neither side sends more data than the
+/// other side has room for.
+///
+/// Both sides start out with [`Self::INITIAL`] bytes of credit for each flow. The receiving
+/// side grants credit again with a [`StupidType::WindowUpdate`] once data has been consumed:
+/// written to the upstream socket by the server, or acknowledged by the application on the
+/// client.
+pub struct Window {
+	/// The amount of data that may still be sent.
+	credit: u32,
+	/// The amount of data the other side may still send.
+	allowed: u32,
+	/// The amount of data consumed since the last update.
+	consumed: u32,
+}
+
+impl Window {
+	/// The credit of a new flow.
+	pub const INITIAL: u32 = 1 << 18;
+	/// The amount of data to consume before granting it back, to avoid an update per frame.
+	const THRESHOLD: u32 = Self::INITIAL / 4;
+
+	pub fn new() -> Self {
+		Self { credit: Self::INITIAL, allowed: Self::INITIAL, consumed: 0 }
+	}
+
+	/// The amount of data that may still be sent.
+	pub fn credit(&self) -> usize {
+		self.credit as usize
+	}
+
+	/// Use credit for data that is being sent, which fails if it is more than the credit left.
+	pub fn sent(&mut self, len: usize) -> Result<(), WindowError> {
+		self.credit = u32::try_from(len).ok()
+			.and_then(|len| self.credit.checked_sub(len))
+			.ok_or(WindowError::NoCredit)?;
+		Ok(())
+	}
+
+	/// Handle a window update of the other side.
+	pub fn update(&mut self, data: &[u8]) -> Result<(), WindowError> {
+		let grant = data.try_into().map(u32::from_le_bytes).map_err(|_| WindowError::Invalid)?;
+		self.credit = self.credit.checked_add(grant)
+			.filter(|c| *c <= Self::INITIAL)
+			.ok_or(WindowError::Invalid)?;
+		Ok(())
+	}
+
+	/// Account for data that was received, which fails if it is more than the other side was
+	/// granted.
+	pub fn received(&mut self, len: usize) -> Result<(), WindowError> {
+		self.allowed = u32::try_from(len).ok()
+			.and_then(|len| self.allowed.checked_sub(len))
+			.ok_or(WindowError::Exceeded)?;
+		Ok(())
+	}
+
+	/// Note that received data was consumed. Returns the window update to send, if one is due.
+	pub fn consumed(&mut self, len: usize) -> Option<[u8; 4]> {
+		self.consumed += u32::try_from(len).unwrap();
+		// Grant right away if the other side is about to run out.
+		(self.consumed >= Self::THRESHOLD || self.allowed < Self::THRESHOLD).then(|| {
+			self.allowed += self.consumed;
+			mem::take(&mut self.consumed).to_le_bytes()
+		})
+	}
+}
+
+#[derive(Debug)]
+pub enum WindowError {
+	/// The window update is malformed or grants more than the initial credit.
+	Invalid,
+	/// The other side sent more than it was granted.
+	Exceeded,
+	/// More data was about to be sent than the other side granted.
+	NoCredit,
+}
+
+impl fmt::Display for WindowError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Invalid => f.write_str("invalid window update"),
+			Self::Exceeded => f.write_str("flow exceeded its window"),
+			Self::NoCredit => f.write_str("no credit left to send"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn grant() {
+		let (mut sender, mut receiver) = (Window::new(), Window::new());
+		sender.sent(Window::INITIAL as usize).unwrap();
+		receiver.received(Window::INITIAL as usize).unwrap();
+		assert_eq!(sender.credit(), 0);
+		assert!(matches!(sender.sent(1), Err(WindowError::NoCredit)));
+		assert!(matches!(receiver.received(1), Err(WindowError::Exceeded)));
+
+		// Running out, so even a little is granted right away.
+		let update = receiver.consumed(1000).unwrap();
+		sender.update(&update).unwrap();
+		assert_eq!(sender.credit(), 1000);
+		receiver.received(1000).unwrap();
+
+		sender.sent(1000).unwrap();
+		let update = receiver.consumed(Window::INITIAL as usize - 1000).unwrap();
+		sender.update(&update).unwrap();
+		assert_eq!(sender.credit(), Window::INITIAL as usize - 1000);
+		assert!(matches!(sender.update(&1001u32.to_le_bytes()), Err(WindowError::Invalid)));
+		assert!(matches!(sender.update(&[1, 2]), Err(WindowError::Invalid)));
+	}
+
+	#[test]
+	fn batch() {
+		let mut receiver = Window::new();
+		receiver.received(10_000).unwrap();
+		assert_eq!(receiver.consumed(5_000), None);
+		assert_eq!(receiver.consumed(5_000), None);
+		receiver.received(Window::THRESHOLD as usize).unwrap();
+		let update = receiver.consumed(Window::THRESHOLD as usize).unwrap();
+		assert_eq!(u32::from_le_bytes(update), Window::THRESHOLD + 10_000);
+	}
+}
@@ -10,6 +10,12 @@ pub struct Tcp6Connection {
 	sequence_num: u32,
 	acknowledge_num: u32,
 	closed: bool,
+	/// The window advertised to the other side.
+	window: u16,
+	/// The sequence number up to which the other side acknowledged data.
+	acknowledged: u32,
+	/// The window advertised by the other side.
+	peer_window: u16,
 }
 
 impl Tcp6Connection {
@@ -24,6 +30,10 @@ impl Tcp6Connection {
 			// SYN increases the ACK by 1
 			acknowledge_num: tcp.sequence_num().wrapping_add(1),
 			closed: false,
+			window: 0xffff,
+			// The SYN isn't data, so acknowledging it doesn't count.
+			acknowledged: sequence_num.wrapping_add(1),
+			peer_window: tcp.window(),
 		};
 
 		let tcp = TcpHeader::new(
@@ -32,7 +42,7 @@ impl Tcp6Connection {
 			slf.sequence_num,
 			slf.acknowledge_num,
 			Flags::new().set_acknowledge(true).set_synchronize(true),
-			0xffff,
+			slf.window,
 			Options::NONE,
 			&[],
 		);
@@ -70,7 +80,7 @@ impl Tcp6Connection {
 			self.sequence_num,
 			self.acknowledge_num,
 			Flags::new().set_acknowledge(true).set_finish(tcp.flags.finish()),
-			0xffff,
+			self.window,
 			Options::NONE,
 			&[],
 		);
@@ -107,7 +117,7 @@ impl Tcp6Connection {
 			self.sequence_num,
 			self.acknowledge_num,
 			Flags::new().set_acknowledge(true),
-			0xffff,
+			self.window,
 			Options::NONE,
 			data,
 		);
@@ -136,7 +146,7 @@ impl Tcp6Connection {
 			self.sequence_num,
 			self.acknowledge_num,
 			Flags::new().set_acknowledge(true).set_finish(true),
-			0xffff,
+			self.window,
 			Options::NONE,
 			data,
 		);
@@ -157,6 +167,83 @@ impl Tcp6Connection {
 		
 		Ok(&out[..ip.byte_len() + tcp.byte_len() + data.len()])
 	}
+
+	/// Handle the acknowledgement and window of a segment of the other side. Returns the amount
+	/// of data that was newly acknowledged.
+	pub fn acknowledge(&mut self, tcp: &TcpHeader) -> usize {
+		if !tcp.flags.acknowledge() {
+			return 0;
+		}
+		let acknowledged = tcp.acknowledge_num().wrapping_sub(self.acknowledged);
+		// Ignore old acknowledgements and those of data that hasn't been sent.
+		if acknowledged > self.sequence_num.wrapping_sub(self.acknowledged) {
+			return 0;
+		}
+		self.acknowledged = tcp.acknowledge_num();
+		self.peer_window = tcp.window();
+		acknowledged as usize
+	}
+
+	/// The amount of data that can be sent before the window of the other side is full.
+	pub fn sendable(&self) -> usize {
+		usize::from(self.peer_window).saturating_sub(self.sequence_num.wrapping_sub(self.acknowledged) as usize)
+	}
+
+	/// The window advertised to the other side.
+	pub fn window(&self) -> u16 {
+		self.window
+	}
+
+	/// Set the window to advertise to the other side. It is limited to 65535 bytes, as window
+	/// scaling isn't used.
+	pub fn set_window(&mut self, window: usize) {
+		self.window = window.min(0xffff) as u16;
+	}
+
+	/// Tell the other side about the current window.
+	pub fn window_update<'a>(&self, out: &'a mut [u8]) -> &'a [u8] {
+		let tcp = TcpHeader::new(
+			(self.local_ip, self.local_port),
+			(self.remote_ip, self.remote_port),
+			self.sequence_num,
+			self.acknowledge_num,
+			Flags::new().set_acknowledge(true),
+			self.window,
+			Options::NONE,
+			&[],
+		);
+
+		let ip = IPv6Header::new(tcp.length(&[]).unwrap(), 6, 255, self.local_ip, self.remote_ip);
+
+		out[..ip.byte_len()].copy_from_slice(ip.as_ref());
+		out[ip.byte_len()..][..tcp.byte_len()].copy_from_slice(tcp.as_ref());
+
+		&out[..ip.byte_len() + tcp.byte_len()]
+	}
+
+	/// Abort the connection.
+	pub fn reset<'a>(&mut self, out: &'a mut [u8]) -> &'a [u8] {
+
+		let tcp = TcpHeader::new(
+			(self.local_ip, self.local_port),
+			(self.remote_ip, self.remote_port),
+			self.sequence_num,
+			self.acknowledge_num,
+			Flags::new().set_acknowledge(true).set_reset(true),
+			0,
+			Options::NONE,
+			&[],
+		);
+
+		let ip = IPv6Header::new(tcp.length(&[]).unwrap(), 6, 255, self.local_ip, self.remote_ip);
+
+		out[..ip.byte_len()].copy_from_slice(ip.as_ref());
+		out[ip.byte_len()..][..tcp.byte_len()].copy_from_slice(tcp.as_ref());
+
+		self.closed = true;
+
+		&out[..ip.byte_len() + tcp.byte_len()]
+	}
 }
 
 pub enum Response<'a> {
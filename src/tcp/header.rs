@@ -21,7 +21,16 @@ pub struct TcpHeader {
 
 impl TcpHeader {
 	pub fn from_raw_ipv6(data: &[u8], source: Ipv6Addr, destination: Ipv6Addr) -> Result<(&Self, Options, &[u8]), FromRawError> {
-		// TODO checksum
+		let (h, o, e) = Self::from_raw_ipv6_unchecked(data)?;
+		// Including the checksum field in the sum results in zero if it is correct.
+		match h.checksum_ipv6(source, destination, o, e) {
+			Ok(0) => Ok((h, o, e)),
+			_ => Err(FromRawError::BadChecksum),
+		}
+	}
+
+	/// Parse the header without verifying the checksum, e.g. if the checksum is offloaded.
+	pub fn from_raw_ipv6_unchecked(data: &[u8]) -> Result<(&Self, Options, &[u8]), FromRawError> {
 		if data.len() < mem::size_of::<Self>() {
 			return Err(FromRawError::Truncated);
 		}
@@ -31,7 +40,13 @@ impl TcpHeader {
 		// SAFETY: the data fits and is properly aligned.
 		let h = unsafe { &*h.as_ptr().cast::<Self>() };
 
-		let (o, e) = e.split_at(usize::from(h.data_offset()) * 4 - mem::size_of_val(h));
+		let o = (usize::from(h.data_offset()) * 4)
+			.checked_sub(mem::size_of_val(h))
+			.ok_or(FromRawError::BadDataOffset)?;
+		if e.len() < o {
+			return Err(FromRawError::Truncated);
+		}
+		let (o, e) = e.split_at(o);
 		let o = Options(o);
 
 		Ok((h, o, e))
@@ -82,21 +97,21 @@ impl TcpHeader {
 		).map_err(|_| ChecksumError::DataTooLarge)?.to_be_bytes();
 
 		let sum = Checksum::new()
-			.feed_ref(&src)
-			.feed_ref(&dest)
-			.feed_ref(&[0, 6]) // zero, protocol
-			.feed_ref(&tcp_length)
-			.feed_ref(&self.source)
-			.feed_ref(&self.destination)
-			.feed_ref(&self.sequence_num)
-			.feed_ref(&self.acknowledge_num)
-			.feed_ref(core::slice::from_ref(&self.data_offset))
-			.feed_ref(core::slice::from_ref(&self.flags.0))
-			.feed_ref(&self.window)
-			.feed_ref(&self.checksum)
-			.feed_ref(&self.urgent_pointer)
-			.feed_ref(options.0)
-			.feed_ref(data)
+			.feed(&src)
+			.feed(&dest)
+			.feed(&[0, 6]) // zero, protocol
+			.feed(&tcp_length)
+			.feed(&self.source)
+			.feed(&self.destination)
+			.feed(&self.sequence_num)
+			.feed(&self.acknowledge_num)
+			.feed(core::slice::from_ref(&self.data_offset))
+			.feed(core::slice::from_ref(&self.flags.0))
+			.feed(&self.window)
+			.feed(&self.checksum)
+			.feed(&self.urgent_pointer)
+			.feed(options.0)
+			.feed(data)
 			.finish();
 		Ok(sum)
 	}
@@ -312,6 +327,7 @@ pub enum FromRawError {
 	BadChecksum,
 	Truncated,
 	BadOption,
+	BadDataOffset,
 }
 
 pub struct Tcp6HeaderBuilder<'a> {
@@ -334,4 +350,25 @@ mod test {
 		let tcp = TcpHeader::new((src, 232), (dst, 244), 58, 23, Flags(23), 22, Options(&[1, 1, 2, 4, 5, 24]), b"gutentag");
 		assert_eq!(tcp.checksum(), 55718);
 	}
+
+	#[test]
+	fn verify_checksum() {
+		let (src, dst) = (Ipv6Addr::LOCALHOST, Ipv6Addr::UNSPECIFIED);
+		let opt = [1, 1, 2, 4, 5, 24, 0, 0];
+		let tcp = TcpHeader::new((src, 232), (dst, 244), 58, 23, Flags(23), 22, Options(&opt), b"gutentag");
+		let mut raw = tcp.as_ref().to_vec();
+		raw.extend_from_slice(&opt);
+		raw.extend_from_slice(b"gutentag");
+
+		let (h, o, data) = TcpHeader::from_raw_ipv6(&raw, src, dst).unwrap();
+		assert_eq!(h, &tcp);
+		assert_eq!(o.as_ref(), &opt);
+		assert_eq!(data, b"gutentag");
+
+		raw[21] ^= 1;
+		assert!(matches!(TcpHeader::from_raw_ipv6(&raw, src, dst), Err(FromRawError::BadChecksum)));
+		assert!(TcpHeader::from_raw_ipv6_unchecked(&raw).is_ok());
+		raw.truncate(22);
+		assert!(matches!(TcpHeader::from_raw_ipv6_unchecked(&raw), Err(FromRawError::Truncated)));
+	}
 }
@@ -1,13 +1,16 @@
 use crate::ifreq::{IfReq, NewIfReqError};
+use core::fmt;
 use core::mem;
 use libc::{c_ulong, ioctl};
-use std::io::{Error, Read, Write};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::process::Command;
 use std::os::unix::io::RawFd;
 use mio::{Registry, Token, Interest};
 use mio::unix::SourceFd;
 
 pub struct Tun {
 	fd: RawFd,
+	name: String,
 }
 
 impl Tun {
@@ -18,32 +21,42 @@ impl Tun {
 		const PATH: &[u8] = b"/dev/net/tun\0";
 
 		let ifr = IfReq::new_tun(name, true).map_err(NewTunError::IfReq)?;
-		let fd = unsafe { libc::open(PATH.as_ptr().cast(), 2) }; // O_RDWR
+		let fd = unsafe { libc::open(PATH.as_ptr().cast(), libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC) };
 		if fd < 0 {
-			todo!("decode the returned error");
+			return Err(NewTunError::Open(Error::last_os_error()));
 		}
 
 		match unsafe { ioctl(fd, Self::SET_IFF, &ifr) } {
 			0 => (),
 			_ => {
+				let e = Error::last_os_error();
 				unsafe { libc::close(fd) };
-				todo!("decode the returned error");
+				return Err(NewTunError::SetIff(e));
 			},
 		}
 
-		Ok(Self { fd })
+		Ok(Self { fd, name: String::from_utf8_lossy(name).into() })
 	}
 
-	pub fn add_ipv6_address(&mut self, ip: std::net::Ipv6Addr) {
+	/// A tun backed by a socket, whose peer is returned to read and write its packets.
+	#[cfg(test)]
+	pub fn pair() -> Result<(Self, std::os::unix::net::UnixDatagram), Error> {
+		use std::os::unix::io::IntoRawFd;
+		let (tun, peer) = std::os::unix::net::UnixDatagram::pair()?;
+		tun.set_nonblocking(true)?;
+		peer.set_nonblocking(true)?;
+		Ok((Self { fd: tun.into_raw_fd(), name: "test".into() }, peer))
+	}
+
+	pub fn set_mtu(&mut self, mtu: usize) -> Result<(), Error> {
 		// TODO use rtnetlink directly
-		std::process::Command::new("ip")
-			.args(["-6", "addr", "add", &*format!("{}/96", ip), "dev", "stupid_tunnel"])
-			.output()
-			.unwrap();
-		std::process::Command::new("ip")
-			.args(["-6", "link", "set", "stupid_tunnel", "up"])
-			.output()
-			.unwrap();
+		run(Command::new("ip").args(["link", "set", "dev", &self.name, "mtu", &*mtu.to_string()]))
+	}
+
+	pub fn add_ipv6_address(&mut self, ip: std::net::Ipv6Addr, prefix_length: u8) -> Result<(), Error> {
+		// TODO use rtnetlink directly
+		run(Command::new("ip").args(["-6", "addr", "add", &*format!("{}/{}", ip, prefix_length), "dev", &self.name]))?;
+		run(Command::new("ip").args(["-6", "link", "set", &self.name, "up"]))
 		/*
 		#[repr(C)]
 		struct IfAddrMsg {
@@ -87,6 +100,17 @@ impl Tun {
 	}
 }
 
+/// Run a command, turning a non-zero exit status into an error.
+fn run(command: &mut Command) -> Result<(), Error> {
+	let out = command.output()?;
+	if out.status.success() {
+		Ok(())
+	} else {
+		let msg = String::from_utf8_lossy(&out.stderr);
+		Err(Error::new(ErrorKind::Other, format!("{:?} failed: {}", command, msg.trim_end())))
+	}
+}
+
 impl Drop for Tun {
 	fn drop(&mut self) {
 		unsafe { libc::close(self.fd) };
@@ -150,4 +174,18 @@ const fn ioc(dir: u8, ty: u8, nr: u8, size: usize) -> c_ulong {
 #[derive(Debug)]
 pub enum NewTunError {
 	IfReq(NewIfReqError),
+	/// Opening `/dev/net/tun` failed.
+	Open(Error),
+	/// Creating or attaching to the interface failed.
+	SetIff(Error),
+}
+
+impl fmt::Display for NewTunError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::IfReq(e) => write!(f, "invalid name: {:?}", e),
+			Self::Open(e) => write!(f, "failed to open /dev/net/tun: {}", e),
+			Self::SetIff(e) => write!(f, "failed to set up interface: {}", e),
+		}
+	}
 }
@@ -14,14 +14,28 @@ pub struct UDPHeader {
 
 impl UDPHeader {
 	pub fn from_raw_ipv6(data: &[u8], source: Ipv6Addr, destination: Ipv6Addr) -> Result<(Self, &[u8]), FromRawError> {
-		// TODO checksum
+		let (h, e) = Self::from_raw_ipv6_unchecked(data)?;
+		// A zero checksum means "no checksum", which is not allowed with IPv6 (RFC 8200 section 8.1)
+		match h.checksum_ipv6(source, destination, e) {
+			Ok(c) if h.checksum() != 0 && c == h.checksum() => Ok((h, e)),
+			_ => Err(FromRawError::BadChecksum),
+		}
+	}
+
+	/// Parse the header without verifying the checksum, e.g. if the checksum is offloaded.
+	pub fn from_raw_ipv6_unchecked(data: &[u8]) -> Result<(Self, &[u8]), FromRawError> {
 		if data.len() < mem::size_of::<Self>() {
 			return Err(FromRawError::Truncated);
 		}
 
 		let (h, e) = data.split_at(mem::size_of::<Self>());
 		// SAFETY: the data fits and is properly aligned.
-		unsafe { Ok((*h.as_ptr().cast::<Self>(), e)) }
+		let h = unsafe { *h.as_ptr().cast::<Self>() };
+		let l = usize::from(u16::from_be_bytes(h.length));
+		let e = l.checked_sub(mem::size_of::<Self>())
+			.and_then(|l| e.get(..l))
+			.ok_or(FromRawError::Truncated)?;
+		Ok((h, e))
 	}
 
 	pub fn new_ipv6(source: SocketAddrV6, destination: SocketAddrV6, data: &[u8]) -> Result<Self, ChecksumError> {
@@ -52,7 +66,7 @@ impl UDPHeader {
 
 	/// Total length of the UDP packet (header + data)
 	pub fn length(&self, data: &[u8]) -> Result<u16, ()> {
-		let l = mem::size_of_val(self) * 4 + data.len();
+		let l = mem::size_of_val(self) + data.len();
 		l.try_into().map_err(|_| ())
 	}
 
@@ -62,20 +76,20 @@ impl UDPHeader {
 	}
 
 	fn checksum_ipv6(&self, source: Ipv6Addr, destination: Ipv6Addr, data: &[u8]) -> Result<u16, ChecksumError> {
-		let mut sum = 0usize;
 		let (src, dest) = (source.octets(), destination.octets());
 		let udp_length = u16::try_from(mem::size_of_val(self) + data.len()).map_err(|_| ChecksumError::DataTooLarge)?.to_be_bytes();
 		let sum = Checksum::new()
-			.feed_ref(&src)
-			.feed_ref(&dest)
-			.feed_ref(&[0, 17]) // zero, protocol
-			.feed_ref(&udp_length)
-			.feed_ref(&self.source_port)
-			.feed_ref(&self.destination_port)
-			.feed_ref(&self.length)
-			.feed_ref(data)
+			.feed(&src)
+			.feed(&dest)
+			.feed(&[0, 17]) // zero, protocol
+			.feed(&udp_length)
+			.feed(&self.source_port)
+			.feed(&self.destination_port)
+			.feed(&self.length)
+			.feed(data)
 			.finish();
-		Ok(sum)
+		// Zero means "no checksum", so use the other representation of zero instead.
+		Ok(if sum == 0 { 0xffff } else { sum })
 	}
 }
 
@@ -115,7 +129,25 @@ mod test {
 	fn checksum() {
 		let src = Ipv6Addr::LOCALHOST;
 		let dst = Ipv6Addr::UNSPECIFIED;
-		let udp = UDPHeader::new_ipv6(src, 232, dst, 244, b"gutentag").unwrap();
+		let udp = UDPHeader::new_ipv6(SocketAddrV6::new(src, 232, 0, 0), SocketAddrV6::new(dst, 244, 0, 0), b"gutentag").unwrap();
 		assert_eq!(udp.checksum(), 21051);
 	}
+
+	#[test]
+	fn verify_checksum() {
+		let (src, dst) = (SocketAddrV6::new(Ipv6Addr::LOCALHOST, 232, 0, 0), SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 244, 0, 0));
+		let udp = UDPHeader::new_ipv6(src, dst, b"gutentag").unwrap();
+		let mut raw = udp.as_ref().to_vec();
+		raw.extend_from_slice(b"gutentag");
+
+		let (h, data) = UDPHeader::from_raw_ipv6(&raw, *src.ip(), *dst.ip()).unwrap();
+		assert_eq!(h.source_port(), 232);
+		assert_eq!(data, b"gutentag");
+
+		raw[10] ^= 1;
+		assert!(matches!(UDPHeader::from_raw_ipv6(&raw, *src.ip(), *dst.ip()), Err(FromRawError::BadChecksum)));
+		assert!(UDPHeader::from_raw_ipv6_unchecked(&raw).is_ok());
+		raw.pop();
+		assert!(matches!(UDPHeader::from_raw_ipv6_unchecked(&raw), Err(FromRawError::Truncated)));
+	}
 }
@@ -0,0 +1,334 @@
+//! Opening flows to destinations through an upstream SOCKS5 proxy instead of directly: TCP
+//! connections with CONNECT and UDP sockets with UDP ASSOCIATE.
+
+use crate::socks::{self, Address, Command, ParseError, Reply, Response};
+use crate::stupid::ConnectError;
+use core::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proxy {
+	pub address: SocketAddr,
+	/// The username and password to authenticate with, if the proxy requires them.
+	pub credentials: Option<(String, String)>,
+}
+
+/// The SOCKS5 handshake of a request over a non-blocking stream to the proxy.
+pub struct Handshake {
+	state: State,
+	credentials: Option<(String, String)>,
+	/// The request to send once authenticated.
+	request: Vec<u8>,
+	/// Data that wasn't written to the proxy yet.
+	out: Vec<u8>,
+	/// What was received of the current reply of the proxy.
+	received: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+	Method,
+	Password,
+	Reply,
+}
+
+impl Handshake {
+	/// A handshake to ask the proxy to connect to `destination`, or to associate a UDP socket
+	/// sending to it.
+	pub fn new(proxy: &Proxy, command: Command, destination: SocketAddr) -> Self {
+		let methods: &[u8] = match proxy.credentials {
+			Some(_) => &[socks::METHOD_NONE, socks::METHOD_PASSWORD],
+			None => &[socks::METHOD_NONE],
+		};
+		Self {
+			state: State::Method,
+			credentials: proxy.credentials.clone(),
+			request: socks::request(command, &Address::Ip(destination)),
+			out: socks::greeting(methods),
+			received: Vec::new(),
+		}
+	}
+
+	/// Continue the handshake once the stream is connected or has become readable or writable.
+	/// Returns the address the proxy bound for the request once it succeeded.
+	///
+	/// Nothing past the reply to the request is read, so the stream can be used as is after.
+	pub fn resume(&mut self, stream: &mut (impl Read + Write)) -> Result<Option<SocketAddr>, Error> {
+		loop {
+			while !self.out.is_empty() {
+				match stream.write(&self.out) {
+					Ok(0) => return Err(Error::Io(ErrorKind::WriteZero.into())),
+					Ok(n) => drop(self.out.drain(..n)),
+					Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+					Err(e) if e.kind() == ErrorKind::Interrupted => (),
+					Err(e) => return Err(Error::Io(e)),
+				}
+			}
+			let missing = match self.advance()? {
+				Step::Done(bound) => return Ok(Some(bound)),
+				Step::Sent => continue,
+				Step::Missing(n) => n,
+			};
+			let start = self.received.len();
+			self.received.resize(start + missing, 0);
+			let result = stream.read(&mut self.received[start..]);
+			self.received.truncate(start + result.as_ref().map_or(0, |n| *n));
+			match result {
+				Ok(0) => return Err(Error::Closed),
+				Ok(_) => (),
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+				Err(e) if e.kind() == ErrorKind::Interrupted => (),
+				Err(e) => return Err(Error::Io(e)),
+			}
+		}
+	}
+
+	/// Handle the reply of the proxy if it is complete.
+	fn advance(&mut self) -> Result<Step, Error> {
+		match self.state {
+			State::Method => {
+				let method = match socks::parse_method(&self.received)? {
+					Some((m, _)) => m,
+					None => return Ok(Step::Missing(2 - self.received.len())),
+				};
+				match (method, &self.credentials) {
+					(socks::METHOD_NONE, _) => self.send_request(),
+					(socks::METHOD_PASSWORD, Some((username, password))) => {
+						self.out = socks::password(username.as_bytes(), password.as_bytes());
+						self.state = State::Password;
+					}
+					_ => return Err(Error::Method),
+				}
+			}
+			State::Password => match socks::parse_password_status(&self.received)? {
+				Some((true, _)) => self.send_request(),
+				Some((false, _)) => return Err(Error::Authentication),
+				None => return Ok(Step::Missing(2 - self.received.len())),
+			},
+			State::Reply => {
+				return match socks::parse_reply(&self.received)? {
+					Some((Response { reply: Reply::Succeeded, bound: Address::Ip(bound) }, _)) => Ok(Step::Done(bound)),
+					// Only the relay of a UDP association matters, which is never a name.
+					Some((Response { reply: Reply::Succeeded, .. }, _)) => Err(Error::Protocol(ParseError::Malformed)),
+					Some((Response { reply, .. }, _)) => Err(Error::Reply(reply)),
+					None => Ok(Step::Missing(socks::missing_reply(&self.received))),
+				};
+			}
+		}
+		self.received.clear();
+		Ok(Step::Sent)
+	}
+
+	fn send_request(&mut self) {
+		self.out = self.request.clone();
+		self.state = State::Reply;
+	}
+}
+
+/// What happened after handling what the proxy sent.
+enum Step {
+	/// The request succeeded, with the address the proxy bound.
+	Done(SocketAddr),
+	/// Another message is waiting to be written.
+	Sent,
+	/// The reply isn't complete, with how many bytes are missing at least.
+	Missing(usize),
+}
+
+#[derive(Debug)]
+pub enum Error {
+	Io(io::Error),
+	/// The proxy closed the connection before the handshake was done.
+	Closed,
+	Protocol(ParseError),
+	/// The proxy accepts none of the methods offered to authenticate with.
+	Method,
+	/// The proxy rejected the username and password.
+	Authentication,
+	/// The proxy failed the request.
+	Reply(Reply),
+}
+
+impl Error {
+	/// What to tell the client about the flow.
+	pub fn connect_error(&self) -> ConnectError {
+		match self {
+			Self::Io(e) => e.into(),
+			Self::Reply(Reply::NotAllowed) => ConnectError::Denied,
+			Self::Reply(Reply::NetworkUnreachable | Reply::HostUnreachable) => ConnectError::Unreachable,
+			Self::Reply(Reply::ConnectionRefused) => ConnectError::Refused,
+			Self::Reply(Reply::TtlExpired) => ConnectError::TimedOut,
+			_ => ConnectError::Failed,
+		}
+	}
+}
+
+impl From<ParseError> for Error {
+	fn from(e: ParseError) -> Self {
+		Self::Protocol(e)
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Io(e) => e.fmt(f),
+			Self::Closed => f.write_str("upstream proxy closed the connection"),
+			Self::Protocol(e) => write!(f, "invalid reply from upstream proxy: {}", e),
+			Self::Method => f.write_str("upstream proxy accepts no offered authentication method"),
+			Self::Authentication => f.write_str("upstream proxy rejected the username and password"),
+			Self::Reply(r) => write!(f, "upstream proxy failed the request: {}", r),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::net::{TcpListener, TcpStream, UdpSocket};
+	use std::thread;
+
+	/// Serve a single request of a SOCKS5 client, requiring the credentials if any.
+	fn stand_in(credentials: Option<(&'static [u8], &'static [u8])>) -> SocketAddr {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap();
+		thread::spawn(move || {
+			let (mut client, _) = listener.accept().unwrap();
+			let read = |client: &mut TcpStream| {
+				let mut buf = [0; 512];
+				let len = client.read(&mut buf).unwrap();
+				buf[..len].to_vec()
+			};
+			let greeting = read(&mut client);
+			let (methods, _) = socks::parse_greeting(&greeting).unwrap().unwrap();
+			let method = if credentials.is_some() { socks::METHOD_PASSWORD } else { socks::METHOD_NONE };
+			if !methods.contains(&method) {
+				client.write_all(&socks::method(socks::METHOD_UNACCEPTABLE)).unwrap();
+				return;
+			}
+			client.write_all(&socks::method(method)).unwrap();
+			if let Some(expected) = credentials {
+				let ok = socks::parse_password(&read(&mut client)).unwrap().unwrap().0 == expected;
+				client.write_all(&socks::password_status(ok)).unwrap();
+				if !ok {
+					return;
+				}
+			}
+			let request = socks::parse_request(&read(&mut client)).unwrap().unwrap().0;
+			let destination = match request.address {
+				Address::Ip(a) => a,
+				Address::Domain(..) => unreachable!(),
+			};
+			match request.command {
+				Command::Connect => match TcpStream::connect(destination) {
+					Ok(mut upstream) => {
+						let bound = upstream.local_addr().unwrap();
+						client.write_all(&socks::reply(Reply::Succeeded, bound)).unwrap();
+						let mut down = upstream.try_clone().unwrap();
+						let mut up = client.try_clone().unwrap();
+						thread::spawn(move || io::copy(&mut down, &mut up));
+						let _ = io::copy(&mut client, &mut upstream);
+					}
+					Err(_) => client.write_all(&socks::reply(Reply::ConnectionRefused, destination)).unwrap(),
+				},
+				Command::UdpAssociate => {
+					let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+					// Tell the client to send to the address it reached the proxy on.
+					let bound = SocketAddr::new("0.0.0.0".parse().unwrap(), relay.local_addr().unwrap().port());
+					client.write_all(&socks::reply(Reply::Succeeded, bound)).unwrap();
+					let (mut buf, mut owner) = ([0; 512], None);
+					loop {
+						let (len, from) = relay.recv_from(&mut buf).unwrap();
+						if owner.is_none_or(|o| o == from) {
+							owner = Some(from);
+							if let Ok((Address::Ip(to), data)) = socks::parse_datagram(&buf[..len]) {
+								relay.send_to(data, to).unwrap();
+							}
+						} else {
+							let mut datagram = Vec::new();
+							socks::datagram_header(&Address::Ip(from), &mut datagram);
+							datagram.extend_from_slice(&buf[..len]);
+							relay.send_to(&datagram, owner.unwrap()).unwrap();
+						}
+					}
+				}
+				Command::Bind => unreachable!(),
+			}
+		});
+		address
+	}
+
+	fn proxy(address: SocketAddr, credentials: Option<(&str, &str)>) -> Proxy {
+		Proxy { address, credentials: credentials.map(|(u, p)| (u.into(), p.into())) }
+	}
+
+	#[test]
+	fn connect() {
+		let server = TcpListener::bind("127.0.0.1:0").unwrap();
+		let destination = server.local_addr().unwrap();
+		thread::spawn(move || {
+			let (mut s, _) = server.accept().unwrap();
+			// Sent right away, so it may arrive together with the reply of the proxy.
+			s.write_all(b"banner").unwrap();
+			io::copy(&mut s.try_clone().unwrap(), &mut s).unwrap();
+		});
+		let proxy = proxy(stand_in(Some((b"user", b"pass"))), Some(("user", "pass")));
+		let mut stream = TcpStream::connect(proxy.address).unwrap();
+		let mut handshake = Handshake::new(&proxy, Command::Connect, destination);
+		assert!(handshake.resume(&mut stream).unwrap().is_some());
+		let mut buf = [0; 6];
+		stream.read_exact(&mut buf).unwrap();
+		assert_eq!(&buf, b"banner");
+		stream.write_all(b"hello!").unwrap();
+		stream.read_exact(&mut buf).unwrap();
+		assert_eq!(&buf, b"hello!");
+	}
+
+	#[test]
+	fn failures() {
+		let proxy = proxy(stand_in(Some((b"user", b"pass"))), Some(("user", "wrong")));
+		let mut stream = TcpStream::connect(proxy.address).unwrap();
+		let result = Handshake::new(&proxy, Command::Connect, "127.0.0.1:1".parse().unwrap()).resume(&mut stream);
+		assert!(matches!(result, Err(Error::Authentication)));
+
+		let proxy = self::proxy(stand_in(Some((b"user", b"pass"))), None);
+		let mut stream = TcpStream::connect(proxy.address).unwrap();
+		let result = Handshake::new(&proxy, Command::Connect, "127.0.0.1:1".parse().unwrap()).resume(&mut stream);
+		assert!(matches!(result, Err(Error::Method)));
+
+		// Nothing listens on the port.
+		let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+		let proxy = self::proxy(stand_in(None), None);
+		let mut stream = TcpStream::connect(proxy.address).unwrap();
+		let error = Handshake::new(&proxy, Command::Connect, closed).resume(&mut stream).unwrap_err();
+		assert!(matches!(error, Error::Reply(Reply::ConnectionRefused)));
+		assert_eq!(error.connect_error(), ConnectError::Refused);
+	}
+
+	#[test]
+	fn associate() {
+		let echo = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let destination = echo.local_addr().unwrap();
+		thread::spawn(move || {
+			let mut buf = [0; 512];
+			let (len, from) = echo.recv_from(&mut buf).unwrap();
+			echo.send_to(&buf[..len], from).unwrap();
+		});
+		let proxy = proxy(stand_in(None), None);
+		let mut control = TcpStream::connect(proxy.address).unwrap();
+		let any = "0.0.0.0:0".parse().unwrap();
+		let relay = Handshake::new(&proxy, Command::UdpAssociate, any).resume(&mut control).unwrap().unwrap();
+		assert!(relay.ip().is_unspecified());
+		let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+		socket.connect((proxy.address.ip(), relay.port())).unwrap();
+		let mut datagram = Vec::new();
+		socks::datagram_header(&Address::Ip(destination), &mut datagram);
+		datagram.extend_from_slice(b"ping");
+		socket.send(&datagram).unwrap();
+		let mut buf = [0; 512];
+		let len = socket.recv(&mut buf).unwrap();
+		let (from, data) = socks::parse_datagram(&buf[..len]).unwrap();
+		assert_eq!((from, data), (Address::Ip(destination), &b"ping"[..]));
+	}
+}
@@ -0,0 +1,105 @@
+//! Runs a server and a client as separate processes, connected over loopback.
+
+#![allow(dead_code)]
+
+use std::fmt::Debug;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::process::{self, Child, Command, Stdio};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A server and a client, which are killed once dropped.
+pub struct Tunnel {
+	server: Child,
+	client: Child,
+}
+
+impl Tunnel {
+	/// Start a server that allows loopback destinations and a client connected to it, each
+	/// with the options given on top.
+	pub fn start(server: &[&str], client: &[&str]) -> Self {
+		let listen = format!("127.0.0.1:{}", free_port());
+		let server = spawn(&[&["--listen", &listen, "--acl", "allow any 127.0.0.0/8"], server, &["server"]].concat());
+		// The client gives up if it can't connect the first time.
+		retry(|| TcpStream::connect(&listen));
+		let client = spawn(&[&["--connect", &listen], client, &["client"]].concat());
+		Self { server, client }
+	}
+}
+
+impl Drop for Tunnel {
+	fn drop(&mut self) {
+		for child in [&mut self.server, &mut self.client] {
+			let _ = child.kill();
+			let _ = child.wait();
+		}
+	}
+}
+
+fn spawn(args: &[&str]) -> Child {
+	Command::new(env!("CARGO_BIN_EXE_stupid_tunnel"))
+		.args(args)
+		.stdout(Stdio::null())
+		.stderr(Stdio::null())
+		.spawn()
+		.unwrap()
+}
+
+/// A loopback port nothing listens on right now. Ports are taken below the ephemeral range, so
+/// that neither the tests running in parallel nor their connections can take them meanwhile.
+pub fn free_port() -> u16 {
+	static NEXT: AtomicU16 = AtomicU16::new(0);
+	let base = 20000 + (process::id() % 500) as u16 * 20;
+	loop {
+		let port = base + NEXT.fetch_add(1, Ordering::Relaxed) % 20;
+		if TcpListener::bind(("127.0.0.1", port)).is_ok() && UdpSocket::bind(("127.0.0.1", port)).is_ok() {
+			return port;
+		}
+	}
+}
+
+/// Call `f` until it succeeds, as the tunnel takes a moment to come up.
+pub fn retry<T, E: Debug>(mut f: impl FnMut() -> Result<T, E>) -> T {
+	let deadline = Instant::now() + Duration::from_secs(10);
+	loop {
+		match f() {
+			Ok(t) => return t,
+			Err(e) if Instant::now() > deadline => panic!("gave up: {:?}", e),
+			Err(_) => thread::sleep(Duration::from_millis(100)),
+		}
+	}
+}
+
+/// Echo everything sent over TCP connections to the returned address.
+pub fn tcp_echo() -> SocketAddr {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let address = listener.local_addr().unwrap();
+	thread::spawn(move || {
+		for mut stream in listener.incoming().flatten() {
+			thread::spawn(move || {
+				let mut buf = [0; 4096];
+				while let Ok(n @ 1..) = stream.read(&mut buf) {
+					if stream.write_all(&buf[..n]).is_err() {
+						break;
+					}
+				}
+			});
+		}
+	});
+	address
+}
+
+/// Echo every datagram sent to the returned address.
+pub fn udp_echo() -> SocketAddr {
+	let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let address = socket.local_addr().unwrap();
+	thread::spawn(move || {
+		let mut buf = [0; 0x10000];
+		while let Ok((n, peer)) = socket.recv_from(&mut buf) {
+			let _ = socket.send_to(&buf[..n], peer);
+		}
+	});
+	address
+}
@@ -0,0 +1,68 @@
+//! End-to-end tests of local and remote forwards.
+
+mod common;
+
+use common::{free_port, retry, tcp_echo, udp_echo, Tunnel};
+use std::io::{Error, Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+/// Connect to a forward and check that what is sent comes back.
+fn echo_tcp(forward: SocketAddr) -> Result<(), Error> {
+	let mut stream = TcpStream::connect(forward)?;
+	stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+	stream.write_all(b"hello")?;
+	let mut buf = [0; 5];
+	stream.read_exact(&mut buf)?;
+	assert_eq!(&buf, b"hello");
+	Ok(())
+}
+
+/// Send datagrams to a forward until one comes back.
+fn echo_udp(forward: SocketAddr) {
+	let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+	let mut buf = [0; 16];
+	let len = retry(|| {
+		socket.send_to(b"query", forward)?;
+		socket.recv(&mut buf)
+	});
+	assert_eq!(&buf[..len], b"query");
+}
+
+/// The option for a forward from `listen` to `target`, resolving the target by name.
+fn forward(listen: SocketAddr, target: SocketAddr, udp: bool) -> String {
+	format!("{}:localhost:{}{}", listen, target.port(), if udp { "/udp" } else { "" })
+}
+
+#[test]
+fn local_tcp() {
+	let echo = tcp_echo();
+	let listen = SocketAddr::from(([127, 0, 0, 1], free_port()));
+	let _tunnel = Tunnel::start(&[], &["--local-forward", &forward(listen, echo, false)]);
+	retry(|| echo_tcp(listen));
+}
+
+#[test]
+fn local_udp() {
+	let echo = udp_echo();
+	let listen = SocketAddr::from(([127, 0, 0, 1], free_port()));
+	let _tunnel = Tunnel::start(&[], &["--local-forward", &forward(listen, echo, true)]);
+	echo_udp(listen);
+}
+
+#[test]
+fn remote_tcp() {
+	let echo = tcp_echo();
+	let listen = SocketAddr::from(([127, 0, 0, 1], free_port()));
+	let _tunnel = Tunnel::start(&["--allow-remote-forwards", "true"], &["--remote-forward", &forward(listen, echo, false)]);
+	retry(|| echo_tcp(listen));
+}
+
+#[test]
+fn remote_udp() {
+	let echo = udp_echo();
+	let listen = SocketAddr::from(([127, 0, 0, 1], free_port()));
+	let _tunnel = Tunnel::start(&["--allow-remote-forwards", "true"], &["--remote-forward", &forward(listen, echo, true)]);
+	echo_udp(listen);
+}
@@ -0,0 +1,88 @@
+//! End-to-end tests of the proxy front end of the client.
+
+mod common;
+
+use common::{free_port, retry, tcp_echo, udp_echo, Tunnel};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+const TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
+
+/// Start a tunnel whose client accepts SOCKS clients that authenticate as user:secret.
+fn socks_tunnel() -> (Tunnel, SocketAddr) {
+	let socks = SocketAddr::from(([127, 0, 0, 1], free_port()));
+	let options = ["--socks", &socks.to_string(), "--socks-username", "user", "--socks-password", "secret"];
+	(Tunnel::start(&[], &options), socks)
+}
+
+/// Authenticate to a SOCKS server and ask it for `command` to `host`:`port`, returning the
+/// connection and the address the server bound to.
+fn request(proxy: SocketAddr, password: &str, command: u8, host: &str, port: u16) -> Result<(TcpStream, SocketAddr), Error> {
+	let mut stream = TcpStream::connect(proxy)?;
+	stream.set_read_timeout(TIMEOUT)?;
+	stream.write_all(&[5, 1, 2])?;
+	let mut reply = [0; 2];
+	stream.read_exact(&mut reply)?;
+	assert_eq!(reply, [5, 2]);
+	stream.write_all(&[&[1, 4][..], b"user", &[password.len() as u8], password.as_bytes()].concat())?;
+	stream.read_exact(&mut reply)?;
+	if reply != [1, 0] {
+		return Err(Error::new(ErrorKind::PermissionDenied, "wrong username or password"));
+	}
+	stream.write_all(&[&[5, command, 0, 3, host.len() as u8][..], host.as_bytes(), &port.to_be_bytes()].concat())?;
+	let mut head = [0; 10];
+	stream.read_exact(&mut head)?;
+	if head[1] != 0 {
+		return Err(Error::other(format!("request failed with reply {}", head[1])));
+	}
+	assert_eq!(head[3], 1, "bound address is IPv4");
+	let bound = SocketAddr::from(([head[4], head[5], head[6], head[7]], u16::from_be_bytes([head[8], head[9]])));
+	Ok((stream, bound))
+}
+
+/// Connect through the proxy to `host`:`port` and check that what is sent comes back.
+fn echo_tcp(proxy: SocketAddr, host: &str, port: u16) -> Result<(), Error> {
+	let (mut stream, _) = request(proxy, "secret", 1, host, port)?;
+	stream.write_all(b"hello")?;
+	let mut buf = [0; 5];
+	stream.read_exact(&mut buf)?;
+	assert_eq!(&buf, b"hello");
+	Ok(())
+}
+
+#[test]
+fn tcp_to_name() {
+	let echo = tcp_echo();
+	let (_tunnel, proxy) = socks_tunnel();
+	retry(|| echo_tcp(proxy, "localhost", echo.port()));
+	echo_tcp(proxy, "127.0.0.1", echo.port()).unwrap();
+}
+
+#[test]
+fn wrong_password() {
+	let echo = tcp_echo();
+	let (_tunnel, proxy) = socks_tunnel();
+	retry(|| echo_tcp(proxy, "localhost", echo.port()));
+	let e = request(proxy, "guess", 1, "localhost", echo.port()).unwrap_err();
+	assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn udp_to_name() {
+	let echo = udp_echo();
+	let (_tunnel, proxy) = socks_tunnel();
+	let (_control, relay) = retry(|| request(proxy, "secret", 3, "0.0.0.0", 0));
+	let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+	let header = [&[0, 0, 0, 3, 9][..], b"localhost", &echo.port().to_be_bytes()].concat();
+	let datagram = [&header[..], b"query"].concat();
+	let mut buf = [0; 512];
+	// The first datagrams may be sent before the client is connected to the server.
+	let len = retry(|| {
+		socket.send_to(&datagram, relay)?;
+		socket.recv(&mut buf)
+	});
+	// Replies come from the name the application sent to.
+	assert_eq!(&buf[..len], &datagram[..]);
+}